@@ -0,0 +1,236 @@
+//! Receiving into real btrfs subvolumes, with the same ioctls that
+//! `btrfs receive` uses. See [Applier::btrfs].
+
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use super::Applier;
+use super::Error;
+use super::Options;
+use super::Result;
+use crate::Command;
+use crate::Ctransid;
+
+/// `BTRFS_SUBVOL_RDONLY`
+const SUBVOL_RDONLY: u64 = 1 << 1;
+
+/// `struct btrfs_ioctl_vol_args`
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; 4088],
+}
+
+/// `struct btrfs_ioctl_vol_args_v2`
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; 4040],
+}
+
+/// `struct btrfs_ioctl_timespec`
+#[repr(C)]
+#[derive(Default)]
+struct Timespec {
+    sec: u64,
+    nsec: u32,
+}
+
+/// `struct btrfs_ioctl_received_subvol_args`
+#[repr(C)]
+#[derive(Default)]
+struct ReceivedSubvolArgs {
+    uuid: [u8; 16],
+    stransid: u64,
+    rtransid: u64,
+    stime: Timespec,
+    rtime: Timespec,
+    flags: u64,
+    reserved: [u64; 16],
+}
+
+nix::ioctl_write_ptr!(subvol_create, 0x94, 14, VolArgs);
+nix::ioctl_write_ptr!(snap_create_v2, 0x94, 23, VolArgsV2);
+nix::ioctl_read!(subvol_getflags, 0x94, 25, u64);
+nix::ioctl_write_ptr!(subvol_setflags, 0x94, 26, u64);
+nix::ioctl_readwrite!(set_received_subvol, 0x94, 37, ReceivedSubvolArgs);
+
+/// Where subvolumes are received, and the one that is being received
+pub(super) struct Btrfs {
+    dir: PathBuf,
+    receiving: Option<(Uuid, Ctransid)>,
+}
+
+/// Copy `name` into an ioctl's name field, which must stay nul-terminated
+fn copy_name<const N: usize>(name: &Path) -> std::io::Result<[u8; N]> {
+    let bytes = name.as_os_str().as_bytes();
+    if name.components().count() != 1 || bytes.len() >= N {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "subvolume name must be a single path component",
+        ));
+    }
+    let mut out = [0; N];
+    out[..bytes.len()].copy_from_slice(bytes);
+    Ok(out)
+}
+
+impl Applier {
+    /// Receive each stream into a new btrfs subvolume in `dir`, named like
+    /// the sent subvolume, instead of into a plain directory. Incremental
+    /// streams start from a snapshot of their parent, which has to be given
+    /// as a [Applier::source]. At the end of each stream its subvolume gets
+    /// the received uuid and ctransid of the sent one and is made read-only,
+    /// like `btrfs receive` does, so that it can be the parent of incremental
+    /// receives later (including of the streams that follow it here).
+    pub fn btrfs(dir: impl Into<PathBuf>, options: Options) -> Self {
+        let dir = dir.into();
+        let mut applier = Self::with_options(dir.clone(), options);
+        applier.btrfs = Some(Btrfs {
+            dir,
+            receiving: None,
+        });
+        applier
+    }
+
+    /// Create the subvolume for a [crate::Subvol] or [crate::Snapshot], and
+    /// apply the rest of the stream inside it
+    pub(super) fn create_subvolume(&mut self, cmd: &Command) -> Result<()> {
+        let Some(btrfs) = &mut self.btrfs else {
+            return Ok(());
+        };
+        let (path, uuid, ctransid) = match cmd {
+            Command::Subvol(s) => (s.path(), s.uuid(), s.ctransid()),
+            Command::Snapshot(s) => (s.path(), s.uuid(), s.ctransid()),
+            _ => return Ok(()),
+        };
+        let root = btrfs.dir.join(path);
+        let io = |error| Error::Io {
+            path: root.clone(),
+            error,
+        };
+        let dir = File::open(&btrfs.dir).map_err(|error| Error::Io {
+            path: btrfs.dir.clone(),
+            error,
+        })?;
+        // SAFETY: the kernel only reads the arguments, which outlive the call
+        let res = match cmd {
+            Command::Snapshot(s) => {
+                let parent = self
+                    .sources
+                    .get(&s.clone_uuid())
+                    .ok_or(Error::CloneSource(s.clone_uuid()))?;
+                let parent = File::open(parent).map_err(|error| Error::Io {
+                    path: parent.clone(),
+                    error,
+                })?;
+                let args = VolArgsV2 {
+                    fd: parent.as_raw_fd().into(),
+                    transid: 0,
+                    flags: 0,
+                    unused: [0; 4],
+                    name: copy_name(path).map_err(io)?,
+                };
+                unsafe { snap_create_v2(dir.as_raw_fd(), &args) }
+            }
+            _ => {
+                let args = VolArgs {
+                    fd: 0,
+                    name: copy_name(path).map_err(io)?,
+                };
+                unsafe { subvol_create(dir.as_raw_fd(), &args) }
+            }
+        };
+        res.map_err(|e| io(e.into()))?;
+        btrfs.receiving = Some((uuid, ctransid));
+        self.root = root;
+        Ok(())
+    }
+
+    /// Mark the subvolume that was just received as received from the sent
+    /// one, and make it read-only
+    pub(super) fn finish_subvolume(&mut self) -> Result<()> {
+        let Some((uuid, ctransid)) = self.btrfs.as_mut().and_then(|b| b.receiving.take()) else {
+            return Ok(());
+        };
+        let io = |error: std::io::Error| Error::Io {
+            path: self.root.clone(),
+            error,
+        };
+        let subvol = File::open(&self.root).map_err(io)?;
+        let fd = subvol.as_raw_fd();
+        let mut args = ReceivedSubvolArgs {
+            uuid: *uuid.as_bytes(),
+            stransid: ctransid.0,
+            ..Default::default()
+        };
+        let mut flags = 0;
+        // SAFETY: the arguments outlive the calls
+        unsafe {
+            set_received_subvol(fd, &mut args)
+                .and_then(|_| subvol_getflags(fd, &mut flags))
+                .and_then(|_| subvol_setflags(fd, &(flags | SUBVOL_RDONLY)))
+        }
+        .map_err(|e| io(e.into()))?;
+        self.sources.insert(uuid, self.root.clone());
+        Ok(())
+    }
+
+    /// Catch up on the subvolume that `cmd` created or finished before the
+    /// checkpoint that is being resumed from
+    pub(super) fn replay_subvolume(&mut self, cmd: &Command) {
+        let Some(btrfs) = &mut self.btrfs else {
+            return;
+        };
+        let (path, uuid, ctransid) = match cmd {
+            Command::Subvol(s) => (s.path(), s.uuid(), s.ctransid()),
+            Command::Snapshot(s) => (s.path(), s.uuid(), s.ctransid()),
+            Command::End => {
+                if let Some((uuid, _)) = btrfs.receiving.take() {
+                    self.sources.insert(uuid, self.root.clone());
+                }
+                return;
+            }
+            _ => return,
+        };
+        btrfs.receiving = Some((uuid, ctransid));
+        self.root = btrfs.dir.join(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sendstream;
+
+    #[test]
+    fn btrfs() {
+        // the sizes are part of the ioctl numbers
+        assert_eq!(4096, std::mem::size_of::<VolArgs>());
+        assert_eq!(4096, std::mem::size_of::<VolArgsV2>());
+        assert_eq!(200, std::mem::size_of::<ReceivedSubvolArgs>());
+
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dir = std::env::temp_dir();
+        let mut applier = Applier::btrfs(&dir, Options::default());
+        // snapshots need their parent
+        assert!(matches!(
+            applier.apply(&sendstreams[1].commands()[0]),
+            Err(Error::CloneSource(_))
+        ));
+        // the temp dir is (most likely) not on btrfs
+        if let Err(Error::Io { path, error }) = applier.apply(&sendstreams[0].commands()[0]) {
+            assert_eq!(dir.join("demo"), path);
+            assert_eq!(Some(nix::libc::ENOTTY), error.raw_os_error());
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Refusing paths that would lead outside of the directory that a stream is
+//! applied to, see [Options::confine].
+//!
+//! [Options::confine]: super::Options::confine
+
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+use nix::libc;
+
+use super::Applier;
+use super::Error;
+use super::Result;
+use crate::Command;
+
+/// `struct open_how` from `linux/openat2.h`
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// `openat2(2)` `path` under `dir` as an `O_PATH` descriptor, without going
+/// through any symlinks or leaving `dir`. With `follow`, a symlink at `path`
+/// itself is refused too.
+fn open_beneath(dir: &File, path: &Path, follow: bool) -> std::io::Result<File> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut flags = libc::O_PATH | libc::O_CLOEXEC;
+    if !follow {
+        flags |= libc::O_NOFOLLOW;
+    }
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: 0,
+        resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_NO_MAGICLINKS,
+    };
+    loop {
+        // SAFETY: the kernel only reads the path and `how`, which outlive
+        // the call, and a descriptor that it returns belongs to nothing else
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dir.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const OpenHow,
+                std::mem::size_of::<OpenHow>(),
+            )
+        };
+        if fd >= 0 {
+            return Ok(unsafe { File::from_raw_fd(fd as i32) });
+        }
+        let error = std::io::Error::last_os_error();
+        // something was renamed while the path was resolved
+        if error.raw_os_error() != Some(libc::EAGAIN) {
+            return Err(error);
+        }
+    }
+}
+
+/// Every path that `cmd` touches, with whether a symlink at the path itself
+/// would be followed
+fn paths<'c>(cmd: &'c Command) -> Vec<(&'c Path, bool)> {
+    match cmd {
+        Command::Write(w) => vec![(&w.path, true)],
+        Command::EncodedWrite(w) => vec![(&w.path, true)],
+        Command::Truncate(t) => vec![(&t.path, true)],
+        Command::Fallocate(f) => vec![(&f.path, true)],
+        Command::Chmod(c) => vec![(&c.path, true)],
+        Command::SetXattr(x) => vec![(&x.path, true)],
+        Command::RemoveXattr(x) => vec![(&x.path, true)],
+        Command::Clone(c) => vec![(&c.dst_path, true)],
+        Command::Chown(c) => vec![(&c.path, false)],
+        Command::Utimes(u) => vec![(&u.path, false)],
+        Command::Mkdir(m) => vec![(m.path.as_path(), false)],
+        Command::Mkfile(m) => vec![(m.path.as_path(), false)],
+        Command::Mknod(m) => vec![(m.path.as_path(), false)],
+        Command::Mkfifo(m) => vec![(m.path.as_path(), false)],
+        Command::Mksock(m) => vec![(m.path.as_path(), false)],
+        Command::Symlink(s) => vec![(&s.link_name, false)],
+        Command::Link(l) => vec![(&l.link_name, false), (l.target.as_path(), false)],
+        Command::Rename(r) => vec![(&r.from, false), (&r.to, false)],
+        Command::Unlink(u) => vec![(&u.path, false)],
+        Command::Rmdir(r) => vec![(&r.path, false)],
+        Command::Subvol(_)
+        | Command::Snapshot(_)
+        | Command::Fileattr(_)
+        | Command::UpdateExtent(_)
+        | Command::End => Vec::new(),
+    }
+}
+
+impl Applier {
+    /// Make sure that `path` stays within `base`, see [super::Options::confine]
+    pub(super) fn confine(&self, base: &Path, path: &Path, follow: bool) -> Result<()> {
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let io = |error| Error::Io {
+            path: base.to_path_buf(),
+            error,
+        };
+        let dir = match File::open(base) {
+            Ok(dir) => dir,
+            // nothing can be under a directory that does not exist yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(io(e)),
+        };
+        match open_beneath(&dir, path, follow) {
+            Ok(_) => Ok(()),
+            Err(e) => match e.raw_os_error() {
+                Some(libc::EXDEV | libc::ELOOP) => Err(Error::Escape(path.to_path_buf())),
+                // whatever is missing is for the command itself to fail on
+                Some(libc::ENOENT | libc::ENOTDIR) => Ok(()),
+                _ => Err(io(e)),
+            },
+        }
+    }
+
+    /// Refuse `cmd` if any path that it touches leads outside of the root
+    pub(super) fn confine_command(&self, cmd: &Command) -> Result<()> {
+        if !self.options.confine {
+            return Ok(());
+        }
+        for (path, follow) in paths(cmd) {
+            // the file that is open already was checked when it was opened
+            if follow && matches!(&self.file, Some((p, _)) if *p == self.path(path)) {
+                continue;
+            }
+            self.confine(&self.root, path, follow)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::apply::Options;
+
+    #[test]
+    fn confine() {
+        let dir = std::env::temp_dir().join(format!("apply_confine.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        std::fs::create_dir_all(&root).expect("failed to create root");
+        std::fs::write(&outside, b"secret").expect("failed to write");
+        let options = Options {
+            confine: true,
+            ..Default::default()
+        };
+        let mut applier = Applier::with_options(&root, options);
+        let path = |p: &'static str| Cow::Borrowed(Path::new(p));
+        let data = |d: &'static [u8]| crate::Data(Cow::Borrowed(d));
+        for cmd in [
+            Command::Symlink(crate::Symlink {
+                link_name: path("link"),
+                target: crate::LinkTarget(path("../outside")),
+                ino: crate::Ino(257),
+            }),
+            Command::Symlink(crate::Symlink {
+                link_name: path("dir"),
+                target: crate::LinkTarget(path("..")),
+                ino: crate::Ino(258),
+            }),
+            Command::Mkdir(crate::Mkdir {
+                path: crate::TemporaryPath(path("real")),
+                ino: crate::Ino(259),
+            }),
+        ] {
+            applier.apply(&cmd).expect("failed to apply");
+        }
+        for (cmd, bad) in [
+            (
+                Command::Write(crate::Write {
+                    path: path("link"),
+                    offset: crate::FileOffset(0),
+                    data: data(b"pwned"),
+                }),
+                "link",
+            ),
+            (
+                Command::Write(crate::Write {
+                    path: path("dir/outside"),
+                    offset: crate::FileOffset(0),
+                    data: data(b"pwned"),
+                }),
+                "dir/outside",
+            ),
+            (
+                Command::Rename(crate::Rename {
+                    from: path("real"),
+                    to: path("../moved"),
+                }),
+                "../moved",
+            ),
+            (
+                Command::Unlink(crate::Unlink {
+                    path: path("/etc/hostname"),
+                }),
+                "/etc/hostname",
+            ),
+        ] {
+            match applier.apply(&cmd) {
+                Err(Error::Escape(p)) => assert_eq!(Path::new(bad), p),
+                res => panic!("{cmd:?}: {res:?}"),
+            }
+        }
+        assert_eq!(
+            b"secret".as_slice(),
+            std::fs::read(&outside).expect("failed to read")
+        );
+        assert!(root.join("real").is_dir());
+        // symlinks themselves can still be renamed and removed
+        applier
+            .apply(&Command::Unlink(crate::Unlink { path: path("link") }))
+            .expect("failed to unlink");
+
+        // a special file that is left out cannot be renamed over anything
+        // outside either
+        let mut applier = Applier::with_options(
+            &root,
+            Options {
+                confine: true,
+                specials: false,
+                ..Default::default()
+            },
+        );
+        applier
+            .apply(&Command::Mkfifo(crate::Mkfifo(crate::Mkspecial {
+                path: crate::TemporaryPath(path("fifo")),
+                ino: crate::Ino(260),
+                rdev: crate::Rdev(0),
+                mode: crate::Mode(libc::S_IFIFO | 0o644),
+            })))
+            .expect("failed to skip fifo");
+        let rename = Command::Rename(crate::Rename {
+            from: path("fifo"),
+            to: Cow::Owned(outside.clone()),
+        });
+        match applier.apply(&rename) {
+            Err(Error::Escape(p)) => assert_eq!(outside, p),
+            res => panic!("{rename:?}: {res:?}"),
+        }
+        assert!(outside.exists());
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
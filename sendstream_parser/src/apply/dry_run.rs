@@ -0,0 +1,431 @@
+//! Work out what applying a sendstream would do to a directory before doing
+//! it, to catch conflicts with what is already there.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::unistd::AccessFlags;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::Applier;
+use super::Options;
+use super::Ownership;
+use super::Unmapped;
+use crate::changes::ChangeReport;
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Sendstream;
+
+/// Something that would go wrong when applying a sendstream
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Issue {
+    /// Something is already at a path that the stream creates
+    Exists,
+    /// A path that the stream expects to be there is not
+    Missing,
+    /// The path is a different kind of file than the stream expects, like a
+    /// directory that is written to
+    WrongType,
+    /// Directory still has entries when the stream removes it
+    NotEmpty,
+    /// Owner is not covered by the [super::IdMap]
+    Unmapped,
+    /// Whoever is applying the stream is not allowed to do this
+    PermissionDenied,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Problem {
+    /// Index of the command that would fail
+    pub index: usize,
+    pub path: PathBuf,
+    pub issue: Issue,
+}
+
+/// What [dry_run] found out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DryRun {
+    /// Everything that the stream would change, relative to the directory
+    pub changes: ChangeReport,
+    /// Commands that would fail, in stream order. Applying stops at the first
+    /// one, but the rest are worked out as if it had succeeded.
+    pub problems: Vec<Problem>,
+}
+
+impl DryRun {
+    /// True if applying the stream would go through
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Dir,
+    File,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    kind: Kind,
+    /// Path in the directory on disk, unless the stream creates the entry
+    origin: Option<PathBuf>,
+}
+
+/// The directory as it would look partway through the stream, with
+/// everything that the stream has not touched yet looked up on disk
+struct Model<'d> {
+    dest: &'d Path,
+    /// Entries that the stream creates, renames or removes (as `None`)
+    paths: BTreeMap<PathBuf, Option<Entry>>,
+    root: bool,
+    problems: Vec<Problem>,
+    index: usize,
+}
+
+impl<'d> Model<'d> {
+    fn problem(&mut self, path: &Path, issue: Issue) {
+        self.problems.push(Problem {
+            index: self.index,
+            path: path.to_path_buf(),
+            issue,
+        });
+    }
+
+    fn disk(&self, rel: PathBuf) -> Option<Entry> {
+        let meta = std::fs::symlink_metadata(self.dest.join(&rel)).ok()?;
+        let kind = match meta.file_type() {
+            t if t.is_dir() => Kind::Dir,
+            t if t.is_file() => Kind::File,
+            _ => Kind::Other,
+        };
+        Some(Entry {
+            kind,
+            origin: Some(rel),
+        })
+    }
+
+    fn lookup(&self, path: &Path) -> Option<Entry> {
+        if let Some(e) = self.paths.get(path) {
+            return e.clone();
+        }
+        for a in path.ancestors().skip(1) {
+            if let Some(e) = self.paths.get(a) {
+                let origin = e.as_ref()?.origin.as_ref()?;
+                return self.disk(renamed(path, a, origin)?);
+            }
+        }
+        self.disk(path.to_path_buf())
+    }
+
+    /// Entry at `path`, which must exist
+    fn require(&mut self, path: &Path) -> Option<Entry> {
+        let e = self.lookup(path);
+        if e.is_none() {
+            self.problem(path, Issue::Missing);
+        }
+        e
+    }
+
+    /// Check that `path` is on disk and can be written to
+    fn writable(&mut self, path: &Path, e: &Entry) {
+        if let Some(origin) = &e.origin {
+            if nix::unistd::access(&self.dest.join(origin), AccessFlags::W_OK).is_err() {
+                self.problem(path, Issue::PermissionDenied);
+            }
+        }
+    }
+
+    /// Check that entries can be added to and removed from the directory
+    /// that `path` is in
+    fn parent(&mut self, path: &Path) {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        match self.require(parent) {
+            Some(e) if e.kind == Kind::Dir => self.writable(parent, &e),
+            Some(_) => self.problem(parent, Issue::WrongType),
+            None => (),
+        }
+    }
+
+    /// Check that the metadata of `path` can be changed, which needs
+    /// ownership of the file
+    fn owner(&mut self, path: &Path) -> Option<Entry> {
+        let e = self.require(path)?;
+        if let (Some(origin), false) = (&e.origin, self.root) {
+            let uid = std::fs::symlink_metadata(self.dest.join(origin)).map(|m| m.uid());
+            if uid.is_ok_and(|uid| uid != Uid::effective().as_raw()) {
+                self.problem(path, Issue::PermissionDenied);
+            }
+        }
+        Some(e)
+    }
+
+    fn create(&mut self, path: &Path, kind: Kind) {
+        self.parent(path);
+        if self.lookup(path).is_some() {
+            self.problem(path, Issue::Exists);
+        }
+        self.paths
+            .insert(path.to_path_buf(), Some(Entry { kind, origin: None }));
+    }
+
+    fn write(&mut self, path: &Path) {
+        match self.require(path) {
+            Some(e) if e.kind == Kind::File => self.writable(path, &e),
+            Some(_) => self.problem(path, Issue::WrongType),
+            None => (),
+        }
+    }
+
+    fn is_empty(&self, path: &Path, e: &Entry) -> bool {
+        let created = self
+            .paths
+            .iter()
+            .any(|(p, e)| e.is_some() && p.parent() == Some(path));
+        if created {
+            return false;
+        }
+        let Some(origin) = &e.origin else {
+            return true;
+        };
+        let Ok(entries) = std::fs::read_dir(self.dest.join(origin)) else {
+            return true;
+        };
+        !entries
+            .filter_map(|d| d.ok())
+            .any(|d| self.lookup(&path.join(d.file_name())).is_some())
+    }
+
+    fn remove(&mut self, path: &Path, dir: bool) {
+        self.parent(path);
+        match self.require(path) {
+            Some(e) if (e.kind == Kind::Dir) != dir => self.problem(path, Issue::WrongType),
+            Some(e) if dir && !self.is_empty(path, &e) => self.problem(path, Issue::NotEmpty),
+            _ => (),
+        }
+        self.paths.retain(|p, _| !p.starts_with(path));
+        self.paths.insert(path.to_path_buf(), None);
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        self.parent(from);
+        self.parent(to);
+        let Some(e) = self.require(from) else {
+            return;
+        };
+        match self.lookup(to) {
+            Some(old) if (old.kind == Kind::Dir) != (e.kind == Kind::Dir) => {
+                self.problem(to, Issue::WrongType)
+            }
+            Some(old) if old.kind == Kind::Dir && !self.is_empty(to, &old) => {
+                self.problem(to, Issue::NotEmpty)
+            }
+            _ => (),
+        }
+        self.paths.retain(|p, _| !p.starts_with(to));
+        let moved: Vec<_> = self
+            .paths
+            .keys()
+            .filter(|p| p.starts_with(from))
+            .cloned()
+            .collect();
+        for p in moved {
+            if let (Some(e), Some(dst)) = (self.paths.remove(&p), renamed(&p, from, to)) {
+                self.paths.insert(dst, e);
+            }
+        }
+        self.paths.insert(from.to_path_buf(), None);
+        self.paths.insert(to.to_path_buf(), Some(e));
+    }
+
+    fn chown(&mut self, path: &Path, uid: Uid, gid: Gid, ownership: &Ownership) {
+        if self.require(path).is_none() {
+            return;
+        }
+        let (uid, gid) = match ownership {
+            Ownership::Skip => return,
+            Ownership::Preserve => (uid, gid),
+            Ownership::Fixed(uid, gid) => (*uid, *gid),
+            Ownership::Map(map) => match (map.uid(uid), map.gid(gid)) {
+                (Some(uid), Some(gid)) => (uid, gid),
+                _ if map.unmapped == Unmapped::Xattr => return,
+                _ => return self.problem(path, Issue::Unmapped),
+            },
+        };
+        let groups = nix::unistd::getgroups().unwrap_or_default();
+        let own = uid == Uid::effective() && (gid == Gid::effective() || groups.contains(&gid));
+        if !self.root && !own {
+            self.problem(path, Issue::PermissionDenied);
+        }
+    }
+}
+
+/// Work out what applying `stream` to `dest` with an [Applier] would do,
+/// without changing anything. The directory is looked at as it is now, so
+/// this is only accurate if nothing else changes it in the meantime.
+pub fn dry_run(stream: &Sendstream, dest: &Path, options: &Options) -> DryRun {
+    let mut applier = Applier::with_options(dest, options.clone());
+    let mut model = Model {
+        dest,
+        paths: BTreeMap::new(),
+        root: Uid::effective().is_root(),
+        problems: Vec::new(),
+        index: 0,
+    };
+    let mut kept = Vec::new();
+    for (index, cmd) in stream.commands.iter().enumerate() {
+        if applier.skip(cmd) {
+            continue;
+        }
+        kept.push(cmd.clone());
+        model.index = index;
+        let privileged = |allowed: bool, m: &mut Model, path: &Path| {
+            if !allowed && !m.root {
+                m.problem(path, Issue::PermissionDenied);
+            }
+        };
+        match cmd {
+            Command::Subvol(_) => {
+                if let Some(parent) = dest.parent().filter(|_| !dest.exists()) {
+                    let parent_ok = nix::unistd::access(parent, AccessFlags::W_OK).is_ok();
+                    if !parent_ok {
+                        model.problem(Path::new(""), Issue::PermissionDenied);
+                    }
+                    model.paths.insert(
+                        PathBuf::new(),
+                        Some(Entry {
+                            kind: Kind::Dir,
+                            origin: None,
+                        }),
+                    );
+                }
+            }
+            Command::Snapshot(_) => match model.lookup(Path::new("")) {
+                Some(e) if e.kind == Kind::Dir => (),
+                Some(_) => model.problem(Path::new(""), Issue::WrongType),
+                None => model.problem(Path::new(""), Issue::Missing),
+            },
+            Command::Mkdir(m) => model.create(&m.path, Kind::Dir),
+            Command::Mkfile(m) => model.create(&m.path, Kind::File),
+            Command::Mknod(m) => {
+                model.create(&m.path, Kind::Other);
+                privileged(false, &mut model, &m.path);
+            }
+            Command::Mkfifo(m) => model.create(&m.path, Kind::Other),
+            Command::Mksock(m) => model.create(&m.path, Kind::Other),
+            Command::Symlink(s) => model.create(&s.link_name, Kind::Other),
+            Command::Link(l) => {
+                if let Some(e) = model.require(&l.target) {
+                    if e.kind == Kind::Dir {
+                        model.problem(&l.target, Issue::WrongType);
+                    }
+                    model.create(&l.link_name, e.kind);
+                    model.paths.insert(l.link_name.to_path_buf(), Some(e));
+                }
+            }
+            Command::Rename(r) => model.rename(&r.from, &r.to),
+            Command::Unlink(u) => model.remove(&u.path, false),
+            Command::Rmdir(r) => model.remove(&r.path, true),
+            Command::Write(w) => model.write(&w.path),
+            Command::EncodedWrite(w) => model.write(&w.path),
+            Command::Truncate(t) => model.write(&t.path),
+            Command::Fallocate(f) => model.write(&f.path),
+            Command::Clone(c) => {
+                model.require(&c.src_path);
+                model.write(&c.dst_path);
+            }
+            Command::Chown(c) => model.chown(&c.path, c.uid, c.gid, &options.ownership),
+            Command::Chmod(c) => {
+                model.owner(&c.path);
+            }
+            Command::Utimes(u) => {
+                model.owner(&u.path);
+            }
+            Command::SetXattr(x) => {
+                model.owner(&x.path);
+                privileged(x.name.starts_with(b"user."), &mut model, &x.path);
+            }
+            Command::RemoveXattr(x) => {
+                model.owner(&x.path);
+                privileged(x.name.starts_with(b"user."), &mut model, &x.path);
+            }
+            Command::Fileattr(_) | Command::UpdateExtent(_) | Command::End => (),
+        }
+    }
+    DryRun {
+        changes: Sendstream { commands: kept }.changes(),
+        problems: model.problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_dry_run.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let options = Options::default();
+
+        let full = dry_run(&sendstreams[0], &dest, &options);
+        assert!(full.is_clean(), "{:?}", full.problems);
+        assert_eq!(sendstreams[0].changes(), full.changes);
+        // there is no parent to apply the incremental to yet
+        let undo = dry_run(&sendstreams[1], &dest, &options);
+        assert!(undo.problems.contains(&Problem {
+            index: 0,
+            path: PathBuf::new(),
+            issue: Issue::Missing,
+        }));
+        assert!(!dest.exists());
+
+        sendstreams[0].apply_to(&dest).expect("failed to apply");
+        let undo = dry_run(&sendstreams[1], &dest, &options);
+        assert!(undo.is_clean(), "{:?}", undo.problems);
+        // a full sendstream can not be applied on top of itself, since its
+        // directories are renamed into place from temporary names
+        let again = dry_run(&sendstreams[0], &dest, &options);
+        assert!(again
+            .problems
+            .iter()
+            .any(|p| p.path == Path::new("hello") && p.issue == Issue::NotEmpty));
+
+        std::fs::write(dest.join("dir-to-be-deleted/extra"), b"").expect("failed to write");
+        std::fs::remove_file(dest.join("to-be-deleted")).expect("failed to remove");
+        let index = |f: fn(&Command) -> bool| {
+            sendstreams[1]
+                .commands()
+                .iter()
+                .position(f)
+                .expect("missing command")
+        };
+        assert_eq!(
+            vec![
+                Problem {
+                    index: index(|c| matches!(c, Command::Unlink(_))),
+                    path: PathBuf::from("to-be-deleted"),
+                    issue: Issue::Missing,
+                },
+                Problem {
+                    index: index(|c| matches!(c, Command::Rmdir(_))),
+                    path: PathBuf::from("dir-to-be-deleted"),
+                    issue: Issue::NotEmpty,
+                },
+            ],
+            dry_run(&sendstreams[1], &dest, &options).problems
+        );
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
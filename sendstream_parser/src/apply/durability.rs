@@ -0,0 +1,237 @@
+//! Making what has been applied survive a crash, see [Options::durability]
+//! and [Options::syncfs].
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::subject;
+use super::Applier;
+use super::Error;
+use super::Options;
+use super::Result;
+use crate::resolve::renamed;
+use crate::Command;
+
+/// When the data of applied files is flushed to disk: each option is safer
+/// and slower than the one before it, except for [Durability::AtEnd], which
+/// is as safe as [Durability::PerFile] once a stream has been applied
+/// completely and much faster on most filesystems.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the kernel to write everything back eventually
+    #[default]
+    None,
+    /// `fdatasync` each file once the stream is done writing to it, leaving
+    /// metadata like its times to be written back eventually. Directories
+    /// are synced like with [Durability::PerFile].
+    DataPerFile,
+    /// `fsync` each file once the stream is done writing to it, and every
+    /// directory that entries were added to or removed from at the end of
+    /// the stream
+    PerFile,
+    /// `fsync` every file that was written to, and every directory that
+    /// entries were added to or removed from, at the end of the stream
+    AtEnd,
+}
+
+fn parent(path: &Path) -> PathBuf {
+    path.parent().unwrap_or(Path::new("")).to_path_buf()
+}
+
+/// Move every path at or beneath `from` in `set` to where it ends up once
+/// `from` is renamed to `to`
+fn rename(set: &mut BTreeSet<PathBuf>, from: &Path, to: &Path) {
+    let moved: Vec<_> = set
+        .range(from.to_path_buf()..)
+        .take_while(|p| p.starts_with(from))
+        .cloned()
+        .collect();
+    for p in moved {
+        set.remove(&p);
+        set.extend(renamed(&p, from, to));
+    }
+}
+
+/// Open something only to `fsync` it, which works for directories and files
+/// that are not readable by whoever applies the stream
+fn open(path: &Path) -> std::io::Result<File> {
+    File::open(path).or_else(|_| std::fs::OpenOptions::new().write(true).open(path))
+}
+
+impl Options {
+    /// Whether files are synced as soon as the stream is done with them
+    fn sync_per_file(&self) -> bool {
+        matches!(
+            self.durability,
+            Durability::PerFile | Durability::DataPerFile
+        )
+    }
+}
+
+impl Applier {
+    /// Stop reusing the last file that was written to, first syncing it if
+    /// the [Options::durability] says so
+    pub(super) fn close_file(&mut self) -> Result<()> {
+        if !self.options.sync_per_file() {
+            self.file = None;
+            return Ok(());
+        }
+        // batched writes to it have to be done first
+        #[cfg(feature = "io-uring")]
+        if self.file.is_some() {
+            self.flush()?;
+        }
+        let Some((path, f)) = self.file.take() else {
+            return Ok(());
+        };
+        match self.options.durability {
+            Durability::DataPerFile => f.sync_data(),
+            _ => f.sync_all(),
+        }
+        .map_err(|error| Error::Io { path, error })
+    }
+
+    /// Close the cached file ahead of `cmd` if `cmd` renames or removes
+    /// paths and the file has to be synced, since it can not be reopened
+    /// by its name afterwards
+    pub(super) fn close_before(&mut self, cmd: &Command) -> Result<()> {
+        let closes = matches!(
+            cmd,
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_) | Command::End
+        );
+        match closes && self.options.sync_per_file() {
+            true => self.close_file(),
+            false => Ok(()),
+        }
+    }
+
+    /// `fsync` a file that has been written to, now, before its name goes
+    /// away
+    fn sync_now(&mut self, path: &Path) -> Result<()> {
+        if !self.dirty.remove(path) {
+            return Ok(());
+        }
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        let dst = self.path(path);
+        match self.tmpfile_for(path) {
+            Some(f) => f.sync_all(),
+            None => open(&dst).and_then(|f| f.sync_all()),
+        }
+        .map_err(|error| Error::Io { path: dst, error })
+    }
+
+    /// Keep track of what `cmd` changes that has to be synced at the end of
+    /// the stream
+    pub(super) fn track(&mut self, cmd: &Command) -> Result<()> {
+        let durability = self.options.durability;
+        match cmd {
+            _ if durability == Durability::None => (),
+            Command::Write(_)
+            | Command::EncodedWrite(_)
+            | Command::Clone(_)
+            | Command::Truncate(_)
+            | Command::Fallocate(_)
+                if durability == Durability::AtEnd =>
+            {
+                self.dirty.insert(subject(cmd).to_path_buf());
+            }
+            Command::Rename(r) => {
+                self.sync_now(&r.to)?;
+                self.dirty_dirs.remove(r.to.as_ref());
+                rename(&mut self.dirty, &r.from, &r.to);
+                rename(&mut self.dirty_dirs, &r.from, &r.to);
+                self.dirty_dirs.insert(parent(&r.from));
+                self.dirty_dirs.insert(parent(&r.to));
+            }
+            // the data may still be reachable through another link
+            Command::Unlink(u) => {
+                self.sync_now(&u.path)?;
+                self.dirty_dirs.insert(parent(&u.path));
+            }
+            Command::Rmdir(r) => {
+                self.dirty_dirs.remove(r.path.as_ref());
+                self.dirty_dirs.insert(parent(&r.path));
+            }
+            Command::Mkdir(_)
+            | Command::Mkfile(_)
+            | Command::Mknod(_)
+            | Command::Mkfifo(_)
+            | Command::Mksock(_)
+            | Command::Symlink(_)
+            | Command::Link(_) => {
+                self.dirty_dirs.insert(parent(subject(cmd)));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Sync everything that was kept track of, and then the whole filesystem
+    /// if [Options::syncfs] is set, at the end of a stream
+    pub(super) fn sync_changes(&mut self) -> Result<()> {
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        let dirty = std::mem::take(&mut self.dirty);
+        let dirs = std::mem::take(&mut self.dirty_dirs);
+        for path in dirty.iter().chain(&dirs) {
+            let dst = self.path(path);
+            open(&dst)
+                .and_then(|f| f.sync_all())
+                .map_err(|error| Error::Io { path: dst, error })?;
+        }
+        if !self.options.syncfs {
+            return Ok(());
+        }
+        let io = |error| Error::Io {
+            path: self.root.clone(),
+            error,
+        };
+        let root = File::open(&self.root).map_err(io)?;
+        nix::unistd::syncfs(std::os::unix::io::AsRawFd::as_raw_fd(&root)).map_err(|e| io(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn durability() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        for durability in [
+            Durability::DataPerFile,
+            Durability::PerFile,
+            Durability::AtEnd,
+        ] {
+            let dest = std::env::temp_dir().join(format!(
+                "apply_durability.{durability:?}.{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dest);
+            let options = Options {
+                durability,
+                syncfs: true,
+                ..Default::default()
+            };
+            let mut applier = Applier::with_options(&dest, options);
+            for s in &sendstreams {
+                applier.apply_all(s).expect("failed to apply");
+                // nothing is left to sync once a stream has ended
+                assert!(applier.file.is_none());
+                assert!(applier.dirty.is_empty());
+                assert!(applier.dirty_dirs.is_empty());
+            }
+            let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+            assert!(diff.is_empty(), "{diff:?}");
+            std::fs::remove_dir_all(&dest).expect("failed to clean up");
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Applying incremental streams onto the directory that holds their parent,
+//! instead of onto a copy of it, see [Applier::receipt].
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Write as _;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use super::Applier;
+use super::Error;
+use super::Result;
+use crate::resolve::renamed;
+use crate::Command;
+
+/// What an incremental stream that is applied in place has done to the files
+/// of its parent, which [crate::Clone]s from the parent read from
+pub(super) struct InPlace {
+    parent: Uuid,
+    /// Every rename, unlink and rmdir so far, in order, from which to work
+    /// out where a path in the parent is now (with `None` for paths that are
+    /// gone)
+    moves: Vec<(PathBuf, Option<PathBuf>)>,
+    /// Device and inode numbers of files that have been written to, which
+    /// no longer hold what they did in the parent
+    changed: BTreeSet<(u64, u64)>,
+}
+
+/// Which subvolume [Applier::root] holds, see [Applier::receipt]
+pub(super) struct Receipt {
+    path: PathBuf,
+    /// uuid and ctransid of the stream being applied
+    receiving: Option<(Uuid, u64)>,
+}
+
+fn read_receipt(path: &Path) -> std::io::Result<Option<(Uuid, u64)>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut words = text.split_whitespace();
+    let uuid = words.next().and_then(|u| Uuid::parse_str(u).ok());
+    let ctransid = words.next().and_then(|c| c.parse().ok());
+    match (uuid, ctransid) {
+        (Some(uuid), Some(ctransid)) => Ok(Some((uuid, ctransid))),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "receipt is not a uuid and ctransid",
+        )),
+    }
+}
+
+fn write_receipt(path: &Path, uuid: Uuid, ctransid: u64) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut f = File::create(&tmp)?;
+    writeln!(f, "{uuid} {ctransid}")?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+impl Applier {
+    /// Keep a receipt at `path` (which should not be under
+    /// [Applier::root]) of which subvolume the root holds. Every stream that
+    /// is applied completely records its uuid and ctransid there, and an
+    /// incremental stream is refused unless the receipt says that the root
+    /// holds exactly the subvolume that it was sent relative to.
+    ///
+    /// Incremental streams are always applied onto the root itself, and
+    /// unless the parent was given as an [Applier::source] their clones from
+    /// the parent read from the files that are already there, following
+    /// whatever the stream renamed. Cloning from a file of the parent that
+    /// the stream has already changed or removed is an
+    /// [Error::StaleCloneSource].
+    pub fn receipt(mut self, path: impl Into<PathBuf>) -> Self {
+        self.receipt = Some(Receipt {
+            path: path.into(),
+            receiving: None,
+        });
+        self
+    }
+
+    /// Get ready for a stream with the uuid and ctransid `receiving`, which
+    /// is incremental if it has a `parent` (with its ctransid)
+    pub(super) fn start_receiving(
+        &mut self,
+        receiving: (Uuid, u64),
+        parent: Option<(Uuid, u64)>,
+    ) -> Result<()> {
+        if let (Some(r), Some((uuid, ctransid))) = (&mut self.receipt, parent) {
+            let holds = read_receipt(&r.path).map_err(|error| Error::Io {
+                path: r.path.clone(),
+                error,
+            })?;
+            if holds != Some((uuid, ctransid)) {
+                return Err(Error::WrongParent {
+                    path: self.root.clone(),
+                    uuid,
+                    ctransid,
+                });
+            }
+        }
+        if let Some(r) = &mut self.receipt {
+            r.receiving = Some(receiving);
+        }
+        self.in_place = parent
+            .filter(|(uuid, _)| !self.sources.contains_key(uuid))
+            .map(|(parent, _)| InPlace {
+                parent,
+                moves: Vec::new(),
+                changed: BTreeSet::new(),
+            });
+        Ok(())
+    }
+
+    /// Keep track of where files of the parent go as `cmd` moves them around
+    pub(super) fn follow(&mut self, cmd: &Command) {
+        let Some(in_place) = &mut self.in_place else {
+            return;
+        };
+        match cmd {
+            Command::Rename(r) => {
+                in_place.moves.push((r.to.to_path_buf(), None));
+                in_place
+                    .moves
+                    .push((r.from.to_path_buf(), Some(r.to.to_path_buf())));
+            }
+            Command::Unlink(u) => in_place.moves.push((u.path.to_path_buf(), None)),
+            Command::Rmdir(r) => in_place.moves.push((r.path.to_path_buf(), None)),
+            _ => (),
+        }
+    }
+
+    /// Remember that `f` is about to be written to
+    pub(super) fn changing(&mut self, f: &File) -> std::io::Result<()> {
+        if let Some(in_place) = &mut self.in_place {
+            let meta = f.metadata()?;
+            in_place.changed.insert((meta.dev(), meta.ino()));
+        }
+        Ok(())
+    }
+
+    /// Where the file at `path` in the parent `uuid` is now, if the stream is
+    /// applied onto that parent and has not removed it
+    pub(super) fn parent_source(&self, uuid: Uuid, path: &Path) -> Option<Result<PathBuf>> {
+        let in_place = self.in_place.as_ref().filter(|p| p.parent == uuid)?;
+        let mut now = path.to_path_buf();
+        for (from, to) in &in_place.moves {
+            match to {
+                Some(to) => {
+                    if let Some(moved) = renamed(&now, from, to) {
+                        now = moved;
+                    }
+                }
+                None if now.starts_with(from) => {
+                    return Some(Err(Error::StaleCloneSource(path.to_path_buf())));
+                }
+                None => (),
+            }
+        }
+        Some(Ok(self.path(&now)))
+    }
+
+    /// Whether `src`, a file of the parent that is being cloned from, has
+    /// been written to
+    pub(super) fn changed(&self, src: &File) -> std::io::Result<bool> {
+        let Some(in_place) = &self.in_place else {
+            return Ok(false);
+        };
+        let meta = src.metadata()?;
+        Ok(in_place.changed.contains(&(meta.dev(), meta.ino())))
+    }
+
+    /// Record that the root now holds the subvolume that was just applied
+    pub(super) fn finish_receiving(&mut self) -> Result<()> {
+        self.in_place = None;
+        let Some(r) = &mut self.receipt else {
+            return Ok(());
+        };
+        match r.receiving.take() {
+            Some((uuid, ctransid)) => {
+                write_receipt(&r.path, uuid, ctransid).map_err(|error| Error::Io {
+                    path: r.path.clone(),
+                    error,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn in_place() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let dir = std::env::temp_dir().join(format!("apply_in_place.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (dest, receipt) = (dir.join("root"), dir.join("receipt"));
+
+        // the incremental does not go onto something that is not its parent
+        std::fs::create_dir_all(&dest).expect("failed to create root");
+        let mut applier = Applier::new(&dest).receipt(&receipt);
+        assert!(matches!(
+            applier.apply_all(&sendstreams[1]),
+            Err(Error::WrongParent { .. })
+        ));
+
+        Applier::new(&dest)
+            .receipt(&receipt)
+            .apply_all(&sendstreams[0])
+            .expect("failed to apply");
+        // a separate run, which only has the directory to go on
+        let mut applier = Applier::new(&dest).receipt(&receipt);
+        applier
+            .apply_all(&sendstreams[1])
+            .expect("failed to apply incremental");
+        let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        let Command::Snapshot(s) = &sendstreams[1].commands()[0] else {
+            panic!("demo-undo is not incremental");
+        };
+        assert_eq!(
+            Some((s.uuid(), s.ctransid().0)),
+            read_receipt(&receipt).expect("failed to read receipt")
+        );
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+
+    #[test]
+    fn stale_clone_source() {
+        let dir = std::env::temp_dir().join(format!("apply_stale.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("failed to create root");
+        std::fs::write(dir.join("a"), b"parent").expect("failed to write");
+        let parent = Uuid::from_u128(1);
+        let mut applier = Applier::new(&dir);
+        applier
+            .start_receiving((Uuid::from_u128(2), 2), Some((parent, 1)))
+            .expect("failed to start");
+        applier.follow(&Command::Rename(crate::Rename {
+            from: std::borrow::Cow::Borrowed(Path::new("a")),
+            to: std::borrow::Cow::Borrowed(Path::new("b")),
+        }));
+        match applier.parent_source(parent, Path::new("a")) {
+            Some(Ok(path)) => assert_eq!(dir.join("b"), path),
+            res => panic!("{res:?}"),
+        }
+        applier.follow(&Command::Unlink(crate::Unlink {
+            path: std::borrow::Cow::Borrowed(Path::new("b")),
+        }));
+        assert!(matches!(
+            applier.parent_source(parent, Path::new("a")),
+            Some(Err(Error::StaleCloneSource(_)))
+        ));
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,328 @@
+//! Checkpoints of how far an [Applier] has gotten, so that an apply that was
+//! interrupted can pick up where it left off instead of starting over.
+
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use super::Applier;
+use super::Error;
+use super::Result;
+use super::Summary;
+use crate::Command;
+
+static MAGIC: &str = "sendstream-apply-journal 1";
+
+/// Everything up to a checkpoint is durable, and everything after it may or
+/// may not have been applied
+pub(super) struct Journal {
+    path: PathBuf,
+    /// Commands between checkpoints
+    interval: u64,
+    /// Commands that were applied at the checkpoint that is being resumed
+    /// from, which are not applied again
+    pub(super) resume_at: u64,
+    /// Commands before this may have been applied after the checkpoint
+    /// already, so it is fine if they find their work done
+    pub(super) redo_until: u64,
+    /// Subvolume that was being received at the checkpoint
+    uuid: Option<Uuid>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, resuming from it if it exists
+    pub(super) fn open(path: PathBuf, interval: u64) -> Result<Self> {
+        let mut journal = Self {
+            path,
+            interval: interval.max(1),
+            resume_at: 0,
+            redo_until: 0,
+            uuid: None,
+        };
+        let text = match std::fs::read_to_string(&journal.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(journal),
+            Err(error) => {
+                return Err(Error::Io {
+                    path: journal.path,
+                    error,
+                })
+            }
+        };
+        let invalid = |reason| Error::Journal {
+            path: journal.path.clone(),
+            reason,
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err(invalid("not an apply journal"));
+        }
+        let mut interval = None;
+        for line in lines {
+            let (key, value) = line.split_once(' ').ok_or(invalid("malformed line"))?;
+            match key {
+                "commands" => {
+                    journal.resume_at = value.parse().map_err(|_| invalid("bad command count"))?
+                }
+                "interval" => interval = Some(value.parse().map_err(|_| invalid("bad interval"))?),
+                "uuid" => journal.uuid = Some(value.parse().map_err(|_| invalid("bad uuid"))?),
+                _ => return Err(invalid("unknown key")),
+            }
+        }
+        let interval: u64 = interval.ok_or(invalid("missing interval"))?;
+        journal.redo_until = journal.resume_at + interval;
+        Ok(journal)
+    }
+
+    /// Atomically replace the journal with a checkpoint after `commands`
+    fn write(&self, commands: u64, uuid: Option<Uuid>) -> std::io::Result<()> {
+        let mut text = format!("{MAGIC}\ncommands {commands}\ninterval {}\n", self.interval);
+        if let Some(uuid) = uuid {
+            text.push_str(&format!("uuid {uuid}\n"));
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(text.as_bytes())?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Applier {
+    /// Record a checkpoint in a journal at `path` after every `interval`
+    /// commands. If the journal already exists, the commands before its last
+    /// checkpoint are not applied again, so the same stream can be passed
+    /// from the start to finish an apply that was interrupted. Call
+    /// [Applier::finish] once the whole stream is applied to remove the
+    /// journal.
+    pub fn journal(mut self, path: impl Into<PathBuf>, interval: u64) -> Result<Self> {
+        self.journal = Some(Journal::open(path.into(), interval)?);
+        Ok(self)
+    }
+
+    /// Whether `cmd` was already applied before the checkpoint that is being
+    /// resumed from
+    pub(super) fn resuming(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|j| self.commands < j.resume_at)
+    }
+
+    /// Catch up on the state that `cmd` would have left behind, without
+    /// applying it again
+    pub(super) fn replay(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Subvol(s) => self.uuid = Some(s.uuid),
+            Command::Snapshot(s) => self.uuid = Some(s.uuid),
+            _ => (),
+        }
+        self.replay_subvolume(cmd);
+        if let (Command::Snapshot(s), None) = (cmd, &self.btrfs) {
+            self.start_receiving(
+                (s.uuid, s.ctransid.0),
+                Some((s.clone_uuid, s.clone_ctransid.0)),
+            )?;
+        }
+        self.follow(cmd);
+        self.skip(cmd);
+        match &self.journal {
+            Some(j) if self.commands + 1 == j.resume_at && j.uuid != self.uuid => {
+                Err(Error::Journal {
+                    path: j.path.clone(),
+                    reason: "journal is for a different sendstream",
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the current command may have been applied already, after the
+    /// checkpoint that is being resumed from
+    pub(super) fn redoing(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|j| self.commands < j.redo_until)
+    }
+
+    /// Whether `error` only means that `cmd` had already been applied after
+    /// the last checkpoint, before being interrupted
+    pub(super) fn redone(&self, cmd: &Command, error: &Error) -> bool {
+        let redo = self.redoing();
+        let Error::Io { error, .. } = error else {
+            return false;
+        };
+        let kind = error.kind();
+        redo && match cmd {
+            Command::Mkdir(_)
+            | Command::Mkfile(_)
+            | Command::Mknod(_)
+            | Command::Mkfifo(_)
+            | Command::Mksock(_)
+            | Command::Symlink(_)
+            | Command::Link(_) => kind == std::io::ErrorKind::AlreadyExists,
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_) => {
+                kind == std::io::ErrorKind::NotFound
+            }
+            Command::RemoveXattr(_) => error.raw_os_error() == Some(nix::libc::ENODATA),
+            _ => false,
+        }
+    }
+
+    /// Write a checkpoint if it is time for one
+    pub(super) fn checkpoint(&mut self, cmd: &Command) -> Result<()> {
+        let Some(j) = &self.journal else {
+            return Ok(());
+        };
+        if self.commands <= j.resume_at
+            || (!self.commands.is_multiple_of(j.interval) && !matches!(cmd, Command::End))
+        {
+            return Ok(());
+        }
+        self.write_checkpoint()
+    }
+
+    /// Write a checkpoint after everything that has been applied so far,
+    /// first making it durable
+    pub(super) fn write_checkpoint(&mut self) -> Result<()> {
+        // a file that has no name yet would be lost
+        self.link_tmpfile()?;
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        // nor would names that are waiting for a fallback
+        self.fall_back()?;
+        let Some(j) = &self.journal else {
+            return Ok(());
+        };
+        let io = |path: &Path| {
+            let path = path.to_path_buf();
+            move |error| Error::Io { path, error }
+        };
+        if let Some((path, f)) = &self.file {
+            f.sync_all().map_err(io(path))?;
+        }
+        let root = std::fs::File::open(&self.root).map_err(io(&self.root))?;
+        nix::unistd::syncfs(std::os::unix::io::AsRawFd::as_raw_fd(&root))
+            .map_err(|e| io(&self.root)(e.into()))?;
+        j.write(self.commands, self.uuid).map_err(io(&j.path))
+    }
+
+    /// Finish applying, removing the journal (if there is one) now that there
+    /// is nothing left to resume, and return the [Applier::summary]
+    pub fn finish(mut self) -> Result<Summary> {
+        self.close_file()?;
+        if self.resuming() {
+            return Err(Error::Journal {
+                path: self.journal.take().map(|j| j.path).unwrap_or_default(),
+                reason: "stream ended before the checkpoint",
+            });
+        }
+        if let Some(j) = self.journal.take() {
+            match std::fs::remove_file(&j.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::Io {
+                        path: j.path,
+                        error: e,
+                    })
+                }
+                _ => (),
+            }
+        }
+        Ok(std::mem::take(&mut self.summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn resume() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let dir = std::env::temp_dir().join(format!("apply_resume.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        let dest = dir.join("dest");
+        let journal = dir.join("journal");
+
+        // get interrupted partway through, some commands after the last
+        // checkpoint
+        let mut applier = Applier::new(&dest)
+            .journal(&journal, 5)
+            .expect("failed to open journal");
+        for cmd in &demo.commands()[..demo.commands().len() / 2 + 2] {
+            applier.apply(cmd).expect("failed to apply");
+        }
+        drop(applier);
+        let text = std::fs::read_to_string(&journal).expect("no journal");
+        assert!(text.contains("interval 5\n"), "{text}");
+
+        // the incremental is not the stream that the journal is for
+        let mut wrong = Applier::new(&dest)
+            .journal(&journal, 5)
+            .expect("failed to open journal");
+        let res = wrong
+            .apply_all(&sendstreams[1])
+            .and_then(|_| wrong.finish());
+        assert!(matches!(res, Err(Error::Journal { .. })), "{res:?}");
+        assert!(journal.exists());
+
+        let mut applier = Applier::new(&dest)
+            .journal(&journal, 5)
+            .expect("failed to open journal");
+        applier.apply_all(demo).expect("failed to resume");
+        applier.finish().expect("failed to finish");
+        assert!(!journal.exists());
+        let fs = Filesystem::from_sendstream(demo).expect("failed to replay");
+        let diff = diff_directory(&fs, Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+
+    #[test]
+    fn cancel() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let dir = std::env::temp_dir().join(format!("apply_cancel.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        let dest = dir.join("dest");
+        let journal = dir.join("journal");
+
+        let token = crate::cancel::CancelToken::new();
+        let canceller = token.clone();
+        let mut applier = Applier::new(&dest)
+            .journal(&journal, 1000)
+            .expect("failed to open journal")
+            .cancel_on(token)
+            .on_progress(move |p| {
+                if p.commands == 10 {
+                    canceller.cancel();
+                }
+            });
+        let res = applier.apply_all(demo);
+        assert!(matches!(res, Err(Error::Cancelled)), "{res:?}");
+        drop(applier);
+        // the checkpoint is right where it stopped, long before the interval
+        let text = std::fs::read_to_string(&journal).expect("no journal");
+        assert!(text.contains("commands 10\n"), "{text}");
+
+        let mut applier = Applier::new(&dest)
+            .journal(&journal, 1000)
+            .expect("failed to open journal");
+        applier.apply_all(demo).expect("failed to resume");
+        applier.finish().expect("failed to finish");
+        let fs = Filesystem::from_sendstream(demo).expect("failed to replay");
+        let diff = diff_directory(&fs, Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,138 @@
+//! Carrying on past failures that do not stop the rest of the stream from
+//! being applied, see [Options::lenient].
+//!
+//! [Options::lenient]: super::Options::lenient
+
+use std::path::PathBuf;
+
+use nix::libc;
+
+use super::subject;
+use super::Applier;
+use super::Error;
+use super::Result;
+use crate::Command;
+
+/// A command that failed without stopping a lenient apply
+#[derive(Debug)]
+pub struct Failure {
+    /// Name of the type of command, like `Chown`
+    pub command: String,
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+/// Whether `error` only loses what `cmd` would have applied, like some
+/// metadata or the data of a clone, instead of leaving the rest of the stream
+/// with nothing to build on
+fn tolerable(cmd: &Command, error: &Error) -> bool {
+    let errno = match error {
+        Error::Io { error, .. } => error.raw_os_error(),
+        Error::CloneSource(_) | Error::StaleCloneSource(_) => {
+            return matches!(cmd, Command::Clone(_))
+        }
+        Error::Unmapped { .. } => return matches!(cmd, Command::Chown(_)),
+        _ => return false,
+    };
+    let Some(errno) = errno else {
+        return false;
+    };
+    match cmd {
+        Command::Chown(_) | Command::Chmod(_) | Command::Utimes(_) => {
+            matches!(errno, libc::EPERM | libc::EINVAL)
+        }
+        Command::SetXattr(_) | Command::RemoveXattr(_) => matches!(
+            errno,
+            libc::EPERM
+                | libc::EACCES
+                | libc::EOPNOTSUPP
+                | libc::ENODATA
+                | libc::ERANGE
+                | libc::E2BIG
+                | libc::EINVAL
+        ),
+        Command::Fileattr(_) => matches!(
+            errno,
+            libc::EPERM | libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL
+        ),
+        // devices need privileges that the rest of the stream does not
+        Command::Mknod(_) => errno == libc::EPERM,
+        _ => false,
+    }
+}
+
+impl Applier {
+    /// Commands that failed without stopping the apply, in the order that
+    /// they were applied (per thread, for [Applier::apply_parallel])
+    pub fn failures(&self) -> &[Failure] {
+        &self.failures
+    }
+
+    /// Keep going after `error` from `cmd` if [super::Options::lenient] allows
+    pub(super) fn tolerate(&mut self, cmd: &Command, error: Error) -> Result<()> {
+        if !self.options.lenient || !tolerable(cmd, &error) {
+            return Err(error);
+        }
+        self.failures.push(Failure {
+            command: format!("{:?}", cmd.command_type()),
+            path: subject(cmd).to_path_buf(),
+            error,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+    use crate::apply::IdMap;
+    use crate::apply::Options;
+    use crate::apply::Ownership;
+    use crate::compare::diff_directory;
+    use crate::compare::Change;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn lenient() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let dest = std::env::temp_dir().join(format!("apply_lenient.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        // nobody is mapped, so every chown fails
+        let options = Options {
+            ownership: Ownership::Map(IdMap::default()),
+            lenient: true,
+            ..Default::default()
+        };
+        let mut applier = Applier::with_options(&dest, options);
+        applier.apply_all(&sendstreams[0]).expect("failed to apply");
+        let chowns = sendstreams[0].stats().commands["Chown"];
+        assert_eq!(chowns as usize, applier.failures().len());
+        assert!(applier
+            .failures()
+            .iter()
+            .all(|f| f.command == "Chown" && matches!(f.error, Error::Unmapped { .. })));
+        // everything else is there
+        let diff = diff_directory(&fs, Path::new(""), &dest).expect("failed to diff");
+        assert!(
+            diff.paths
+                .iter()
+                .all(|p| matches!(p.change, Change::Modified(_))),
+            "{diff:?}"
+        );
+
+        // losing a whole file is not something to carry on from
+        let write = Command::Write(crate::Write {
+            path: Cow::Borrowed(Path::new("missing/file")),
+            offset: crate::FileOffset(0),
+            data: crate::Data(Cow::Borrowed(b"data")),
+        });
+        assert!(applier.apply(&write).is_err());
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,345 @@
+//! Hard links that the filesystem refuses to create, see [Options::links].
+//!
+//! Streams link a new file before sending its data and metadata, so the copy
+//! or symlink only takes the place of the link at the end of the stream. Until
+//! then commands for the name that could not be linked go to the name that it
+//! links to instead.
+//!
+//! [Options::links]: super::Options::links
+
+use std::borrow::Cow;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::libc;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+
+use super::subject;
+use super::Applier;
+use super::Durability;
+use super::Result;
+use crate::resolve::renamed;
+use crate::Command;
+
+/// What takes the place of a [crate::Link] that the filesystem refuses, like
+/// one across mount points or on a filesystem without hard links (such as FAT)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum LinkFallback {
+    /// Fail like any other command
+    #[default]
+    Error,
+    /// Copy the file, along with its metadata, which then no longer shares
+    /// changes with the original
+    Copy,
+    /// Make a relative symlink to the name that was linked to
+    Symlink,
+}
+
+/// A name that could not be linked yet
+#[derive(Debug, Clone)]
+pub(super) struct Fallback {
+    name: PathBuf,
+    target: PathBuf,
+}
+
+/// Whether linking failed because of where the link would be, and not
+/// because of what it links to
+fn refused(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV | libc::EPERM | libc::EMLINK | libc::EOPNOTSUPP | libc::ENOSYS)
+    )
+}
+
+/// Copy `src` to `dst` with its ownership, permissions, xattrs and times
+fn copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::copy(src, dst)?;
+    let meta = std::fs::metadata(src)?;
+    std::os::unix::fs::chown(dst, Some(meta.uid()), Some(meta.gid()))?;
+    // chown clears setuid and setgid
+    std::fs::set_permissions(dst, meta.permissions())?;
+    for name in xattr::list(src)? {
+        if let Some(value) = xattr::get(src, &name)? {
+            xattr::set(dst, &name, &value)?;
+        }
+    }
+    nix::sys::stat::utimensat(
+        None,
+        dst,
+        &TimeSpec::new(meta.atime(), meta.atime_nsec()),
+        &TimeSpec::new(meta.mtime(), meta.mtime_nsec()),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+/// Target of a symlink at `name` that leads to `target`, relative to the
+/// directory that `name` is in
+fn relative(name: &Path, target: &Path) -> PathBuf {
+    let up = name.parent().map_or(0, |p| p.components().count());
+    std::iter::repeat_n(Path::new(".."), up)
+        .collect::<PathBuf>()
+        .join(target)
+}
+
+fn replace(dst: &Path, f: impl FnOnce() -> std::io::Result<()>) -> std::io::Result<()> {
+    match std::fs::remove_file(dst) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => f(),
+    }
+}
+
+impl Applier {
+    /// Apply a [crate::Link], keeping track of it instead if it is refused and
+    /// [super::Options::links] has a fallback
+    pub(super) fn apply_link(&mut self, l: &crate::Link) -> Result<()> {
+        let res = std::fs::hard_link(self.path(&l.target), self.path(&l.link_name));
+        match res {
+            Err(e) if self.falls_back(&e) => {
+                self.fallbacks.push(Fallback {
+                    name: l.link_name.to_path_buf(),
+                    target: l.target.to_path_buf(),
+                });
+                Ok(())
+            }
+            res => res.map_err(|error| self.err(&l.link_name, error)),
+        }
+    }
+
+    /// Whether a hard link that failed with `e` gets a fallback
+    pub(super) fn falls_back(&self, e: &std::io::Error) -> bool {
+        self.options.links != LinkFallback::Error && refused(e)
+    }
+
+    /// Keep track of a fallback, for a file that was linked by
+    /// [super::Options::atomic_files]
+    pub(super) fn fall_back_later(&mut self, name: &Path, target: &Path) {
+        self.fallbacks.push(Fallback {
+            name: name.to_path_buf(),
+            target: target.to_path_buf(),
+        });
+    }
+
+    /// Whether `path` is waiting for its fallback
+    pub(super) fn unlinked(&self, path: &Path) -> bool {
+        self.fallbacks.iter().any(|f| f.name == path)
+    }
+
+    /// What `path` links to, if it is waiting for its fallback
+    fn linked_to(&self, path: &Path) -> Option<&Path> {
+        self.fallbacks
+            .iter()
+            .find(|f| f.name == path)
+            .map(|f| f.target.as_path())
+    }
+
+    /// Keep the file at `path`, which is about to be unlinked or replaced, by
+    /// moving it to a name that is waiting for its fallback, if there is one
+    fn keep(&mut self, path: &Path) -> Result<bool> {
+        let Some(i) = self.fallbacks.iter().position(|f| f.target == path) else {
+            return Ok(false);
+        };
+        let name = self.fallbacks.remove(i).name;
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        self.close_file()?;
+        let (src, dst) = (self.path(path), self.path(&name));
+        match std::fs::rename(&src, &dst) {
+            // the reason that it could not be linked in the first place
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                copy(&src, &dst).and_then(|()| std::fs::remove_file(&src))
+            }
+            res => res,
+        }
+        .map_err(|error| self.err(path, error))?;
+        for f in &mut self.fallbacks {
+            if f.target == path {
+                f.target = name.clone();
+            }
+        }
+        if self.dirty.remove(path) {
+            self.dirty.insert(name);
+        }
+        Ok(true)
+    }
+
+    /// Follow renames and unlinks of names that are waiting for their
+    /// fallback, and of what they link to. Returns whether that was all there
+    /// was to do for `cmd`.
+    pub(super) fn relink(&mut self, cmd: &Command) -> Result<bool> {
+        if self.fallbacks.is_empty() {
+            return Ok(false);
+        }
+        match cmd {
+            Command::Rename(r) => {
+                self.keep(&r.to)?;
+                self.fallbacks.retain(|f| f.name != *r.to);
+                let unlinked = self.unlinked(&r.from);
+                for f in &mut self.fallbacks {
+                    f.name = renamed(&f.name, &r.from, &r.to).unwrap_or(f.name.clone());
+                    f.target = renamed(&f.target, &r.from, &r.to).unwrap_or(f.target.clone());
+                }
+                // unless a checkpoint already fell back, nothing is there yet
+                Ok(unlinked && std::fs::symlink_metadata(self.path(&r.from)).is_err())
+            }
+            Command::Unlink(u) if self.unlinked(&u.path) => {
+                self.fallbacks.retain(|f| f.name != *u.path);
+                Ok(std::fs::symlink_metadata(self.path(&u.path)).is_err())
+            }
+            Command::Unlink(u) => self.keep(&u.path),
+            _ => Ok(false),
+        }
+    }
+
+    /// `cmd` with names that are waiting for their fallback replaced by what
+    /// they link to, if it refers to any
+    pub(super) fn redirect<'c>(&self, cmd: &Command<'c>) -> Option<Command<'c>> {
+        if self.fallbacks.is_empty() {
+            return None;
+        }
+        let other = match cmd {
+            Command::Clone(c) => Some(&c.src_path),
+            Command::Link(l) => Some(&l.target.0),
+            _ => None,
+        };
+        let mentioned = other.is_some_and(|p| self.unlinked(p));
+        if !mentioned && !self.unlinked(subject(cmd)) {
+            return None;
+        }
+        let mut cmd = cmd.clone();
+        let paths = match &mut cmd {
+            Command::Write(w) => vec![&mut w.path],
+            Command::EncodedWrite(w) => vec![&mut w.path],
+            Command::Clone(c) => vec![&mut c.dst_path, &mut c.src_path],
+            Command::Truncate(t) => vec![&mut t.path],
+            Command::Fallocate(f) => vec![&mut f.path],
+            Command::Chmod(c) => vec![&mut c.path],
+            Command::Chown(c) => vec![&mut c.path],
+            Command::Utimes(u) => vec![&mut u.path],
+            Command::SetXattr(x) => vec![&mut x.path],
+            Command::RemoveXattr(x) => vec![&mut x.path],
+            Command::Fileattr(f) => vec![&mut f.path],
+            Command::UpdateExtent(u) => vec![&mut u.path],
+            Command::Link(l) => vec![&mut l.target.0],
+            _ => return None,
+        };
+        for path in paths {
+            if let Some(target) = self.linked_to(path) {
+                *path = Cow::Owned(target.to_path_buf());
+            }
+        }
+        Some(cmd)
+    }
+
+    /// Copy or symlink every name that is waiting for its fallback. They are
+    /// still kept track of, since a checkpoint does this before the stream
+    /// is done with them.
+    pub(super) fn fall_back(&mut self) -> Result<()> {
+        for f in self.fallbacks.clone() {
+            let (target, name) = (self.path(&f.target), self.path(&f.name));
+            match self.options.links {
+                LinkFallback::Error => Ok(()),
+                LinkFallback::Copy => replace(&name, || copy(&target, &name)),
+                LinkFallback::Symlink => replace(&name, || {
+                    std::os::unix::fs::symlink(relative(&f.name, &f.target), &name)
+                }),
+            }
+            .map_err(|error| self.err(&f.name, error))?;
+            if self.options.durability != Durability::None {
+                self.dirty.insert(f.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::Options;
+
+    fn path(p: &'static str) -> Cow<'static, Path> {
+        Cow::Borrowed(Path::new(p))
+    }
+
+    fn write(p: &'static str, offset: u64, data: &'static [u8]) -> Command<'static> {
+        Command::Write(crate::Write {
+            path: path(p),
+            offset: crate::FileOffset(offset),
+            data: crate::Data(Cow::Borrowed(data)),
+        })
+    }
+
+    #[test]
+    fn link_fallback() {
+        let dir = std::env::temp_dir().join(format!("apply_links.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for links in [LinkFallback::Copy, LinkFallback::Symlink] {
+            let dest = dir.join(format!("{links:?}"));
+            std::fs::create_dir_all(dest.join("mnt")).expect("failed to create root");
+            // links across mount points are always refused
+            if nix::mount::mount(
+                Some("tmpfs"),
+                &dest.join("mnt"),
+                Some("tmpfs"),
+                nix::mount::MsFlags::empty(),
+                None::<&str>,
+            )
+            .is_err()
+            {
+                // needs privileges
+                let _ = std::fs::remove_dir_all(&dir);
+                return;
+            }
+            let options = Options {
+                links,
+                ..Default::default()
+            };
+            let mut applier = Applier::with_options(&dest, options);
+            let commands = [
+                Command::Mkfile(crate::Mkfile {
+                    path: crate::TemporaryPath(path("o258-1-0")),
+                    ino: crate::Ino(258),
+                }),
+                Command::Rename(crate::Rename {
+                    from: path("o258-1-0"),
+                    to: path("file"),
+                }),
+                Command::Link(crate::Link {
+                    link_name: path("mnt/link"),
+                    target: crate::LinkTarget(path("file")),
+                }),
+                Command::Rename(crate::Rename {
+                    from: path("mnt/link"),
+                    to: path("mnt/renamed"),
+                }),
+                write("file", 0, b"data"),
+                write("mnt/renamed", 4, b"more"),
+                Command::Chmod(crate::Chmod {
+                    path: path("mnt/renamed"),
+                    mode: crate::Mode(0o100600),
+                }),
+                Command::End,
+            ];
+            let res = commands.iter().try_for_each(|c| applier.apply(c));
+            let copied = std::fs::read(dest.join("mnt/renamed"));
+            let target = std::fs::read_link(dest.join("mnt/renamed"));
+            let mode = std::fs::metadata(dest.join("mnt/renamed")).map(|m| m.mode());
+            nix::mount::umount(&dest.join("mnt")).expect("failed to unmount");
+            res.expect("failed to apply");
+            let data = std::fs::read(dest.join("file")).expect("failed to read");
+            assert_eq!(b"datamore".as_slice(), data);
+            assert_eq!(0o100600, mode.expect("failed to stat"));
+            match links {
+                LinkFallback::Copy => assert_eq!(data, copied.expect("failed to read copy")),
+                _ => assert_eq!(
+                    Path::new("../file"),
+                    target.expect("failed to read symlink")
+                ),
+            }
+        }
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,1470 @@
+//! Receive sendstreams into an ordinary directory, without needing btrfs. This
+//! replays each command with plain filesystem operations as it arrives, like
+//! `btrfs receive` does, so the stream never has to be held in memory.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use nix::errno::Errno;
+use nix::fcntl::FallocateFlags;
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::unistd::FchownatFlags;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use uuid::Uuid;
+
+use crate::cancel::CancelToken;
+use crate::extract::timespec;
+use crate::receive::Receiver;
+use crate::relabel::relabel;
+use crate::resolve::renamed;
+use crate::throttle::Throttle;
+use crate::Command;
+use crate::FallocateMode;
+use crate::Sendstream;
+
+mod btrfs;
+mod confine;
+mod dry_run;
+mod durability;
+mod in_place;
+mod journal;
+mod lenient;
+mod links;
+mod parallel;
+mod sparse;
+mod summary;
+mod tmpfile;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod verify;
+pub use dry_run::dry_run;
+pub use dry_run::DryRun;
+pub use dry_run::Issue;
+pub use dry_run::Problem;
+pub use durability::Durability;
+pub use lenient::Failure;
+pub use links::LinkFallback;
+pub use summary::Summary;
+pub use summary::Timing;
+pub use verify::verify;
+pub use verify::Mismatch;
+pub use verify::PathMismatch;
+pub use verify::Verification;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error("failed to apply to {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("clone source subvolume {0} is not available")]
+    CloneSource(Uuid),
+    #[error("data written to {0:?} is compressed or encrypted")]
+    Undecodable(PathBuf),
+    #[error("{path:?} is owned by {uid}:{gid}, which is not mapped")]
+    Unmapped { path: PathBuf, uid: Uid, gid: Gid },
+    #[error("{path:?} does not hold the parent subvolume {uuid} at ctransid {ctransid}")]
+    WrongParent {
+        path: PathBuf,
+        uuid: Uuid,
+        ctransid: u64,
+    },
+    #[error("clone source {0:?} in the parent has been changed or removed")]
+    StaleCloneSource(PathBuf),
+    #[error("can not resume from journal {path:?}: {reason}")]
+    Journal { path: PathBuf, reason: &'static str },
+    #[error("{0:?} leads outside of the directory that is applied to")]
+    Escape(PathBuf),
+    #[error("apply was cancelled")]
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Data is copied for [crate::Clone]s in pieces of at most this size
+const COPY_LEN: u64 = 1 << 20;
+
+/// xattr that [Unmapped::Xattr] records the original ownership in
+pub const OWNER_XATTR: &str = "user.sendstream.owner";
+
+/// A contiguous range of ids, like a line of `/proc/self/uid_map`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IdRange {
+    /// First id in the sendstream
+    pub inside: u32,
+    /// What [IdRange::inside] is mapped to on disk
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdRange {
+    fn map(&self, id: u32) -> Option<u32> {
+        let off = id.checked_sub(self.inside).filter(|o| *o < self.count)?;
+        self.outside.checked_add(off)
+    }
+}
+
+/// What to do with files whose owner is not covered by an [IdMap]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Unmapped {
+    /// Fail with [Error::Unmapped]
+    #[default]
+    Error,
+    /// Leave the file owned by whoever is applying the stream, and record the
+    /// original `uid:gid` in the [OWNER_XATTR] xattr. Symlinks can not have
+    /// `user.` xattrs, so their ownership is lost.
+    Xattr,
+}
+
+/// Translates the ownership in a sendstream to ids that an unprivileged user
+/// can actually hand out, such as their subordinate ids from `/etc/subuid`
+/// when applying inside a user namespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    pub uids: Vec<IdRange>,
+    pub gids: Vec<IdRange>,
+    pub unmapped: Unmapped,
+}
+
+impl IdMap {
+    /// Map both uids and gids `0..count` to `outside..outside + count`
+    pub fn shifted(outside: u32, count: u32) -> Self {
+        let range = IdRange {
+            inside: 0,
+            outside,
+            count,
+        };
+        Self {
+            uids: vec![range],
+            gids: vec![range],
+            unmapped: Unmapped::Error,
+        }
+    }
+
+    pub fn uid(&self, uid: Uid) -> Option<Uid> {
+        self.uids
+            .iter()
+            .find_map(|r| r.map(uid.as_raw()))
+            .map(Uid::from_raw)
+    }
+
+    pub fn gid(&self, gid: Gid) -> Option<Gid> {
+        self.gids
+            .iter()
+            .find_map(|r| r.map(gid.as_raw()))
+            .map(Gid::from_raw)
+    }
+}
+
+/// What applying a [crate::Chown] does
+enum Owner {
+    Keep,
+    Ids(Uid, Gid),
+    /// Set [OWNER_XATTR] to this instead
+    Record(String),
+}
+
+/// How the ownership set by [crate::Chown]s is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ownership {
+    /// Use the ids in the sendstream as they are, which needs privileges
+    Preserve,
+    /// Leave everything owned by whoever is applying the stream
+    Skip,
+    /// Translate the ids first
+    Map(IdMap),
+    /// Give everything this owner, whatever the stream says
+    Fixed(Uid, Gid),
+}
+
+/// Which xattrs are applied
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Xattrs {
+    #[default]
+    All,
+    /// Only the `user.` namespace, which needs no privileges and works on
+    /// most filesystems
+    User,
+    Skip,
+}
+
+impl Xattrs {
+    fn allows(self, name: &[u8]) -> bool {
+        match self {
+            Self::All => true,
+            Self::User => name.starts_with(b"user."),
+            Self::Skip => false,
+        }
+    }
+}
+
+/// What happens to SELinux labels, which are the `security.selinux` xattrs.
+/// Labels are set with `lsetxattr` after each file is created, like `btrfs
+/// receive` does, since that is when streams send them. On hosts that
+/// enforce a policy this needs privileges, and labels that the policy does
+/// not know are refused.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Selinux {
+    /// Whatever [Options::xattrs] says
+    #[default]
+    Xattrs,
+    /// Apply them even if [Options::xattrs] leaves out the `security.`
+    /// namespace
+    Apply,
+    /// Apply them after translating them through a table, like
+    /// [Sendstream::relabel_selinux] does
+    Relabel(BTreeMap<Vec<u8>, Option<Vec<u8>>>),
+    Skip,
+}
+
+/// Settings for an [Applier]. Restoring onto a foreign system often means
+/// leaving out some metadata instead of failing on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Defaults to [Ownership::Preserve] when running as root and
+    /// [Ownership::Skip] otherwise
+    pub ownership: Ownership,
+    pub xattrs: Xattrs,
+    pub selinux: Selinux,
+    /// Permission bits to clear from every mode that is applied, like a
+    /// `umask` (so `0o022` leaves nothing writable by anyone but the owner),
+    /// instead of restoring modes exactly. Defaults to 0, which clears nothing.
+    pub umask: u32,
+    /// Apply [crate::Utimes]
+    pub times: bool,
+    /// Create device nodes, fifos and sockets. If not, everything done to
+    /// them (including hard links) is left out too.
+    pub specials: bool,
+    pub durability: Durability,
+    /// `syncfs` the filesystem that the stream was applied to at the end of
+    /// each stream, which also makes the metadata of every file durable
+    pub syncfs: bool,
+    /// Create regular files with `O_TMPFILE`, and only link them into place
+    /// once the stream moves on to something else, so that files are never
+    /// visible before all of their data and metadata has been applied.
+    /// Where `O_TMPFILE` is not supported files are created as usual.
+    pub atomic_files: bool,
+    /// Carry on past failures that only lose some metadata (like a chown
+    /// without privileges or an xattr that the filesystem does not support)
+    /// or the data of a clone whose source is missing, recording them in
+    /// [Applier::failures] instead
+    pub lenient: bool,
+    /// Refuse every path that would lead outside of the [Applier::root]
+    /// with [Error::Escape], so that a malicious stream can not get at other
+    /// files through `..`, absolute paths or symlinks that it created.
+    /// Paths are resolved with `openat2(2)` (which needs Linux 5.6) and
+    /// `RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS` before each command, and
+    /// io-uring batching is not used, since it can not check what is queued.
+    pub confine: bool,
+    /// Leave holes instead of writing blocks of zeroes, punching them out of
+    /// data that was there before, so that files stay as sparse as they
+    /// were on the sending side even when the stream writes their holes
+    /// out in full
+    pub sparse: bool,
+    /// What takes the place of hard links that the filesystem refuses
+    pub links: LinkFallback,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            ownership: match Uid::effective().is_root() {
+                true => Ownership::Preserve,
+                false => Ownership::Skip,
+            },
+            xattrs: Xattrs::All,
+            selinux: Selinux::Xattrs,
+            umask: 0,
+            times: true,
+            specials: true,
+            durability: Durability::None,
+            syncfs: false,
+            atomic_files: false,
+            lenient: false,
+            confine: false,
+            sparse: false,
+            links: LinkFallback::Error,
+        }
+    }
+}
+
+impl Options {
+    /// What `mode` is applied as, after [Options::umask]
+    fn mode(&self, mode: crate::Mode) -> crate::Mode {
+        crate::Mode(mode.0 & !(self.umask & 0o7777))
+    }
+
+    fn allows_xattr(&self, name: &[u8]) -> bool {
+        match &self.selinux {
+            _ if name != b"security.selinux" => self.xattrs.allows(name),
+            Selinux::Xattrs => self.xattrs.allows(name),
+            Selinux::Apply | Selinux::Relabel(_) => true,
+            Selinux::Skip => false,
+        }
+    }
+
+    /// What an xattr that is set to `data` is applied with, if it is applied
+    fn xattr<'d>(&self, name: &[u8], data: &'d [u8]) -> Option<Cow<'d, [u8]>> {
+        match &self.selinux {
+            _ if !self.allows_xattr(name) => None,
+            Selinux::Relabel(labels) if name == b"security.selinux" => relabel(labels, data),
+            _ => Some(Cow::Borrowed(data)),
+        }
+    }
+}
+
+/// How far an [Applier] has gotten, see [Applier::on_progress]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress<'p> {
+    /// Commands applied so far, including the current one
+    pub commands: u64,
+    /// Bytes of file data written or cloned so far
+    pub bytes: u64,
+    /// Path that the current command applies to
+    pub path: &'p Path,
+    /// Commands in the whole stream, if known
+    pub total_commands: Option<u64>,
+    /// Bytes of file data in the whole stream, if known. When applying from a
+    /// [crate::CommandReader] the size of the input is a good estimate.
+    pub total_bytes: Option<u64>,
+}
+
+type ProgressFn = Box<dyn FnMut(&Progress) + Send>;
+
+/// Bytes of file data that `cmd` writes or clones
+fn data_len(cmd: &Command) -> u64 {
+    match cmd {
+        Command::Write(w) => w.data.len() as u64,
+        Command::EncodedWrite(w) => w.unencoded_file_len,
+        Command::Clone(c) => c.len.as_u64(),
+        _ => 0,
+    }
+}
+
+/// Path that `cmd` applies to, the subvolume root for commands without one
+fn subject<'c>(cmd: &'c Command) -> &'c Path {
+    match cmd {
+        Command::Subvol(_) | Command::Snapshot(_) | Command::End => Path::new(""),
+        Command::Mkdir(c) => c.path.as_path(),
+        Command::Mkfile(c) => c.path.as_path(),
+        Command::Mknod(c) => c.path.as_path(),
+        Command::Mkfifo(c) => c.path.as_path(),
+        Command::Mksock(c) => c.path.as_path(),
+        Command::Symlink(c) => &c.link_name,
+        Command::Link(c) => &c.link_name,
+        Command::Rename(c) => &c.from,
+        Command::Unlink(c) => &c.path,
+        Command::Rmdir(c) => &c.path,
+        Command::Write(c) => &c.path,
+        Command::EncodedWrite(c) => &c.path,
+        Command::Clone(c) => &c.dst_path,
+        Command::Truncate(c) => &c.path,
+        Command::Fallocate(c) => &c.path,
+        Command::Chmod(c) => &c.path,
+        Command::Chown(c) => &c.path,
+        Command::Utimes(c) => &c.path,
+        Command::SetXattr(c) => &c.path,
+        Command::RemoveXattr(c) => &c.path,
+        Command::Fileattr(c) => &c.path,
+        Command::UpdateExtent(c) => &c.path,
+    }
+}
+
+/// Replays commands onto the directory that takes the place of the
+/// subvolume root. A full sendstream creates it (if it does not exist yet),
+/// an incremental one expects it to already hold the parent subvolume.
+/// [crate::Clone]s share data where the filesystem supports reflinks, and are
+/// copied otherwise (see [CloneMethod]).
+///
+/// Ownership is only restored when running as root (unless the [Options] say
+/// otherwise), and creating device nodes or setting `trusted.` and
+/// `security.` xattrs may need privileges too. Inode flags
+/// ([crate::Fileattr]) and [crate::UpdateExtent]s (which carry no data) are
+/// ignored.
+pub struct Applier {
+    /// Batched commands, which are waited for when this is dropped and so
+    /// must come before the file that they write to
+    #[cfg(feature = "io-uring")]
+    uring: Option<uring::Uring>,
+    root: PathBuf,
+    /// uuid of the subvolume being received, which [crate::Clone]s within the
+    /// stream refer to
+    uuid: Option<Uuid>,
+    /// The file that was written to last, which is usually written to again
+    /// by the next command
+    file: Option<(PathBuf, File)>,
+    /// The regular file that [Options::atomic_files] has not linked into
+    /// place yet
+    tmpfile: Option<tmpfile::TmpFile>,
+    options: Options,
+    clone_method: CloneMethod,
+    /// Special files that are not created because of [Options::specials]
+    skipped: BTreeSet<PathBuf>,
+    /// Files that [Durability::AtEnd] syncs at the end of the stream
+    dirty: BTreeSet<PathBuf>,
+    /// Directories whose entries changed, which are synced at the end of the
+    /// stream unless the [Durability] is [Durability::None]
+    dirty_dirs: BTreeSet<PathBuf>,
+    commands: u64,
+    bytes: u64,
+    total_commands: Option<u64>,
+    total_bytes: Option<u64>,
+    on_progress: Option<ProgressFn>,
+    throttle: Option<Throttle>,
+    cancel: Option<CancelToken>,
+    failures: Vec<Failure>,
+    /// Names that [Options::links] falls back on at the end of the stream
+    fallbacks: Vec<links::Fallback>,
+    summary: Summary,
+    /// When the first command was applied, for [Summary::duration]
+    started: Option<Instant>,
+    journal: Option<journal::Journal>,
+    /// Subvolumes that snapshots and clones may refer to, by uuid
+    sources: BTreeMap<Uuid, PathBuf>,
+    btrfs: Option<btrfs::Btrfs>,
+    receipt: Option<in_place::Receipt>,
+    /// Set while an incremental stream is applied onto its parent
+    in_place: Option<in_place::InPlace>,
+}
+
+impl Applier {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_options(root, Options::default())
+    }
+
+    pub fn with_options(root: impl Into<PathBuf>, options: Options) -> Self {
+        Self {
+            #[cfg(feature = "io-uring")]
+            uring: None,
+            root: root.into(),
+            uuid: None,
+            file: None,
+            tmpfile: None,
+            options,
+            clone_method: CloneMethod::Reflink,
+            skipped: BTreeSet::new(),
+            dirty: BTreeSet::new(),
+            dirty_dirs: BTreeSet::new(),
+            commands: 0,
+            bytes: 0,
+            total_commands: None,
+            total_bytes: None,
+            on_progress: None,
+            throttle: None,
+            cancel: None,
+            failures: Vec::new(),
+            fallbacks: Vec::new(),
+            summary: Summary::default(),
+            started: None,
+            journal: None,
+            sources: BTreeMap::new(),
+            btrfs: None,
+            receipt: None,
+            in_place: None,
+        }
+    }
+
+    /// Call `f` after every command that is applied
+    pub fn on_progress(mut self, f: impl FnMut(&Progress) + Send + 'static) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Apply no faster than `throttle` allows, counting every command and
+    /// the data that it writes (see [Progress::bytes])
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Stop with [Error::Cancelled] before the next command once `token` is
+    /// cancelled. What was applied until then is left as if the stream had
+    /// ended there: everything that was written is flushed and synced as
+    /// the [Options::durability] says, a [Applier::journal] gets a
+    /// checkpoint right there (so applying the same stream again picks up
+    /// where this left off), and without a journal a file that
+    /// [Options::atomic_files] has not linked yet never shows up.
+    pub fn cancel_on(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Set the totals that [Progress] reports, which [Applier::apply_all]
+    /// does by itself
+    pub fn set_totals(&mut self, commands: Option<u64>, bytes: Option<u64>) {
+        self.total_commands = commands;
+        self.total_bytes = bytes;
+    }
+
+    /// Make the subvolume (or plain copy of one) at `path` available for
+    /// [crate::Clone]s to clone from, and for [Applier::btrfs] to snapshot,
+    /// under `uuid`. That should be the uuid that the subvolume was sent
+    /// with, which is its received uuid if it was received.
+    pub fn source(mut self, uuid: Uuid, path: impl Into<PathBuf>) -> Self {
+        self.sources.insert(uuid, path.into());
+        self
+    }
+
+    /// Directory that the subvolume is received into
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The cheapest way of carrying out [crate::Clone]s that has not turned
+    /// out to be unsupported yet
+    pub fn clone_method(&self) -> CloneMethod {
+        self.clone_method
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Open the regular file at `path` for writing, reusing the last one if
+    /// it is the same
+    fn file(&mut self, path: &Path) -> Result<&File> {
+        let dst = self.path(path);
+        if self.file.as_ref().is_some_and(|(p, _)| *p != dst) {
+            self.close_file()?;
+        }
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => {
+                let f = match self.tmpfile_for(path) {
+                    Some(f) => f.try_clone(),
+                    None => std::fs::OpenOptions::new().write(true).open(&dst),
+                };
+                match f.and_then(|f| self.changing(&f).map(|_| f)) {
+                    Ok(f) => (dst, f),
+                    Err(error) => return Err(Error::Io { path: dst, error }),
+                }
+            }
+        };
+        Ok(&self.file.insert(file).1)
+    }
+
+    /// Whether the [Options] leave out `cmd`, keeping track of the special
+    /// files that are left out, along with everything done to them
+    fn skip(&mut self, cmd: &Command) -> bool {
+        let specials = self.options.specials;
+        let path = match cmd {
+            Command::Mknod(m) if !specials => m.path.as_path(),
+            Command::Mkfifo(m) if !specials => m.path.as_path(),
+            Command::Mksock(m) if !specials => m.path.as_path(),
+            Command::Utimes(_) if !self.options.times => return true,
+            Command::SetXattr(x) if self.options.xattr(&x.name, &x.data).is_none() => return true,
+            Command::RemoveXattr(x) if !self.options.allows_xattr(&x.name) => return true,
+            Command::Link(l) if self.skipped.contains(l.target.as_path()) => &l.link_name,
+            Command::Unlink(u) => return self.skipped.remove(u.path.as_ref()),
+            Command::Rename(r) => {
+                self.skipped.remove(r.to.as_ref());
+                let moved: Vec<_> = self
+                    .skipped
+                    .iter()
+                    .filter(|p| p.starts_with(&r.from))
+                    .cloned()
+                    .collect();
+                for p in moved {
+                    self.skipped.remove(&p);
+                    self.skipped.extend(renamed(&p, &r.from, &r.to));
+                }
+                return self.skipped.contains(r.to.as_ref());
+            }
+            Command::Chmod(c) => return self.skipped.contains(c.path.as_ref()),
+            Command::Chown(c) => return self.skipped.contains(c.path.as_ref()),
+            Command::Utimes(u) => return self.skipped.contains(u.path.as_ref()),
+            Command::SetXattr(x) => return self.skipped.contains(x.path.as_ref()),
+            Command::RemoveXattr(x) => return self.skipped.contains(x.path.as_ref()),
+            Command::Fileattr(f) => return self.skipped.contains(f.path.as_ref()),
+            _ => return false,
+        };
+        self.skipped.insert(path.to_path_buf());
+        true
+    }
+
+    /// Apply a single command
+    pub fn apply(&mut self, cmd: &Command) -> Result<()> {
+        if self.cancelled() {
+            self.stop()?;
+            return Err(Error::Cancelled);
+        }
+        if self.resuming() {
+            self.replay(cmd)?;
+        } else {
+            self.pace(cmd);
+            if let Err(e) = self.timed(cmd) {
+                if !self.redone(cmd, &e) {
+                    self.tolerate(cmd, e)?;
+                }
+            }
+        }
+        self.commands += 1;
+        self.bytes += data_len(cmd);
+        self.checkpoint(cmd)?;
+        self.report(cmd);
+        Ok(())
+    }
+
+    /// Wait for [Applier::throttle] to let `cmd` through
+    fn pace(&mut self, cmd: &Command) {
+        if let Some(t) = &mut self.throttle {
+            t.wait(data_len(cmd), 1);
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Leave what has been applied in a state to pick up from, see
+    /// [Applier::cancel_on]
+    fn stop(&mut self) -> Result<()> {
+        match &self.journal {
+            Some(j) if self.commands > j.resume_at => self.write_checkpoint()?,
+            Some(_) => (),
+            None => self.discard_tmpfile(),
+        }
+        self.close_file()?;
+        self.sync_changes()
+    }
+
+    /// Tell [Applier::on_progress] about `cmd`, which has been counted
+    fn report(&mut self, cmd: &Command) {
+        if let Some(f) = &mut self.on_progress {
+            f(&Progress {
+                commands: self.commands,
+                bytes: self.bytes,
+                path: subject(cmd),
+                total_commands: self.total_commands,
+                total_bytes: self.total_bytes,
+            });
+        }
+    }
+
+    /// Apply every command of `stream`, with known totals
+    pub fn apply_all(&mut self, stream: &Sendstream) -> Result<()> {
+        self.set_totals(
+            Some(self.commands + stream.commands.len() as u64),
+            Some(self.bytes + stream.commands.iter().map(data_len).sum::<u64>()),
+        );
+        stream.commands.iter().try_for_each(|c| self.apply(c))
+    }
+
+    fn apply_command(&mut self, cmd: &Command) -> Result<()> {
+        self.confine_command(cmd)?;
+        if self.skip(cmd) {
+            // whatever a special file that is left out replaces goes away
+            if let Command::Rename(r) = cmd {
+                #[cfg(feature = "io-uring")]
+                self.flush()?;
+                return match std::fs::remove_file(self.path(&r.to)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(self.err(&r.to, e)),
+                    _ => Ok(()),
+                };
+            }
+            return Ok(());
+        }
+        if self.relink(cmd)? {
+            return Ok(());
+        }
+        if let Some(cmd) = self.redirect(cmd) {
+            return self.apply_command(&cmd);
+        }
+        self.count(cmd);
+        self.track(cmd)?;
+        self.follow(cmd);
+        self.close_before(cmd)?;
+        if self.apply_tmpfile(cmd)? {
+            return Ok(());
+        }
+        #[cfg(feature = "io-uring")]
+        if self.queue(cmd)? {
+            return Ok(());
+        }
+        if matches!(
+            cmd,
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_) | Command::End
+        ) {
+            self.close_file()?;
+        }
+        let path = match cmd {
+            Command::Write(w) => {
+                let sparse = self.options.sparse;
+                let f = self.file(&w.path)?;
+                return write(f, &w.data, w.offset.as_u64(), sparse)
+                    .map_err(|error| self.err(&w.path, error));
+            }
+            Command::EncodedWrite(w) => {
+                let data = w
+                    .decoded()
+                    .ok_or_else(|| Error::Undecodable(w.path.to_path_buf()))?;
+                let sparse = self.options.sparse;
+                let f = self.file(&w.path)?;
+                return write(f, data, w.offset.as_u64(), sparse)
+                    .map_err(|error| self.err(&w.path, error));
+            }
+            Command::Truncate(t) => {
+                let f = self.file(&t.path)?;
+                return f.set_len(t.size).map_err(|error| self.err(&t.path, error));
+            }
+            Command::Clone(c) => return self.apply_clone(c),
+            Command::Link(l) => return self.apply_link(l),
+            Command::Chown(c) => return self.apply_chown(c),
+            Command::Fallocate(f) => {
+                let file = self.file(&f.path)?;
+                return fallocate(file, f.mode, f.offset.as_u64(), f.len)
+                    .map_err(|error| self.err(&f.path, error));
+            }
+            Command::Subvol(s) if self.btrfs.is_some() => {
+                self.uuid = Some(s.uuid);
+                return self.create_subvolume(cmd);
+            }
+            Command::Snapshot(s) if self.btrfs.is_some() => {
+                self.uuid = Some(s.uuid);
+                return self.create_subvolume(cmd);
+            }
+            Command::Subvol(s) => {
+                self.uuid = Some(s.uuid);
+                self.start_receiving((s.uuid, s.ctransid.0), None)?;
+                return std::fs::create_dir_all(&self.root).map_err(|error| Error::Io {
+                    path: self.root.clone(),
+                    error,
+                });
+            }
+            Command::Snapshot(s) => {
+                self.uuid = Some(s.uuid);
+                self.start_receiving(
+                    (s.uuid, s.ctransid.0),
+                    Some((s.clone_uuid, s.clone_ctransid.0)),
+                )?;
+                return match std::fs::metadata(&self.root) {
+                    Ok(m) if m.is_dir() => Ok(()),
+                    Ok(_) => Err(std::io::Error::from_raw_os_error(nix::libc::ENOTDIR)),
+                    Err(e) => Err(e),
+                }
+                .map_err(|error| Error::Io {
+                    path: self.root.clone(),
+                    error,
+                });
+            }
+            Command::Mkdir(m) => m.path.as_path(),
+            Command::Mkfile(m) => m.path.as_path(),
+            Command::Mknod(m) => m.path.as_path(),
+            Command::Mkfifo(m) => m.path.as_path(),
+            Command::Mksock(m) => m.path.as_path(),
+            Command::Symlink(s) => &s.link_name,
+            Command::Rename(r) => &r.from,
+            Command::Unlink(u) => &u.path,
+            Command::Rmdir(r) => &r.path,
+            Command::Chmod(c) => &c.path,
+            Command::Utimes(u) => &u.path,
+            Command::SetXattr(x) => &x.path,
+            Command::RemoveXattr(x) => &x.path,
+            Command::End => {
+                self.fall_back()?;
+                self.fallbacks.clear();
+                self.sync_changes()?;
+                self.finish_subvolume()?;
+                return self.finish_receiving();
+            }
+            Command::Fileattr(_) | Command::UpdateExtent(_) => return Ok(()),
+        };
+        self.apply_path(cmd, &self.path(path))
+            .map_err(|error| self.err(path, error))
+    }
+
+    /// Apply a command that only operates on paths (and not file data)
+    fn apply_path(&self, cmd: &Command, dst: &Path) -> std::io::Result<()> {
+        let special = |kind, mode: crate::Mode, rdev| {
+            let mode = self.options.mode(mode).mode();
+            nix::sys::stat::mknod(dst, kind, mode, rdev).map_err(std::io::Error::from)
+        };
+        match cmd {
+            Command::Mkdir(_) => std::fs::create_dir(dst),
+            Command::Mkfile(_) => std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dst)
+                .map(drop),
+            Command::Mknod(m) => special(m.mode.file_type(), m.mode, m.rdev.as_u64()),
+            Command::Mkfifo(m) => special(SFlag::S_IFIFO, m.mode, 0),
+            Command::Mksock(m) => special(SFlag::S_IFSOCK, m.mode, 0),
+            Command::Symlink(s) => std::os::unix::fs::symlink(&s.target, dst),
+            Command::Rename(r) => std::fs::rename(dst, self.path(&r.to)),
+            Command::Unlink(_) => std::fs::remove_file(dst),
+            Command::Rmdir(_) => std::fs::remove_dir(dst),
+            Command::Chmod(c) => {
+                std::fs::set_permissions(dst, self.options.mode(c.mode).permissions())
+            }
+            Command::Utimes(u) => Ok(nix::sys::stat::utimensat(
+                None,
+                dst,
+                &timespec(*u.atime),
+                &timespec(*u.mtime),
+                UtimensatFlags::NoFollowSymlink,
+            )?),
+            Command::SetXattr(x) => match self.options.xattr(&x.name, &x.data) {
+                Some(data) => xattr::set(dst, OsStr::from_bytes(&x.name), &data),
+                None => Ok(()),
+            },
+            Command::RemoveXattr(x) => xattr::remove(dst, OsStr::from_bytes(&x.name)),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_chown(&self, c: &crate::Chown) -> Result<()> {
+        let dst = self.path(&c.path);
+        match self.owner(c)? {
+            Owner::Keep => Ok(()),
+            Owner::Ids(uid, gid) => nix::unistd::fchownat(
+                None,
+                &dst,
+                Some(uid),
+                Some(gid),
+                FchownatFlags::NoFollowSymlink,
+            )
+            .map_err(std::io::Error::from),
+            Owner::Record(value) => match std::fs::symlink_metadata(&dst) {
+                Ok(m) if m.file_type().is_symlink() => Ok(()),
+                Ok(_) => xattr::set(&dst, OWNER_XATTR, value.as_bytes()),
+                Err(e) => Err(e),
+            },
+        }
+        .map_err(|error| Error::Io { path: dst, error })
+    }
+
+    /// What a [crate::Chown] comes down to under the [Options::ownership]
+    fn owner(&self, c: &crate::Chown) -> Result<Owner> {
+        match &self.options.ownership {
+            Ownership::Preserve => Ok(Owner::Ids(c.uid, c.gid)),
+            Ownership::Skip => Ok(Owner::Keep),
+            Ownership::Fixed(uid, gid) => Ok(Owner::Ids(*uid, *gid)),
+            Ownership::Map(map) => match (map.uid(c.uid), map.gid(c.gid)) {
+                (Some(uid), Some(gid)) => Ok(Owner::Ids(uid, gid)),
+                _ if map.unmapped == Unmapped::Xattr => {
+                    Ok(Owner::Record(format!("{}:{}", c.uid, c.gid)))
+                }
+                _ => Err(Error::Unmapped {
+                    path: self.path(&c.path),
+                    uid: c.uid,
+                    gid: c.gid,
+                }),
+            },
+        }
+    }
+
+    /// Carry out a [crate::Clone], which may refer to the subvolume being
+    /// received or to one of the [Applier::source]s
+    fn apply_clone(&mut self, c: &crate::Clone) -> Result<()> {
+        let src_path = match self.sources.get(&c.uuid) {
+            _ if Some(c.uuid) == self.uuid => self.path(&c.src_path),
+            Some(source) => source.join(&c.src_path),
+            None => match self.parent_source(c.uuid, &c.src_path) {
+                Some(path) => path?,
+                None => return Err(Error::CloneSource(c.uuid)),
+            },
+        };
+        if self.options.confine {
+            let (base, rel) = match self.sources.get(&c.uuid) {
+                Some(source) if Some(c.uuid) != self.uuid => {
+                    (source.as_path(), src_path.strip_prefix(source))
+                }
+                _ => (self.root.as_path(), src_path.strip_prefix(&self.root)),
+            };
+            let rel = rel.map_err(|_| Error::Escape(c.src_path.to_path_buf()))?;
+            self.confine(base, rel, true)?;
+        }
+        let src = match self.tmpfile_for(&c.src_path) {
+            Some(f) if Some(c.uuid) == self.uuid => f.try_clone(),
+            _ => File::open(&src_path),
+        };
+        let src = src.map_err(|error| Error::Io {
+            path: src_path.clone(),
+            error,
+        })?;
+        match self.changed(&src) {
+            Ok(false) => (),
+            Ok(true) => return Err(Error::StaleCloneSource(c.src_path.to_path_buf())),
+            Err(error) => {
+                return Err(Error::Io {
+                    path: src_path,
+                    error,
+                })
+            }
+        }
+        let mut method = self.clone_method;
+        let sparse = self.options.sparse;
+        let dst = self.file(&c.dst_path)?;
+        let res = clone_data(
+            &mut method,
+            &src,
+            c.src_offset.as_u64(),
+            dst,
+            c.dst_offset.as_u64(),
+            c.len.as_u64(),
+            sparse,
+        );
+        self.clone_method = method;
+        res.map_err(|error| self.err(&c.dst_path, error))
+    }
+
+    fn err(&self, path: &Path, error: std::io::Error) -> Error {
+        Error::Io {
+            path: self.path(path),
+            error,
+        }
+    }
+
+    /// Apply every command from `commands` (for example a
+    /// [crate::CommandReader]), stopping at the first error. This is
+    /// [crate::receive::receive] with this as the [Receiver].
+    pub fn run<'e, 'a, I>(&mut self, commands: I) -> Result<()>
+    where
+        I: IntoIterator<Item = crate::Result<'e, Command<'a>>>,
+    {
+        crate::receive::receive(self, commands)
+    }
+}
+
+/// Apply a command given to one of the [Receiver] methods
+macro_rules! forward {
+    ($($f:ident($t:ident)),+ $(,)?) => {
+        $(
+            fn $f(&mut self, cmd: &crate::$t) -> Result<()> {
+                self.apply(&cmd.clone().into())
+            }
+        )+
+    };
+}
+
+impl Receiver for Applier {
+    type Error = Error;
+
+    forward!(
+        chmod(Chmod),
+        chown(Chown),
+        clone_range(Clone),
+        encoded_write(EncodedWrite),
+        fallocate(Fallocate),
+        fileattr(Fileattr),
+        link(Link),
+        mkdir(Mkdir),
+        mkfifo(Mkfifo),
+        mkfile(Mkfile),
+        mknod(Mknod),
+        mksock(Mksock),
+        remove_xattr(RemoveXattr),
+        rename(Rename),
+        rmdir(Rmdir),
+        set_xattr(SetXattr),
+        snapshot(Snapshot),
+        subvol(Subvol),
+        symlink(Symlink),
+        truncate(Truncate),
+        unlink(Unlink),
+        update_extent(UpdateExtent),
+        utimes(Utimes),
+        write(Write),
+    );
+
+    fn end(&mut self) -> Result<()> {
+        self.apply(&Command::End)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        self.close_file()
+    }
+
+    /// Commands are applied as they are, without going through the methods
+    /// for each kind
+    fn command(&mut self, cmd: &Command) -> Result<()> {
+        self.apply(cmd)
+    }
+}
+
+/// How [crate::Clone]s are carried out, from cheapest to most expensive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CloneMethod {
+    /// Share the extents with `FICLONERANGE`, like `btrfs receive` does
+    Reflink,
+    /// Let the kernel copy the data with `copy_file_range(2)`, which some
+    /// filesystems (like XFS and NFS) turn into a reflink or server-side copy
+    CopyFileRange,
+    /// Read the data and write it back out
+    Copy,
+}
+
+nix::ioctl_write_ptr!(ficlonerange, 0x94, 13, nix::libc::file_clone_range);
+
+/// Errors that mean a [CloneMethod] will never work on this filesystem, as
+/// opposed to just not for some particular range (like an unaligned one)
+fn unsupported(e: Errno) -> bool {
+    matches!(
+        e,
+        Errno::EOPNOTSUPP | Errno::ENOTTY | Errno::ENOSYS | Errno::EXDEV
+    )
+}
+
+/// Copy `len` bytes from `src` to `dst` with the cheapest `method` that
+/// works, downgrading `method` if it turns out to be unsupported. Copies by
+/// hand leave holes if `sparse`.
+fn clone_data(
+    method: &mut CloneMethod,
+    src: &File,
+    src_off: u64,
+    dst: &File,
+    dst_off: u64,
+    len: u64,
+    sparse: bool,
+) -> std::io::Result<()> {
+    if *method == CloneMethod::Reflink {
+        let range = nix::libc::file_clone_range {
+            src_fd: src.as_raw_fd().into(),
+            src_offset: src_off,
+            src_length: len,
+            dest_offset: dst_off,
+        };
+        // SAFETY: the kernel only reads `range`, which outlives the call
+        match unsafe { ficlonerange(dst.as_raw_fd(), &range) } {
+            Ok(_) => return Ok(()),
+            Err(e) if unsupported(e) => *method = CloneMethod::CopyFileRange,
+            // most likely a range that is not block aligned
+            Err(_) => (),
+        }
+    }
+    let mut done = 0;
+    if *method == CloneMethod::CopyFileRange {
+        while done < len {
+            let mut off_in = (src_off + done) as i64;
+            let mut off_out = (dst_off + done) as i64;
+            match nix::fcntl::copy_file_range(
+                src.as_raw_fd(),
+                Some(&mut off_in),
+                dst.as_raw_fd(),
+                Some(&mut off_out),
+                (len - done) as usize,
+            ) {
+                Ok(0) => return Ok(()),
+                Ok(n) => done += n as u64,
+                Err(Errno::EINTR) => (),
+                Err(e) => {
+                    if unsupported(e) {
+                        *method = CloneMethod::Copy;
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    copy_range(src, src_off + done, dst, dst_off + done, len - done, sparse)
+}
+
+/// Preallocate or punch a hole in `file`, emulating it (see
+/// [emulate_fallocate]) if the filesystem does not support `fallocate(2)`
+fn fallocate(file: &File, mode: FallocateMode, offset: u64, len: u64) -> std::io::Result<()> {
+    match nix::fcntl::fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::from_bits_truncate(mode.as_u32() as i32),
+        offset as i64,
+        len as i64,
+    ) {
+        Ok(()) => Ok(()),
+        Err(e) if unsupported(e) || e == Errno::EINVAL => {
+            emulate_fallocate(file, mode, offset, len)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get the same file contents as [fallocate] would: holes that are punched
+/// are filled with zeroes instead, and preallocation only changes the size.
+fn emulate_fallocate(
+    file: &File,
+    mode: FallocateMode,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    let size = file.metadata()?.len();
+    let end = offset.saturating_add(len);
+    if mode.punch_hole() {
+        let zeroes = vec![0; COPY_LEN.min(len) as usize];
+        let mut pos = offset;
+        while pos < end.min(size) {
+            let n = (end.min(size) - pos).min(COPY_LEN);
+            file.write_all_at(&zeroes[..n as usize], pos)?;
+            pos += n;
+        }
+    }
+    if !mode.keep_size() && end > size {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// Write `data` at `offset`, leaving holes if `sparse` (see [Options::sparse])
+fn write(file: &File, data: &[u8], offset: u64, sparse: bool) -> std::io::Result<()> {
+    match sparse {
+        true => sparse::write_sparse(file, data, offset),
+        false => file.write_all_at(data, offset),
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, stopping early at the end of `src`
+fn copy_range(
+    src: &File,
+    src_off: u64,
+    dst: &File,
+    dst_off: u64,
+    len: u64,
+    sparse: bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; COPY_LEN.min(len) as usize];
+    let mut done = 0;
+    while done < len {
+        let want = (len - done).min(COPY_LEN) as usize;
+        let n = src.read_at(&mut buf[..want], src_off + done)?;
+        if n == 0 {
+            break;
+        }
+        write(dst, &buf[..n], dst_off + done, sparse)?;
+        done += n as u64;
+    }
+    Ok(())
+}
+
+impl<'a> Sendstream<'a> {
+    /// Receive this sendstream into the directory `dest`, see [Applier].
+    pub fn apply_to(&self, dest: &Path) -> Result<()> {
+        Applier::new(dest).apply_all(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::compare::Change;
+    use crate::compare::Delta;
+    use crate::fs::Filesystem;
+    use crate::CommandReader;
+
+    #[test]
+    fn apply_demo() {
+        let input = include_bytes!("../../testdata/demo.sendstream");
+        let sendstreams = Sendstream::parse_all(input).expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+
+        let dest = std::env::temp_dir().join(format!("apply_demo.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        // without root there are no device nodes or owners to compare, and
+        // the incremental can not write to the read-only hello/msg
+        if !Uid::effective().is_root() {
+            let options = Options {
+                specials: false,
+                ..Default::default()
+            };
+            Applier::with_options(&dest, options.clone())
+                .apply_all(&sendstreams[0])
+                .expect("failed to apply");
+            let verification = verify(&fs[0], &dest, &options).expect("failed to verify");
+            assert!(verification.is_clean(), "{:?}", verification.mismatches);
+            std::fs::remove_dir_all(&dest).expect("failed to clean up");
+            return;
+        }
+        sendstreams[0].apply_to(&dest).expect("failed to apply");
+        let diff = diff_directory(&fs[0], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        sendstreams[1]
+            .apply_to(&dest)
+            .expect("failed to apply incremental");
+        let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        assert_eq!(
+            b"Goodbye!\n".as_slice(),
+            std::fs::read(dest.join("hello/msg")).expect("failed to read")
+        );
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+
+        // the same, streamed straight from the reader
+        Applier::new(&dest)
+            .run(CommandReader::new(&input[..]))
+            .expect("failed to apply");
+        let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn id_map() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let stream = sendstreams[0]
+            .extract_paths(&[PathBuf::from("hello")])
+            .expect("failed to extract");
+        let fs = Filesystem::from_sendstream(&stream).expect("failed to replay");
+        let msg = fs.get(Path::new("hello/msg")).expect("missing hello/msg");
+        let (uid, gid) = (
+            msg.uid().expect("no uid").as_raw(),
+            msg.gid().expect("no gid").as_raw(),
+        );
+
+        let dest = std::env::temp_dir().join(format!("apply_id_map.{}", std::process::id()));
+        let apply = |ownership| {
+            let _ = std::fs::remove_dir_all(&dest);
+            let mut applier = Applier::with_options(
+                &dest,
+                Options {
+                    ownership,
+                    ..Default::default()
+                },
+            );
+            stream.commands().iter().try_for_each(|c| applier.apply(c))
+        };
+        // only root can give files to the mapped owners
+        if Uid::effective().is_root() {
+            apply(Ownership::Map(IdMap::shifted(100000, 65536))).expect("failed to apply");
+            let meta = std::fs::metadata(dest.join("hello/msg")).expect("missing");
+            assert_eq!((uid + 100000, gid + 100000), (meta.uid(), meta.gid()));
+        }
+
+        let nothing = IdMap::default();
+        assert!(matches!(
+            apply(Ownership::Map(nothing.clone())),
+            Err(Error::Unmapped { .. })
+        ));
+        apply(Ownership::Map(IdMap {
+            unmapped: Unmapped::Xattr,
+            ..nothing
+        }))
+        .expect("failed to apply");
+        assert_eq!(
+            Some(format!("{uid}:{gid}").into_bytes()),
+            xattr::get(dest.join("hello/msg"), OWNER_XATTR).expect("failed to get xattr")
+        );
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn policy() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_policy.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let mut applier = Applier::with_options(
+            &dest,
+            Options {
+                xattrs: Xattrs::Skip,
+                times: false,
+                specials: false,
+                ..Default::default()
+            },
+        );
+        for s in &sendstreams {
+            s.commands()
+                .iter()
+                .try_for_each(|c| applier.apply(c))
+                .expect("failed to apply");
+        }
+        assert_eq!(
+            None,
+            xattr::get(dest.join("hello/msg"), "user.antlir.demo").expect("failed to get xattr")
+        );
+        for special in ["null", "myfifo", "socket-node.sock"] {
+            assert!(!dest.join(special).exists(), "{special} exists");
+        }
+        // nothing else should be different
+        let mut fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let diff = diff_directory(&fs.remove(1), Path::new(""), &dest).expect("failed to diff");
+        for d in &diff.paths {
+            match &d.change {
+                Change::Removed => assert!(applier.skipped.contains(&d.path), "{d:?}"),
+                Change::Modified(deltas) => assert!(
+                    deltas
+                        .iter()
+                        .all(|d| matches!(d, Delta::Mtime { .. } | Delta::Xattr { .. })),
+                    "{d:?}"
+                ),
+                Change::Added => panic!("{d:?}"),
+            }
+        }
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn selinux() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_selinux.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let labels = BTreeMap::from([
+            (
+                b"system_u:object_r:etc_t:s0".to_vec(),
+                Some(b"system_u:object_r:container_file_t:s0".to_vec()),
+            ),
+            (b"system_u:object_r:tmp_t:s0".to_vec(), None),
+        ]);
+        let mut applier = Applier::with_options(
+            &dest,
+            Options {
+                xattrs: Xattrs::User,
+                selinux: Selinux::Relabel(labels),
+                ..Default::default()
+            },
+        );
+        let label = |path: &'static str, label: &'static [u8]| -> Command<'static> {
+            crate::SetXattr {
+                path: Cow::Borrowed(Path::new(path)),
+                name: crate::XattrName(Cow::Borrowed(b"security.selinux")),
+                data: crate::XattrData(Cow::Borrowed(label)),
+            }
+            .into()
+        };
+        let (end, commands) = sendstreams[0].commands().split_last().expect("empty");
+        for cmd in commands
+            .iter()
+            .cloned()
+            .chain([
+                label("hello/msg", b"system_u:object_r:etc_t:s0\0"),
+                label("hello/lorem", b"system_u:object_r:tmp_t:s0\0"),
+                label("hello", b"system_u:object_r:usr_t:s0\0"),
+            ])
+            .chain([end.clone()])
+        {
+            applier.apply(&cmd).expect("failed to apply");
+        }
+        let get = |path| xattr::get(dest.join(path), "security.selinux").expect("failed to get");
+        assert_eq!(
+            Some(b"system_u:object_r:container_file_t:s0\0".to_vec()),
+            get("hello/msg")
+        );
+        assert_eq!(None, get("hello/lorem"));
+        assert_eq!(Some(b"system_u:object_r:usr_t:s0\0".to_vec()), get("hello"));
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn umask_and_owner() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let dest = std::env::temp_dir().join(format!("apply_umask.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        // owners that can be given without being root
+        let (uid, gid) = (Uid::effective(), Gid::effective());
+        let options = Options {
+            ownership: Ownership::Fixed(uid, gid),
+            umask: 0o077,
+            specials: uid.is_root(),
+            ..Default::default()
+        };
+        let mut applier = Applier::with_options(&dest, options.clone());
+        applier.apply_all(&sendstreams[0]).expect("failed to apply");
+        let meta = std::fs::metadata(dest.join("hello")).expect("no hello");
+        assert_eq!((uid.as_raw(), gid.as_raw()), (meta.uid(), meta.gid()));
+        assert_eq!(0, meta.mode() & 0o077);
+        let verification = verify(&fs, &dest, &options).expect("failed to verify");
+        assert!(verification.is_clean(), "{:?}", verification.mismatches);
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn progress() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_progress.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut applier = Applier::new(&dest).on_progress({
+            let seen = seen.clone();
+            move |p| {
+                if let Ok(mut seen) = seen.lock() {
+                    seen.push((p.commands, p.bytes, p.path.to_path_buf(), p.total_bytes));
+                }
+            }
+        });
+        applier.apply_all(&sendstreams[0]).expect("failed to apply");
+        let seen = seen.lock().expect("poisoned");
+        assert_eq!(sendstreams[0].commands().len(), seen.len());
+        let (commands, bytes, _, total) = seen.last().cloned().expect("no progress");
+        assert_eq!((seen.len() as u64, Some(bytes)), (commands, total));
+        assert!(bytes > 2 * 223446);
+        assert!(seen.iter().any(|(_, _, p, _)| p == Path::new("hello/msg")));
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn fallocate() {
+        let dir = std::env::temp_dir().join(format!("apply_fallocate.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        let keep_size = nix::libc::FALLOC_FL_KEEP_SIZE as u32;
+        let punch = nix::libc::FALLOC_FL_PUNCH_HOLE as u32 | keep_size;
+        for emulate in [false, true] {
+            let path = dir.join(format!("emulate-{emulate}"));
+            std::fs::write(&path, b"Hello world!\n").expect("failed to write");
+            let f = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .expect("failed to open");
+            let fallocate = |mode, offset, len| match emulate {
+                false => super::fallocate(&f, FallocateMode(mode), offset, len),
+                true => emulate_fallocate(&f, FallocateMode(mode), offset, len),
+            };
+            fallocate(punch, 5, 100).expect("failed to punch hole");
+            fallocate(keep_size, 0, 4096).expect("failed to preallocate");
+            assert_eq!(
+                b"Hello\0\0\0\0\0\0\0\0".as_slice(),
+                std::fs::read(&path).expect("failed to read")
+            );
+            fallocate(0, 0, 20).expect("failed to preallocate");
+            assert_eq!(20, f.metadata().expect("failed to stat").len());
+        }
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+
+    #[test]
+    fn clone_fallback() {
+        let dir = std::env::temp_dir().join(format!("clone_fallback.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        let data: Vec<u8> = (0..3 * COPY_LEN).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("src"), &data).expect("failed to write");
+        let src = File::open(dir.join("src")).expect("failed to open");
+        for method in [
+            CloneMethod::Reflink,
+            CloneMethod::CopyFileRange,
+            CloneMethod::Copy,
+        ] {
+            let dst = File::create(dir.join(format!("{method:?}"))).expect("failed to create");
+            let mut m = method;
+            // not block aligned, and running past the end of the source
+            clone_data(&mut m, &src, 7, &dst, 3, 3 * COPY_LEN, false).expect("failed to clone");
+            assert!(m >= method);
+            let mut expected = vec![0; 3];
+            expected.extend_from_slice(&data[7..]);
+            assert_eq!(
+                expected,
+                std::fs::read(dir.join(format!("{method:?}"))).expect("failed to read")
+            );
+        }
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,426 @@
+//! Applying the commands for different files at the same time, see
+//! [Applier::apply_parallel].
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+
+use uuid::Uuid;
+
+use super::data_len;
+use super::subject;
+use super::Applier;
+use super::CloneMethod;
+use super::Durability;
+use super::Error;
+use super::Failure;
+use super::LinkFallback;
+use super::Options;
+use super::Result;
+use super::Summary;
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Sendstream;
+
+enum Job<'c> {
+    Apply(&'c Command<'c>),
+    /// Paths were renamed or removed (or the stream is ending), so files
+    /// that were opened by path can not be reused
+    Forget,
+}
+
+/// Jobs that each worker has finished, and the first error any of them ran
+/// into (after which they stop applying anything)
+struct Done {
+    jobs: Vec<u64>,
+    error: Option<Error>,
+}
+
+type Shared = (Mutex<Done>, Condvar);
+
+fn lock(shared: &Shared) -> MutexGuard<'_, Done> {
+    shared.0.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a worker needs to build its own [Applier], which can not be sent to
+/// another thread as a whole
+struct Setup {
+    root: PathBuf,
+    options: Options,
+    uuid: Option<Uuid>,
+    sources: BTreeMap<Uuid, PathBuf>,
+    clone_method: CloneMethod,
+}
+
+fn work(
+    n: usize,
+    setup: Setup,
+    jobs: mpsc::Receiver<Job>,
+    shared: &Shared,
+) -> (Summary, Vec<Failure>) {
+    let mut applier = Applier::with_options(setup.root, setup.options);
+    applier.uuid = setup.uuid;
+    applier.sources = setup.sources;
+    applier.clone_method = setup.clone_method;
+    for job in jobs {
+        let failed = lock(shared).error.is_some();
+        let res = match job {
+            Job::Apply(cmd) if !failed => match applier.timed(cmd) {
+                Err(e) => applier.tolerate(cmd, e),
+                ok => ok,
+            },
+            Job::Apply(_) => Ok(()),
+            Job::Forget => applier.close_file(),
+        };
+        let mut done = lock(shared);
+        if let Err(e) = res {
+            done.error.get_or_insert(e);
+        }
+        done.jobs[n] += 1;
+        shared.1.notify_all();
+    }
+    (applier.summary, applier.failures)
+}
+
+/// Commands that only change one existing file (or, for clones, read
+/// another), which are handed out to the workers
+fn per_file(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Write(_)
+            | Command::EncodedWrite(_)
+            | Command::Clone(_)
+            | Command::Truncate(_)
+            | Command::Fallocate(_)
+            | Command::Chmod(_)
+            | Command::Chown(_)
+            | Command::Utimes(_)
+            | Command::SetXattr(_)
+            | Command::RemoveXattr(_)
+            | Command::Fileattr(_)
+            | Command::UpdateExtent(_)
+    )
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Hands out commands to the workers, so that the commands for each file run
+/// in order on one of them, and waits for them whenever a command applied on
+/// this thread depends on what they are doing
+struct Scheduler<'s, 'c> {
+    workers: Vec<mpsc::Sender<Job<'c>>>,
+    sent: Vec<u64>,
+    shared: &'s Shared,
+    /// Worker that each path's last command went to, and how many jobs it
+    /// has to finish for that command to be done
+    pending: BTreeMap<PathBuf, (usize, u64)>,
+    /// Paths with more than one name, which all go to the first worker so
+    /// that their commands stay in order
+    linked: BTreeSet<PathBuf>,
+    next: usize,
+}
+
+impl<'s, 'c> Scheduler<'s, 'c> {
+    fn send(&mut self, worker: usize, job: Job<'c>) -> Result<()> {
+        self.sent[worker] += 1;
+        self.workers[worker]
+            .send(job)
+            .map_err(|_| std::io::Error::other("apply worker exited"))
+            .map_err(|error| Error::Io {
+                path: PathBuf::new(),
+                error,
+            })
+    }
+
+    /// Block until `worker` has finished `jobs` jobs, or any worker failed
+    fn wait(&self, worker: usize, jobs: u64) -> Result<()> {
+        let mut done = lock(self.shared);
+        while done.jobs[worker] < jobs && done.error.is_none() {
+            done = self
+                .shared
+                .1
+                .wait(done)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        match done.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait for everything that was handed out for `path` (and, with
+    /// `below`, everything under it) to be done
+    fn settle(&mut self, path: &Path, below: bool) -> Result<()> {
+        let paths: Vec<_> = match below {
+            true => self
+                .pending
+                .range(path.to_path_buf()..)
+                .take_while(|(p, _)| p.starts_with(path))
+                .map(|(p, _)| p.clone())
+                .collect(),
+            false => self
+                .pending
+                .get_key_value(path)
+                .map(|(p, _)| p.clone())
+                .into_iter()
+                .collect(),
+        };
+        for p in paths {
+            if let Some((worker, jobs)) = self.pending.remove(&p) {
+                self.wait(worker, jobs)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn settle_all(&mut self) -> Result<()> {
+        self.pending.clear();
+        for worker in 0..self.workers.len() {
+            self.wait(worker, self.sent[worker])?;
+        }
+        Ok(())
+    }
+
+    /// Wait for every worker to be done, with their files closed
+    fn finish_all(&mut self) -> Result<()> {
+        for worker in 0..self.workers.len() {
+            self.send(worker, Job::Forget)?;
+        }
+        self.settle_all()
+    }
+
+    fn dispatch(&mut self, cmd: &'c Command<'c>) -> Result<()> {
+        let path = subject(cmd);
+        let worker = match self.pending.get(path) {
+            _ if self.linked.contains(path) => 0,
+            Some((worker, _)) => *worker,
+            None => {
+                self.next = (self.next + 1) % self.workers.len();
+                self.next
+            }
+        };
+        // clones read from another file, which has to be done being
+        // written, and must not be written again until the clone is done
+        let source = match cmd {
+            Command::Clone(c) => Some(c.src_path.as_ref()),
+            _ => None,
+        };
+        if let Some((other, jobs)) = source.and_then(|src| self.pending.get(src)) {
+            if *other != worker {
+                self.wait(*other, *jobs)?;
+            }
+        }
+        self.send(worker, Job::Apply(cmd))?;
+        let mark = (worker, self.sent[worker]);
+        self.pending.insert(path.to_path_buf(), mark);
+        if let Some(src) = source.filter(|src| !self.linked.contains(*src)) {
+            self.pending.insert(src.to_path_buf(), mark);
+        }
+        Ok(())
+    }
+
+    /// Wait for whatever `cmd` depends on before it is applied on this thread
+    fn prepare(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Rename(r) => {
+                self.settle(&r.from, true)?;
+                self.settle(&r.to, true)?;
+                self.settle(parent(&r.from), false)?;
+                self.settle(parent(&r.to), false)?;
+            }
+            Command::Unlink(_) | Command::Rmdir(_) => {
+                let path = subject(cmd);
+                self.settle(path, true)?;
+                self.settle(parent(path), false)?;
+            }
+            Command::Link(l) => {
+                self.settle(l.target.as_path(), false)?;
+                self.settle(parent(&l.link_name), false)?;
+            }
+            // files that are synced once written have to be closed first
+            Command::End => self.finish_all()?,
+            Command::Subvol(_) | Command::Snapshot(_) => self.settle_all()?,
+            _ => self.settle(parent(subject(cmd)), false)?,
+        }
+        Ok(())
+    }
+
+    /// Keep track of the names of linked files after `cmd` was applied
+    fn applied(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Link(l) => {
+                self.linked.insert(l.target.to_path_buf());
+                self.linked.insert(l.link_name.to_path_buf());
+                return Ok(());
+            }
+            Command::Rename(r) => {
+                self.linked.remove(r.to.as_ref());
+                let moved: Vec<_> = self
+                    .linked
+                    .range(r.from.to_path_buf()..)
+                    .take_while(|p| p.starts_with(&r.from))
+                    .cloned()
+                    .collect();
+                for p in moved {
+                    self.linked.remove(&p);
+                    self.linked.extend(renamed(&p, &r.from, &r.to));
+                }
+            }
+            Command::Unlink(u) => {
+                self.linked.remove(u.path.as_ref());
+            }
+            Command::Rmdir(_) => (),
+            _ => return Ok(()),
+        }
+        for worker in 0..self.workers.len() {
+            self.send(worker, Job::Forget)?;
+        }
+        Ok(())
+    }
+}
+
+impl Applier {
+    /// [Applier::apply_all] on `threads` threads: the data and metadata of
+    /// different files are applied at the same time, while everything that
+    /// creates, renames or removes paths is applied in order on this thread
+    /// once whatever it depends on is done. [Applier::on_progress] hears
+    /// about commands as they are handed out, and the order in which
+    /// commands for different files reach the disk is not the order of the
+    /// stream. Applies that keep a [Applier::journal] are not parallel,
+    /// since a checkpoint needs everything before it to be done, and
+    /// neither are those with [Options::atomic_files], which create each
+    /// file on the thread that writes it. Incremental streams that are
+    /// applied onto their parent (see [Applier::receipt]) are applied on
+    /// this thread after the header, since clones from the parent depend on
+    /// everything before them.
+    pub fn apply_parallel(&mut self, stream: &Sendstream, threads: usize) -> Result<()> {
+        let Some((header, rest)) = stream.commands.split_first() else {
+            return Ok(());
+        };
+        if threads <= 1
+            || self.journal.is_some()
+            || self.options.atomic_files
+            || self.options.links != LinkFallback::Error
+        {
+            return self.apply_all(stream);
+        }
+        self.set_totals(
+            Some(self.commands + stream.commands.len() as u64),
+            Some(self.bytes + stream.commands.iter().map(data_len).sum::<u64>()),
+        );
+        // the header decides where everything goes
+        self.apply(header)?;
+        if self.in_place.is_some() {
+            return rest.iter().try_for_each(|c| self.apply(c));
+        }
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        let shared: Shared = (
+            Mutex::new(Done {
+                jobs: vec![0; threads],
+                error: None,
+            }),
+            Condvar::new(),
+        );
+        std::thread::scope(|scope| {
+            let mut sched = Scheduler {
+                workers: Vec::new(),
+                sent: vec![0; threads],
+                shared: &shared,
+                pending: BTreeMap::new(),
+                linked: BTreeSet::new(),
+                next: 0,
+            };
+            let mut handles = Vec::new();
+            for n in 0..threads {
+                let (tx, rx) = mpsc::channel();
+                // only this thread knows where files end up, so it keeps
+                // track of what to sync at the end
+                let mut options = self.options.clone();
+                if options.durability == Durability::AtEnd {
+                    options.durability = Durability::None;
+                }
+                let setup = Setup {
+                    root: self.root.clone(),
+                    options,
+                    uuid: self.uuid,
+                    sources: self.sources.clone(),
+                    clone_method: self.clone_method,
+                };
+                let shared = &shared;
+                handles.push(scope.spawn(move || work(n, setup, rx, shared)));
+                sched.workers.push(tx);
+            }
+            for cmd in rest {
+                // the workers have to stop first
+                if self.cancelled() {
+                    sched.finish_all()?;
+                    self.stop()?;
+                    return Err(Error::Cancelled);
+                }
+                if !per_file(cmd) {
+                    sched.prepare(cmd)?;
+                    self.apply(cmd)?;
+                    #[cfg(feature = "io-uring")]
+                    self.flush()?;
+                    sched.applied(cmd)?;
+                    continue;
+                }
+                self.pace(cmd);
+                if !self.skip(cmd) {
+                    self.track(cmd)?;
+                    sched.dispatch(cmd)?;
+                }
+                self.commands += 1;
+                self.bytes += data_len(cmd);
+                self.report(cmd);
+            }
+            sched.settle_all()?;
+            // the workers stop once there is nothing more to send them
+            drop(sched);
+            for handle in handles {
+                if let Ok((summary, failures)) = handle.join() {
+                    self.summary.merge(&summary);
+                    self.failures.extend(failures);
+                }
+            }
+            if let Some(started) = self.started {
+                self.summary.duration = started.elapsed();
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+
+    #[test]
+    fn parallel() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_parallel.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let mut applier = Applier::new(&dest);
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        for (stream, fs) in sendstreams.iter().zip(&fs) {
+            applier
+                .apply_parallel(stream, 4)
+                .expect("failed to apply in parallel");
+            let diff = diff_directory(fs, Path::new(""), &dest).expect("failed to diff");
+            assert!(diff.is_empty(), "{diff:?}");
+        }
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,165 @@
+//! Writing data without filling in the holes of files, see
+//! [Options::sparse](super::Options::sparse).
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use nix::fcntl::FallocateFlags;
+
+use super::unsupported;
+
+/// Holes are left in pieces of this many bytes, which is the block size of
+/// most filesystems
+const BLOCK: u64 = 4096;
+
+/// `data`, written at `offset`, split at block boundaries of the file, with
+/// whether each piece is zeroes that can be left as a hole: whole blocks,
+/// and whatever is left at the end
+fn blocks(data: &[u8], offset: u64) -> impl Iterator<Item = (u64, &[u8], bool)> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos == data.len() {
+            return None;
+        }
+        let off = offset + pos as u64;
+        let n = (BLOCK - off % BLOCK).min((data.len() - pos) as u64) as usize;
+        let piece = &data[pos..pos + n];
+        pos += n;
+        let hole = (n as u64 == BLOCK || pos == data.len()) && piece.iter().all(|b| *b == 0);
+        Some((off, piece, hole))
+    })
+}
+
+/// Whether writing `data` at `offset` would fill in at least one block with
+/// zeroes
+pub(super) fn has_hole(data: &[u8], offset: u64) -> bool {
+    blocks(data, offset).any(|(_, _, hole)| hole)
+}
+
+/// Punch a hole in `file`, or write zeroes where that is not supported
+fn punch(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    match nix::fcntl::fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        offset as i64,
+        len as i64,
+    ) {
+        Ok(()) => Ok(()),
+        Err(e) if unsupported(e) || e == nix::errno::Errno::EINVAL => {
+            file.write_all_at(&vec![0; len as usize], offset)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `data` at `offset` in `file`, leaving holes instead of whole blocks
+/// of zeroes: past the end of the file they are skipped (growing the file
+/// with `ftruncate` if nothing comes after them), and before it they are
+/// punched out of whatever was there.
+pub(super) fn write_sparse(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    if !has_hole(data, offset) {
+        return file.write_all_at(data, offset);
+    }
+    let mut size = file.metadata()?.len();
+    // consecutive pieces of the same kind, to write or punch at once
+    let mut run: Option<(u64, usize, bool)> = None;
+    let mut start = 0;
+    let flush = |run: (u64, usize, bool), end: usize, size: &mut u64| {
+        let (off, from, hole) = run;
+        let len = (end - from) as u64;
+        match hole {
+            true if off < *size => punch(file, off, len.min(*size - off)),
+            true => Ok(()),
+            false => {
+                file.write_all_at(&data[from..end], off)?;
+                *size = (*size).max(off + len);
+                Ok(())
+            }
+        }
+    };
+    for (off, piece, hole) in blocks(data, offset) {
+        match run {
+            Some((_, _, h)) if h == hole => (),
+            Some(r) => {
+                flush(r, start, &mut size)?;
+                run = Some((off, start, hole));
+            }
+            None => run = Some((off, start, hole)),
+        }
+        start += piece.len();
+    }
+    if let Some(r) = run {
+        flush(r, start, &mut size)?;
+    }
+    let end = offset + data.len() as u64;
+    if end > size {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use std::path::Path;
+
+    use super::*;
+    use crate::apply::Applier;
+    use crate::apply::Options;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn sparse_writes() {
+        let path = std::env::temp_dir().join(format!("apply_sparse.{}", std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .expect("failed to create");
+        let mut data = vec![0; 16 * BLOCK as usize];
+        data[..3].copy_from_slice(b"abc");
+        write_sparse(&file, &data, 10).expect("failed to write");
+        // the zero blocks after the data were left out, including at the end
+        let meta = file.metadata().expect("failed to stat");
+        assert_eq!(10 + data.len() as u64, meta.len());
+        assert!(meta.blocks() * 512 <= BLOCK, "{}", meta.blocks());
+
+        // zeroes over existing data make holes
+        file.write_all_at(&vec![1; 8 * BLOCK as usize], 0)
+            .expect("failed to write");
+        write_sparse(&file, &vec![0; 8 * BLOCK as usize], 0).expect("failed to write");
+        let mut read = vec![1; 8 * BLOCK as usize];
+        file.read_exact_at(&mut read, 0).expect("failed to read");
+        assert!(read.iter().all(|b| *b == 0));
+        assert!(file.metadata().expect("failed to stat").blocks() * 512 <= 2 * BLOCK);
+        std::fs::remove_file(&path).expect("failed to clean up");
+    }
+
+    #[test]
+    fn apply_sparse() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let dest = std::env::temp_dir().join(format!("apply_sparse_demo.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let options = Options {
+            sparse: true,
+            ..Default::default()
+        };
+        let mut applier = Applier::with_options(&dest, options);
+        for (stream, fs) in sendstreams.iter().zip(&fs) {
+            applier.apply_all(stream).expect("failed to apply");
+            let diff = diff_directory(fs, Path::new(""), &dest).expect("failed to diff");
+            assert!(diff.is_empty(), "{diff:?}");
+        }
+        let huge = std::fs::metadata(dest.join("huge-empty-file")).expect("no huge file");
+        assert!(huge.blocks() * 512 < huge.len());
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
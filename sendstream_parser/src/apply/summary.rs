@@ -0,0 +1,163 @@
+//! What an [Applier] has done and how long it took, see [Applier::summary].
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::Applier;
+use super::Result;
+use crate::Command;
+
+/// How many commands of one type were applied, and the time spent on them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Timing {
+    pub count: u64,
+    pub duration: Duration,
+}
+
+/// Totals of everything that was applied. The counts leave out what the
+/// [Options] skip, while the timings include it. Batched commands (with
+/// io-uring) are timed when they are waited for, and the commands of
+/// [Applier::apply_parallel] on every thread add up to more than the time
+/// that it took.
+///
+/// [Options]: super::Options
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Summary {
+    pub files_created: u64,
+    pub directories_created: u64,
+    pub symlinks_created: u64,
+    /// Fifos, sockets and device nodes
+    pub specials_created: u64,
+    pub links_created: u64,
+    /// Bytes of file data written, after decoding [crate::EncodedWrite]s
+    pub bytes_written: u64,
+    pub clones: u64,
+    pub bytes_cloned: u64,
+    pub xattrs_set: u64,
+    /// From the start of the first command until the last one was done
+    pub duration: Duration,
+    /// Applied commands of each type, keyed by its name (like `Write`)
+    pub timings: BTreeMap<String, Timing>,
+}
+
+impl Summary {
+    /// Count `cmd`, which is about to be applied
+    fn add(&mut self, cmd: &Command) {
+        match cmd {
+            Command::Mkfile(_) => self.files_created += 1,
+            Command::Mkdir(_) => self.directories_created += 1,
+            Command::Symlink(_) => self.symlinks_created += 1,
+            Command::Mknod(_) | Command::Mkfifo(_) | Command::Mksock(_) => {
+                self.specials_created += 1
+            }
+            Command::Link(_) => self.links_created += 1,
+            Command::Write(w) => self.bytes_written += w.data.len() as u64,
+            Command::EncodedWrite(w) => self.bytes_written += w.unencoded_file_len,
+            Command::Clone(c) => {
+                self.clones += 1;
+                self.bytes_cloned += c.len.as_u64();
+            }
+            Command::SetXattr(_) => self.xattrs_set += 1,
+            _ => (),
+        }
+    }
+
+    /// Add up what happened on another thread
+    pub(super) fn merge(&mut self, other: &Summary) {
+        self.files_created += other.files_created;
+        self.directories_created += other.directories_created;
+        self.symlinks_created += other.symlinks_created;
+        self.specials_created += other.specials_created;
+        self.links_created += other.links_created;
+        self.bytes_written += other.bytes_written;
+        self.clones += other.clones;
+        self.bytes_cloned += other.bytes_cloned;
+        self.xattrs_set += other.xattrs_set;
+        for (name, t) in &other.timings {
+            let mine = self.timings.entry(name.clone()).or_default();
+            mine.count += t.count;
+            mine.duration += t.duration;
+        }
+    }
+}
+
+impl Applier {
+    /// Totals of everything that was applied so far, which
+    /// [Applier::finish] also returns
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Count `cmd` in the [Summary] once it is known to be applied, and not
+    /// left out by the [super::Options]
+    pub(super) fn count(&mut self, cmd: &Command) {
+        match cmd {
+            Command::SetXattr(x) if self.options.xattr(&x.name, &x.data).is_none() => (),
+            _ => self.summary.add(cmd),
+        }
+    }
+
+    /// [Applier::apply_command], keeping time
+    pub(super) fn timed(&mut self, cmd: &Command) -> Result<()> {
+        let start = Instant::now();
+        let started = *self.started.get_or_insert(start);
+        let res = self.apply_command(cmd);
+        let t = self
+            .summary
+            .timings
+            .entry(format!("{:?}", cmd.command_type()))
+            .or_default();
+        t.count += 1;
+        t.duration += start.elapsed();
+        self.summary.duration = started.elapsed();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sendstream;
+
+    #[test]
+    fn summary() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_summary.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let mut applier = Applier::new(&dest);
+        applier.apply_all(&sendstreams[0]).expect("failed to apply");
+        let stats = sendstreams[0].stats();
+        let summary = applier.summary().clone();
+        assert_eq!(stats.files, summary.files_created);
+        assert_eq!(stats.directories, summary.directories_created);
+        assert_eq!(stats.links, summary.links_created);
+        assert_eq!(stats.write_bytes, summary.bytes_written);
+        assert_eq!(stats.clone_bytes, summary.bytes_cloned);
+        assert_eq!(stats.xattrs_set, summary.xattrs_set);
+        let counts: BTreeMap<_, _> = summary
+            .timings
+            .iter()
+            .map(|(name, t)| (name.clone(), t.count))
+            .collect();
+        assert_eq!(stats.commands, counts);
+        assert!(summary.duration >= summary.timings["Write"].duration);
+
+        // the incremental keeps adding up
+        applier
+            .apply_parallel(&sendstreams[1], 2)
+            .expect("failed to apply incremental");
+        let total = applier.finish().expect("failed to finish");
+        assert_eq!(
+            stats.total_commands() + sendstreams[1].stats().total_commands(),
+            total.timings.values().map(|t| t.count).sum::<u64>()
+        );
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,274 @@
+//! Creating regular files without a name until the stream is done with them,
+//! see [Options::atomic_files].
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use nix::errno::Errno;
+use nix::sys::stat::UtimensatFlags;
+use nix::unistd::LinkatFlags;
+use xattr::FileExt;
+
+use super::subject;
+use super::Applier;
+use super::Error;
+use super::Owner;
+use super::Result;
+use super::OWNER_XATTR;
+use crate::extract::timespec;
+use crate::Command;
+
+/// A regular file that was created with `O_TMPFILE`, which is linked into
+/// place by [Applier::link_tmpfile]
+pub(super) struct TmpFile {
+    file: File,
+    /// Names that it will be linked at, the first of which is the one that
+    /// it was created with or renamed to
+    names: Vec<PathBuf>,
+    /// [crate::Utimes] of other paths, which are there to restore the times of
+    /// the directories that the file is linked into and so have to wait
+    /// for it
+    times: Vec<(PathBuf, SystemTime, SystemTime)>,
+}
+
+/// Link a file that has no name (through its `/proc` path) at `dst`, atomically
+/// replacing whatever is there
+fn link_over(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let link = |to: &Path| nix::unistd::linkat(None, src, None, to, LinkatFlags::SymlinkFollow);
+    match link(dst) {
+        Err(Errno::EEXIST) => {
+            let mut tmp = dst.as_os_str().to_owned();
+            tmp.push(".tmpfile");
+            link(Path::new(&tmp))?;
+            std::fs::rename(&tmp, dst)
+        }
+        res => Ok(res?),
+    }
+}
+
+impl Applier {
+    /// The file that `path` will be linked to, if it has not been yet
+    pub(super) fn tmpfile_for(&self, path: &Path) -> Option<&File> {
+        self.tmpfile
+            .as_ref()
+            .filter(|t| t.names.iter().any(|n| n == path))
+            .map(|t| &t.file)
+    }
+
+    /// Create the file of a [crate::Mkfile] without a name, if the
+    /// filesystem supports that
+    fn create_tmpfile(&mut self, path: &Path) -> Result<bool> {
+        let dst = self.path(path);
+        if dst.symlink_metadata().is_ok() {
+            return Err(Error::Io {
+                path: dst,
+                error: std::io::ErrorKind::AlreadyExists.into(),
+            });
+        }
+        let dir = dst.parent().unwrap_or(&self.root);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(nix::libc::O_TMPFILE)
+            .mode(0o666)
+            .open(dir);
+        let file = match file {
+            Ok(file) => file,
+            // older kernels and some filesystems only know the usual way
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(nix::libc::EOPNOTSUPP | nix::libc::EISDIR)
+                ) =>
+            {
+                return Ok(false);
+            }
+            Err(error) => return Err(Error::Io { path: dst, error }),
+        };
+        self.tmpfile = Some(TmpFile {
+            file,
+            names: vec![path.to_path_buf()],
+            times: Vec::new(),
+        });
+        Ok(true)
+    }
+
+    /// Apply `cmd` to the file that has not been linked into place yet, if
+    /// it is about that file, and link the file first otherwise. Returns
+    /// whether there is nothing left to do for `cmd`.
+    pub(super) fn apply_tmpfile(&mut self, cmd: &Command) -> Result<bool> {
+        if let Command::Mkfile(m) = cmd {
+            if !self.options.atomic_files {
+                return Ok(false);
+            }
+            self.link_tmpfile()?;
+            return self.create_tmpfile(&m.path);
+        }
+        let Some(tmp) = &mut self.tmpfile else {
+            return Ok(false);
+        };
+        let path = subject(cmd);
+        let ours = tmp.names.iter().any(|n| n == path);
+        let fd = tmp.file.as_raw_fd();
+        let res: std::io::Result<()> = match cmd {
+            // data goes through the cached file, see Applier::file
+            Command::Write(_)
+            | Command::EncodedWrite(_)
+            | Command::Truncate(_)
+            | Command::Fallocate(_)
+            | Command::Clone(_)
+                if ours =>
+            {
+                return Ok(false);
+            }
+            Command::Chmod(c) if ours => tmp
+                .file
+                .set_permissions(self.options.mode(c.mode).permissions()),
+            Command::Chown(c) if ours => match self.owner(c)? {
+                Owner::Keep => Ok(()),
+                Owner::Ids(uid, gid) => {
+                    nix::unistd::fchown(fd, Some(uid), Some(gid)).map_err(Into::into)
+                }
+                Owner::Record(value) => self
+                    .tmpfile
+                    .as_ref()
+                    .map_or(Ok(()), |t| t.file.set_xattr(OWNER_XATTR, value.as_bytes())),
+            },
+            Command::Utimes(u) if ours => {
+                nix::sys::stat::futimens(fd, &timespec(*u.atime), &timespec(*u.mtime))
+                    .map_err(Into::into)
+            }
+            Command::Utimes(u) => {
+                tmp.times.push((u.path.to_path_buf(), *u.atime, *u.mtime));
+                Ok(())
+            }
+            Command::SetXattr(x) if ours => match self.options.xattr(&x.name, &x.data) {
+                Some(data) => tmp.file.set_xattr(OsStr::from_bytes(&x.name), &data),
+                None => Ok(()),
+            },
+            Command::RemoveXattr(x) if ours => tmp.file.remove_xattr(OsStr::from_bytes(&x.name)),
+            Command::Fileattr(_) | Command::UpdateExtent(_) if ours => Ok(()),
+            Command::Link(l) if tmp.names.iter().any(|n| n == l.target.as_path()) => {
+                tmp.names.push(l.link_name.to_path_buf());
+                Ok(())
+            }
+            Command::Rename(r) if ours => {
+                tmp.names
+                    .retain(|n| n != r.from.as_ref() && n != r.to.as_ref());
+                tmp.names.insert(0, r.to.to_path_buf());
+                self.close_file()?;
+                Ok(())
+            }
+            // it never shows up if that was its only name
+            Command::Unlink(u) if ours => {
+                tmp.names.retain(|n| n != u.path.as_ref());
+                if tmp.names.is_empty() {
+                    self.tmpfile = None;
+                }
+                self.close_file()?;
+                Ok(())
+            }
+            _ => {
+                self.link_tmpfile()?;
+                return Ok(false);
+            }
+        };
+        res.map_err(|error| self.err(path, error))?;
+        Ok(true)
+    }
+
+    /// Throw away the file that was created without a name, which nothing
+    /// will ever finish
+    pub(super) fn discard_tmpfile(&mut self) {
+        if let Some(tmp) = self.tmpfile.take() {
+            for name in &tmp.names {
+                self.dirty.remove(name);
+            }
+        }
+    }
+
+    /// Give the file that was created without a name all of its names, and
+    /// then the directories that it is in the times that they should have
+    pub(super) fn link_tmpfile(&mut self) -> Result<()> {
+        let Some(tmp) = self.tmpfile.take() else {
+            return Ok(());
+        };
+        // batched writes go to the file by itself, but batched renames may
+        // be of the directories that it is linked into
+        #[cfg(feature = "io-uring")]
+        self.flush()?;
+        let src = PathBuf::from(format!("/proc/self/fd/{}", tmp.file.as_raw_fd()));
+        for (i, name) in tmp.names.iter().enumerate() {
+            match link_over(&src, &self.path(name)) {
+                // the first name is linked like any new file
+                Err(e) if i > 0 && self.falls_back(&e) => self.fall_back_later(name, &tmp.names[0]),
+                res => res.map_err(|error| self.err(name, error))?,
+            }
+        }
+        for (path, atime, mtime) in &tmp.times {
+            if self.unlinked(path) {
+                continue;
+            }
+            nix::sys::stat::utimensat(
+                None,
+                &self.path(path),
+                &timespec(*atime),
+                &timespec(*mtime),
+                UtimensatFlags::NoFollowSymlink,
+            )
+            .map_err(|e| self.err(path, e.into()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::Options;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn atomic_files() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let dest = std::env::temp_dir().join(format!("apply_tmpfile.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let options = Options {
+            atomic_files: true,
+            ..Default::default()
+        };
+        let mut applier = Applier::with_options(&dest, options);
+        let (header, rest) = sendstreams[0].commands().split_first().expect("empty");
+        applier.apply(header).expect("failed to apply");
+        let mut created = 0;
+        for cmd in rest {
+            applier.apply(cmd).expect("failed to apply");
+            // files are never visible before they are complete
+            if let Some(tmp) = &applier.tmpfile {
+                created += 1;
+                for name in &tmp.names {
+                    assert!(dest.join(name).symlink_metadata().is_err(), "{name:?}");
+                }
+            }
+        }
+        assert!(created > 0);
+        assert!(applier.tmpfile.is_none());
+        let diff = diff_directory(&fs[0], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        applier
+            .apply_all(&sendstreams[1])
+            .expect("failed to apply incremental");
+        let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,540 @@
+//! Batching of the syscalls that applying makes through io_uring, so that
+//! runs of writes and directory operations cost one syscall instead of one
+//! each. See [Applier::io_uring].
+
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+use rustix::fs::AtFlags;
+use rustix::io::Errno;
+use rustix::io_uring::io_uring_cqe;
+use rustix::io_uring::io_uring_enter;
+use rustix::io_uring::io_uring_params;
+use rustix::io_uring::io_uring_ptr;
+use rustix::io_uring::io_uring_register;
+use rustix::io_uring::io_uring_setup;
+use rustix::io_uring::io_uring_sqe;
+use rustix::io_uring::io_uring_user_data;
+use rustix::io_uring::IoringEnterFlags;
+use rustix::io_uring::IoringOp;
+use rustix::io_uring::IoringRegisterOp;
+use rustix::io_uring::IoringSqeFlags;
+use rustix::io_uring::IORING_OFF_CQ_RING;
+use rustix::io_uring::IORING_OFF_SQES;
+use rustix::io_uring::IORING_OFF_SQ_RING;
+use rustix::mm::MapFlags;
+use rustix::mm::ProtFlags;
+
+use super::Applier;
+use super::Error;
+use super::LinkFallback;
+use super::Result;
+use crate::Command;
+
+/// Highest opcode that is probed for
+const PROBE_OPS: usize = 256;
+
+/// A shared mapping of part of the ring
+struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> std::io::Result<Self> {
+        // SAFETY: a new mapping, which nothing else refers to
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                fd,
+                offset,
+            )
+        }?;
+        Ok(Self { ptr, len })
+    }
+
+    /// Pointer to whatever is `offset` bytes into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!((offset as usize) < self.len);
+        // SAFETY: within the mapping
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: only the ring refers to the mapping, and it is going away
+        let _ = unsafe { rustix::mm::munmap(self.ptr, self.len) };
+    }
+}
+
+/// The submission and completion queues of an io_uring instance
+struct Ring {
+    _sq: Mmap,
+    _cq: Mmap,
+    sqes: Mmap,
+    entries: u32,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    /// Closed after the mappings are gone
+    fd: OwnedFd,
+}
+
+// SAFETY: the mappings are only ever accessed through `&mut Ring`
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> std::io::Result<Self> {
+        let mut params = io_uring_params::default();
+        // SAFETY: `params` is valid for the kernel to fill in
+        let fd = unsafe { io_uring_setup(entries, &mut params) }?;
+        let sq = Mmap::new(
+            &fd,
+            params.sq_off.array as usize + params.sq_entries as usize * 4,
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq = Mmap::new(
+            &fd,
+            params.cq_off.cqes as usize
+                + params.cq_entries as usize * std::mem::size_of::<io_uring_cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = Mmap::new(
+            &fd,
+            params.sq_entries as usize * std::mem::size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        // SAFETY: the kernel filled these in, and never changes them
+        let (sq_mask, cq_mask) = unsafe {
+            (
+                *sq.at::<u32>(params.sq_off.ring_mask),
+                *cq.at::<u32>(params.cq_off.ring_mask),
+            )
+        };
+        Ok(Self {
+            sq_head: sq.at(params.sq_off.head),
+            sq_tail: sq.at(params.sq_off.tail),
+            sq_mask,
+            sq_array: sq.at(params.sq_off.array),
+            cq_head: cq.at(params.cq_off.head),
+            cq_tail: cq.at(params.cq_off.tail),
+            cq_mask,
+            cqes: cq.at(params.cq_off.cqes),
+            entries: params.sq_entries,
+            fd,
+            _sq: sq,
+            _cq: cq,
+            sqes,
+        })
+    }
+
+    /// Which opcodes the kernel supports, indexed by opcode
+    fn probe(&self) -> Vec<bool> {
+        // `io_uring_probe` is 16 bytes, followed by 8 bytes for each op
+        let mut probe = vec![0u64; 2 + PROBE_OPS];
+        // SAFETY: `probe` has room for `PROBE_OPS` ops
+        let res = unsafe {
+            io_uring_register(
+                &self.fd,
+                IoringRegisterOp::RegisterProbe,
+                probe.as_mut_ptr().cast(),
+                PROBE_OPS as u32,
+            )
+        };
+        let mut supported = vec![false; PROBE_OPS];
+        if res.is_ok() {
+            let ops_len = probe[0].to_ne_bytes()[1] as usize;
+            for (op, ok) in supported.iter_mut().enumerate().take(ops_len) {
+                // `flags` is the second u16 of each `io_uring_probe_op`
+                let b = probe[2 + op].to_ne_bytes();
+                *ok = u16::from_ne_bytes([b[2], b[3]]) & 1 != 0;
+            }
+        }
+        supported
+    }
+
+    /// Submission entries that have been queued but not submitted
+    fn queued(&self) -> u32 {
+        // SAFETY: the kernel only writes the head, and nobody else the tail
+        unsafe {
+            (*self.sq_tail)
+                .load(Ordering::Relaxed)
+                .wrapping_sub((*self.sq_head).load(Ordering::Acquire))
+        }
+    }
+
+    /// Queue `sqe` for the next [Ring::submit], which there must be room for
+    fn push(&mut self, sqe: io_uring_sqe) {
+        debug_assert!(self.queued() < self.entries);
+        // SAFETY: the entry at the tail is not owned by the kernel until the
+        // tail moves past it
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let idx = tail & self.sq_mask;
+            self.sqes.at::<io_uring_sqe>(0).add(idx as usize).write(sqe);
+            self.sq_array.add(idx as usize).write(idx);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    /// Clear `IOSQE_IO_LINK` on the last entry that was pushed, so that the
+    /// chain ends with it
+    fn end_chain(&mut self) {
+        // SAFETY: as in `push`, the entry is not submitted yet
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed).wrapping_sub(1);
+            let idx = (*self.sq_array.add((tail & self.sq_mask) as usize)) as usize;
+            let sqe = self.sqes.at::<io_uring_sqe>(0).add(idx);
+            (*sqe).flags.remove(IoringSqeFlags::IO_LINK);
+        }
+    }
+
+    /// Submit everything that is queued, and wait for all of it to complete,
+    /// calling `f` with the user data and result of every completion
+    fn submit(&mut self, mut f: impl FnMut(u64, i32)) -> std::io::Result<()> {
+        let mut to_submit = self.queued();
+        let mut left = to_submit;
+        while left > 0 {
+            // SAFETY: every queued entry only refers to memory that outlives
+            // its completion
+            match unsafe { io_uring_enter(&self.fd, to_submit, left, IoringEnterFlags::GETEVENTS) }
+            {
+                Ok(n) => to_submit -= n.min(to_submit),
+                Err(Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            // SAFETY: the kernel only writes the tail, and the entries before
+            // it are ours until the head moves past them
+            unsafe {
+                let mut head = (*self.cq_head).load(Ordering::Relaxed);
+                let tail = (*self.cq_tail).load(Ordering::Acquire);
+                while head != tail && left > 0 {
+                    let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+                    f(cqe.user_data.u64_(), cqe.res);
+                    head = head.wrapping_add(1);
+                    left -= 1;
+                }
+                (*self.cq_head).store(head, Ordering::Release);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A queued command, along with everything that the kernel reads for it
+struct Pending {
+    /// Relative to the root, for errors
+    path: PathBuf,
+    /// Bytes that a write must write
+    len: u32,
+    _data: Vec<u8>,
+    _paths: Vec<CString>,
+}
+
+pub(super) struct Uring {
+    ring: Ring,
+    supported: Vec<bool>,
+    pending: Vec<Pending>,
+    /// Files that were replaced as the cached file while queued writes still
+    /// refer to them
+    retired: Vec<File>,
+}
+
+impl Uring {
+    fn supports(&self, op: IoringOp) -> bool {
+        self.supported[op as u8 as usize]
+    }
+
+    /// Queue `op`, chained to whatever was queued before it so that the
+    /// kernel carries them out in order
+    fn push(&mut self, mut sqe: io_uring_sqe, pending: Pending) {
+        sqe.flags = IoringSqeFlags::IO_LINK;
+        sqe.user_data = io_uring_user_data::from_u64(self.pending.len() as u64);
+        self.ring.push(sqe);
+        self.pending.push(pending);
+    }
+
+    fn full(&self) -> bool {
+        self.pending.len() as u32 >= self.ring.entries
+    }
+
+    /// Carry out everything that is queued. On failure, returns the path of
+    /// the command that failed first, after which nothing else was done.
+    fn drain(&mut self) -> std::result::Result<(), (PathBuf, std::io::Error)> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.ring.end_chain();
+        let mut failed: Option<(usize, std::io::Error)> = None;
+        let pending = &self.pending;
+        let res = self.ring.submit(|index, res| {
+            let index = index as usize;
+            let error = match res {
+                // everything after a failure in the chain is cancelled
+                res if res == -(Errno::CANCELED.raw_os_error()) => return,
+                res if res < 0 => std::io::Error::from_raw_os_error(-res),
+                res if (res as u32) < pending[index].len => {
+                    std::io::Error::from(std::io::ErrorKind::WriteZero)
+                }
+                _ => return,
+            };
+            if failed.as_ref().is_none_or(|(i, _)| index < *i) {
+                failed = Some((index, error));
+            }
+        });
+        if let Err(e) = res {
+            // the kernel may still be using the buffers
+            std::mem::forget(std::mem::take(&mut self.pending));
+            return Err((PathBuf::new(), e));
+        }
+        let failed = failed.map(|(index, e)| (self.pending[index].path.clone(), e));
+        self.pending.clear();
+        self.retired.clear();
+        failed.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        let _ = self.drain();
+    }
+}
+
+fn sqe(opcode: IoringOp, fd: RawFd, addr: *const u8, len: u32) -> io_uring_sqe {
+    let mut sqe = io_uring_sqe {
+        opcode,
+        fd,
+        ..Default::default()
+    };
+    sqe.addr_or_splice_off_in.addr = io_uring_ptr::new(addr as *mut c_void);
+    sqe.len.len = len;
+    sqe
+}
+
+fn cstring(path: &Path) -> std::io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+impl Applier {
+    /// Batch up to `depth` writes, renames, unlinks, mkdirs, symlinks and
+    /// hard links at a time through io_uring, which is much cheaper than a
+    /// syscall for each of them when a stream has many small files. Batches
+    /// are carried out in order, so the result is the same. Everything else
+    /// is applied as usual once whatever came before it is done, which also
+    /// means that an error from a batched command may only be returned when
+    /// applying a later command (or by [Applier::flush]), and
+    /// [Applier::on_progress] counts batched commands as they are queued.
+    ///
+    /// Commands that the kernel has no io_uring support for are not batched.
+    pub fn io_uring(mut self, depth: u32) -> Result<Self> {
+        let ring = Ring::new(depth.max(1)).map_err(|error| Error::Io {
+            path: self.root.clone(),
+            error,
+        })?;
+        self.uring = Some(Uring {
+            supported: ring.probe(),
+            ring,
+            pending: Vec::new(),
+            retired: Vec::new(),
+        });
+        Ok(self)
+    }
+
+    /// Wait for every batched command to be carried out, see
+    /// [Applier::io_uring]. This happens by itself at the end of a stream.
+    pub fn flush(&mut self) -> Result<()> {
+        let Some(uring) = &mut self.uring else {
+            return Ok(());
+        };
+        uring
+            .drain()
+            .map_err(|(path, error)| self.err(&path, error))
+    }
+
+    /// Queue `cmd` if it can be batched, and otherwise wait for everything
+    /// that was queued before it so that it can be applied as usual
+    pub(super) fn queue(&mut self, cmd: &Command) -> Result<bool> {
+        match &self.uring {
+            Some(uring) if uring.full() => self.flush()?,
+            Some(_) => (),
+            None => return Ok(false),
+        }
+        let queued = match self.try_queue(cmd) {
+            Ok(queued) => queued,
+            Err(error) => return Err(self.err(super::subject(cmd), error)),
+        };
+        if !queued {
+            self.flush()?;
+        }
+        Ok(queued)
+    }
+
+    fn try_queue(&mut self, cmd: &Command) -> std::io::Result<bool> {
+        // commands that may have been applied already must see their errors,
+        // and confined paths are checked against what is on disk
+        if self.redoing() || self.options.confine {
+            return Ok(false);
+        }
+        let op = match cmd {
+            Command::Write(_) => IoringOp::Write,
+            Command::Mkdir(_) => IoringOp::Mkdirat,
+            Command::Rename(_) => IoringOp::Renameat,
+            Command::Unlink(_) | Command::Rmdir(_) => IoringOp::Unlinkat,
+            Command::Symlink(_) => IoringOp::Symlinkat,
+            // refused links fall back
+            Command::Link(_) if self.options.links == LinkFallback::Error => IoringOp::Linkat,
+            _ => return Ok(false),
+        };
+        match &self.uring {
+            Some(uring) if uring.supports(op) => (),
+            _ => return Ok(false),
+        }
+        if let Command::Write(w) = cmd {
+            let cached = matches!(&self.file, Some((p, _)) if *p == self.path(&w.path));
+            if !cached || u32::try_from(w.data.len()).is_err() {
+                // opening a file depends on whatever is queued
+                return Ok(false);
+            }
+            if self.options.sparse && super::sparse::has_hole(&w.data, w.offset.as_u64()) {
+                return Ok(false);
+            }
+        }
+
+        let cwd = nix::libc::AT_FDCWD;
+        let (sqe, pending) = match cmd {
+            Command::Write(w) => {
+                let fd = self.file.as_ref().map_or(-1, |(_, f)| f.as_raw_fd());
+                let data = w.data.to_vec();
+                let len = data.len() as u32;
+                let mut sqe = sqe(IoringOp::Write, fd, data.as_ptr(), len);
+                sqe.off_or_addr2.off = w.offset.as_u64();
+                (sqe, (w.path.to_path_buf(), len, data, vec![]))
+            }
+            Command::Mkdir(m) => {
+                let path = cstring(&self.path(&m.path))?;
+                let sqe = sqe(IoringOp::Mkdirat, cwd, path.as_ptr().cast(), 0o777);
+                (sqe, (m.path.to_path_buf(), 0, vec![], vec![path]))
+            }
+            Command::Rename(r) => {
+                let (from, to) = (cstring(&self.path(&r.from))?, cstring(&self.path(&r.to))?);
+                let mut sqe = sqe(IoringOp::Renameat, cwd, from.as_ptr().cast(), cwd as u32);
+                sqe.off_or_addr2.addr2 = io_uring_ptr::new(to.as_ptr() as *mut c_void);
+                (sqe, (r.from.to_path_buf(), 0, vec![], vec![from, to]))
+            }
+            Command::Unlink(u) => {
+                let path = cstring(&self.path(&u.path))?;
+                let sqe = sqe(IoringOp::Unlinkat, cwd, path.as_ptr().cast(), 0);
+                (sqe, (u.path.to_path_buf(), 0, vec![], vec![path]))
+            }
+            Command::Rmdir(r) => {
+                let path = cstring(&self.path(&r.path))?;
+                let mut sqe = sqe(IoringOp::Unlinkat, cwd, path.as_ptr().cast(), 0);
+                sqe.op_flags.unlink_flags = AtFlags::REMOVEDIR;
+                (sqe, (r.path.to_path_buf(), 0, vec![], vec![path]))
+            }
+            Command::Symlink(s) => {
+                let target = cstring(&s.target)?;
+                let link = cstring(&self.path(&s.link_name))?;
+                let mut sqe = sqe(IoringOp::Symlinkat, cwd, target.as_ptr().cast(), 0);
+                sqe.off_or_addr2.addr2 = io_uring_ptr::new(link.as_ptr() as *mut c_void);
+                (
+                    sqe,
+                    (s.link_name.to_path_buf(), 0, vec![], vec![target, link]),
+                )
+            }
+            Command::Link(l) => {
+                let target = cstring(&self.path(&l.target))?;
+                let link = cstring(&self.path(&l.link_name))?;
+                let mut sqe = sqe(IoringOp::Linkat, cwd, target.as_ptr().cast(), cwd as u32);
+                sqe.off_or_addr2.addr2 = io_uring_ptr::new(link.as_ptr() as *mut c_void);
+                (
+                    sqe,
+                    (l.link_name.to_path_buf(), 0, vec![], vec![target, link]),
+                )
+            }
+            _ => return Ok(false),
+        };
+        let retire = matches!(
+            cmd,
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_)
+        );
+        let retired = self.file.take_if(|_| retire);
+        let Some(uring) = &mut self.uring else {
+            return Ok(false);
+        };
+        uring.retired.extend(retired.map(|(_, f)| f));
+        let (path, len, data, paths) = pending;
+        uring.push(
+            sqe,
+            Pending {
+                path,
+                len,
+                _data: data,
+                _paths: paths,
+            },
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::fs::Filesystem;
+    use crate::Sendstream;
+
+    #[test]
+    fn io_uring() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_uring.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let mut applier = Applier::new(&dest).io_uring(8).expect("no io_uring");
+        for s in &sendstreams {
+            applier.apply_all(s).expect("failed to apply");
+        }
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let diff = diff_directory(&fs[1], Path::new(""), &dest).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+
+        // errors come out of whatever waits for the batch
+        applier
+            .apply(
+                &crate::Unlink {
+                    path: Cow::Borrowed(Path::new("not-there")),
+                }
+                .into(),
+            )
+            .expect("unlink is batched");
+        match applier.flush() {
+            Err(Error::Io { path, error }) => {
+                assert_eq!(dest.join("not-there"), path);
+                assert_eq!(std::io::ErrorKind::NotFound, error.kind());
+            }
+            res => panic!("{res:?}"),
+        }
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,363 @@
+//! Check that a directory that a sendstream was applied to really ended up
+//! with what the stream describes.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::Sha256;
+
+use super::Error;
+use super::Options;
+use super::Ownership;
+use super::Result;
+use super::Unmapped;
+use super::OWNER_XATTR;
+use crate::compare::data_ranges;
+use crate::compare::disk_inode;
+use crate::compare::file_type;
+use crate::compare::walk_dir;
+use crate::fs::Filesystem;
+use crate::fs::Inode;
+use crate::fs::InodeKind;
+use crate::hash::hash_sparse;
+use crate::hash::hash_sparse_by;
+use crate::manifest::FileType;
+
+/// One way in which a path on disk is not what the stream describes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Mismatch {
+    Missing,
+    /// The path is on disk, but not in the stream
+    Unexpected,
+    /// Nothing else about the path is checked
+    FileType {
+        expected: FileType,
+        actual: FileType,
+    },
+    /// Size of a regular file, whose contents are not hashed if it is wrong
+    Size {
+        expected: u64,
+        actual: u64,
+    },
+    /// Permission bits
+    Mode {
+        expected: u32,
+        actual: u32,
+    },
+    Uid {
+        expected: u32,
+        actual: u32,
+    },
+    Gid {
+        expected: u32,
+        actual: u32,
+    },
+    Xattr {
+        name: String,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+    /// Hex-encoded SHA-256 of the contents (see [crate::fs::FileContents]),
+    /// hashed without reading holes
+    Contents {
+        expected: String,
+        actual: String,
+    },
+    /// Target of a symlink
+    Target {
+        expected: PathBuf,
+        actual: PathBuf,
+    },
+    /// Device number of a character or block device
+    Rdev {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PathMismatch {
+    pub path: PathBuf,
+    pub mismatch: Mismatch,
+}
+
+/// Result of [verify], with every mismatch in path order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Verification {
+    /// Paths that were checked
+    pub checked: usize,
+    pub mismatches: Vec<PathMismatch>,
+}
+
+impl Verification {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+fn special(kind: &InodeKind) -> bool {
+    matches!(
+        kind,
+        InodeKind::Fifo | InodeKind::Socket | InodeKind::CharDevice(_) | InodeKind::BlockDevice(_)
+    )
+}
+
+/// Where applying records who owns a file
+enum Owner {
+    Ids(u32, u32),
+    /// Value of [OWNER_XATTR]
+    Xattr(Vec<u8>),
+}
+
+/// Ownership that applying with `options` gives a file that the stream says
+/// is owned by `inode`'s uid and gid. `None` means that ownership is left
+/// alone.
+fn expected_owner(inode: &Inode, options: &Options) -> Option<Owner> {
+    let (uid, gid) = (inode.uid()?, inode.gid()?);
+    match &options.ownership {
+        Ownership::Preserve => Some(Owner::Ids(uid.as_raw(), gid.as_raw())),
+        Ownership::Skip => None,
+        Ownership::Fixed(uid, gid) => Some(Owner::Ids(uid.as_raw(), gid.as_raw())),
+        Ownership::Map(map) => match (map.uid(uid), map.gid(gid)) {
+            (Some(u), Some(g)) => Some(Owner::Ids(u.as_raw(), g.as_raw())),
+            _ if map.unmapped == Unmapped::Xattr
+                && !matches!(inode.kind(), InodeKind::Symlink(_)) =>
+            {
+                Some(Owner::Xattr(format!("{uid}:{gid}").into_bytes()))
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Every [Mismatch] between `expected` and the file at `path`
+fn check(
+    expected: &Inode,
+    path: &Path,
+    meta: &std::fs::Metadata,
+    options: &Options,
+) -> std::io::Result<Vec<Mismatch>> {
+    let disk = disk_inode(path, meta)?;
+    let (e, a) = (file_type(expected.kind()), file_type(disk.kind()));
+    if e != a {
+        return Ok(vec![Mismatch::FileType {
+            expected: e,
+            actual: a,
+        }]);
+    }
+    let mut out = Vec::new();
+    let symlink = matches!(expected.kind(), InodeKind::Symlink(_));
+    if let (Some(mode), false) = (expected.mode(), symlink) {
+        let mode = options.mode(mode);
+        let actual = meta.mode() & 0o7777;
+        if mode.0 != actual {
+            out.push(Mismatch::Mode {
+                expected: mode.0,
+                actual,
+            });
+        }
+    }
+
+    let mut xattrs: BTreeMap<_, _> = expected
+        .xattrs()
+        .iter()
+        .filter_map(|(n, v)| Some((n.clone(), options.xattr(n, v)?.into_owned())))
+        .collect();
+    match expected_owner(expected, options) {
+        Some(Owner::Ids(uid, gid)) => {
+            if uid != meta.uid() {
+                out.push(Mismatch::Uid {
+                    expected: uid,
+                    actual: meta.uid(),
+                });
+            }
+            if gid != meta.gid() {
+                out.push(Mismatch::Gid {
+                    expected: gid,
+                    actual: meta.gid(),
+                });
+            }
+        }
+        Some(Owner::Xattr(owner)) => {
+            xattrs.insert(OWNER_XATTR.as_bytes().to_vec(), owner);
+        }
+        None => (),
+    }
+    let owner_xattr =
+        matches!(&options.ownership, Ownership::Map(m) if m.unmapped == Unmapped::Xattr);
+    let names: BTreeSet<_> = xattrs
+        .keys()
+        .chain(disk.xattrs().keys().filter(|name| {
+            options.allows_xattr(name) || (owner_xattr && name[..] == *OWNER_XATTR.as_bytes())
+        }))
+        .collect();
+    for name in names {
+        let (e, a) = (xattrs.get(name), disk.xattrs().get(name));
+        if e != a {
+            out.push(Mismatch::Xattr {
+                name: String::from_utf8_lossy(name).into_owned(),
+                expected: e.cloned(),
+                actual: a.cloned(),
+            });
+        }
+    }
+
+    match (expected.kind(), disk.kind()) {
+        (InodeKind::File(contents), _) if contents.len() != meta.len() => {
+            out.push(Mismatch::Size {
+                expected: contents.len(),
+                actual: meta.len(),
+            })
+        }
+        (InodeKind::File(contents), _) => {
+            let file = File::open(path)?;
+            let actual = hash_sparse_by::<Sha256, _>(
+                meta.len(),
+                data_ranges(&file, meta.len()),
+                |off, len| {
+                    let mut buf = vec![0; len as usize];
+                    file.read_exact_at(&mut buf, off)?;
+                    Ok::<_, std::io::Error>(buf)
+                },
+            )?;
+            let expected = hash_sparse::<Sha256>(contents);
+            if expected != actual {
+                out.push(Mismatch::Contents {
+                    expected: hex::encode(expected),
+                    actual: hex::encode(actual),
+                });
+            }
+        }
+        (InodeKind::Symlink(e), InodeKind::Symlink(a)) if e != a => out.push(Mismatch::Target {
+            expected: e.clone(),
+            actual: a.clone(),
+        }),
+        (InodeKind::CharDevice(e), InodeKind::CharDevice(a))
+        | (InodeKind::BlockDevice(e), InodeKind::BlockDevice(a))
+            if e != a =>
+        {
+            out.push(Mismatch::Rdev {
+                expected: e.as_u64(),
+                actual: a.as_u64(),
+            })
+        }
+        _ => (),
+    }
+    Ok(out)
+}
+
+/// Re-walk `dest` after applying a stream to it with `options`, and check
+/// every path against `expected` (the subvolume that the stream produces,
+/// see [Filesystem::from_sendstream] and [Filesystem::from_incremental]).
+/// Sizes, modes, ownership, xattrs, symlink targets and content hashes are
+/// checked, as far as `options` says they were applied. Times are not, since
+/// they can change just by looking at the files.
+pub fn verify(expected: &Filesystem, dest: &Path, options: &Options) -> Result<Verification> {
+    let io = |path: &Path| {
+        let path = path.to_path_buf();
+        move |error| Error::Io { path, error }
+    };
+    let mut want = BTreeMap::from([(PathBuf::new(), expected.root())]);
+    want.extend(expected.walk());
+    want.retain(|_, id| options.specials || !special(expected[*id].kind()));
+    let have = walk_dir(dest).map_err(io(dest))?;
+
+    let mut report = Verification::default();
+    let all: BTreeSet<_> = want.keys().chain(have.keys()).collect();
+    for p in all {
+        let mismatches = match (want.get(p), have.get(p)) {
+            (Some(_), None) => vec![Mismatch::Missing],
+            (None, Some(_)) => vec![Mismatch::Unexpected],
+            (Some(id), Some(meta)) => {
+                report.checked += 1;
+                let path = dest.join(p);
+                check(&expected[*id], &path, meta, options).map_err(io(&path))?
+            }
+            (None, None) => continue,
+        };
+        report
+            .mismatches
+            .extend(mismatches.into_iter().map(|mismatch| PathMismatch {
+                path: p.clone(),
+                mismatch,
+            }));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::Applier;
+    use crate::Sendstream;
+
+    #[test]
+    fn verify_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("apply_verify.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let options = Options::default();
+        let mut applier = Applier::with_options(&dest, options.clone());
+        applier.apply_all(&sendstreams[0]).expect("failed to apply");
+        drop(applier);
+        let fs = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let report = verify(&fs, &dest, &options).expect("failed to verify");
+        assert!(report.is_clean(), "{report:?}");
+        assert!(report.checked > 10);
+
+        // damage it a bit
+        let msg = dest.join("hello/msg");
+        std::fs::remove_file(dest.join("myfifo")).expect("failed to remove fifo");
+        std::fs::set_permissions(&msg, std::os::unix::fs::PermissionsExt::from_mode(0o600))
+            .expect("failed to chmod");
+        std::fs::write(&msg, "Hello world!!").expect("failed to write");
+        std::fs::write(dest.join("extra"), "").expect("failed to write");
+        let report = verify(&fs, &dest, &options).expect("failed to verify");
+        let mismatch = |path: &str, mismatch| PathMismatch {
+            path: PathBuf::from(path),
+            mismatch,
+        };
+        for m in [
+            mismatch("extra", Mismatch::Unexpected),
+            mismatch(
+                "hello/msg",
+                Mismatch::Mode {
+                    expected: 0o400,
+                    actual: 0o600,
+                },
+            ),
+            mismatch("myfifo", Mismatch::Missing),
+        ] {
+            assert!(report.mismatches.contains(&m), "{report:?}");
+        }
+        assert!(report
+            .mismatches
+            .iter()
+            .any(|m| m.path == Path::new("hello/msg")
+                && matches!(m.mismatch, Mismatch::Contents { .. })));
+
+        // leaving out specials means they are not expected either
+        let options = Options {
+            specials: false,
+            ..options
+        };
+        let report = verify(&fs, &dest, &options).expect("failed to verify");
+        assert!(!report
+            .mismatches
+            .iter()
+            .any(|m| m.mismatch == Mismatch::Missing));
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+}
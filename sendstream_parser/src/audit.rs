@@ -0,0 +1,194 @@
+//! Security-relevant artifacts in a sendstream, for reviewing images before
+//! they are received.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::files::created_type;
+use crate::manifest::FileType;
+use crate::Command;
+use crate::Sendstream;
+
+static CAPABILITY_XATTR: &[u8] = b"security.capability";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Concern {
+    Setuid,
+    Setgid,
+    /// Writable by anyone, and not a directory with the sticky bit set (like
+    /// `/tmp`)
+    WorldWritable,
+    /// Character or block device created with [crate::Mknod]
+    DeviceNode {
+        block: bool,
+        rdev: u64,
+    },
+    /// File capabilities, as the raw value of the `security.capability` xattr
+    Capability(Vec<u8>),
+    /// Owned by root but inside a user's home directory (`home/<user>/...`)
+    RootOwnedInHome,
+}
+
+/// A [Concern] about the file at `path` (its final path once the sendstream
+/// has been received)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AuditFinding {
+    pub path: PathBuf,
+    pub concern: Concern,
+}
+
+/// Whether `path` (relative to the subvolume root) is inside a user's home
+/// directory, as opposed to being `home` or a home directory itself
+fn in_home(path: &Path) -> bool {
+    path.starts_with("home") && path.components().count() > 2
+}
+
+impl<'a> Sendstream<'a> {
+    /// Report security-relevant metadata that this sendstream sets on each
+    /// file, in order of final path. Metadata that an incremental sendstream
+    /// leaves unchanged is not considered, so audit the full sendstream to
+    /// review an entire subvolume.
+    pub fn audit(&self) -> Vec<AuditFinding> {
+        let mut findings = Vec::new();
+        for file in self.files() {
+            let mut report = |concern| {
+                findings.push(AuditFinding {
+                    path: file.final_path.clone(),
+                    concern,
+                })
+            };
+            let created = file
+                .commands
+                .iter()
+                .find_map(|i| Some((&self.commands[*i], created_type(&self.commands[*i])?)));
+            let file_type = created.as_ref().map(|(_, t)| *t);
+            if let Some((Command::Mknod(m), t)) = created {
+                report(Concern::DeviceNode {
+                    block: t == FileType::BlockDevice,
+                    rdev: m.rdev.as_u64(),
+                });
+            }
+            if let Some(mode) = file.mode.map(|m| m.0) {
+                if mode & 0o4000 != 0 {
+                    report(Concern::Setuid);
+                }
+                if mode & 0o2000 != 0 {
+                    report(Concern::Setgid);
+                }
+                let sticky_dir = file_type == Some(FileType::Directory) && mode & 0o1000 != 0;
+                if mode & 0o002 != 0 && file_type != Some(FileType::Symlink) && !sticky_dir {
+                    report(Concern::WorldWritable);
+                }
+            }
+            if let Some(Some(cap)) = file.xattrs.get(CAPABILITY_XATTR) {
+                report(Concern::Capability(cap.to_vec()));
+            }
+            if file.uid.is_some_and(|u| u.is_root()) && in_home(&file.final_path) {
+                report(Concern::RootOwnedInHome);
+            }
+        }
+        findings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use nix::unistd::Gid;
+    use nix::unistd::Uid;
+
+    use super::*;
+    use crate::Mode;
+
+    #[test]
+    fn audit() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        assert_eq!(
+            vec![AuditFinding {
+                path: PathBuf::from("null"),
+                concern: Concern::DeviceNode {
+                    block: false,
+                    rdev: sendstreams[0]
+                        .commands()
+                        .iter()
+                        .find_map(|c| match c {
+                            Command::Mknod(m) => Some(m.rdev().as_u64()),
+                            _ => None,
+                        })
+                        .expect("missing null"),
+                },
+            }],
+            sendstreams[0].audit()
+        );
+
+        let mut commands = sendstreams[0].commands().to_vec();
+        let end = commands.pop();
+        let path = |p: &'static str| Cow::Borrowed(Path::new(p));
+        let chmod = |p, mode| {
+            Command::from(crate::Chmod {
+                path: path(p),
+                mode,
+            })
+        };
+        commands.extend([
+            chmod("hello/lorem", Mode(0o4755)),
+            chmod("hello", Mode(0o1777)),
+            chmod("hello/msg", Mode(0o666)),
+            crate::SetXattr {
+                path: path("hello/lorem-reflinked"),
+                name: crate::XattrName(Cow::Borrowed(CAPABILITY_XATTR)),
+                data: crate::XattrData(Cow::Borrowed(b"\x01\x00\x00\x02")),
+            }
+            .into(),
+            crate::Mkdir {
+                path: crate::TemporaryPath(path("home")),
+                ino: crate::Ino(1000),
+            }
+            .into(),
+            crate::Mkdir {
+                path: crate::TemporaryPath(path("home/alice")),
+                ino: crate::Ino(1001),
+            }
+            .into(),
+            crate::Mkfile {
+                path: crate::TemporaryPath(path("home/alice/.bashrc")),
+                ino: crate::Ino(1002),
+            }
+            .into(),
+            crate::Chown {
+                path: path("home/alice/.bashrc"),
+                uid: Uid::from_raw(0),
+                gid: Gid::from_raw(0),
+            }
+            .into(),
+        ]);
+        commands.extend(end);
+        let findings = Sendstream { commands }.audit();
+        let concerns = |p: &str| {
+            findings
+                .iter()
+                .filter(|f| f.path == Path::new(p))
+                .map(|f| f.concern.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![Concern::Setuid], concerns("hello/lorem"));
+        assert!(concerns("hello").is_empty(), "sticky directories are fine");
+        assert_eq!(vec![Concern::WorldWritable], concerns("hello/msg"));
+        assert_eq!(
+            vec![Concern::Capability(b"\x01\x00\x00\x02".to_vec())],
+            concerns("hello/lorem-reflinked")
+        );
+        assert_eq!(
+            vec![Concern::RootOwnedInHome],
+            concerns("home/alice/.bashrc")
+        );
+    }
+}
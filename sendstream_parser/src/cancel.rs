@@ -0,0 +1,27 @@
+//! Stopping long reads and applies from another thread, see
+//! [crate::CommandReader::cancel_on] and [crate::apply::Applier::cancel_on].
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Flag that is checked between commands. Clones share the flag, so one can
+/// be handed to whatever is cancelled and another kept to cancel it with.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop everything that checks this token (or one of its clones) before
+    /// it starts on the next command
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,342 @@
+//! Checks over a set of sendstreams that are meant to be received one after
+//! another, each incremental on top of its parent.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::fs::SubvolumeInfo;
+use crate::Ctransid;
+
+/// Something that would make receiving a chain of sendstreams fail, with
+/// indices into the slice that was validated
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ChainError {
+    #[error("stream {index} needs parent {uuid}, which none of the streams produce")]
+    MissingParent { index: usize, uuid: Uuid },
+    #[error("stream {index} needs parent {uuid} at ctransid {expected:?}, but stream {parent} produces it at {actual:?}")]
+    WrongCtransid {
+        index: usize,
+        parent: usize,
+        uuid: Uuid,
+        expected: Ctransid,
+        actual: Ctransid,
+    },
+    #[error("stream {index} comes before its parent, stream {parent}")]
+    OutOfOrder { index: usize, parent: usize },
+    #[error("streams {first} and {second} both produce subvolume {uuid}")]
+    DuplicateUuid {
+        first: usize,
+        second: usize,
+        uuid: Uuid,
+    },
+    #[error("streams {0:?} are each other's parents")]
+    Cycle(Vec<usize>),
+    #[error("streams {first} and {second} both receive into {path:?}")]
+    DuplicatePath {
+        first: usize,
+        second: usize,
+        path: PathBuf,
+    },
+    #[error("stream {index} receives into {path:?}, which already exists")]
+    PathExists { index: usize, path: PathBuf },
+}
+
+/// One sendstream to receive, see [plan]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Step {
+    /// Index of the sendstream
+    pub index: usize,
+    /// Index of the sendstream that produces the parent, which is always
+    /// received in an earlier step
+    pub parent: Option<usize>,
+}
+
+/// Order to receive a set of sendstreams in, see [plan]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+/// Index of the stream that produces each subvolume, and any duplicates
+fn by_uuid(streams: &[SubvolumeInfo]) -> (BTreeMap<Uuid, usize>, Vec<ChainError>) {
+    let mut by_uuid = BTreeMap::new();
+    let mut errors = Vec::new();
+    for (index, s) in streams.iter().enumerate() {
+        if let Some(first) = by_uuid.insert(s.uuid(), index) {
+            by_uuid.insert(s.uuid(), first);
+            errors.push(ChainError::DuplicateUuid {
+                first,
+                second: index,
+                uuid: s.uuid(),
+            });
+        }
+    }
+    (by_uuid, errors)
+}
+
+/// Check that the parent of every incremental sendstream in `streams` (as
+/// returned by [crate::peek] or [crate::Sendstream::subvolume]) is produced by an
+/// earlier stream, at the generation that the incremental expects. Full
+/// sendstreams are always fine on their own.
+pub fn validate(streams: &[SubvolumeInfo]) -> Result<(), Vec<ChainError>> {
+    let (by_uuid, mut errors) = by_uuid(streams);
+    for (index, s) in streams.iter().enumerate() {
+        let (Some(uuid), Some(expected)) = (s.parent_uuid(), s.parent_ctransid()) else {
+            continue;
+        };
+        let Some(parent) = by_uuid.get(&uuid).copied() else {
+            errors.push(ChainError::MissingParent { index, uuid });
+            continue;
+        };
+        let actual = streams[parent].ctransid();
+        if actual != expected {
+            errors.push(ChainError::WrongCtransid {
+                index,
+                parent,
+                uuid,
+                expected,
+                actual,
+            });
+        } else if parent > index {
+            errors.push(ChainError::OutOfOrder { index, parent });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Find streams that would be received to the same path, which `btrfs
+/// receive` refuses since it never replaces an existing subvolume. If
+/// `dest` is given (the directory that everything will be received into),
+/// paths that already exist there are reported too.
+pub fn collisions(streams: &[SubvolumeInfo], dest: Option<&Path>) -> Vec<ChainError> {
+    let mut by_path: BTreeMap<&Path, usize> = BTreeMap::new();
+    let mut errors = Vec::new();
+    for (index, s) in streams.iter().enumerate() {
+        if let Some(first) = by_path.get(s.path()).copied() {
+            errors.push(ChainError::DuplicatePath {
+                first,
+                second: index,
+                path: s.path().to_path_buf(),
+            });
+            continue;
+        }
+        by_path.insert(s.path(), index);
+        let exists = dest.is_some_and(|d| d.join(s.path()).symlink_metadata().is_ok());
+        if exists {
+            errors.push(ChainError::PathExists {
+                index,
+                path: s.path().to_path_buf(),
+            });
+        }
+    }
+    errors
+}
+
+/// Work out an order to receive `streams` in (as returned by [crate::peek],
+/// for example on every file in a directory of archived sendstreams) so
+/// that every incremental comes after its parent. Independent sendstreams
+/// stay in the order they are given in.
+///
+/// Fails with every parent that is missing (or at the wrong generation) and
+/// every cycle, since nothing in those chains can be received.
+pub fn plan(streams: &[SubvolumeInfo]) -> Result<Plan, Vec<ChainError>> {
+    let (by_uuid, mut errors) = by_uuid(streams);
+    let mut parents = vec![None; streams.len()];
+    let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (index, s) in streams.iter().enumerate() {
+        let (Some(uuid), Some(expected)) = (s.parent_uuid(), s.parent_ctransid()) else {
+            continue;
+        };
+        match by_uuid.get(&uuid).copied() {
+            None => errors.push(ChainError::MissingParent { index, uuid }),
+            Some(parent) if streams[parent].ctransid() != expected => {
+                errors.push(ChainError::WrongCtransid {
+                    index,
+                    parent,
+                    uuid,
+                    expected,
+                    actual: streams[parent].ctransid(),
+                })
+            }
+            Some(parent) => {
+                parents[index] = Some(parent);
+                children.entry(parent).or_default().push(index);
+            }
+        }
+    }
+
+    let mut ready: BTreeSet<usize> = (0..streams.len())
+        .filter(|i| parents[*i].is_none())
+        .collect();
+    let mut steps = Vec::with_capacity(streams.len());
+    while let Some(index) = ready.pop_first() {
+        steps.push(Step {
+            index,
+            parent: parents[index],
+        });
+        ready.extend(children.get(&index).into_iter().flatten());
+    }
+    if steps.len() < streams.len() {
+        // whatever is left is only reachable through a cycle
+        let planned: BTreeSet<_> = steps.iter().map(|s| s.index).collect();
+        let mut left: BTreeSet<usize> = (0..streams.len())
+            .filter(|i| !planned.contains(i))
+            .collect();
+        while let Some(start) = left.first().copied() {
+            // follow the parents until coming back around, anything before
+            // that point merely descends from the cycle
+            let mut walk = Vec::new();
+            let mut cur = Some(start);
+            while let Some(c) = cur.filter(|c| left.contains(c)) {
+                if let Some(pos) = walk.iter().position(|w| *w == c) {
+                    let mut cycle = walk[pos..].to_vec();
+                    cycle.sort();
+                    errors.push(ChainError::Cycle(cycle));
+                    break;
+                }
+                walk.push(c);
+                cur = parents[c];
+            }
+            for w in walk {
+                left.remove(&w);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(Plan { steps })
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sendstream;
+
+    #[test]
+    fn validate_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let infos: Vec<_> = sendstreams
+            .iter()
+            .map(|s| s.subvolume().expect("has a header"))
+            .collect();
+        assert_eq!(Ok(()), validate(&infos));
+
+        let reversed: Vec<_> = infos.iter().rev().cloned().collect();
+        assert_eq!(
+            Err(vec![ChainError::OutOfOrder {
+                index: 0,
+                parent: 1
+            }]),
+            validate(&reversed)
+        );
+
+        assert_eq!(
+            Err(vec![ChainError::MissingParent {
+                index: 0,
+                uuid: infos[0].uuid(),
+            }]),
+            validate(&infos[1..])
+        );
+
+        let mut stale = infos.clone();
+        stale[0].ctransid = Ctransid(1);
+        assert!(matches!(
+            validate(&stale).as_ref().map_err(Vec::as_slice),
+            Err([ChainError::WrongCtransid { index: 1, .. }])
+        ));
+    }
+
+    #[test]
+    fn collisions_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let infos: Vec<_> = sendstreams
+            .iter()
+            .map(|s| s.subvolume().expect("has a header"))
+            .collect();
+        let dest = std::env::temp_dir().join(format!("collisions.{}", std::process::id()));
+        std::fs::create_dir_all(&dest).expect("failed to create dir");
+        assert_eq!(Vec::<ChainError>::new(), collisions(&infos, Some(&dest)));
+
+        std::fs::create_dir(dest.join("demo-undo")).expect("failed to mkdir");
+        let mut again = infos[0].clone();
+        again.uuid = Uuid::from_u128(1);
+        let streams = [infos[0].clone(), infos[1].clone(), again];
+        let found = collisions(&streams, Some(&dest));
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+        assert_eq!(
+            vec![
+                ChainError::PathExists {
+                    index: 1,
+                    path: PathBuf::from("demo-undo"),
+                },
+                ChainError::DuplicatePath {
+                    first: 0,
+                    second: 2,
+                    path: PathBuf::from("demo"),
+                },
+            ],
+            found
+        );
+        assert_eq!(1, collisions(&streams, None).len());
+    }
+
+    #[test]
+    fn plan_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = sendstreams[0].subvolume().expect("has a header");
+        let undo = sendstreams[1].subvolume().expect("has a header");
+        let mut other = demo.clone();
+        other.uuid = Uuid::from_u128(1);
+        let streams = [undo.clone(), other, demo.clone()];
+        assert_eq!(
+            Ok(Plan {
+                steps: vec![
+                    Step {
+                        index: 1,
+                        parent: None
+                    },
+                    Step {
+                        index: 2,
+                        parent: None
+                    },
+                    Step {
+                        index: 0,
+                        parent: Some(2)
+                    },
+                ]
+            }),
+            plan(&streams)
+        );
+
+        // two snapshots that are each other's parent
+        let mut a = undo.clone();
+        a.parent = Some((undo.uuid(), undo.ctransid()));
+        a.uuid = demo.uuid();
+        a.ctransid = demo.ctransid();
+        // and a snapshot of one of them, which cannot be received either
+        let mut b = undo.clone();
+        b.uuid = Uuid::from_u128(2);
+        assert_eq!(
+            Err(vec![ChainError::Cycle(vec![1, 2])]),
+            plan(&[b, undo, a])
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Summary of what an incremental sendstream changes, in terms of paths,
+//! worked out from the commands alone so that the parent is not needed.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Sendstream;
+
+/// A path along with how many bytes of data were sent for it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ChangedPath {
+    pub path: PathBuf,
+    /// Bytes of the file that are written, cloned or (in `--no-data`
+    /// sendstreams) updated
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RenamedPath {
+    /// Path in the parent subvolume
+    pub from: PathBuf,
+    /// Path at the end of the stream
+    pub to: PathBuf,
+}
+
+/// What a sendstream changes, see [Sendstream::changes]. Everything is sorted
+/// by path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ChangeReport {
+    /// New paths, at their final location
+    pub created: Vec<ChangedPath>,
+    /// Paths of the parent that are removed
+    pub deleted: Vec<PathBuf>,
+    /// Paths of the parent that are moved somewhere else. Everything beneath
+    /// a renamed directory moves along with it, but is not listed here.
+    pub renamed: Vec<RenamedPath>,
+    /// Files of the parent whose data changes, at their final location
+    pub content_changed: Vec<ChangedPath>,
+    /// Paths of the parent (at their final location) whose mode, ownership,
+    /// times, xattrs or flags change, but not their data
+    pub metadata_changed: Vec<PathBuf>,
+}
+
+/// One directory entry, and what has happened to it so far
+#[derive(Default)]
+struct Entry {
+    /// Path in the parent, unless the entry is created by the stream
+    origin: Option<PathBuf>,
+    renamed: bool,
+    deleted: bool,
+    data: bool,
+    bytes: u64,
+    metadata: bool,
+}
+
+#[derive(Default)]
+struct Tracker {
+    paths: BTreeMap<PathBuf, usize>,
+    entries: Vec<Entry>,
+}
+
+impl Tracker {
+    /// Entry at `path`, which must be in the parent if it has not been seen
+    /// yet. Its path in the parent depends on what its closest known
+    /// ancestor was called there.
+    fn at(&mut self, path: &Path) -> usize {
+        if let Some(e) = self.paths.get(path) {
+            return *e;
+        }
+        let origin = path
+            .ancestors()
+            .skip(1)
+            .find_map(|a| {
+                let e = self.paths.get(a)?;
+                let origin = self.entries[*e].origin.as_ref()?;
+                renamed(path, a, origin)
+            })
+            .unwrap_or_else(|| path.to_path_buf());
+        self.insert(
+            path,
+            Entry {
+                origin: Some(origin),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn insert(&mut self, path: &Path, entry: Entry) -> usize {
+        let e = self.entries.len();
+        self.entries.push(entry);
+        self.paths.insert(path.to_path_buf(), e);
+        e
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let e = self.at(path);
+        self.paths.remove(path);
+        self.entries[e].deleted = true;
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let e = self.at(from);
+        self.entries[e].renamed = true;
+        if let Some(replaced) = self.paths.remove(to) {
+            self.entries[replaced].deleted = true;
+        }
+        let moved: Vec<_> = self
+            .paths
+            .keys()
+            .filter(|p| p.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            if let (Some(e), Some(dst)) = (self.paths.remove(&path), renamed(&path, from, to)) {
+                self.paths.insert(dst, e);
+            }
+        }
+    }
+
+    fn data(&mut self, path: &Path, bytes: u64) {
+        let e = self.at(path);
+        self.entries[e].data = true;
+        self.entries[e].bytes += bytes;
+    }
+
+    fn metadata(&mut self, path: &Path) {
+        let e = self.at(path);
+        self.entries[e].metadata = true;
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// List the paths that this (incremental) sendstream creates, deletes,
+    /// renames or changes, without needing the parent subvolume. Paths that
+    /// the stream does not mention are unchanged. Temporary files that are
+    /// created and removed again are left out entirely. For a full
+    /// sendstream, everything is created.
+    pub fn changes(&self) -> ChangeReport {
+        let mut t = Tracker::default();
+        for cmd in &self.commands {
+            match cmd {
+                Command::Mkdir(c) => {
+                    t.insert(&c.path, Entry::default());
+                }
+                Command::Mkfile(c) => {
+                    t.insert(&c.path, Entry::default());
+                }
+                Command::Mkfifo(c) => {
+                    t.insert(&c.path, Entry::default());
+                }
+                Command::Mknod(c) => {
+                    t.insert(&c.path, Entry::default());
+                }
+                Command::Mksock(c) => {
+                    t.insert(&c.path, Entry::default());
+                }
+                Command::Symlink(c) => {
+                    t.insert(&c.link_name, Entry::default());
+                }
+                Command::Link(c) => {
+                    t.insert(&c.link_name, Entry::default());
+                }
+                Command::Rename(c) => t.rename(&c.from, &c.to),
+                Command::Unlink(c) => t.remove(&c.path),
+                Command::Rmdir(c) => t.remove(&c.path),
+                Command::Write(c) => t.data(&c.path, c.data.len() as u64),
+                Command::EncodedWrite(c) => t.data(&c.path, c.unencoded_file_len),
+                Command::Clone(c) => t.data(&c.dst_path, c.len.as_u64()),
+                Command::Truncate(c) => t.data(&c.path, 0),
+                Command::Fallocate(c) => t.data(&c.path, 0),
+                Command::UpdateExtent(c) => t.data(&c.path, c.len),
+                Command::Chmod(c) => t.metadata(&c.path),
+                Command::Chown(c) => t.metadata(&c.path),
+                Command::Utimes(c) => t.metadata(&c.path),
+                Command::SetXattr(c) => t.metadata(&c.path),
+                Command::RemoveXattr(c) => t.metadata(&c.path),
+                Command::Fileattr(c) => t.metadata(&c.path),
+                Command::Subvol(_) | Command::Snapshot(_) | Command::End => (),
+            }
+        }
+
+        let mut report = ChangeReport::default();
+        for (path, e) in &t.paths {
+            let entry = &t.entries[*e];
+            let Some(origin) = &entry.origin else {
+                report.created.push(ChangedPath {
+                    path: path.clone(),
+                    bytes: entry.bytes,
+                });
+                continue;
+            };
+            if entry.renamed && origin != path {
+                report.renamed.push(RenamedPath {
+                    from: origin.clone(),
+                    to: path.clone(),
+                });
+            }
+            if entry.data {
+                report.content_changed.push(ChangedPath {
+                    path: path.clone(),
+                    bytes: entry.bytes,
+                });
+            } else if entry.metadata {
+                report.metadata_changed.push(path.clone());
+            }
+        }
+        report.deleted = t
+            .entries
+            .iter()
+            .filter(|e| e.deleted)
+            .filter_map(|e| e.origin.clone())
+            .collect();
+        report.deleted.sort();
+        report.renamed.sort();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::Data;
+    use crate::FileOffset;
+
+    #[test]
+    fn changes_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let full = sendstreams[0].changes();
+        assert!(full.deleted.is_empty() && full.content_changed.is_empty());
+        assert!(full.created.contains(&ChangedPath {
+            path: PathBuf::from("hello/msg"),
+            bytes: 13,
+        }));
+
+        let undo = &sendstreams[1];
+        assert_eq!(
+            ChangeReport {
+                created: vec![],
+                deleted: vec![
+                    PathBuf::from("dir-to-be-deleted"),
+                    PathBuf::from("to-be-deleted")
+                ],
+                renamed: vec![],
+                content_changed: vec![ChangedPath {
+                    path: PathBuf::from("hello/msg"),
+                    bytes: 9,
+                }],
+                metadata_changed: vec![PathBuf::new()],
+            },
+            undo.changes()
+        );
+
+        // rename a directory of the parent, and then change a file in it
+        let mut commands = undo.commands().to_vec();
+        let end = commands.pop().expect("demo ends");
+        commands.extend([
+            crate::Rename {
+                from: Cow::Borrowed(Path::new("hello")),
+                to: Cow::Borrowed(Path::new("bye")),
+            }
+            .into(),
+            crate::Write {
+                path: Cow::Borrowed(Path::new("bye/lorem")),
+                offset: FileOffset(0),
+                data: Data(Cow::Borrowed(b"Lorem")),
+            }
+            .into(),
+            end,
+        ]);
+        let report = Sendstream { commands }.changes();
+        assert_eq!(
+            vec![RenamedPath {
+                from: PathBuf::from("hello"),
+                to: PathBuf::from("bye"),
+            }],
+            report.renamed
+        );
+        assert_eq!(
+            vec![
+                ChangedPath {
+                    path: PathBuf::from("bye/lorem"),
+                    bytes: 5,
+                },
+                ChangedPath {
+                    path: PathBuf::from("bye/msg"),
+                    bytes: 9,
+                },
+            ],
+            report.content_changed
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Content-defined chunking of file data, for storing sendstream payloads in
+//! deduplicating chunk stores.
+//!
+//! Chunk boundaries are found with FastCDC, so inserting or removing data in
+//! the middle of a write only changes the chunks around the edit. Each
+//! [crate::Write] is chunked on its own, which keeps every chunk tied to a
+//! single command so that the stream can be rebuilt from its chunks.
+//! Uncompressed [crate::EncodedWrite]s are chunked by the data that ends up in
+//! the file. Compressed or encrypted ones are left out, since their data
+//! could not be chunked without decoding it.
+
+use std::path::Path;
+
+use fastcdc::v2020;
+use fastcdc::v2020::FastCDC;
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// Target sizes of the chunks that [Chunker::chunks] produces. Values outside
+/// of what FastCDC supports are clamped into range.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Chunker {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        // small enough that the kernel's 48K writes are split into a few
+        // chunks each
+        Self {
+            min_size: 2048,
+            avg_size: 8192,
+            max_size: 32768,
+        }
+    }
+}
+
+/// A piece of the data carried by a single [crate::Write] or
+/// [crate::EncodedWrite].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk<'s> {
+    /// Index of the write in the sendstream
+    pub index: usize,
+    /// File being written
+    pub path: &'s Path,
+    /// Offset in the file where this chunk goes
+    pub file_offset: u64,
+    pub data: &'s [u8],
+    /// SHA-256 of `data`
+    pub hash: [u8; 32],
+}
+
+impl Chunker {
+    /// Split the data of every [crate::Write] (and uncompressed
+    /// [crate::EncodedWrite]) in `sendstream` into chunks, in stream order.
+    pub fn chunks<'s>(&self, sendstream: &'s Sendstream) -> impl Iterator<Item = Chunk<'s>> {
+        let min = self.min_size.clamp(v2020::MINIMUM_MIN, v2020::MINIMUM_MAX);
+        let avg = self.avg_size.clamp(v2020::AVERAGE_MIN, v2020::AVERAGE_MAX);
+        let max = self.max_size.clamp(v2020::MAXIMUM_MIN, v2020::MAXIMUM_MAX);
+        sendstream
+            .commands()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, cmd)| match cmd {
+                Command::Write(w) => Some((index, w.path(), w.offset(), &w.data()[..])),
+                Command::EncodedWrite(w) => w.decoded().map(|d| (index, w.path(), w.offset(), d)),
+                _ => None,
+            })
+            .flat_map(move |(index, path, offset, payload)| {
+                FastCDC::new(payload, min, avg, max).map(move |c| {
+                    let data = &payload[c.offset..c.offset + c.length];
+                    Chunk {
+                        index,
+                        path,
+                        file_offset: offset.as_u64() + c.offset as u64,
+                        data,
+                        hash: Sha256::digest(data).into(),
+                    }
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn chunk_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let chunker = Chunker::default();
+        let chunks: Vec<_> = chunker.chunks(&sendstreams[0]).collect();
+        assert!(chunks.iter().all(|c| c.data.len() <= 32768));
+
+        // the chunks of each write put back together make up the original
+        for (index, cmd) in sendstreams[0].commands().iter().enumerate() {
+            if let Command::Write(w) = cmd {
+                let data: Vec<u8> = chunks
+                    .iter()
+                    .filter(|c| c.index == index)
+                    .flat_map(|c| c.data.iter().copied())
+                    .collect();
+                assert_eq!(w.data().as_ref(), data);
+            }
+        }
+
+        // hello/lorem and the part of hello/lorem-reflinked that is written out
+        // in full share chunks
+        let unique: BTreeSet<_> = chunks.iter().map(|c| c.hash).collect();
+        assert!(unique.len() < chunks.len());
+    }
+
+    #[test]
+    fn chunk_encoded() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let encoded = |compression| {
+            crate::EncodedWrite {
+                path: Cow::Borrowed(Path::new("file")),
+                offset: crate::FileOffset(4096),
+                unencoded_file_len: data.len() as u64 - 1000,
+                unencoded_len: data.len() as u64,
+                unencoded_offset: 1000,
+                compression,
+                encryption: 0,
+                data: crate::Data(Cow::Borrowed(&data)),
+            }
+            .into()
+        };
+        let stream = Sendstream {
+            commands: vec![
+                encoded(crate::Compression::None),
+                encoded(crate::Compression::Zstd),
+            ],
+        };
+        let chunks: Vec<_> = Chunker::default().chunks(&stream).collect();
+        assert!(chunks.iter().all(|c| c.index == 0));
+        assert_eq!(4096, chunks[0].file_offset);
+        let joined: Vec<u8> = chunks.iter().flat_map(|c| c.data.iter().copied()).collect();
+        assert_eq!(&data[1000..], joined);
+    }
+}
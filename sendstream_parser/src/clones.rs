@@ -0,0 +1,166 @@
+//! Which files share data with each other through [crate::Clone]s.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::extents::Source;
+use crate::Command;
+use crate::Sendstream;
+
+/// A range of a file in the sent subvolume that shares data with a range of
+/// another file (possibly in another subvolume)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CloneEdge {
+    /// Final path of the destination file
+    pub dst: PathBuf,
+    pub dst_offset: u64,
+    pub len: u64,
+    /// Subvolume that the data is cloned from
+    pub src_uuid: Uuid,
+    /// Source file, as its final path if it is in the sent subvolume
+    pub src: PathBuf,
+    pub src_offset: u64,
+}
+
+/// Graph of every range shared through a [crate::Clone], see
+/// [Sendstream::clone_graph]. With the `serde` feature this can be exported
+/// to JSON (or any other serde format).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CloneGraph {
+    /// Uuid of the sent subvolume
+    pub uuid: Option<Uuid>,
+    pub edges: Vec<CloneEdge>,
+}
+
+impl CloneGraph {
+    /// Every shared range that `path` (in the sent subvolume) is either the
+    /// destination or the source of
+    pub fn shares_with<'g>(&'g self, path: &'g Path) -> impl Iterator<Item = &'g CloneEdge> {
+        self.edges
+            .iter()
+            .filter(move |e| e.dst == path || (Some(e.src_uuid) == self.uuid && e.src == path))
+    }
+
+    /// Every file (in the sent subvolume) that shares data with `path`,
+    /// directly or through other files
+    pub fn connected<'g>(&'g self, path: &'g Path) -> BTreeSet<&'g Path> {
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![path];
+        while let Some(p) = todo.pop() {
+            for e in self.shares_with(p) {
+                let local_src = Some(e.src_uuid) == self.uuid;
+                for other in [Some(e.dst.as_path()), local_src.then_some(e.src.as_path())]
+                    .into_iter()
+                    .flatten()
+                {
+                    if other != path && seen.insert(other) {
+                        todo.push(other);
+                    }
+                }
+            }
+        }
+        seen
+    }
+
+    /// Render the graph in graphviz dot format, with one node per file and
+    /// one edge per shared range pointing from the source to the destination.
+    /// Files in other subvolumes are prefixed with their subvolume uuid.
+    pub fn to_dot(&self) -> String {
+        let node = |uuid: Uuid, path: &Path| {
+            let name = if Some(uuid) == self.uuid {
+                path.display().to_string()
+            } else {
+                format!("{uuid}:{}", path.display())
+            };
+            format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+        };
+        let mut out = String::from("digraph clones {\n");
+        for e in &self.edges {
+            let dst = node(self.uuid.unwrap_or_default(), &e.dst);
+            let src = node(e.src_uuid, &e.src);
+            // writing to a String never fails
+            let _ = writeln!(
+                out,
+                "    {src} -> {dst} [label=\"{}+{} @ {}\"];",
+                e.src_offset, e.len, e.dst_offset
+            );
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Build a [CloneGraph] of the ranges that still share data once the
+    /// whole sendstream has been received, so parts of a [crate::Clone] that
+    /// are overwritten later are left out (see [Sendstream::extent_map]).
+    pub fn clone_graph(&self) -> CloneGraph {
+        let uuid = self.commands.iter().find_map(|c| match c {
+            Command::Subvol(s) => Some(s.uuid),
+            Command::Snapshot(s) => Some(s.uuid),
+            _ => None,
+        });
+        let edges = self
+            .extent_map()
+            .into_iter()
+            .flat_map(|f| {
+                let dst = f.path;
+                f.extents.into_iter().filter_map(move |e| match e.source {
+                    Source::Clone { uuid, path, offset } => Some(CloneEdge {
+                        dst: dst.clone(),
+                        dst_offset: e.offset,
+                        len: e.len,
+                        src_uuid: uuid,
+                        src: path,
+                        src_offset: offset,
+                    }),
+                    _ => None,
+                })
+            })
+            .collect();
+        CloneGraph { uuid, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clone_graph_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let graph = sendstreams[0].clone_graph();
+        let uuid = Uuid::parse_str("0fbf2b5f-ff82-a748-8b41-e35aec190b49").expect("valid");
+        assert_eq!(Some(uuid), graph.uuid);
+        assert_eq!(
+            vec![CloneEdge {
+                dst: PathBuf::from("hello/lorem-reflinked"),
+                dst_offset: 0,
+                len: 131072,
+                src_uuid: uuid,
+                src: PathBuf::from("hello/lorem"),
+                src_offset: 0,
+            }],
+            graph.edges
+        );
+        assert_eq!(1, graph.shares_with(Path::new("hello/lorem")).count());
+        assert_eq!(0, graph.shares_with(Path::new("hello/msg")).count());
+        assert_eq!(
+            BTreeSet::from([Path::new("hello/lorem-reflinked")]),
+            graph.connected(Path::new("hello/lorem"))
+        );
+        assert_eq!(
+            "digraph clones {\n    \"hello/lorem\" -> \"hello/lorem-reflinked\" [label=\"0+131072 @ 0\"];\n}\n",
+            graph.to_dot()
+        );
+    }
+}
@@ -0,0 +1,627 @@
+//! Compare the subvolumes that sendstreams produce, with each other or with
+//! a directory on disk.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::errno::Errno;
+use nix::unistd::lseek;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use nix::unistd::Whence;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::Inode;
+use crate::fs::InodeKind;
+use crate::manifest::FileType;
+use crate::Atime;
+use crate::Mode;
+use crate::Mtime;
+use crate::Rdev;
+use crate::Sendstream;
+
+/// One way in which a path differs between two subvolumes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Delta {
+    /// The path is a different kind of file, in which case nothing else about
+    /// it is compared
+    FileType {
+        old: FileType,
+        new: FileType,
+    },
+    /// Permission bits
+    Mode {
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    Uid {
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    Gid {
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    Mtime {
+        old: Option<Mtime>,
+        new: Option<Mtime>,
+    },
+    /// An xattr is added (`old` is `None`), removed (`new` is `None`) or
+    /// changed
+    Xattr {
+        name: String,
+        old: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    /// Contents of a regular file, with every byte range that differs (see
+    /// [fs::FileContents::changed_ranges])
+    Contents {
+        old_size: u64,
+        new_size: u64,
+        changed: Vec<Range<u64>>,
+    },
+    /// Target of a symlink
+    Target {
+        old: PathBuf,
+        new: PathBuf,
+    },
+    /// Device number of a character or block device
+    Rdev {
+        old: u64,
+        new: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Change {
+    Added,
+    Removed,
+    Modified(Vec<Delta>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PathDiff {
+    pub path: PathBuf,
+    pub change: Change,
+}
+
+/// Every path that differs between two subvolumes, in sorted order, see
+/// [diff]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Diff {
+    pub paths: Vec<PathDiff>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn added(&self) -> impl Iterator<Item = &PathDiff> {
+        self.paths.iter().filter(|p| p.change == Change::Added)
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &PathDiff> {
+        self.paths.iter().filter(|p| p.change == Change::Removed)
+    }
+
+    pub fn modified(&self) -> impl Iterator<Item = &PathDiff> {
+        self.paths
+            .iter()
+            .filter(|p| matches!(p.change, Change::Modified(_)))
+    }
+}
+
+pub(crate) fn file_type(kind: &InodeKind) -> FileType {
+    match kind {
+        InodeKind::Directory(_) => FileType::Directory,
+        InodeKind::File(_) => FileType::File,
+        InodeKind::Symlink(_) => FileType::Symlink,
+        InodeKind::Fifo => FileType::Fifo,
+        InodeKind::Socket => FileType::Socket,
+        InodeKind::CharDevice(_) => FileType::CharDevice,
+        InodeKind::BlockDevice(_) => FileType::BlockDevice,
+    }
+}
+
+/// The [Delta] for a path that is a different kind of file in each
+/// subvolume, in which case nothing else needs comparing
+fn type_delta(old: &Inode, new: &Inode) -> Option<Delta> {
+    let (old, new) = (file_type(old.kind()), file_type(new.kind()));
+    (old != new).then_some(Delta::FileType { old, new })
+}
+
+/// Every [Delta] in the metadata that all kinds of files have
+fn metadata_deltas(old: &Inode, new: &Inode) -> Vec<Delta> {
+    let mut deltas = Vec::new();
+    let (old_mode, new_mode) = (old.mode().map(|m| m.0), new.mode().map(|m| m.0));
+    if old_mode != new_mode {
+        deltas.push(Delta::Mode {
+            old: old_mode,
+            new: new_mode,
+        });
+    }
+    let (old_uid, new_uid) = (old.uid().map(|u| u.as_raw()), new.uid().map(|u| u.as_raw()));
+    if old_uid != new_uid {
+        deltas.push(Delta::Uid {
+            old: old_uid,
+            new: new_uid,
+        });
+    }
+    let (old_gid, new_gid) = (old.gid().map(|g| g.as_raw()), new.gid().map(|g| g.as_raw()));
+    if old_gid != new_gid {
+        deltas.push(Delta::Gid {
+            old: old_gid,
+            new: new_gid,
+        });
+    }
+    if old.mtime() != new.mtime() {
+        deltas.push(Delta::Mtime {
+            old: old.mtime(),
+            new: new.mtime(),
+        });
+    }
+    let names: BTreeSet<_> = old.xattrs().keys().chain(new.xattrs().keys()).collect();
+    for name in names {
+        let (o, n) = (old.xattrs().get(name), new.xattrs().get(name));
+        if o != n {
+            deltas.push(Delta::Xattr {
+                name: String::from_utf8_lossy(name).into_owned(),
+                old: o.cloned(),
+                new: n.cloned(),
+            });
+        }
+    }
+    deltas
+}
+
+/// The [Delta] in whatever is specific to the kind of file
+fn kind_delta(old: &InodeKind, new: &InodeKind) -> Option<Delta> {
+    match (old, new) {
+        (InodeKind::File(o), InodeKind::File(n)) => {
+            let changed = o.changed_ranges(n);
+            (!changed.is_empty()).then(|| Delta::Contents {
+                old_size: o.len(),
+                new_size: n.len(),
+                changed,
+            })
+        }
+        (InodeKind::Symlink(o), InodeKind::Symlink(n)) if o != n => Some(Delta::Target {
+            old: o.clone(),
+            new: n.clone(),
+        }),
+        (InodeKind::CharDevice(o), InodeKind::CharDevice(n))
+        | (InodeKind::BlockDevice(o), InodeKind::BlockDevice(n))
+            if o != n =>
+        {
+            Some(Delta::Rdev {
+                old: o.as_u64(),
+                new: n.as_u64(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Every [Delta] between the same path in two subvolumes
+fn deltas(old: &Inode, new: &Inode) -> Vec<Delta> {
+    if let Some(d) = type_delta(old, new) {
+        return vec![d];
+    }
+    let mut deltas = metadata_deltas(old, new);
+    deltas.extend(kind_delta(old.kind(), new.kind()));
+    deltas
+}
+
+/// Compare every path in two subvolumes. Paths are compared on their own, so
+/// a file that is moved shows up as removed from one path and added at
+/// another, and hard links are compared once per path.
+pub fn diff_filesystems(old: &Filesystem, new: &Filesystem) -> Diff {
+    let old_paths: BTreeMap<_, _> = old.walk().into_iter().collect();
+    let new_paths: BTreeMap<_, _> = new.walk().into_iter().collect();
+    let all: BTreeSet<_> = old_paths.keys().chain(new_paths.keys()).collect();
+    let paths = all
+        .into_iter()
+        .filter_map(|path| {
+            let change = match (old_paths.get(path), new_paths.get(path)) {
+                (Some(_), None) => Change::Removed,
+                (None, Some(_)) => Change::Added,
+                (Some(o), Some(n)) => {
+                    let deltas = deltas(&old[*o], &new[*n]);
+                    if deltas.is_empty() {
+                        return None;
+                    }
+                    Change::Modified(deltas)
+                }
+                (None, None) => return None,
+            };
+            Some(PathDiff {
+                path: path.clone(),
+                change,
+            })
+        })
+        .collect();
+    Diff { paths }
+}
+
+/// Compare the subvolumes that two full sendstreams produce, for example two
+/// builds of the same image. Incremental sendstreams need to be replayed on
+/// top of their parents first, after which [diff_filesystems] can be used.
+pub fn diff(old: &Sendstream, new: &Sendstream) -> fs::Result<Diff> {
+    Ok(diff_filesystems(
+        &Filesystem::from_sendstream(old)?,
+        &Filesystem::from_sendstream(new)?,
+    ))
+}
+
+/// Every path under `dir` (including `dir` itself, as the empty path),
+/// without following symlinks
+pub(crate) fn walk_dir(dir: &Path) -> io::Result<BTreeMap<PathBuf, Metadata>> {
+    let mut out = BTreeMap::from([(PathBuf::new(), std::fs::symlink_metadata(dir)?)]);
+    let mut todo = vec![PathBuf::new()];
+    while let Some(parent) = todo.pop() {
+        for entry in std::fs::read_dir(dir.join(&parent))? {
+            let entry = entry?;
+            let path = parent.join(entry.file_name());
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                todo.push(path.clone());
+            }
+            out.insert(path, meta);
+        }
+    }
+    Ok(out)
+}
+
+/// Model of the file at `path` with everything but its contents
+pub(crate) fn disk_inode(path: &Path, meta: &Metadata) -> io::Result<Inode> {
+    let ft = meta.file_type();
+    let kind = if ft.is_dir() {
+        InodeKind::Directory(BTreeMap::new())
+    } else if ft.is_file() {
+        InodeKind::File(FileContents::default())
+    } else if ft.is_symlink() {
+        InodeKind::Symlink(std::fs::read_link(path)?)
+    } else if ft.is_fifo() {
+        InodeKind::Fifo
+    } else if ft.is_socket() {
+        InodeKind::Socket
+    } else if ft.is_char_device() {
+        InodeKind::CharDevice(Rdev(meta.rdev()))
+    } else {
+        InodeKind::BlockDevice(Rdev(meta.rdev()))
+    };
+    let names = match xattr::list(path) {
+        Ok(names) => names.collect(),
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.insert(name.as_bytes().to_vec(), value);
+        }
+    }
+    Ok(Inode {
+        ino: None,
+        kind,
+        nlink: meta.nlink() as usize,
+        mode: Some(Mode(meta.mode() & 0o7777)),
+        uid: Some(Uid::from_raw(meta.uid())),
+        gid: Some(Gid::from_raw(meta.gid())),
+        atime: meta.accessed().ok().map(Atime),
+        mtime: meta.modified().ok().map(Mtime),
+        ctime: None,
+        xattrs,
+    })
+}
+
+/// Ranges of `file` that contain data, according to `SEEK_DATA` and
+/// `SEEK_HOLE`, so that holes do not have to be read. Filesystems that do not
+/// report holes have data everywhere.
+pub(crate) fn data_ranges(file: &File, size: u64) -> Vec<(u64, u64)> {
+    let fd = file.as_raw_fd();
+    let mut data = Vec::new();
+    let mut off = 0;
+    while off < size {
+        let start = match lseek(fd, off as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // nothing but a hole after `off`
+            Err(Errno::ENXIO) => break,
+            Err(_) => return vec![(0, size)],
+        };
+        let end = lseek(fd, start as i64, Whence::SeekHole).map_or(size, |end| end as u64);
+        data.push((start, end));
+        off = end.max(start + 1);
+    }
+    data
+}
+
+/// Every [Delta] between `expected` and the file at `path`. Metadata that the
+/// model does not know (like the mode of a file that is never chmod-ed) is
+/// not compared.
+fn disk_deltas(expected: &Inode, path: &Path, meta: &Metadata) -> io::Result<Vec<Delta>> {
+    let disk = disk_inode(path, meta)?;
+    if let Some(d) = type_delta(expected, &disk) {
+        return Ok(vec![d]);
+    }
+    let mut deltas: Vec<_> = metadata_deltas(expected, &disk)
+        .into_iter()
+        .filter(|d| {
+            !matches!(
+                d,
+                Delta::Mode { old: None, .. }
+                    | Delta::Uid { old: None, .. }
+                    | Delta::Gid { old: None, .. }
+                    | Delta::Mtime { old: None, .. }
+            )
+        })
+        .collect();
+    match expected.kind() {
+        InodeKind::File(contents) => {
+            let file = File::open(path)?;
+            let changed = contents.changed_ranges_by(
+                meta.len(),
+                data_ranges(&file, meta.len()),
+                |off, len| {
+                    let mut buf = vec![0; len as usize];
+                    file.read_exact_at(&mut buf, off)?;
+                    Ok::<_, io::Error>(buf)
+                },
+            )?;
+            if !changed.is_empty() {
+                deltas.push(Delta::Contents {
+                    old_size: contents.len(),
+                    new_size: meta.len(),
+                    changed,
+                });
+            }
+        }
+        kind => deltas.extend(kind_delta(kind, disk.kind())),
+    }
+    Ok(deltas)
+}
+
+/// Compare everything at or below `path` in `expected` (for example a
+/// subvolume replayed from the sendstream it was received from) with the
+/// directory tree at `dir`, which takes the place of `path` itself like in
+/// [crate::extract::extract_tree_to]. Paths in the [Diff] are relative to
+/// `path`, with the "old" side being `expected` and the "new" side the
+/// directory, so files that only exist on disk are [Change::Added].
+///
+/// Contents are compared byte by byte, skipping ranges that are holes both in
+/// `expected` and on disk.
+pub fn diff_directory(expected: &Filesystem, path: &Path, dir: &Path) -> io::Result<Diff> {
+    let root = expected.lookup(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in the subvolume", path.display()),
+        )
+    })?;
+    let mut old = BTreeMap::from([(PathBuf::new(), root)]);
+    old.extend(
+        expected
+            .walk()
+            .into_iter()
+            .filter_map(|(p, id)| Some((p.strip_prefix(path).ok()?.to_path_buf(), id))),
+    );
+    let new = walk_dir(dir)?;
+    let all: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    let mut paths = Vec::new();
+    for p in all {
+        let change = match (old.get(p), new.get(p)) {
+            (Some(_), None) => Change::Removed,
+            (None, Some(_)) => Change::Added,
+            (Some(id), Some(meta)) => {
+                let deltas = disk_deltas(&expected[*id], &dir.join(p), meta)?;
+                if deltas.is_empty() {
+                    continue;
+                }
+                Change::Modified(deltas)
+            }
+            (None, None) => continue,
+        };
+        paths.push(PathDiff {
+            path: p.clone(),
+            change,
+        });
+    }
+    Ok(Diff { paths })
+}
+
+/// Whether two subvolumes have exactly the same contents: the same paths,
+/// with the same hard links between them, and the same metadata (including
+/// all timestamps but ctime, which cannot be set when receiving) and data.
+/// Inode numbers are not compared.
+pub fn equivalent(a: &Filesystem, b: &Filesystem) -> bool {
+    let same = |x: &Inode, y: &Inode| {
+        deltas(x, y).is_empty() && x.atime() == y.atime() && x.nlink() == y.nlink()
+    };
+    let (wa, wb) = (a.walk(), b.walk());
+    if wa.len() != wb.len() || !same(&a[a.root()], &b[b.root()]) {
+        return false;
+    }
+    // hard links must connect the same paths on both sides
+    let mut a_to_b = BTreeMap::new();
+    let mut b_to_a = BTreeMap::new();
+    wa.iter().zip(&wb).all(|((pa, ia), (pb, ib))| {
+        pa == pb
+            && *a_to_b.entry(*ia).or_insert(*ib) == *ib
+            && *b_to_a.entry(*ib).or_insert(*ia) == *ia
+            && same(&a[*ia], &b[*ib])
+    })
+}
+
+impl<'a> Sendstream<'a> {
+    /// Whether this and `other` (both full sendstreams) produce
+    /// [equivalent] subvolumes, regardless of the temporary names, order and
+    /// split of the commands that they do it with. The headers are not
+    /// compared, so two sends of different subvolumes with the same contents
+    /// are equal too.
+    pub fn semantically_equal(&self, other: &Sendstream) -> fs::Result<bool> {
+        Ok(equivalent(
+            &Filesystem::from_sendstream(self)?,
+            &Filesystem::from_sendstream(other)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn diff_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        assert!(diff(&sendstreams[0], &sendstreams[0])
+            .expect("failed to replay")
+            .is_empty());
+
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let undo = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        let diff = diff_filesystems(&demo, &undo);
+        assert!(diff.added().next().is_none());
+        assert_eq!(
+            BTreeSet::from([Path::new("dir-to-be-deleted"), Path::new("to-be-deleted")]),
+            diff.removed().map(|p| p.path.as_path()).collect()
+        );
+        let msg = diff
+            .paths
+            .iter()
+            .find(|p| p.path == Path::new("hello/msg"))
+            .expect("msg is modified");
+        let Change::Modified(deltas) = &msg.change else {
+            panic!("msg is not modified: {msg:?}");
+        };
+        assert!(deltas.contains(&Delta::Contents {
+            old_size: 13,
+            new_size: 9,
+            changed: vec![Range { start: 0, end: 13 }],
+        }));
+        assert!(deltas.contains(&Delta::Xattr {
+            name: "user.antlir.demo".into(),
+            old: demo
+                .get(Path::new("hello/msg"))
+                .and_then(|i| i.xattrs().get(b"user.antlir.demo".as_slice()))
+                .cloned(),
+            new: None,
+        }));
+
+        let reverse = diff_filesystems(&undo, &demo);
+        assert_eq!(2, reverse.added().count());
+        assert_eq!(diff.modified().count(), reverse.modified().count());
+    }
+
+    #[test]
+    fn diff_directory_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let dest = std::env::temp_dir().join(format!("diff_directory.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        crate::extract::extract_tree_to(&sendstreams[0], Path::new("hello"), &dest)
+            .expect("failed to extract");
+        // ownership is only restored when running as root
+        let root = Uid::effective().is_root();
+        let drift = |diff: &Diff| {
+            diff.paths
+                .iter()
+                .filter(|p| match &p.change {
+                    Change::Modified(d) => {
+                        root || d
+                            .iter()
+                            .any(|d| !matches!(d, Delta::Uid { .. } | Delta::Gid { .. }))
+                    }
+                    _ => true,
+                })
+                .count()
+        };
+        let diff = diff_directory(&demo, Path::new("hello"), &dest).expect("failed to compare");
+        assert_eq!(0, drift(&diff), "{diff:#?}");
+
+        std::fs::write(dest.join("lorem"), b"Lorem").expect("failed to write");
+        std::fs::remove_file(dest.join("msg-sym")).expect("failed to remove");
+        std::fs::write(dest.join("extra"), b"").expect("failed to write");
+        let diff = diff_directory(&demo, Path::new("hello"), &dest).expect("failed to compare");
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+        assert_eq!(
+            vec![Path::new("extra")],
+            diff.added().map(|p| p.path.as_path()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Path::new("msg-sym")],
+            diff.removed().map(|p| p.path.as_path()).collect::<Vec<_>>()
+        );
+        let lorem = diff
+            .modified()
+            .find(|p| p.path == Path::new("lorem"))
+            .expect("lorem is modified");
+        let Change::Modified(deltas) = &lorem.change else {
+            panic!("lorem is not modified: {lorem:?}");
+        };
+        let Some(Delta::Contents {
+            old_size, new_size, ..
+        }) = deltas.iter().find(|d| matches!(d, Delta::Contents { .. }))
+        else {
+            panic!("contents of lorem did not change: {deltas:?}");
+        };
+        assert_eq!((223446, 5), (*old_size, *new_size));
+        assert!(deltas.iter().any(|d| matches!(d, Delta::Mtime { .. })));
+        assert!(diff_directory(&demo, Path::new("nope"), &dest).is_err());
+    }
+
+    #[test]
+    fn semantically_equal() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let normalized = demo.normalize(None).expect("failed to normalize");
+        assert_ne!(demo.commands(), normalized.commands());
+        assert!(demo
+            .semantically_equal(&normalized)
+            .expect("failed to replay"));
+
+        let mut commands = demo.commands().to_vec();
+        let end = commands.pop();
+        commands.push(
+            crate::Chmod {
+                path: std::borrow::Cow::Borrowed(Path::new("hello/msg")),
+                mode: Mode(0o444),
+            }
+            .into(),
+        );
+        commands.extend(end);
+        let chmodded = Sendstream { commands };
+        assert!(!demo
+            .semantically_equal(&chmodded)
+            .expect("failed to replay"));
+        assert!(matches!(
+            demo.semantically_equal(&sendstreams[1]),
+            Err(fs::Error::Incremental)
+        ));
+    }
+}
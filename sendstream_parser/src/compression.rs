@@ -0,0 +1,156 @@
+//! Report how well the data of a (v2) sendstream is compressed. Version 1
+//! sendstreams only have plain [crate::Write]s, which are reported as
+//! uncompressed.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::Compression;
+use crate::Sendstream;
+
+/// Bytes of data sent for some set of writes, see [Sendstream::compression]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CompressionTotals {
+    /// Number of [crate::Write]s and [crate::EncodedWrite]s
+    pub writes: u64,
+    /// Bytes of data in the sendstream
+    pub encoded_bytes: u64,
+    /// Bytes of file contents that the data turns into
+    pub unencoded_bytes: u64,
+}
+
+impl CompressionTotals {
+    fn add(&mut self, encoded: u64, unencoded: u64) {
+        self.writes += 1;
+        self.encoded_bytes += encoded;
+        self.unencoded_bytes += unencoded;
+    }
+
+    /// Unencoded bytes per encoded byte, or `None` if there is no data
+    pub fn ratio(&self) -> Option<f64> {
+        (self.encoded_bytes > 0).then(|| self.unencoded_bytes as f64 / self.encoded_bytes as f64)
+    }
+}
+
+/// Data sent by a sendstream, split up by how it is compressed
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CompressionReport {
+    /// Keyed by [Compression::algorithm], plain writes count as `"none"`
+    pub by_algorithm: BTreeMap<&'static str, CompressionTotals>,
+    /// Keyed by the final path of each file (see [Sendstream::final_paths]),
+    /// files that do not survive to the end of the stream are left out
+    pub by_file: BTreeMap<PathBuf, CompressionTotals>,
+    /// Everything together
+    pub total: CompressionTotals,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Add up the encoded and unencoded sizes of every write. The unencoded
+    /// size of an [crate::EncodedWrite] is how much of it ends up in the file
+    /// ([crate::EncodedWrite::unencoded_file_len]) rather than the whole
+    /// decoded extent, so an extent that is only partly used looks worse than
+    /// it compresses.
+    pub fn compression(&self) -> CompressionReport {
+        let finals = self.final_paths();
+        let mut report = CompressionReport::default();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let (compression, encoded, unencoded) = match cmd {
+                Command::Write(w) => (Compression::None, w.data.len() as u64, w.data.len() as u64),
+                Command::EncodedWrite(w) => {
+                    (w.compression, w.data.len() as u64, w.unencoded_file_len)
+                }
+                _ => continue,
+            };
+            report
+                .by_algorithm
+                .entry(compression.algorithm())
+                .or_default()
+                .add(encoded, unencoded);
+            if let Some(path) = finals.get(index) {
+                report
+                    .by_file
+                    .entry(path.to_path_buf())
+                    .or_default()
+                    .add(encoded, unencoded);
+            }
+            report.total.add(encoded, unencoded);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+    use crate::Data;
+    use crate::EncodedWrite;
+    use crate::FileOffset;
+
+    #[test]
+    fn compression_v2() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let plain = sendstreams[0].compression();
+        assert_eq!(
+            vec!["none"],
+            plain.by_algorithm.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(1.0), plain.total.ratio());
+
+        let mut commands = sendstreams[0].commands().to_vec();
+        let end = commands.pop().expect("demo ends");
+        let encoded = |compression, data: &'static [u8]| {
+            Command::from(EncodedWrite {
+                path: Cow::Borrowed(Path::new("hello/msg")),
+                offset: FileOffset(0),
+                unencoded_file_len: 1000,
+                unencoded_len: 4096,
+                unencoded_offset: 0,
+                compression,
+                encryption: 0,
+                data: Data(Cow::Borrowed(data)),
+            })
+        };
+        commands.extend([
+            encoded(Compression::Zstd, b"pretend this is zstd"),
+            encoded(Compression::Lzo4K, b"and this is lzo"),
+            end,
+        ]);
+        // encoded writes only exist in v2, which is what gets written
+        let bytes = Sendstream { commands }
+            .to_bytes()
+            .expect("failed to encode");
+        let parsed = Sendstream::parse_all(&bytes).expect("failed to parse v2 stream");
+        assert_eq!(2, parsed[0].version());
+
+        let report = parsed[0].compression();
+        assert_eq!(plain.by_algorithm["none"], report.by_algorithm["none"]);
+        assert_eq!(
+            CompressionTotals {
+                writes: 1,
+                encoded_bytes: 20,
+                unencoded_bytes: 1000,
+            },
+            report.by_algorithm["zstd"]
+        );
+        assert_eq!(Some(1000.0 / 15.0), report.by_algorithm["lzo"].ratio());
+        let msg = plain.by_file[Path::new("hello/msg")];
+        assert_eq!(
+            CompressionTotals {
+                writes: msg.writes + 2,
+                encoded_bytes: msg.encoded_bytes + 35,
+                unencoded_bytes: msg.unencoded_bytes + 2000,
+            },
+            report.by_file[Path::new("hello/msg")]
+        );
+    }
+}
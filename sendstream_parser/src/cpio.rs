@@ -0,0 +1,262 @@
+//! Write the subvolume that a sendstream produces as a cpio archive in the
+//! "newc" format, which is what the Linux kernel unpacks initramfs images
+//! from.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use nix::libc;
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::Sendstream;
+
+/// File data is copied into the archive in pieces of at most this size
+const COPY_LEN: u64 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to write archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{path:?} is {size} bytes, which is too large for cpio")]
+    TooLarge { path: PathBuf, size: u64 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// One `070701` header, whose fields are all 8 hex digits
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    size: u32,
+    rdev: u64,
+}
+
+/// Appends entries to an archive, keeping track of how far into it they are
+struct CpioWriter<W> {
+    w: W,
+    pos: u64,
+}
+
+impl<W: Write> CpioWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.w.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Zeroes up to the next multiple of 4 bytes
+    fn pad(&mut self) -> std::io::Result<()> {
+        let rem = (4 - self.pos % 4) % 4;
+        self.write(&[0; 3][..rem as usize])
+    }
+
+    fn header(&mut self, h: &Header, name: &[u8]) -> std::io::Result<()> {
+        let fields = [
+            h.ino,
+            h.mode,
+            h.uid,
+            h.gid,
+            h.nlink,
+            h.mtime,
+            h.size,
+            // the device that the archive came from
+            0,
+            0,
+            nix::sys::stat::major(h.rdev) as u32,
+            nix::sys::stat::minor(h.rdev) as u32,
+            name.len() as u32 + 1,
+            // checksum, which only the "070702" format has
+            0,
+        ];
+        let mut out = b"070701".to_vec();
+        for f in fields {
+            out.extend_from_slice(format!("{f:08X}").as_bytes());
+        }
+        out.extend_from_slice(name);
+        out.push(0);
+        self.write(&out)?;
+        self.pad()
+    }
+}
+
+/// Name of `path` in the archive, which is relative like `find . | cpio`
+/// would have it, but without the `./`
+fn archive_name(path: &Path) -> &[u8] {
+    match path.as_os_str().as_bytes() {
+        b"" => b".",
+        name => name,
+    }
+}
+
+fn seconds(t: Option<crate::Mtime>) -> u32 {
+    t.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs().min(u32::MAX.into()) as u32)
+}
+
+/// Write the subvolume that the full stream `stream` creates to `w` as a
+/// newc cpio archive, returning `w` once the archive is complete.
+///
+/// Hard links share an inode number, and their data is only in the entry for
+/// the first name, which is how the kernel expects it. cpio has no sparse
+/// files, so holes are written out as zeroes and files have to be smaller
+/// than 4GiB. It has no xattrs either, so those are left out.
+pub fn to_cpio<W: Write>(stream: &Sendstream, w: W) -> Result<W> {
+    let fs = Filesystem::from_sendstream(stream)?;
+    let mut cpio = CpioWriter { w, pos: 0 };
+    let mut inos: BTreeMap<InodeId, u32> = BTreeMap::new();
+    for (path, id) in std::iter::once((PathBuf::new(), fs.root())).chain(fs.walk()) {
+        let inode = &fs[id];
+        let (kind, contents) = match inode.kind() {
+            InodeKind::Directory(_) => (libc::S_IFDIR, None),
+            InodeKind::File(c) => (libc::S_IFREG, Some(c)),
+            InodeKind::Symlink(_) => (libc::S_IFLNK, None),
+            InodeKind::Fifo => (libc::S_IFIFO, None),
+            InodeKind::Socket => (libc::S_IFSOCK, None),
+            InodeKind::CharDevice(_) => (libc::S_IFCHR, None),
+            InodeKind::BlockDevice(_) => (libc::S_IFBLK, None),
+        };
+        let default = match inode.kind() {
+            InodeKind::Directory(_) => 0o755,
+            InodeKind::Symlink(_) => 0o777,
+            _ => 0o644,
+        };
+        let next = inos.len() as u32 + 1;
+        let seen = inos.contains_key(&id);
+        let ino = *inos.entry(id).or_insert(next);
+        let data = match inode.kind() {
+            InodeKind::Symlink(target) => target.as_os_str().as_bytes().len() as u64,
+            _ if seen => 0,
+            _ => contents.map_or(0, |c| c.len()),
+        };
+        let size = u32::try_from(data).map_err(|_| Error::TooLarge {
+            path: path.clone(),
+            size: data,
+        })?;
+        let rdev = match inode.kind() {
+            InodeKind::CharDevice(r) | InodeKind::BlockDevice(r) => r.as_u64(),
+            _ => 0,
+        };
+        let header = Header {
+            ino,
+            mode: kind | (inode.mode().map_or(default, |m| m.0) & 0o7777),
+            uid: inode.uid().map_or(0, |u| u.as_raw()),
+            gid: inode.gid().map_or(0, |g| g.as_raw()),
+            nlink: match inode.kind() {
+                InodeKind::Directory(_) => 2,
+                _ => inode.nlink() as u32,
+            },
+            mtime: seconds(inode.mtime()),
+            size,
+            rdev,
+        };
+        cpio.header(&header, archive_name(&path))?;
+        match inode.kind() {
+            InodeKind::Symlink(target) => cpio.write(target.as_os_str().as_bytes())?,
+            InodeKind::File(c) if !seen => {
+                let mut off = 0;
+                while off < c.len() {
+                    let data = c.read(off, COPY_LEN);
+                    cpio.write(&data)?;
+                    off += data.len() as u64;
+                }
+            }
+            _ => (),
+        }
+        cpio.pad()?;
+    }
+    let trailer = Header {
+        ino: 0,
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        nlink: 1,
+        mtime: 0,
+        size: 0,
+        rdev: 0,
+    };
+    cpio.header(&trailer, b"TRAILER!!!")?;
+    cpio.w.flush()?;
+    Ok(cpio.w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Name, mode, ino and data of every entry in `archive`
+    fn entries(archive: &[u8]) -> Vec<(String, u32, u32, Vec<u8>)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        loop {
+            assert_eq!(b"070701", &archive[pos..pos + 6]);
+            let field = |i: usize| {
+                let f = std::str::from_utf8(&archive[pos + 6 + i * 8..pos + 14 + i * 8])
+                    .expect("bad field");
+                u32::from_str_radix(f, 16).expect("bad field") as usize
+            };
+            let (namesize, size) = (field(11), field(6));
+            let name = &archive[pos + 110..pos + 110 + namesize - 1];
+            let data = (pos + 110 + namesize).next_multiple_of(4);
+            if name == b"TRAILER!!!" {
+                return out;
+            }
+            out.push((
+                String::from_utf8_lossy(name).into_owned(),
+                field(1) as u32,
+                field(0) as u32,
+                archive[data..data + size].to_vec(),
+            ));
+            pos = (data + size).next_multiple_of(4);
+        }
+    }
+
+    #[test]
+    fn to_cpio() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        assert!(matches!(
+            super::to_cpio(&sendstreams[0], Vec::new()),
+            Err(Error::TooLarge { path, .. }) if path == Path::new("huge-empty-file")
+        ));
+        let paths: Vec<_> = ["hello", "myfifo", "null", "socket-node.sock"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        let small = sendstreams[0]
+            .extract_paths(&paths)
+            .expect("failed to extract");
+        let archive = super::to_cpio(&small, Vec::new()).expect("failed to archive");
+        assert_eq!(0, archive.len() % 4);
+        let entries = entries(&archive);
+        let find = |name: &str| {
+            entries
+                .iter()
+                .find(|e| e.0 == name)
+                .unwrap_or_else(|| panic!("{name} is missing from {entries:?}"))
+        };
+        assert_eq!(libc::S_IFDIR | 0o755, find(".").1);
+        let (msg, hard) = (find("hello/msg"), find("hello/msg-hard"));
+        assert_eq!(libc::S_IFREG | 0o400, msg.1);
+        assert_eq!(b"Hello world!\n".as_slice(), msg.3);
+        // the data only goes with the first name
+        assert_eq!(msg.2, hard.2);
+        assert!(hard.3.is_empty());
+        assert_eq!(b"hello/msg".as_slice(), find("hello/msg-sym").3);
+        assert_eq!(libc::S_IFIFO, find("myfifo").1 & libc::S_IFMT);
+        assert_eq!(libc::S_IFCHR, find("null").1 & libc::S_IFMT);
+        assert_eq!(libc::S_IFSOCK, find("socket-node.sock").1 & libc::S_IFMT);
+    }
+}
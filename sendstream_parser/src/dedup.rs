@@ -0,0 +1,357 @@
+//! Find duplicated file data, and replace it with [Clone]s of an earlier copy.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::resolve::renamed;
+use crate::Clone;
+use crate::CloneLen;
+use crate::Command;
+use crate::FileOffset;
+use crate::Sendstream;
+
+/// Clone ranges must be aligned to the filesystem block size
+const BLOCK_SIZE: u64 = 4096;
+
+/// Where a fingerprinted payload was last seen
+struct Occurrence {
+    path: PathBuf,
+    offset: u64,
+}
+
+fn fingerprint(data: &[u8]) -> u64 {
+    let mut h = DefaultHasher::new();
+    data.hash(&mut h);
+    h.finish()
+}
+
+/// A file cannot be cloned onto an overlapping range of itself
+fn overlaps(o: &Occurrence, path: &Path, offset: u64, len: u64) -> bool {
+    o.path == path && o.offset < offset + len && offset < o.offset + len
+}
+
+/// A range of written data that is identical to a range written elsewhere,
+/// see [Sendstream::dedup_report]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Duplicate {
+    /// Final path of the file with the duplicated data
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+    /// Final path of the file with the copy that would be kept
+    pub original: PathBuf,
+    pub original_offset: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DedupReport {
+    /// Duplicated ranges in order of path and offset. Adjacent duplicated
+    /// blocks of the same original are merged into a single range.
+    pub duplicates: Vec<Duplicate>,
+    /// How many bytes the receiver would not have to store if every
+    /// duplicate was shared with its original
+    pub bytes_saved: u64,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Find blocks of written data that are identical to other blocks written
+    /// in this sendstream but are not already shared with a [Clone], to
+    /// estimate how much space a dedup pass (like [Sendstream::dedup]) over
+    /// the received subvolume would save.
+    ///
+    /// Only whole blocks (4K) that are completely written by a single
+    /// [crate::Write] are considered, and blocks that are later overwritten,
+    /// cloned over or truncated away are not counted. This works directly on
+    /// the payloads of the sendstream, so no parent is needed for
+    /// incrementals, but data that is unchanged from the parent is not
+    /// considered either.
+    pub fn dedup_report(&self) -> DedupReport {
+        let finals = self.final_paths();
+        // digest of every surviving whole block, by inode and block offset
+        let mut blocks: BTreeMap<usize, BTreeMap<u64, [u8; 32]>> = BTreeMap::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let Some(inode) = finals.inode(index) else {
+                continue;
+            };
+            let file = blocks.entry(inode).or_default();
+            let (start, end) = match cmd {
+                Command::Write(w) => (w.offset.0, w.offset.0 + w.data.len() as u64),
+                Command::Clone(c) => (c.dst_offset.0, c.dst_offset.0 + c.len.0),
+                Command::UpdateExtent(u) => (u.offset.0, u.offset.0 + u.len),
+                Command::Truncate(t) => (t.size, u64::MAX),
+                _ => continue,
+            };
+            // anything partially or completely changed is no longer known
+            let first = start - start % BLOCK_SIZE;
+            file.retain(|off, _| *off + BLOCK_SIZE <= first || *off >= end);
+            if let Command::Write(w) = cmd {
+                let mut off = start.next_multiple_of(BLOCK_SIZE);
+                while off + BLOCK_SIZE <= end {
+                    let rel = (off - start) as usize;
+                    let digest = Sha256::digest(&w.data[rel..rel + BLOCK_SIZE as usize]);
+                    file.insert(off, digest.into());
+                    off += BLOCK_SIZE;
+                }
+            }
+        }
+
+        let mut by_digest: BTreeMap<[u8; 32], Vec<(&Path, u64)>> = BTreeMap::new();
+        for (inode, file) in &blocks {
+            let Some(path) = finals.links(*inode).first() else {
+                continue;
+            };
+            for (off, digest) in file {
+                by_digest
+                    .entry(*digest)
+                    .or_default()
+                    .push((path.as_path(), *off));
+            }
+        }
+        let mut dups: Vec<Duplicate> = Vec::new();
+        for mut copies in by_digest.into_values() {
+            copies.sort();
+            let (original, original_offset) = copies[0];
+            dups.extend(copies[1..].iter().map(|(path, offset)| Duplicate {
+                path: path.to_path_buf(),
+                offset: *offset,
+                len: BLOCK_SIZE,
+                original: original.to_path_buf(),
+                original_offset,
+            }));
+        }
+        dups.sort_by(|a, b| (&a.path, a.offset).cmp(&(&b.path, b.offset)));
+
+        let bytes_saved = dups.iter().map(|d| d.len).sum();
+        let mut duplicates: Vec<Duplicate> = Vec::new();
+        for d in dups {
+            match duplicates.last_mut() {
+                Some(last)
+                    if last.path == d.path
+                        && last.original == d.original
+                        && last.offset + last.len == d.offset
+                        && last.original_offset + last.len == d.original_offset =>
+                {
+                    last.len += d.len
+                }
+                _ => duplicates.push(d),
+            }
+        }
+        DedupReport {
+            duplicates,
+            bytes_saved,
+        }
+    }
+
+    /// Replace [crate::Write]s whose data was already written earlier in this
+    /// sendstream with [Clone]s of that earlier copy, so that the receiver
+    /// shares the extents instead of storing the data twice.
+    ///
+    /// Only writes that start and end on a block boundary (4K) are
+    /// considered, since those are the only ones that can always be cloned.
+    /// Candidates are checked byte-for-byte against a replay of the
+    /// sendstream, so a source that was later overwritten or removed is never
+    /// cloned from.
+    ///
+    /// Incremental sendstreams require a model of their `parent`, full
+    /// sendstreams ignore it.
+    pub fn dedup(&self, parent: Option<&Filesystem>) -> fs::Result<Sendstream<'a>> {
+        let (mut model, sources) = Filesystem::replay_start(self, parent)?;
+        let mut seen: HashMap<u64, Occurrence> = HashMap::new();
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            let mut out = cmd.clone();
+            match cmd {
+                Command::Write(w)
+                    if !w.data.is_empty()
+                        && w.offset.0 % BLOCK_SIZE == 0
+                        && (w.data.len() as u64).is_multiple_of(BLOCK_SIZE) =>
+                {
+                    let fp = fingerprint(&w.data);
+                    let len = w.data.len() as u64;
+                    let sub = model.subvolume().ok_or(fs::Error::MissingHeader)?;
+                    let matches = seen.get(&fp).is_some_and(|o| {
+                        model
+                            .get(&o.path)
+                            .and_then(fs::Inode::contents)
+                            .is_some_and(|c| c.read(o.offset, len) == *w.data)
+                    });
+                    match seen.get(&fp) {
+                        Some(o) if matches && !overlaps(o, &w.path, w.offset.0, len) => {
+                            out = Clone {
+                                src_offset: FileOffset(o.offset),
+                                len: CloneLen(len),
+                                src_path: o.path.clone().into(),
+                                uuid: sub.uuid(),
+                                ctransid: sub.ctransid(),
+                                dst_path: w.path.clone(),
+                                dst_offset: w.offset,
+                            }
+                            .into();
+                        }
+                        // keep the earlier copy as the clone source
+                        Some(_) if matches => (),
+                        _ => {
+                            seen.insert(
+                                fp,
+                                Occurrence {
+                                    path: w.path.to_path_buf(),
+                                    offset: w.offset.0,
+                                },
+                            );
+                        }
+                    }
+                }
+                Command::Rename(r) => {
+                    for o in seen.values_mut() {
+                        if let Some(p) = renamed(&o.path, &r.from, &r.to) {
+                            o.path = p;
+                        }
+                    }
+                }
+                _ => (),
+            }
+            model.apply_with_sources(&out, &sources)?;
+            commands.push(out);
+        }
+        Ok(Sendstream { commands })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn dedup_report() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        // hello/lorem-reflinked clones the start of hello/lorem, but writes
+        // the rest out again, all but the last partial block of which could
+        // be shared
+        let report = sendstreams[0].dedup_report();
+        assert_eq!(
+            vec![Duplicate {
+                path: PathBuf::from("hello/lorem-reflinked"),
+                offset: 131072,
+                len: 90112,
+                original: PathBuf::from("hello/lorem"),
+                original_offset: 131072,
+            }],
+            report.duplicates
+        );
+        assert_eq!(90112, report.bytes_saved);
+
+        let deduped = sendstreams[0].dedup(None).expect("failed to dedup");
+        assert!(deduped.dedup_report().bytes_saved < report.bytes_saved);
+    }
+
+    #[test]
+    fn dedup() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let copy = Path::new("lorem-copy");
+        // append a second copy of hello/lorem, written out in full
+        let mut commands = sendstreams[0].commands().to_vec();
+        let end = commands.pop();
+        commands.push(
+            crate::Mkfile {
+                path: crate::TemporaryPath(Cow::Borrowed(copy)),
+                ino: crate::Ino(1000),
+            }
+            .into(),
+        );
+        for cmd in sendstreams[0].commands() {
+            if let Command::Write(w) = cmd {
+                if w.path() == Path::new("hello/lorem") {
+                    let mut w = w.clone();
+                    w.path = Cow::Borrowed(copy);
+                    commands.push(w.into());
+                }
+            }
+        }
+        commands.extend(end);
+        let stream = Sendstream { commands };
+
+        let deduped = stream.dedup(None).expect("failed to dedup");
+        let clones: Vec<_> = deduped
+            .commands()
+            .iter()
+            .filter_map(|c| match c {
+                Command::Clone(c) if c.dst_path() == copy => Some(c),
+                _ => None,
+            })
+            .collect();
+        // everything but the unaligned tail is cloned
+        assert_eq!(4, clones.len());
+        assert!(clones
+            .iter()
+            .all(|c| c.src_path() == Path::new("hello/lorem")));
+
+        // the demo already writes a copy of part of hello/lorem into
+        // hello/lorem-reflinked, which is deduped too
+        assert!(deduped.commands().iter().any(|c| matches!(
+            c,
+            Command::Clone(c) if c.dst_path() == Path::new("hello/lorem-reflinked")
+                && c.dst_offset() == FileOffset(131072)
+        )));
+
+        let original = Filesystem::from_sendstream(&stream).expect("failed to replay");
+        let fs = Filesystem::from_sendstream(&deduped).expect("failed to replay");
+        for path in [copy, Path::new("hello/lorem-reflinked")] {
+            assert_eq!(
+                original.get(path).and_then(fs::Inode::contents),
+                fs.get(path).and_then(fs::Inode::contents),
+            );
+        }
+    }
+
+    #[test]
+    fn dedup_incremental() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let parent = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let sub = parent.subvolume().expect("parent is a full subvol");
+        let copy = Path::new("lorem-copy");
+        let stream = Sendstream {
+            commands: vec![
+                sendstreams[1].commands()[0].clone(),
+                crate::Mkfile {
+                    path: crate::TemporaryPath(Cow::Borrowed(copy)),
+                    ino: crate::Ino(1000),
+                }
+                .into(),
+                Clone {
+                    src_offset: FileOffset(0),
+                    len: CloneLen(BLOCK_SIZE),
+                    src_path: Cow::Borrowed(Path::new("hello/lorem")),
+                    uuid: sub.uuid(),
+                    ctransid: sub.ctransid(),
+                    dst_path: Cow::Borrowed(copy),
+                    dst_offset: FileOffset(0),
+                }
+                .into(),
+                Command::End,
+            ],
+        };
+        // clones from the parent are resolved against it
+        let deduped = stream.dedup(Some(&parent)).expect("failed to dedup");
+        assert_eq!(stream, deduped);
+        assert!(matches!(stream.dedup(None), Err(fs::Error::Incremental)));
+    }
+}
@@ -0,0 +1,301 @@
+//! Replace [Clone]s with plain [crate::Write]s, for receivers that do not
+//! support reflinks (or do not have the clone sources available).
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::incremental::MAX_WRITE_LEN;
+use crate::Clone;
+use crate::Command;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to read clone source {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("clone source {0:?} is outside of the subvolume")]
+    Outside(PathBuf),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A subvolume that [Clone]s can copy data out of.
+pub trait CloneSource {
+    /// Uuid of the subvolume, as referenced by [Clone::uuid]
+    fn uuid(&self) -> Option<Uuid>;
+
+    /// Read `len` bytes of `path` starting at `offset`. The result may be cut
+    /// short at the end of the file.
+    fn read(&self, path: &Path, offset: u64, len: u64) -> Result<Vec<u8>>;
+
+    /// The full model of this subvolume, if there is one. Incremental
+    /// sendstreams can only have clones within themselves replaced if their
+    /// parent has a model.
+    fn filesystem(&self) -> Option<&Filesystem> {
+        None
+    }
+}
+
+impl CloneSource for Filesystem {
+    fn uuid(&self) -> Option<Uuid> {
+        self.subvolume().map(fs::SubvolumeInfo::uuid)
+    }
+
+    fn read(&self, path: &Path, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.get(path)
+            .and_then(fs::Inode::contents)
+            .map(|c| c.read(offset, len))
+            .ok_or_else(|| fs::Error::NotAFile(path.to_path_buf()).into())
+    }
+
+    fn filesystem(&self) -> Option<&Filesystem> {
+        Some(self)
+    }
+}
+
+/// Subvolume that is available as a directory on disk, such as a previously
+/// received snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directory {
+    root: PathBuf,
+    uuid: Uuid,
+}
+
+impl Directory {
+    pub fn new(root: impl Into<PathBuf>, uuid: Uuid) -> Self {
+        Self {
+            root: root.into(),
+            uuid,
+        }
+    }
+}
+
+impl CloneSource for Directory {
+    fn uuid(&self) -> Option<Uuid> {
+        Some(self.uuid)
+    }
+
+    fn read(&self, path: &Path, offset: u64, len: u64) -> Result<Vec<u8>> {
+        // the path comes from the sendstream, so it must not lead anywhere
+        // but into the subvolume
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(Error::Outside(path.to_path_buf()));
+        }
+        let path = self.root.join(path);
+        let io = |error| Error::Io {
+            path: path.clone(),
+            error,
+        };
+        let f = File::open(&path).map_err(io)?;
+        let size = f.metadata().map_err(io)?.len();
+        let end = len.min(size.saturating_sub(offset)) as usize;
+        // grow the buffer as data actually comes in, in case the file
+        // shrinks while it is read
+        let mut buf = Vec::new();
+        let mut filled = 0;
+        while filled < end {
+            if filled == buf.len() {
+                buf.resize(end.min(filled + MAX_WRITE_LEN as usize), 0);
+            }
+            match f.read_at(&mut buf[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(io(e)),
+            }
+        }
+        buf.truncate(filled);
+        Ok(buf)
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Replace every [Clone] with [crate::Write]s of the data that it would
+    /// have copied, reading that data from whichever of `sources` has the
+    /// uuid of the clone source.
+    ///
+    /// Clones within this same subvolume are resolved by replaying the
+    /// sendstream as it is rewritten. For incremental sendstreams this
+    /// requires the parent to be one of `sources` with a full model (a
+    /// [Filesystem]).
+    pub fn dereflink(&self, sources: &[&dyn CloneSource]) -> Result<Sendstream<'a>> {
+        let mut model = match self.commands.first() {
+            Some(Command::Snapshot(s)) => sources
+                .iter()
+                .find(|src| src.uuid() == Some(s.clone_uuid))
+                .and_then(|src| src.filesystem())
+                .cloned(),
+            _ => Some(Filesystem::new()),
+        };
+        let mut own_uuid = None;
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            match cmd {
+                Command::Subvol(s) => own_uuid = Some(s.uuid),
+                Command::Snapshot(s) => own_uuid = Some(s.uuid),
+                _ => (),
+            }
+            let start = commands.len();
+            match cmd {
+                Command::Clone(c) if Some(c.uuid) == own_uuid => {
+                    let fs = model
+                        .as_ref()
+                        .ok_or(fs::Error::MissingCloneSource(c.uuid))?;
+                    materialize(c, fs, &mut commands)?;
+                }
+                Command::Clone(c) => {
+                    let src = sources
+                        .iter()
+                        .find(|src| src.uuid() == Some(c.uuid))
+                        .ok_or(fs::Error::MissingCloneSource(c.uuid))?;
+                    materialize(c, *src, &mut commands)?;
+                }
+                _ => commands.push(cmd.clone()),
+            }
+            if let Some(fs) = &mut model {
+                for cmd in &commands[start..] {
+                    fs.apply(cmd)?;
+                }
+            }
+        }
+        Ok(Sendstream { commands })
+    }
+}
+
+fn materialize<'a>(
+    c: &Clone<'a>,
+    src: &(impl CloneSource + ?Sized),
+    out: &mut Vec<Command<'a>>,
+) -> Result<()> {
+    let end = c.src_offset.0.saturating_add(c.len.0);
+    let mut off = c.src_offset.0;
+    while off < end {
+        let data = src.read(&c.src_path, off, MAX_WRITE_LEN.min(end - off))?;
+        if data.is_empty() {
+            break;
+        }
+        let len = data.len() as u64;
+        out.push(
+            crate::Write {
+                path: c.dst_path.clone(),
+                offset: crate::FileOffset(c.dst_offset.0 + (off - c.src_offset.0)),
+                data: crate::Data(Cow::Owned(data)),
+            }
+            .into(),
+        );
+        off += len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_clones(s: &Sendstream) -> bool {
+        s.commands().iter().any(|c| matches!(c, Command::Clone(_)))
+    }
+
+    #[test]
+    fn dereflink_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let original = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        assert!(has_clones(&sendstreams[0]));
+        let flat = sendstreams[0].dereflink(&[]).expect("failed to dereflink");
+        assert!(!has_clones(&flat));
+        let fs = Filesystem::from_sendstream(&flat).expect("failed to replay");
+        let path = Path::new("hello/lorem-reflinked");
+        assert_eq!(
+            original.get(path).and_then(fs::Inode::contents),
+            fs.get(path).and_then(fs::Inode::contents),
+        );
+    }
+
+    #[test]
+    fn dereflink_from_directory() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let parent = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let sub = parent.subvolume().expect("parent is a full subvol");
+        let parent_uuid = sub.uuid();
+        let incremental = Sendstream {
+            commands: vec![
+                crate::Snapshot {
+                    path: Cow::Borrowed(Path::new("copy")),
+                    uuid: Uuid::from_u128(2),
+                    ctransid: crate::Ctransid(2),
+                    clone_uuid: parent_uuid,
+                    clone_ctransid: sub.ctransid(),
+                }
+                .into(),
+                crate::Clone {
+                    src_offset: crate::FileOffset(4),
+                    len: crate::CloneLen(1 << 20),
+                    src_path: Cow::Borrowed(Path::new("hello/msg")),
+                    uuid: parent_uuid,
+                    ctransid: sub.ctransid(),
+                    dst_path: Cow::Borrowed(Path::new("hello/lorem")),
+                    dst_offset: crate::FileOffset(0),
+                }
+                .into(),
+                Command::End,
+            ],
+        };
+
+        let dir = std::env::temp_dir().join(format!("dereflink-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("hello")).expect("failed to create dir");
+        std::fs::write(dir.join("hello/msg"), b"Hello world!\n").expect("failed to write");
+        let flat = incremental
+            .dereflink(&[&Directory::new(&dir, parent_uuid)])
+            .expect("failed to dereflink");
+        let source = Directory::new(&dir, parent_uuid);
+        assert_eq!(
+            b"Hello world!\n".as_slice(),
+            source
+                .read(Path::new("hello/msg"), 0, u64::MAX)
+                .expect("failed to read")
+        );
+        for path in ["../outside", "/etc/hostname"] {
+            assert!(matches!(
+                source.read(Path::new(path), 0, 1),
+                Err(Error::Outside(_))
+            ));
+        }
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+
+        assert_eq!(
+            vec![
+                incremental.commands[0].clone(),
+                crate::Write {
+                    path: Cow::Borrowed(Path::new("hello/lorem")),
+                    offset: crate::FileOffset(0),
+                    data: crate::Data(Cow::Borrowed(b"o world!\n")),
+                }
+                .into(),
+                Command::End,
+            ],
+            flat.commands
+        );
+        // the same result is reached when reading from the parent's model
+        assert_eq!(
+            flat,
+            incremental
+                .dereflink(&[&parent])
+                .expect("failed to dereflink")
+        );
+    }
+}
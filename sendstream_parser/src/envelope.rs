@@ -0,0 +1,314 @@
+//! Encrypted envelope for storing encoded sendstreams at rest.
+//!
+//! The envelope is a short header (magic, version and a random nonce prefix)
+//! followed by the sendstream encrypted with XChaCha20-Poly1305 in the STREAM
+//! construction: the plaintext is split into 64K segments that are each
+//! authenticated on their own, so neither encryption nor decryption needs to
+//! buffer more than one segment. The final segment is marked as such, so
+//! truncating an envelope is detected just like any other tampering.
+
+use std::io::Read;
+use std::io::Write;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::DecryptorBE32;
+use chacha20poly1305::aead::stream::EncryptorBE32;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::CommandReader;
+
+/// Marks the start of an encrypted envelope
+static MAGIC: &[u8] = b"btrfs-stream-enc\0";
+const VERSION: u32 = 1;
+/// 24 byte XChaCha20 nonce minus the 5 bytes used by STREAM
+const NONCE_PREFIX_LEN: usize = 19;
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_PREFIX_LEN;
+/// Plaintext bytes per segment. Every segment except the last one is exactly
+/// this size, and the last one is always smaller (possibly empty).
+const SEGMENT_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Input is not an encrypted sendstream")]
+    Malformed,
+    #[error("Decryption failed, the envelope was tampered with or the key is wrong")]
+    Decrypt,
+    #[error("Envelope ends before its final segment")]
+    Truncated,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// 256-bit symmetric key for the envelope.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generate a new random key
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn aead(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Check if `input` starts with an envelope header
+pub fn is_encrypted(input: &[u8]) -> bool {
+    input.starts_with(MAGIC)
+}
+
+/// Encrypts everything written to it into an envelope. [EncryptWriter::finish]
+/// must be called to write the final segment, otherwise the envelope will be
+/// rejected as truncated.
+pub struct EncryptWriter<W: Write> {
+    w: W,
+    enc: EncryptorBE32<XChaCha20Poly1305>,
+    header: [u8; HEADER_LEN],
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut w: W, key: &Key) -> std::io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&VERSION.to_le_bytes());
+        OsRng.fill_bytes(&mut header[MAGIC.len() + 4..]);
+        w.write_all(&header)?;
+        let nonce = &header[MAGIC.len() + 4..];
+        Ok(Self {
+            w,
+            enc: EncryptorBE32::from_aead(key.aead(), nonce.into()),
+            header,
+            buf: Vec::with_capacity(SEGMENT_LEN),
+        })
+    }
+
+    /// Write the final segment and get the underlying writer back.
+    pub fn finish(self) -> std::io::Result<W> {
+        let Self {
+            mut w,
+            enc,
+            header,
+            buf,
+        } = self;
+        let ct = enc
+            .encrypt_last(Payload {
+                msg: &buf,
+                aad: &header,
+            })
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        w.write_all(&ct)?;
+        w.flush()?;
+        Ok(w)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(SEGMENT_LEN - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == SEGMENT_LEN {
+            let ct = self
+                .enc
+                .encrypt_next(Payload {
+                    msg: &self.buf,
+                    aad: &self.header,
+                })
+                .map_err(|_| std::io::Error::other("encryption failed"))?;
+            self.w.write_all(&ct)?;
+            self.buf.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Decrypts an envelope as it is read.
+pub struct DecryptReader<R: Read> {
+    r: R,
+    dec: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: [u8; HEADER_LEN],
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read and check the envelope header. Nothing is decrypted until the
+    /// first read.
+    pub fn new(mut r: R, key: &Key) -> Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::Malformed,
+            _ => e.into(),
+        })?;
+        if !is_encrypted(&header) || header[MAGIC.len()..MAGIC.len() + 4] != VERSION.to_le_bytes() {
+            return Err(Error::Malformed);
+        }
+        let nonce = &header[MAGIC.len() + 4..];
+        Ok(Self {
+            r,
+            dec: Some(DecryptorBE32::from_aead(key.aead(), nonce.into())),
+            header,
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Decrypt the next segment into `self.plain`
+    fn fill(&mut self) -> Result<()> {
+        let mut ct = vec![0; SEGMENT_LEN + TAG_LEN];
+        let mut len = 0;
+        while len < ct.len() {
+            match self.r.read(&mut ct[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let payload = Payload {
+            msg: &ct[..len],
+            aad: &self.header,
+        };
+        self.plain = match self.dec.take() {
+            None => Vec::new(),
+            Some(mut dec) if len == ct.len() => {
+                let plain = dec.decrypt_next(payload).map_err(|_| Error::Decrypt)?;
+                self.dec = Some(dec);
+                plain
+            }
+            Some(_) if len < TAG_LEN => return Err(Error::Truncated),
+            Some(dec) => dec.decrypt_last(payload).map_err(|_| Error::Decrypt)?,
+        };
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.dec.is_none() {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Encrypt an already-encoded sendstream.
+pub fn encrypt(input: &[u8], key: &Key) -> std::io::Result<Vec<u8>> {
+    let mut w = EncryptWriter::new(Vec::new(), key)?;
+    w.write_all(input)?;
+    w.finish()
+}
+
+/// Decrypt an entire envelope, which can then be parsed with
+/// [crate::Sendstream::parse_all].
+pub fn decrypt(input: &[u8], key: &Key) -> Result<Vec<u8>> {
+    let mut r = DecryptReader::new(input, key)?;
+    let mut out = Vec::with_capacity(input.len());
+    while r.dec.is_some() {
+        r.fill()?;
+        out.extend_from_slice(&r.plain);
+    }
+    Ok(out)
+}
+
+/// Parse [crate::Command]s out of an envelope, decrypting as they are read.
+pub fn read_commands<R: Read>(r: R, key: &Key) -> Result<CommandReader<DecryptReader<R>>> {
+    Ok(CommandReader::new(DecryptReader::new(r, key)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sendstream;
+
+    #[test]
+    fn roundtrip_envelope() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let key = Key::from_bytes([7; 32]);
+        let sealed = encrypt(input, &key).expect("failed to encrypt");
+        assert!(is_encrypted(&sealed));
+        assert!(!is_encrypted(input));
+        assert_eq!(
+            input.as_slice(),
+            decrypt(&sealed, &key).expect("valid envelope")
+        );
+
+        let commands: Vec<_> = read_commands(sealed.as_slice(), &key)
+            .expect("valid header")
+            .collect::<crate::Result<_>>()
+            .expect("valid envelope");
+        let expected: Vec<_> = Sendstream::parse_all(input)
+            .expect("failed to parse demo.sendstream")
+            .into_iter()
+            .flat_map(Sendstream::into_commands)
+            .collect();
+        assert_eq!(expected, commands);
+    }
+
+    #[test]
+    fn reject_tampering() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let key = Key::from_bytes([7; 32]);
+        let sealed = encrypt(input, &key).expect("failed to encrypt");
+
+        assert!(matches!(
+            decrypt(&sealed, &Key::from_bytes([8; 32])),
+            Err(Error::Decrypt)
+        ));
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN + 100] ^= 1;
+        assert!(matches!(decrypt(&flipped, &key), Err(Error::Decrypt)));
+        // cut off exactly at a segment boundary, so that every remaining
+        // segment is intact
+        let cut = HEADER_LEN + SEGMENT_LEN + TAG_LEN;
+        assert!(matches!(
+            decrypt(&sealed[..cut], &key),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(decrypt(input, &key), Err(Error::Malformed)));
+    }
+}
@@ -0,0 +1,487 @@
+//! Layout of the data of every file in a sendstream: which ranges are
+//! written, which are cloned from elsewhere and which are holes.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// Where the data of an [Extent] comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Source {
+    /// Sent in a [crate::Write]
+    Write,
+    /// Shared with `path` in the subvolume `uuid`, starting at `offset`.
+    /// Clones within the subvolume being sent use the final path of the
+    /// source file.
+    Clone {
+        uuid: Uuid,
+        path: PathBuf,
+        offset: u64,
+    },
+    /// Changed by an [crate::UpdateExtent] (in a sendstream sent without
+    /// file data), so the contents are unknown
+    Updated,
+    /// Never written, reads back as zeroes
+    Hole,
+    /// Not touched by an incremental sendstream, so whatever the parent has
+    /// there (data or a hole)
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+    pub source: Source,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    /// The part of this extent within `[from, to)`
+    fn slice(&self, from: u64, to: u64) -> Self {
+        let source = match &self.source {
+            Source::Clone { uuid, path, offset } => Source::Clone {
+                uuid: *uuid,
+                path: path.clone(),
+                offset: offset + (from - self.offset),
+            },
+            s => s.clone(),
+        };
+        Self {
+            offset: from,
+            len: to - from,
+            source,
+        }
+    }
+}
+
+/// Layout of a single regular file, see [Sendstream::extent_map]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileExtents {
+    /// Final path of the file (see [Sendstream::final_paths])
+    pub path: PathBuf,
+    /// Size of the file, unless an incremental sendstream changes part of an
+    /// existing file without setting its size
+    pub size: Option<u64>,
+    /// Non-overlapping extents in offset order, covering the whole file (or
+    /// up to the end of the last changed range if the size is unknown).
+    /// Consecutive commands are not merged, so every [crate::Write] and
+    /// [crate::Clone] shows up as (what remains of) its own extent.
+    pub extents: Vec<Extent>,
+}
+
+/// A regular file with holes in it, see [Sendstream::holes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SparseFile {
+    /// Final path of the file (see [Sendstream::final_paths])
+    pub path: PathBuf,
+    /// Same as [FileExtents::size]
+    pub size: Option<u64>,
+    /// Every hole, in offset order
+    pub holes: Vec<Range<u64>>,
+}
+
+impl SparseFile {
+    /// Total size of all the holes
+    pub fn hole_bytes(&self) -> u64 {
+        self.holes.iter().map(|h| h.end - h.start).sum()
+    }
+
+    /// How much of the file actually needs to be stored on disk (ignoring
+    /// compression and sharing), if its size is known
+    pub fn allocated_bytes(&self) -> Option<u64> {
+        self.size.map(|s| s - self.hole_bytes())
+    }
+}
+
+/// Which parts of a regular file have data, see [Sendstream::coverage]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileCoverage {
+    /// Final path of the file (see [Sendstream::final_paths])
+    pub path: PathBuf,
+    /// Same as [FileExtents::size]
+    pub size: Option<u64>,
+    /// Ranges that are written or cloned into, in offset order
+    pub covered: Vec<Range<u64>>,
+    /// Ranges that are left as holes, in offset order
+    pub holes: Vec<Range<u64>>,
+    /// Ranges that are [Source::Updated] or [Source::Unchanged], so whether
+    /// they have data is not known from the sendstream alone
+    pub unknown: Vec<Range<u64>>,
+}
+
+impl FileCoverage {
+    /// Total size of all the covered ranges
+    pub fn covered_bytes(&self) -> u64 {
+        self.covered.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Fraction of the file that is covered, from 0 to 1. `None` if the size
+    /// of the file is unknown, and 1 for empty files.
+    pub fn covered_fraction(&self) -> Option<f64> {
+        self.size.map(|size| match size {
+            0 => 1.0,
+            size => self.covered_bytes() as f64 / size as f64,
+        })
+    }
+}
+
+/// Merge the ranges of consecutive `extents` that match `pred`
+fn merged(extents: &[Extent], pred: impl Fn(&Source) -> bool) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for e in extents.iter().filter(|e| pred(&e.source)) {
+        match ranges.last_mut() {
+            Some(r) if r.end == e.offset => r.end = e.end(),
+            _ => ranges.push(e.offset..e.end()),
+        }
+    }
+    ranges
+}
+
+/// Data extents of a file keyed by offset, where later commands replace
+/// whatever they overlap
+#[derive(Default)]
+struct Layout(BTreeMap<u64, Extent>);
+
+impl Layout {
+    fn overwrite(&mut self, new: Extent) {
+        let end = new.end();
+        let overlapping: Vec<u64> = self
+            .0
+            .range(..new.offset)
+            .next_back()
+            .filter(|(_, e)| e.end() > new.offset)
+            .map(|(k, _)| *k)
+            .into_iter()
+            .chain(self.0.range(new.offset..end).map(|(k, _)| *k))
+            .collect();
+        for k in overlapping {
+            let Some(e) = self.0.remove(&k) else {
+                continue;
+            };
+            if e.offset < new.offset {
+                self.0.insert(e.offset, e.slice(e.offset, new.offset));
+            }
+            if e.end() > end {
+                self.0.insert(end, e.slice(end, e.end()));
+            }
+        }
+        self.0.insert(new.offset, new);
+    }
+
+    fn truncate(&mut self, size: u64) {
+        let beyond: Vec<u64> = self
+            .0
+            .iter()
+            .filter(|(_, e)| e.end() > size)
+            .map(|(k, _)| *k)
+            .collect();
+        for k in beyond {
+            if let Some(e) = self.0.remove(&k) {
+                if e.offset < size {
+                    self.0.insert(e.offset, e.slice(e.offset, size));
+                }
+            }
+        }
+    }
+
+    /// Every extent up to `end`, with the gaps between data extents filled
+    /// in. Gaps below `unchanged_below` are left as they were in the parent.
+    fn finish(self, end: u64, unchanged_below: u64) -> Vec<Extent> {
+        let mut out = Vec::with_capacity(self.0.len() * 2 + 1);
+        let gap = |from: u64, to: u64, out: &mut Vec<Extent>| {
+            let split = unchanged_below.clamp(from, to);
+            for (from, to, source) in [(from, split, Source::Unchanged), (split, to, Source::Hole)]
+            {
+                if to > from {
+                    out.push(Extent {
+                        offset: from,
+                        len: to - from,
+                        source,
+                    });
+                }
+            }
+        };
+        let mut pos = 0;
+        for e in self.0.into_values() {
+            gap(pos, e.offset, &mut out);
+            pos = e.end();
+            out.push(e);
+        }
+        gap(pos, end, &mut out);
+        out
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Work out the layout of every regular file that this sendstream
+    /// creates or changes the contents of, in order of final path, without
+    /// having to replay it. Files that no longer exist at the end of the
+    /// sendstream are left out.
+    pub fn extent_map(&self) -> Vec<FileExtents> {
+        let finals = self.final_paths();
+        self.files()
+            .into_iter()
+            .filter_map(|f| {
+                let created = f
+                    .commands
+                    .iter()
+                    .any(|i| matches!(self.commands[*i], Command::Mkfile(_)));
+                let mut layout = Layout::default();
+                let mut size = None;
+                let mut unchanged_below = if created { 0 } else { u64::MAX };
+                let mut changed = created;
+                for i in &f.commands {
+                    match &self.commands[*i] {
+                        Command::Write(w) => layout.overwrite(Extent {
+                            offset: w.offset.as_u64(),
+                            len: w.data().len() as u64,
+                            source: Source::Write,
+                        }),
+                        Command::Clone(c) => layout.overwrite(Extent {
+                            offset: c.dst_offset.as_u64(),
+                            len: c.len.as_u64(),
+                            source: Source::Clone {
+                                uuid: c.uuid,
+                                path: finals.clone_source(*i).unwrap_or(&c.src_path).to_path_buf(),
+                                offset: c.src_offset.as_u64(),
+                            },
+                        }),
+                        Command::UpdateExtent(u) => layout.overwrite(Extent {
+                            offset: u.offset.as_u64(),
+                            len: u.len,
+                            source: Source::Updated,
+                        }),
+                        Command::Truncate(t) => {
+                            layout.truncate(t.size);
+                            unchanged_below = unchanged_below.min(t.size);
+                            size = Some(t.size);
+                        }
+                        _ => continue,
+                    }
+                    changed = true;
+                }
+                if !changed {
+                    return None;
+                }
+                let data_end = layout.0.values().next_back().map_or(0, Extent::end);
+                if created && size.is_none() {
+                    size = Some(data_end);
+                }
+                Some(FileExtents {
+                    path: f.final_path,
+                    size,
+                    extents: layout.finish(size.unwrap_or(data_end), unchanged_below),
+                })
+            })
+            .collect()
+    }
+
+    /// Find the sparse ranges of every file, inferred from [crate::Truncate]s
+    /// that extend files and ranges that are never written, cloned or
+    /// updated (see [Sendstream::extent_map]). Only files with at least one
+    /// hole are returned. For incremental sendstreams only ranges that are
+    /// known to be holes are reported, anything left unchanged from the
+    /// parent may or may not be sparse.
+    ///
+    /// Holes punched with fallocate are only sent explicitly by v2
+    /// sendstreams, which are not supported yet.
+    pub fn holes(&self) -> Vec<SparseFile> {
+        self.extent_map()
+            .into_iter()
+            .filter_map(|f| {
+                let holes = merged(&f.extents, |s| *s == Source::Hole);
+                (!holes.is_empty()).then_some(SparseFile {
+                    path: f.path,
+                    size: f.size,
+                    holes,
+                })
+            })
+            .collect()
+    }
+
+    /// Report which ranges of every file (see [Sendstream::extent_map]) are
+    /// covered by writes and clones and which are left as holes, to spot
+    /// files that are much sparser than expected. Files are in order of final
+    /// path.
+    pub fn coverage(&self) -> Vec<FileCoverage> {
+        self.extent_map()
+            .into_iter()
+            .map(|f| FileCoverage {
+                covered: merged(&f.extents, |s| {
+                    matches!(s, Source::Write | Source::Clone { .. })
+                }),
+                holes: merged(&f.extents, |s| *s == Source::Hole),
+                unknown: merged(&f.extents, |s| {
+                    matches!(s, Source::Updated | Source::Unchanged)
+                }),
+                path: f.path,
+                size: f.size,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn extent_map_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let map = sendstreams[0].extent_map();
+        let file = |p: &str| {
+            map.iter()
+                .find(|f| f.path == Path::new(p))
+                .unwrap_or_else(|| panic!("{p} missing"))
+        };
+        let ranges = |f: &FileExtents| {
+            f.extents
+                .iter()
+                .map(|e| (e.offset, e.len))
+                .collect::<Vec<_>>()
+        };
+
+        let huge = file("huge-empty-file");
+        assert_eq!(Some(107374182400), huge.size);
+        assert_eq!(
+            vec![Extent {
+                offset: 0,
+                len: 107374182400,
+                source: Source::Hole
+            }],
+            huge.extents
+        );
+
+        let reflinked = file("hello/lorem-reflinked");
+        assert_eq!(
+            vec![(0, 131072), (131072, 49152), (180224, 43222)],
+            ranges(reflinked)
+        );
+        assert_eq!(
+            Source::Clone {
+                uuid: Uuid::parse_str("0fbf2b5f-ff82-a748-8b41-e35aec190b49").expect("valid"),
+                path: PathBuf::from("hello/lorem"),
+                offset: 0,
+            },
+            reflinked.extents[0].source
+        );
+        assert_eq!(Some(223446), reflinked.size);
+        assert!(!map.iter().any(|f| f.path == Path::new("hello")));
+
+        // an incremental only knows about the part of the file that it changes
+        let undo = sendstreams[1].extent_map();
+        assert_eq!(
+            vec![PathBuf::from("hello/msg")],
+            undo.iter().map(|f| f.path.clone()).collect::<Vec<_>>()
+        );
+        assert!(undo[0].extents.iter().all(|e| e.source != Source::Hole));
+    }
+
+    #[test]
+    fn holes() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let sparse = sendstreams[0].holes();
+        assert_eq!(1, sparse.len());
+        assert_eq!(Path::new("huge-empty-file"), sparse[0].path);
+        assert_eq!(
+            vec![(0, 107374182400)],
+            sparse[0]
+                .holes
+                .iter()
+                .map(|h| (h.start, h.end))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(0), sparse[0].allocated_bytes());
+        assert!(sendstreams[1].holes().is_empty());
+    }
+
+    #[test]
+    fn coverage() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let coverage = sendstreams[0].coverage();
+        let file = |p: &str| {
+            coverage
+                .iter()
+                .find(|f| f.path == Path::new(p))
+                .unwrap_or_else(|| panic!("{p} missing"))
+        };
+        let reflinked = file("hello/lorem-reflinked");
+        assert_eq!(
+            vec![Range {
+                start: 0,
+                end: 223446
+            }],
+            reflinked.covered,
+            "the clone and the writes after it are merged"
+        );
+        assert_eq!(Some(1.0), reflinked.covered_fraction());
+        let huge = file("huge-empty-file");
+        assert!(huge.covered.is_empty());
+        assert_eq!(107374182400, huge.holes.iter().map(|h| h.end).sum::<u64>());
+        assert_eq!(Some(0.0), huge.covered_fraction());
+
+        let undo = sendstreams[1].coverage();
+        assert_eq!(9, undo[0].covered_bytes());
+        assert!(undo[0].holes.is_empty());
+    }
+
+    #[test]
+    fn overwrite() {
+        let mut layout = Layout::default();
+        let clone = |offset, len| Extent {
+            offset,
+            len,
+            source: Source::Clone {
+                uuid: Uuid::nil(),
+                path: PathBuf::from("src"),
+                offset: 1000 + offset,
+            },
+        };
+        layout.overwrite(clone(0, 100));
+        layout.overwrite(Extent {
+            offset: 40,
+            len: 20,
+            source: Source::Write,
+        });
+        layout.truncate(80);
+        assert_eq!(
+            vec![
+                clone(0, 40),
+                Extent {
+                    offset: 40,
+                    len: 20,
+                    source: Source::Write
+                },
+                clone(60, 20),
+                Extent {
+                    offset: 80,
+                    len: 20,
+                    source: Source::Hole
+                },
+            ],
+            layout.finish(100, 0)
+        );
+    }
+}
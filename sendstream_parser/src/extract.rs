@@ -0,0 +1,550 @@
+//! Carve out the subset of a sendstream that is needed to reconstruct only
+//! some paths, and pull files out of a sendstream without receiving it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::ops::Bound;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use nix::sys::stat::SFlag;
+use nix::sys::stat::UtimensatFlags;
+use nix::sys::time::TimeSpec;
+use nix::unistd::FchownatFlags;
+use nix::unistd::Uid;
+use uuid::Uuid;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::Inode;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to extract {path:?}: {error}")]
+    Extract {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Holes are written out as zeroes in pieces of at most this size
+const ZEROES_LEN: u64 = 1 << 20;
+
+/// Tracks which inode (identified by an arbitrary counter, since not every
+/// command carries an inode number) lives at each path as the stream
+/// progresses.
+#[derive(Default)]
+struct Tracker {
+    paths: BTreeMap<PathBuf, usize>,
+    next: usize,
+}
+
+impl Tracker {
+    /// Look up the inode at `path`, assuming that paths that have not been seen
+    /// yet already existed in the parent subvolume.
+    fn id(&mut self, path: &Path) -> usize {
+        if let Some(id) = self.paths.get(path) {
+            return *id;
+        }
+        self.create(path)
+    }
+
+    fn create(&mut self, path: &Path) -> usize {
+        let id = self.next;
+        self.next += 1;
+        self.paths.insert(path.to_path_buf(), id);
+        id
+    }
+
+    /// Inode at `path`, recording the directories above it as dependencies
+    fn at(&mut self, path: &Path, deps: &mut Vec<usize>) -> usize {
+        self.ancestors(path, deps);
+        self.id(path)
+    }
+
+    /// Create a new inode at `path`, which depends on the directories above it
+    fn create_at(&mut self, path: &Path, deps: &mut Vec<usize>) -> usize {
+        self.ancestors(path, deps);
+        self.create(path)
+    }
+
+    /// Inodes of every directory leading up to `path`
+    fn ancestors(&mut self, path: &Path, deps: &mut Vec<usize>) {
+        for a in path.ancestors().skip(1) {
+            if a.as_os_str().is_empty() {
+                break;
+            }
+            deps.push(self.id(a));
+        }
+    }
+
+    fn under<'p>(&'p self, dir: &'p Path) -> impl Iterator<Item = (&'p PathBuf, &'p usize)> {
+        self.paths
+            .range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+            .take_while(move |(p, _)| p.starts_with(dir))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<_> = self.under(from).map(|(p, id)| (p.clone(), *id)).collect();
+        let replaced: Vec<_> = self.under(to).map(|(p, _)| p.clone()).collect();
+        for p in replaced {
+            self.paths.remove(&p);
+        }
+        for (p, id) in moved {
+            self.paths.remove(&p);
+            let dst = renamed(&p, from, to).unwrap_or(p);
+            self.paths.insert(dst, id);
+        }
+    }
+}
+
+/// The inode a command applies to, and all the other inodes that need to be
+/// present for it to be received.
+struct Step {
+    subject: Option<usize>,
+    deps: Vec<usize>,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Produce a sendstream that contains only the commands needed to
+    /// reconstruct `paths` (relative to the subvolume root).
+    ///
+    /// All the parent directories of each path are included, as are the
+    /// temporary names, renames and metadata changes that lead up to the final
+    /// state of each path. If a requested path is a directory, everything
+    /// beneath it is included as well.
+    pub fn extract_paths(&self, paths: &[PathBuf]) -> fs::Result<Sendstream<'a>> {
+        let mut t = Tracker::default();
+        let mut uuid: Option<Uuid> = None;
+        let mut steps = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            let mut deps = Vec::new();
+            let subject = match cmd {
+                Command::Subvol(s) => {
+                    uuid = Some(s.uuid);
+                    None
+                }
+                Command::Snapshot(s) => {
+                    uuid = Some(s.uuid);
+                    None
+                }
+                Command::End => None,
+                Command::Mkdir(c) => Some(t.create_at(&c.path, &mut deps)),
+                Command::Mkfile(c) => Some(t.create_at(&c.path, &mut deps)),
+                Command::Mkfifo(c) => Some(t.create_at(&c.path, &mut deps)),
+                Command::Mknod(c) => Some(t.create_at(&c.path, &mut deps)),
+                Command::Mksock(c) => Some(t.create_at(&c.path, &mut deps)),
+                Command::Symlink(c) => Some(t.create_at(&c.link_name, &mut deps)),
+                Command::Link(c) => {
+                    let id = t.at(&c.target, &mut deps);
+                    t.ancestors(&c.link_name, &mut deps);
+                    t.paths.insert(c.link_name.to_path_buf(), id);
+                    Some(id)
+                }
+                Command::Rename(c) => {
+                    let id = t.at(&c.from, &mut deps);
+                    t.ancestors(&c.to, &mut deps);
+                    t.rename(&c.from, &c.to);
+                    Some(id)
+                }
+                Command::Unlink(c) => {
+                    let id = t.at(&c.path, &mut deps);
+                    t.paths.remove(c.path.as_ref());
+                    Some(id)
+                }
+                Command::Rmdir(c) => {
+                    let id = t.at(&c.path, &mut deps);
+                    t.paths.remove(c.path.as_ref());
+                    Some(id)
+                }
+                Command::Clone(c) => {
+                    if Some(c.uuid) == uuid {
+                        let src = t.at(&c.src_path, &mut deps);
+                        deps.push(src);
+                    }
+                    Some(t.at(&c.dst_path, &mut deps))
+                }
+                Command::Chmod(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Chown(c) => Some(t.at(&c.path, &mut deps)),
+                Command::EncodedWrite(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Fallocate(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Fileattr(c) => Some(t.at(&c.path, &mut deps)),
+                Command::RemoveXattr(c) => Some(t.at(&c.path, &mut deps)),
+                Command::SetXattr(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Truncate(c) => Some(t.at(&c.path, &mut deps)),
+                Command::UpdateExtent(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Utimes(c) => Some(t.at(&c.path, &mut deps)),
+                Command::Write(c) => Some(t.at(&c.path, &mut deps)),
+            };
+            steps.push(Step { subject, deps });
+        }
+
+        let mut needed = BTreeSet::new();
+        for path in paths {
+            let id = *t
+                .paths
+                .get(path)
+                .ok_or_else(|| fs::Error::NotFound(path.clone()))?;
+            needed.insert(id);
+            needed.extend(t.under(path).map(|(_, id)| *id));
+            let mut deps = Vec::new();
+            t.ancestors(path, &mut deps);
+            needed.extend(deps);
+        }
+        // keep pulling in dependencies until nothing changes, a command late in
+        // the stream might depend on a directory that was created earlier
+        loop {
+            let before = needed.len();
+            for step in &steps {
+                if step.subject.is_some_and(|s| needed.contains(&s)) {
+                    needed.extend(step.deps.iter().copied());
+                }
+            }
+            if needed.len() == before {
+                break;
+            }
+        }
+
+        let commands = self
+            .commands
+            .iter()
+            .zip(&steps)
+            .filter(|(_, step)| step.subject.is_none_or(|s| needed.contains(&s)))
+            .map(|(cmd, _)| cmd.clone())
+            .collect();
+        Ok(Sendstream { commands })
+    }
+}
+
+/// Write the final contents of the regular file at `path` (as it is named once
+/// the whole sendstream has been received, not any temporary name) to `w`.
+/// Holes are written out as zeroes.
+///
+/// Only the commands needed to reconstruct `path` are replayed (see
+/// [Sendstream::extract_paths]), so this is much cheaper than replaying the
+/// entire sendstream. `stream` must be a full sendstream, since the contents
+/// of an incremental depend on its parent.
+pub fn extract_file<W: Write>(stream: &Sendstream, path: &Path, mut w: W) -> Result<W> {
+    let subset = stream.extract_paths(&[path.to_path_buf()])?;
+    let fs = Filesystem::from_sendstream(&subset)?;
+    let contents = fs
+        .get(path)
+        .ok_or_else(|| fs::Error::NotFound(path.to_path_buf()))?
+        .contents()
+        .ok_or_else(|| fs::Error::NotAFile(path.to_path_buf()))?;
+    write_contents(contents, &mut w)?;
+    Ok(w)
+}
+
+/// Replay only the part of a full sendstream that is under `path`
+fn replay_subtree(
+    stream: &Sendstream,
+    path: &Path,
+) -> Result<(Filesystem, Vec<(PathBuf, InodeId)>)> {
+    let subset = stream.extract_paths(&[path.to_path_buf()])?;
+    let fs = Filesystem::from_sendstream(&subset)?;
+    let entries = fs
+        .walk()
+        .into_iter()
+        .filter_map(|(p, id)| {
+            let rel = p.strip_prefix(path).ok()?.to_path_buf();
+            Some((rel, id))
+        })
+        .collect();
+    Ok((fs, entries))
+}
+
+/// Every file (and its metadata) at or below `path` once the full sendstream
+/// `stream` has been received, keyed by path relative to `path` (so `path`
+/// itself is the empty path). Hard links show up once for each path.
+pub fn extract_tree(stream: &Sendstream, path: &Path) -> Result<BTreeMap<PathBuf, Inode>> {
+    let (fs, entries) = replay_subtree(stream, path)?;
+    Ok(entries
+        .into_iter()
+        .map(|(rel, id)| (rel, fs[id].clone()))
+        .collect())
+}
+
+/// Materialize everything at or below `path` in the full sendstream `stream`
+/// into `dest`, which takes the place of `path` itself. If `path` is a
+/// directory, `dest` may already exist (but nothing beneath it can).
+///
+/// Ownership is only restored when running as root, and creating device
+/// nodes or setting `trusted.` and `security.` xattrs may need privileges
+/// too. Timestamps are set last, once nothing else will change them.
+pub fn extract_tree_to(stream: &Sendstream, path: &Path, dest: &Path) -> Result<()> {
+    let (fs, entries) = replay_subtree(stream, path)?;
+    let mut linked: BTreeMap<InodeId, PathBuf> = BTreeMap::new();
+    for (rel, id) in &entries {
+        let inode = &fs[*id];
+        let dst = dest.join(rel);
+        let err = |error| Error::Extract {
+            path: dst.clone(),
+            error,
+        };
+        if inode.nlink() > 1 && !matches!(inode.kind(), InodeKind::Directory(_)) {
+            if let Some(first) = linked.get(id) {
+                std::fs::hard_link(first, &dst).map_err(err)?;
+                continue;
+            }
+            linked.insert(*id, dst.clone());
+        }
+        create(inode, &dst).map_err(err)?;
+    }
+    // children first, so that directory timestamps and permissions are not
+    // disturbed by anything created inside them afterwards
+    let root = Uid::effective().is_root();
+    for (rel, id) in entries.iter().rev() {
+        let dst = dest.join(rel);
+        set_metadata(&fs[*id], &dst, root).map_err(|error| Error::Extract { path: dst, error })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn create(inode: &Inode, dst: &Path) -> std::io::Result<()> {
+    let special = |kind, rdev| {
+        nix::sys::stat::mknod(dst, kind, nix::sys::stat::Mode::S_IRUSR, rdev)
+            .map_err(std::io::Error::from)
+    };
+    match inode.kind() {
+        InodeKind::Directory(_) if dst.is_dir() => Ok(()),
+        InodeKind::Directory(_) => std::fs::create_dir(dst),
+        InodeKind::File(contents) => {
+            let f = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dst)?;
+            for (off, data) in contents.extents() {
+                f.write_all_at(data, off)?;
+            }
+            f.set_len(contents.len())
+        }
+        InodeKind::Symlink(target) => std::os::unix::fs::symlink(target, dst),
+        InodeKind::Fifo => special(SFlag::S_IFIFO, 0),
+        InodeKind::Socket => special(SFlag::S_IFSOCK, 0),
+        InodeKind::CharDevice(rdev) => special(SFlag::S_IFCHR, rdev.as_u64()),
+        InodeKind::BlockDevice(rdev) => special(SFlag::S_IFBLK, rdev.as_u64()),
+    }
+}
+
+pub(crate) fn set_metadata(inode: &Inode, dst: &Path, chown: bool) -> std::io::Result<()> {
+    if chown && (inode.uid().is_some() || inode.gid().is_some()) {
+        nix::unistd::fchownat(
+            None,
+            dst,
+            inode.uid(),
+            inode.gid(),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+    let symlink = matches!(inode.kind(), InodeKind::Symlink(_));
+    if let (Some(mode), false) = (inode.mode(), symlink) {
+        std::fs::set_permissions(dst, mode.permissions())?;
+    }
+    // only now, since chown clears security.capability
+    for (name, value) in inode.xattrs() {
+        xattr::set(dst, std::ffi::OsStr::from_bytes(name), value)?;
+    }
+    if let (Some(atime), Some(mtime)) = (inode.atime(), inode.mtime()) {
+        nix::sys::stat::utimensat(
+            None,
+            dst,
+            &timespec(*atime),
+            &timespec(*mtime),
+            UtimensatFlags::NoFollowSymlink,
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn timespec(t: SystemTime) -> TimeSpec {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => TimeSpec::from(d),
+        Err(e) => -TimeSpec::from(e.duration()),
+    }
+}
+
+/// Write out `contents` one extent at a time, filling the holes in between
+fn write_contents<W: Write>(contents: &FileContents, w: &mut W) -> std::io::Result<()> {
+    let zeroes = vec![0; ZEROES_LEN.min(contents.len()) as usize];
+    let fill = |w: &mut W, mut len: u64| {
+        while len > 0 {
+            let n = len.min(zeroes.len() as u64);
+            w.write_all(&zeroes[..n as usize])?;
+            len -= n;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let mut pos = 0;
+    for (off, data) in contents.extents() {
+        fill(w, off.saturating_sub(pos))?;
+        let keep = contents.len().saturating_sub(off).min(data.len() as u64);
+        w.write_all(&data[..keep as usize])?;
+        pos = pos.max(off + keep);
+    }
+    fill(w, contents.len().saturating_sub(pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::fs::Filesystem;
+
+    #[test]
+    fn extract_paths() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let full = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let subset = sendstreams[0]
+            .extract_paths(&[PathBuf::from("hello/lorem-reflinked")])
+            .expect("failed to extract");
+        assert!(subset.commands.len() < sendstreams[0].commands.len());
+        let fs = Filesystem::from_sendstream(&subset).expect("failed to replay subset");
+        assert_eq!(
+            vec![
+                PathBuf::from("hello"),
+                PathBuf::from("hello/lorem"),
+                PathBuf::from("hello/lorem-reflinked"),
+            ],
+            fs.walk().into_iter().map(|(p, _)| p).collect::<Vec<_>>(),
+        );
+        for p in ["hello", "hello/lorem-reflinked"] {
+            let a = &full[full.lookup(Path::new(p)).expect("missing from full")];
+            let b = &fs[fs.lookup(Path::new(p)).expect("missing from subset")];
+            assert_eq!(a.contents(), b.contents());
+            assert_eq!(a.mode(), b.mode());
+            assert_eq!(a.mtime(), b.mtime());
+        }
+
+        let undo = sendstreams[1]
+            .extract_paths(&[PathBuf::from("hello/msg")])
+            .expect("failed to extract");
+        assert!(!undo
+            .commands
+            .iter()
+            .any(|c| matches!(c, Command::Unlink(_) | Command::Rmdir(_))));
+    }
+
+    #[test]
+    fn extract_file() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let msg = super::extract_file(demo, Path::new("hello/msg"), Vec::new())
+            .expect("failed to extract");
+        assert_eq!(b"Hello world!\n".as_slice(), msg);
+
+        let full = Filesystem::from_sendstream(demo).expect("failed to replay");
+        let lorem = super::extract_file(demo, Path::new("hello/lorem-reflinked"), Vec::new())
+            .expect("failed to extract");
+        assert_eq!(
+            full.get(Path::new("hello/lorem-reflinked"))
+                .and_then(|i| i.contents())
+                .map(FileContents::to_vec),
+            Some(lorem)
+        );
+
+        assert!(matches!(
+            super::extract_file(demo, Path::new("hello"), Vec::new()),
+            Err(Error::Fs(fs::Error::NotAFile(_)))
+        ));
+        assert!(matches!(
+            super::extract_file(&sendstreams[1], Path::new("hello/msg"), Vec::new()),
+            Err(Error::Fs(fs::Error::Incremental))
+        ));
+    }
+
+    #[test]
+    fn extract_tree() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let full = Filesystem::from_sendstream(demo).expect("failed to replay");
+
+        let tree = super::extract_tree(demo, Path::new("hello")).expect("failed to extract");
+        assert_eq!(
+            vec!["", "lorem", "lorem-reflinked", "msg", "msg-hard", "msg-sym"],
+            tree.keys()
+                .map(|p| p.to_str().expect("utf8"))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(full.get(Path::new("hello/msg")), tree.get(Path::new("msg")));
+
+        let dest = std::env::temp_dir().join(format!("extract_tree.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        super::extract_tree_to(demo, Path::new("hello"), &dest).expect("failed to extract");
+        let read = |p: &str| std::fs::read(dest.join(p)).expect("failed to read");
+        assert_eq!(b"Hello world!\n".as_slice(), read("msg"));
+        assert_eq!(read("lorem"), read("lorem-reflinked"));
+        assert_eq!(
+            Path::new("hello/msg"),
+            std::fs::read_link(dest.join("msg-sym")).expect("not a symlink")
+        );
+        let meta = |p: &str| std::fs::symlink_metadata(dest.join(p)).expect("missing");
+        assert_eq!(meta("msg").ino(), meta("msg-hard").ino());
+        assert_eq!(0o400, meta("msg").mode() & 0o7777);
+        let msg = full.get(Path::new("hello/msg")).expect("missing hello/msg");
+        assert_eq!(
+            Some(*msg.mtime().expect("no mtime")),
+            meta("msg").modified().ok()
+        );
+        assert_eq!(
+            msg.xattrs().get(b"user.antlir.demo".as_slice()).cloned(),
+            xattr::get(dest.join("msg"), "user.antlir.demo").expect("failed to get xattr")
+        );
+        // creating the children must not have clobbered the directory's mtime
+        let hello = full.get(Path::new("hello")).expect("missing hello");
+        assert_eq!(
+            Some(*hello.mtime().expect("no mtime")),
+            meta("").modified().ok()
+        );
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+    }
+
+    #[test]
+    fn set_metadata_keeps_capabilities() {
+        // only root can set file capabilities
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let full = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let mut inode = full
+            .get(Path::new("hello/msg"))
+            .expect("missing hello/msg")
+            .clone();
+        // cap_net_bind_service=p
+        let cap =
+            b"\x00\x00\x00\x02\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        inode
+            .xattrs
+            .insert(b"security.capability".to_vec(), cap.to_vec());
+        let dest = std::env::temp_dir().join(format!("set_metadata.{}", std::process::id()));
+        let _ = std::fs::remove_file(&dest);
+        create(&inode, &dest).expect("failed to create");
+        set_metadata(&inode, &dest, true).expect("failed to set metadata");
+        assert!(xattr::get(&dest, "security.capability")
+            .expect("failed to get xattr")
+            .is_some());
+        std::fs::remove_file(&dest).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,250 @@
+//! File-centric view of a sendstream: every command grouped by the file that
+//! it affects, instead of one flat command log.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+
+use crate::manifest::FileType;
+use crate::Atime;
+use crate::Clone;
+use crate::Command;
+use crate::Ctime;
+use crate::Ino;
+use crate::Mode;
+use crate::Mtime;
+use crate::Sendstream;
+use crate::Write;
+
+/// Everything that a sendstream does to a single file (or directory, symlink,
+/// etc). Metadata fields are only set if the sendstream sets them, so for
+/// incremental sendstreams anything left as `None` is unchanged from the
+/// parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry<'s, 'a> {
+    /// Where this file is once the sendstream has been received. Files with
+    /// more than one hard link use the first of `links`.
+    pub final_path: PathBuf,
+    /// Every final path of this file, in sorted order
+    pub links: Vec<PathBuf>,
+    /// Indices of every command that affects this file, in stream order
+    pub commands: Vec<usize>,
+    pub mode: Option<Mode>,
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+    pub atime: Option<Atime>,
+    pub mtime: Option<Mtime>,
+    pub ctime: Option<Ctime>,
+    /// Final value of each xattr that is changed, or `None` if it is removed
+    pub xattrs: BTreeMap<&'s [u8], Option<&'s [u8]>>,
+    /// Size set by the last [crate::Truncate]
+    pub size: Option<u64>,
+    pub writes: Vec<&'s Write<'a>>,
+    /// Clones into this file
+    pub clones: Vec<&'s Clone<'a>>,
+}
+
+impl<'s, 'a> FileEntry<'s, 'a> {
+    fn new(final_path: PathBuf, links: Vec<PathBuf>) -> Self {
+        Self {
+            final_path,
+            links,
+            commands: Vec::new(),
+            mode: None,
+            uid: None,
+            gid: None,
+            atime: None,
+            mtime: None,
+            ctime: None,
+            xattrs: BTreeMap::new(),
+            size: None,
+            writes: Vec::new(),
+            clones: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, index: usize, cmd: &'s Command<'a>) {
+        self.commands.push(index);
+        match cmd {
+            Command::Chmod(c) => self.mode = Some(c.mode),
+            Command::Mknod(c) => self.mode = Some(c.mode),
+            Command::Mkfifo(c) => self.mode = Some(c.mode),
+            Command::Mksock(c) => self.mode = Some(c.mode),
+            Command::Chown(c) => {
+                self.uid = Some(c.uid);
+                self.gid = Some(c.gid);
+            }
+            Command::Utimes(c) => {
+                self.atime = Some(c.atime);
+                self.mtime = Some(c.mtime);
+                self.ctime = Some(c.ctime);
+            }
+            Command::SetXattr(c) => {
+                self.xattrs.insert(&c.name, Some(&c.data));
+            }
+            Command::RemoveXattr(c) => {
+                self.xattrs.insert(&c.name, None);
+            }
+            Command::Truncate(c) => self.size = Some(c.size),
+            Command::Write(w) => self.writes.push(w),
+            Command::Clone(c) => self.clones.push(c),
+            _ => (),
+        }
+    }
+}
+
+/// A file created by a sendstream, see [Sendstream::inode_table]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeTableEntry<'s, 'a> {
+    pub ino: Ino,
+    /// Index of the command that creates the file
+    pub created_by: usize,
+    pub file_type: FileType,
+    /// Final paths and metadata of the file
+    pub file: FileEntry<'s, 'a>,
+}
+
+/// Type of the file that `cmd` creates, if it creates one
+pub(crate) fn created_type(cmd: &Command) -> Option<FileType> {
+    match cmd {
+        Command::Mkdir(_) => Some(FileType::Directory),
+        Command::Mkfile(_) => Some(FileType::File),
+        Command::Symlink(_) => Some(FileType::Symlink),
+        Command::Mkfifo(_) => Some(FileType::Fifo),
+        Command::Mksock(_) => Some(FileType::Socket),
+        Command::Mknod(m) => match m.mode.0 & nix::libc::S_IFMT {
+            nix::libc::S_IFBLK => Some(FileType::BlockDevice),
+            _ => Some(FileType::CharDevice),
+        },
+        _ => None,
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Every file that this sendstream creates and that still exists at the
+    /// end of it, keyed by inode number, for joining against a live
+    /// filesystem. Files that an incremental sendstream only changes are not
+    /// included, since the sendstream does not carry their inode numbers.
+    pub fn inode_table(&self) -> BTreeMap<Ino, InodeTableEntry<'_, 'a>> {
+        self.files()
+            .into_iter()
+            .filter_map(|file| {
+                let (created_by, ino, file_type) = file.commands.iter().find_map(|i| {
+                    let cmd = &self.commands[*i];
+                    Some((*i, cmd.ino()?, created_type(cmd)?))
+                })?;
+                Some((
+                    ino,
+                    InodeTableEntry {
+                        ino,
+                        created_by,
+                        file_type,
+                        file,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Group the commands of this sendstream by the file that they affect
+    /// (following temporary names and hard links, see
+    /// [Sendstream::final_paths]). Files are returned in order of their final
+    /// path, and files that no longer exist at the end of the sendstream are
+    /// left out.
+    pub fn files(&self) -> Vec<FileEntry<'_, 'a>> {
+        let finals = self.final_paths();
+        let mut files: BTreeMap<usize, FileEntry> = BTreeMap::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let Some(inode) = finals.inode(index) else {
+                continue;
+            };
+            let links = finals.links(inode);
+            let Some(first) = links.first() else {
+                continue;
+            };
+            files
+                .entry(inode)
+                .or_insert_with(|| FileEntry::new(first.clone(), links.to_vec()))
+                .add(index, cmd);
+        }
+        let mut files: Vec<_> = files.into_values().collect();
+        files.sort_by(|a, b| a.final_path.cmp(&b.final_path));
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::fs::Filesystem;
+
+    #[test]
+    fn files_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let files = sendstreams[0].files();
+
+        // one entry per inode, with the same metadata that the model ends up with
+        let mut inodes: Vec<_> = demo.walk().into_iter().map(|(_, id)| id).collect();
+        inodes.sort();
+        inodes.dedup();
+        assert_eq!(inodes.len() + 1, files.len(), "every inode plus the root");
+        for f in &files {
+            let inode = demo.get(&f.final_path).expect("file exists");
+            assert_eq!(inode.mode(), f.mode.map(|m| Mode(m.0 & 0o7777)));
+            assert_eq!(inode.uid(), f.uid);
+            assert_eq!(inode.mtime(), f.mtime);
+        }
+
+        let msg = files
+            .iter()
+            .find(|f| f.final_path == Path::new("hello/msg"))
+            .expect("missing hello/msg");
+        assert_eq!(2, msg.links.len());
+        assert!(!msg.xattrs.is_empty());
+        assert_eq!(
+            b"Hello world!\n".len(),
+            msg.writes.iter().map(|w| w.data().len()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn inode_table() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let table = sendstreams[0].inode_table();
+        for (path, id) in demo.walk() {
+            let ino = demo[id].ino().expect("every inode has a number");
+            let entry = table
+                .get(&ino)
+                .unwrap_or_else(|| panic!("{path:?} missing"));
+            assert!(entry.file.links.contains(&path));
+        }
+        let msg = table.get(&Ino(258)).expect("missing hello/msg");
+        assert_eq!(FileType::File, msg.file_type);
+        assert_eq!(
+            vec![PathBuf::from("hello/msg"), PathBuf::from("hello/msg-hard")],
+            msg.file.links
+        );
+        assert!(matches!(
+            sendstreams[0].commands()[msg.created_by],
+            Command::Mkfile(_)
+        ));
+        assert_eq!(
+            Some(FileType::CharDevice),
+            table
+                .values()
+                .find(|e| e.file.final_path == Path::new("null"))
+                .map(|e| e.file_type)
+        );
+
+        // nothing is created by the incremental
+        assert!(sendstreams[1].inode_table().is_empty());
+    }
+}
@@ -0,0 +1,1047 @@
+//! In-memory model of a subvolume, built by replaying the [Command]s of a
+//! [Sendstream].
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::ops::Range;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use uuid::Uuid;
+
+use crate::Atime;
+use crate::Command;
+use crate::Ctime;
+use crate::Ctransid;
+use crate::Ino;
+use crate::Mode;
+use crate::Mtime;
+use crate::Rdev;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0:?} does not exist")]
+    NotFound(PathBuf),
+    #[error("{0:?} already exists")]
+    Exists(PathBuf),
+    #[error("{0:?} is not a directory")]
+    NotADirectory(PathBuf),
+    #[error("{0:?} is a directory")]
+    IsADirectory(PathBuf),
+    #[error("{0:?} is not a regular file")]
+    NotAFile(PathBuf),
+    #[error("directory {0:?} is not empty")]
+    NotEmpty(PathBuf),
+    #[error("{0:?} is not a valid path within a subvolume")]
+    InvalidPath(PathBuf),
+    #[error("{path:?} has no xattr {name:?}")]
+    MissingXattr { path: PathBuf, name: String },
+    #[error("clone source subvolume {0} is not available")]
+    MissingCloneSource(Uuid),
+    #[error("sendstream does not start with a Subvol or Snapshot command")]
+    MissingHeader,
+    #[error("sendstream is incremental and requires a parent subvolume")]
+    Incremental,
+    #[error("sendstream expects parent {expected}, but was given {actual:?}")]
+    WrongParent {
+        expected: Uuid,
+        actual: Option<Uuid>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Identity of an inode within a [Filesystem]. This is distinct from [Ino]
+/// since not every inode has a known inode number (for example files that
+/// already existed in the parent of an incremental sendstream).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InodeId(u64);
+
+/// Identifying information for the subvolume that a sendstream produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubvolumeInfo {
+    pub(crate) path: PathBuf,
+    pub(crate) uuid: Uuid,
+    pub(crate) ctransid: Ctransid,
+    /// Set if this subvolume was created by an incremental sendstream
+    pub(crate) parent: Option<(Uuid, Ctransid)>,
+}
+
+impl SubvolumeInfo {
+    /// Info from the [Command::Subvol] or [Command::Snapshot] that starts a
+    /// sendstream
+    pub(crate) fn from_command(cmd: &Command) -> Option<Self> {
+        match cmd {
+            Command::Subvol(s) => Some(Self {
+                path: s.path.to_path_buf(),
+                uuid: s.uuid,
+                ctransid: s.ctransid,
+                parent: None,
+            }),
+            Command::Snapshot(s) => Some(Self {
+                path: s.path.to_path_buf(),
+                uuid: s.uuid,
+                ctransid: s.ctransid,
+                parent: Some((s.clone_uuid, s.clone_ctransid)),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn ctransid(&self) -> Ctransid {
+        self.ctransid
+    }
+
+    pub fn parent_uuid(&self) -> Option<Uuid> {
+        self.parent.map(|(uuid, _)| uuid)
+    }
+
+    pub fn parent_ctransid(&self) -> Option<Ctransid> {
+        self.parent.map(|(_, ctransid)| ctransid)
+    }
+}
+
+/// Most data that [FileContents::changed_ranges] holds in memory at once
+pub(crate) const CHANGED_RANGES_CHUNK: u64 = 1024 * 1024;
+
+/// Sparse file contents. Ranges that were never written are holes and read
+/// back as zeroes.
+#[derive(Debug, Clone, Default, Eq)]
+pub struct FileContents {
+    size: u64,
+    /// Non-overlapping data extents keyed by their starting offset
+    extents: BTreeMap<u64, Vec<u8>>,
+    undecodable: bool,
+}
+
+impl FileContents {
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether some of the data was written by a compressed or encrypted
+    /// [crate::EncodedWrite], which can not be decoded and so reads back as
+    /// a hole
+    pub fn undecodable(&self) -> bool {
+        self.undecodable
+    }
+
+    /// Iterate over all the ranges of this file that contain data (as opposed
+    /// to holes) in offset order.
+    pub fn extents(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.extents
+            .iter()
+            .map(|(off, data)| (*off, data.as_slice()))
+    }
+
+    /// Ranges of this file that contain data, in offset order, with adjacent
+    /// extents merged
+    pub fn data_ranges(&self) -> Vec<Range<u64>> {
+        let mut out: Vec<Range<u64>> = Vec::new();
+        for (offset, data) in self.extents() {
+            let end = offset + data.len() as u64;
+            match out.last_mut() {
+                Some(last) if last.end == offset => last.end = end,
+                _ => out.push(offset..end),
+            }
+        }
+        out
+    }
+
+    /// Read `len` bytes starting at `offset`. Holes are filled with zeroes and
+    /// the result is cut short at the end of the file.
+    pub fn read(&self, offset: u64, len: u64) -> Vec<u8> {
+        let end = self.size.min(offset.saturating_add(len));
+        if end <= offset {
+            return Vec::new();
+        }
+        let mut buf = vec![0; (end - offset) as usize];
+        for (ext_off, data) in self.overlapping(offset, end) {
+            let start = ext_off.max(offset);
+            let stop = (ext_off + data.len() as u64).min(end);
+            buf[(start - offset) as usize..(stop - offset) as usize]
+                .copy_from_slice(&data[(start - ext_off) as usize..(stop - ext_off) as usize]);
+        }
+        buf
+    }
+
+    /// Read the entire contents of the file, including holes
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.read(0, self.size)
+    }
+
+    /// Byte ranges in which this file and `other` differ, in offset order.
+    /// If one file is longer than the other, everything past the end of the
+    /// shorter one counts as changed.
+    pub fn changed_ranges(&self, other: &FileContents) -> Vec<Range<u64>> {
+        let other_data = other
+            .extents()
+            .map(|(off, data)| (off, off + data.len() as u64));
+        let read = |off, len| Ok::<_, std::convert::Infallible>(other.read(off, len));
+        match self.changed_ranges_by(other.size, other_data, read) {
+            Ok(changed) => changed,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like [FileContents::changed_ranges], but against a file of
+    /// `other_size` bytes that is read with `read_other(offset, len)` and
+    /// only has data in the `other_data` ranges (anything else reads back as
+    /// zeroes). Data is read at most [CHANGED_RANGES_CHUNK] bytes at a time.
+    pub(crate) fn changed_ranges_by<E>(
+        &self,
+        other_size: u64,
+        other_data: impl IntoIterator<Item = (u64, u64)>,
+        mut read_other: impl FnMut(u64, u64) -> std::result::Result<Vec<u8>, E>,
+    ) -> std::result::Result<Vec<Range<u64>>, E> {
+        fn push(changed: &mut Vec<Range<u64>>, r: Range<u64>) {
+            match changed.last_mut() {
+                Some(last) if last.end == r.start => last.end = r.end,
+                _ => changed.push(r),
+            }
+        }
+        let common = self.size.min(other_size);
+        // ranges that are holes in both files are equal, so only data needs
+        // comparing
+        let mut ranges: Vec<(u64, u64)> = self
+            .extents()
+            .map(|(off, data)| (off, off + data.len() as u64))
+            .chain(other_data)
+            .map(|(start, end)| (start, end.min(common)))
+            .filter(|(start, end)| start < end)
+            .collect();
+        ranges.sort_unstable();
+        let mut changed = Vec::new();
+        let mut compared = 0;
+        for (start, end) in ranges {
+            let mut off = start.max(compared);
+            while off < end {
+                let len = (end - off).min(CHANGED_RANGES_CHUNK);
+                let a = self.read(off, len);
+                let b = read_other(off, len)?;
+                for (i, _) in a.iter().zip(&b).enumerate().filter(|(_, (x, y))| x != y) {
+                    let at = off + i as u64;
+                    push(&mut changed, at..at + 1);
+                }
+                off += len;
+            }
+            compared = compared.max(end);
+        }
+        if self.size != other_size {
+            push(&mut changed, common..self.size.max(other_size));
+        }
+        Ok(changed)
+    }
+
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, &Vec<u8>)> {
+        // the extent containing `start` may begin before it
+        let first = self
+            .extents
+            .range(..=start)
+            .next_back()
+            .map(|(off, _)| *off)
+            .unwrap_or(start);
+        self.extents
+            .range(first..end)
+            .filter(move |(off, data)| **off + data.len() as u64 > start)
+            .map(|(off, data)| (*off, data))
+    }
+
+    /// Remove any data in the range `[start, end)`, leaving a hole
+    fn punch(&mut self, start: u64, end: u64) {
+        let overlapping: Vec<u64> = self.overlapping(start, end).map(|(off, _)| off).collect();
+        for off in overlapping {
+            let Some(mut data) = self.extents.remove(&off) else {
+                continue;
+            };
+            let ext_end = off + data.len() as u64;
+            if ext_end > end {
+                self.extents
+                    .insert(end, data.split_off((end - off) as usize));
+            }
+            if off < start {
+                data.truncate((start - off) as usize);
+                self.extents.insert(off, data);
+            }
+        }
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+        self.punch(offset, end);
+        // append to the previous extent if it ends exactly where this one
+        // starts, which is the common case for a sequence of writes
+        let prev = self
+            .extents
+            .range_mut(..offset)
+            .next_back()
+            .filter(|(off, d)| **off + d.len() as u64 == offset);
+        match prev {
+            Some((_, d)) => d.extend_from_slice(data),
+            None => {
+                self.extents.insert(offset, data.to_vec());
+            }
+        }
+        self.size = self.size.max(end);
+    }
+
+    /// Leave a hole of `len` bytes at `offset` where the data could not be
+    /// decoded
+    pub(crate) fn write_undecodable(&mut self, offset: u64, len: u64) {
+        let end = offset.saturating_add(len);
+        self.punch(offset, end);
+        self.size = self.size.max(end);
+        self.undecodable = true;
+    }
+
+    /// Drop all the data, leaving a file of the same size that is one big
+    /// hole
+    pub(crate) fn clear(&mut self) {
+        self.extents.clear();
+    }
+
+    pub(crate) fn truncate(&mut self, size: u64) {
+        self.punch(size, u64::MAX);
+        self.size = size;
+    }
+
+    /// Preallocated space reads back as zeroes, so only punching a hole or
+    /// growing the file makes a difference.
+    pub(crate) fn fallocate(&mut self, mode: crate::FallocateMode, offset: u64, len: u64) {
+        let end = offset.saturating_add(len);
+        if mode.punch_hole() {
+            self.punch(offset, end);
+        }
+        if !mode.keep_size() {
+            self.size = self.size.max(end);
+        }
+    }
+
+    /// Copy `len` bytes (preserving holes) from `src` starting at `src_offset`
+    /// into this file at `dst_offset`.
+    pub(crate) fn clone_range(
+        &mut self,
+        src: &FileContents,
+        src_offset: u64,
+        len: u64,
+        dst_offset: u64,
+    ) {
+        let src_end = src_offset.saturating_add(len);
+        let copied: Vec<(u64, Vec<u8>)> = src
+            .overlapping(src_offset, src_end)
+            .map(|(off, data)| {
+                let start = off.max(src_offset);
+                let stop = (off + data.len() as u64).min(src_end);
+                (
+                    start - src_offset + dst_offset,
+                    data[(start - off) as usize..(stop - off) as usize].to_vec(),
+                )
+            })
+            .collect();
+        self.punch(dst_offset, dst_offset.saturating_add(len));
+        for (off, data) in copied {
+            self.write(off, &data);
+        }
+        self.size = self.size.max(dst_offset.saturating_add(len));
+    }
+}
+
+impl PartialEq for FileContents {
+    /// Files are equal if they read back the same bytes, regardless of how
+    /// the data is split into extents.
+    fn eq(&self, other: &Self) -> bool {
+        if self.size != other.size || self.undecodable != other.undecodable {
+            return false;
+        }
+        let mut ranges: Vec<(u64, u64)> = self
+            .extents()
+            .chain(other.extents())
+            .map(|(off, data)| (off, off + data.len() as u64))
+            .collect();
+        ranges.sort_unstable();
+        ranges
+            .into_iter()
+            .all(|(start, end)| self.read(start, end - start) == other.read(start, end - start))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InodeKind {
+    Directory(BTreeMap<OsString, InodeId>),
+    File(FileContents),
+    Symlink(PathBuf),
+    Fifo,
+    Socket,
+    CharDevice(Rdev),
+    BlockDevice(Rdev),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inode {
+    pub(crate) ino: Option<Ino>,
+    pub(crate) kind: InodeKind,
+    pub(crate) nlink: usize,
+    pub(crate) mode: Option<Mode>,
+    pub(crate) uid: Option<Uid>,
+    pub(crate) gid: Option<Gid>,
+    pub(crate) atime: Option<Atime>,
+    pub(crate) mtime: Option<Mtime>,
+    pub(crate) ctime: Option<Ctime>,
+    pub(crate) xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Inode {
+    fn new(ino: Option<Ino>, kind: InodeKind) -> Self {
+        Self {
+            ino,
+            kind,
+            nlink: 0,
+            mode: None,
+            uid: None,
+            gid: None,
+            atime: None,
+            mtime: None,
+            ctime: None,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// Inode number as reported by the sendstream, if known
+    pub fn ino(&self) -> Option<Ino> {
+        self.ino
+    }
+
+    pub fn kind(&self) -> &InodeKind {
+        &self.kind
+    }
+
+    /// Number of paths that refer to this inode
+    pub fn nlink(&self) -> usize {
+        self.nlink
+    }
+
+    /// Permission bits (without the file type)
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode
+    }
+
+    pub fn uid(&self) -> Option<Uid> {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Option<Gid> {
+        self.gid
+    }
+
+    pub fn atime(&self) -> Option<Atime> {
+        self.atime
+    }
+
+    pub fn mtime(&self) -> Option<Mtime> {
+        self.mtime
+    }
+
+    pub fn ctime(&self) -> Option<Ctime> {
+        self.ctime
+    }
+
+    pub fn xattrs(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.xattrs
+    }
+
+    pub fn contents(&self) -> Option<&FileContents> {
+        match &self.kind {
+            InodeKind::File(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn entries(&self) -> Option<&BTreeMap<OsString, InodeId>> {
+        match &self.kind {
+            InodeKind::Directory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+const ROOT: InodeId = InodeId(0);
+
+/// The logical state of a subvolume after replaying some sequence of
+/// [Command]s.
+///
+/// Compressed and encrypted [crate::EncodedWrite]s can not be decoded, so
+/// the extents they write become holes of the right length in files that are
+/// marked [FileContents::undecodable].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filesystem {
+    subvol: Option<SubvolumeInfo>,
+    inodes: BTreeMap<InodeId, Inode>,
+    next_id: u64,
+}
+
+impl std::ops::Index<InodeId> for Filesystem {
+    type Output = Inode;
+
+    /// Panics if the inode does not exist in this filesystem
+    fn index(&self, id: InodeId) -> &Inode {
+        &self.inodes[&id]
+    }
+}
+
+impl Default for Filesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filesystem {
+    /// Empty filesystem containing only the root directory
+    pub fn new() -> Self {
+        let mut root = Inode::new(None, InodeKind::Directory(BTreeMap::new()));
+        root.nlink = 1;
+        Self {
+            subvol: None,
+            inodes: BTreeMap::from([(ROOT, root)]),
+            next_id: ROOT.0 + 1,
+        }
+    }
+
+    /// Replay a full (non-incremental) sendstream
+    pub fn from_sendstream(sendstream: &Sendstream) -> Result<Self> {
+        match sendstream.commands.first() {
+            Some(Command::Subvol(_)) => (),
+            Some(Command::Snapshot(_)) => return Err(Error::Incremental),
+            _ => return Err(Error::MissingHeader),
+        }
+        let mut fs = Self::new();
+        for cmd in &sendstream.commands {
+            fs.apply(cmd)?;
+        }
+        Ok(fs)
+    }
+
+    /// Replay an incremental sendstream on top of its parent
+    pub fn from_incremental(parent: &Filesystem, sendstream: &Sendstream) -> Result<Self> {
+        let (mut fs, sources) = Self::replay_start(sendstream, Some(parent))?;
+        for cmd in &sendstream.commands {
+            fs.apply_with_sources(cmd, &sources)?;
+        }
+        Ok(fs)
+    }
+
+    /// State to start replaying `sendstream` from, and the subvolumes it may
+    /// clone from: empty for a full sendstream, or a copy of `parent` for an
+    /// incremental (in which case `parent` is required).
+    pub(crate) fn replay_start<'p>(
+        sendstream: &Sendstream,
+        parent: Option<&'p Filesystem>,
+    ) -> Result<(Self, Vec<&'p Filesystem>)> {
+        match (sendstream.commands.first(), parent) {
+            (Some(Command::Subvol(_)), _) => Ok((Self::new(), Vec::new())),
+            (Some(Command::Snapshot(s)), Some(parent)) => {
+                let uuid = parent.subvol.as_ref().map(|s| s.uuid);
+                if uuid.is_some_and(|u| u != s.clone_uuid) {
+                    return Err(Error::WrongParent {
+                        expected: s.clone_uuid,
+                        actual: uuid,
+                    });
+                }
+                Ok((parent.clone(), vec![parent]))
+            }
+            (Some(Command::Snapshot(_)), None) => Err(Error::Incremental),
+            _ => Err(Error::MissingHeader),
+        }
+    }
+
+    /// Replay a chain of sendstreams: a full sendstream followed by any
+    /// number of incrementals, each of which is received on top of whichever
+    /// earlier subvolume in the chain is its parent. Every earlier subvolume
+    /// is available as a clone source. Returns the state of every subvolume in
+    /// the chain, in the same order.
+    pub fn from_chain<'s, I>(chain: I) -> Result<Vec<Self>>
+    where
+        I: IntoIterator<Item = &'s Sendstream<'s>>,
+    {
+        let mut out: Vec<Self> = Vec::new();
+        for sendstream in chain {
+            let fs = match sendstream.commands.first() {
+                Some(Command::Snapshot(s)) => {
+                    let parent = out
+                        .iter()
+                        .rev()
+                        .find(|fs| fs.subvol.as_ref().map(|s| s.uuid) == Some(s.clone_uuid))
+                        .ok_or_else(|| Error::WrongParent {
+                            expected: s.clone_uuid,
+                            actual: out.last().and_then(|fs| fs.subvol.as_ref()).map(|s| s.uuid),
+                        })?;
+                    let sources: Vec<_> = out.iter().collect();
+                    let mut fs = parent.clone();
+                    for cmd in &sendstream.commands {
+                        fs.apply_with_sources(cmd, &sources)?;
+                    }
+                    fs
+                }
+                _ => Self::from_sendstream(sendstream)?,
+            };
+            out.push(fs);
+        }
+        Ok(out)
+    }
+
+    pub fn subvolume(&self) -> Option<&SubvolumeInfo> {
+        self.subvol.as_ref()
+    }
+
+    pub fn root(&self) -> InodeId {
+        ROOT
+    }
+
+    pub fn inode(&self, id: InodeId) -> Option<&Inode> {
+        self.inodes.get(&id)
+    }
+
+    /// Find the inode at `path` (relative to the root of the subvolume)
+    pub fn lookup(&self, path: &Path) -> Option<InodeId> {
+        self.resolve(path).ok()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Inode> {
+        self.lookup(path).and_then(|id| self.inode(id))
+    }
+
+    /// Every path in the filesystem (excluding the root) in depth-first order.
+    /// Directory entries are visited in sorted order, so parents always come
+    /// before their children. Hard links will show up as more than one path
+    /// with the same [InodeId].
+    pub fn walk(&self) -> Vec<(PathBuf, InodeId)> {
+        let mut out = Vec::new();
+        let mut stack = vec![(PathBuf::new(), ROOT)];
+        while let Some((path, id)) = stack.pop() {
+            if let Some(entries) = self.inodes.get(&id).and_then(Inode::entries) {
+                for (name, child) in entries.iter().rev() {
+                    stack.push((path.join(name), *child));
+                }
+            }
+            if id != ROOT {
+                out.push((path, id));
+            }
+        }
+        out
+    }
+
+    /// Apply a single command. [crate::Clone] commands may only refer to this
+    /// same subvolume, see [Filesystem::apply_with_sources] for cloning from
+    /// other subvolumes.
+    pub fn apply(&mut self, cmd: &Command) -> Result<()> {
+        self.apply_with_sources(cmd, &[])
+    }
+
+    /// Apply a single command, resolving [crate::Clone] sources from other
+    /// subvolumes in `sources` by their uuid.
+    pub fn apply_with_sources(&mut self, cmd: &Command, sources: &[&Filesystem]) -> Result<()> {
+        match cmd {
+            Command::Subvol(_) | Command::Snapshot(_) => {
+                self.subvol = SubvolumeInfo::from_command(cmd);
+            }
+            Command::Mkdir(m) => {
+                self.create(
+                    &m.path,
+                    Inode::new(Some(m.ino), InodeKind::Directory(BTreeMap::new())),
+                )?;
+            }
+            Command::Mkfile(m) => {
+                self.create(
+                    &m.path,
+                    Inode::new(Some(m.ino), InodeKind::File(FileContents::default())),
+                )?;
+            }
+            Command::Mkfifo(m) => self.mkspecial(m, InodeKind::Fifo)?,
+            Command::Mksock(m) => self.mkspecial(m, InodeKind::Socket)?,
+            Command::Mknod(m) => {
+                let kind = match m.mode.0 & nix::libc::S_IFMT {
+                    nix::libc::S_IFBLK => InodeKind::BlockDevice(m.rdev),
+                    _ => InodeKind::CharDevice(m.rdev),
+                };
+                self.mkspecial(m, kind)?;
+            }
+            Command::Symlink(s) => {
+                self.create(
+                    &s.link_name,
+                    Inode::new(Some(s.ino), InodeKind::Symlink(s.target.to_path_buf())),
+                )?;
+            }
+            Command::Rename(r) => self.rename(&r.from, &r.to)?,
+            Command::Link(l) => {
+                let target = self.resolve(&l.target)?;
+                if self.inodes[&target].entries().is_some() {
+                    return Err(Error::IsADirectory(l.target.to_path_buf()));
+                }
+                self.link(&l.link_name, target)?;
+            }
+            Command::Unlink(u) => {
+                let (dir, name) = self.entry(&u.path)?;
+                if self.inodes[&self.child(dir, &name, &u.path)?]
+                    .entries()
+                    .is_some()
+                {
+                    return Err(Error::IsADirectory(u.path.to_path_buf()));
+                }
+                self.remove_entry(dir, &name);
+            }
+            Command::Rmdir(r) => {
+                let (dir, name) = self.entry(&r.path)?;
+                match self.inodes[&self.child(dir, &name, &r.path)?].entries() {
+                    Some(e) if e.is_empty() => (),
+                    Some(_) => return Err(Error::NotEmpty(r.path.to_path_buf())),
+                    None => return Err(Error::NotADirectory(r.path.to_path_buf())),
+                }
+                self.remove_entry(dir, &name);
+            }
+            Command::SetXattr(x) => {
+                self.inode_mut(&x.path)?
+                    .xattrs
+                    .insert(x.name.to_vec(), x.data.to_vec());
+            }
+            Command::RemoveXattr(x) => {
+                if self
+                    .inode_mut(&x.path)?
+                    .xattrs
+                    .remove(x.name.as_slice())
+                    .is_none()
+                {
+                    return Err(Error::MissingXattr {
+                        path: x.path.to_path_buf(),
+                        name: String::from_utf8_lossy(&x.name).into_owned(),
+                    });
+                }
+            }
+            Command::Write(w) => self.contents_mut(&w.path)?.write(w.offset.0, &w.data),
+            Command::EncodedWrite(w) => {
+                let contents = self.contents_mut(&w.path)?;
+                match w.decoded() {
+                    Some(data) => contents.write(w.offset.0, data),
+                    None => contents.write_undecodable(w.offset.0, w.unencoded_file_len),
+                }
+            }
+            Command::Fallocate(f) => self
+                .contents_mut(&f.path)?
+                .fallocate(f.mode, f.offset.0, f.len),
+            Command::Fileattr(f) => {
+                // inode flags are not modeled, but the file must exist
+                self.inode_mut(&f.path)?;
+            }
+            Command::Truncate(t) => self.contents_mut(&t.path)?.truncate(t.size),
+            Command::UpdateExtent(u) => {
+                // there is no data to update, but the file must exist
+                self.contents_mut(&u.path)?;
+            }
+            Command::Clone(c) => {
+                let src = match self.clone_source(c.uuid, sources)? {
+                    Some(fs) => fs.contents(&c.src_path)?.clone(),
+                    None => self.contents(&c.src_path)?.clone(),
+                };
+                self.contents_mut(&c.dst_path)?.clone_range(
+                    &src,
+                    c.src_offset.0,
+                    c.len.0,
+                    c.dst_offset.0,
+                );
+            }
+            Command::Chmod(c) => self.inode_mut(&c.path)?.mode = Some(Mode(c.mode.0 & 0o7777)),
+            Command::Chown(c) => {
+                let inode = self.inode_mut(&c.path)?;
+                inode.uid = Some(c.uid);
+                inode.gid = Some(c.gid);
+            }
+            Command::Utimes(u) => {
+                let inode = self.inode_mut(&u.path)?;
+                inode.atime = Some(u.atime);
+                inode.mtime = Some(u.mtime);
+                inode.ctime = Some(u.ctime);
+            }
+            Command::End => (),
+        }
+        Ok(())
+    }
+
+    /// Find the subvolume that a clone refers to, or None if it is this one
+    fn clone_source<'s>(
+        &self,
+        uuid: Uuid,
+        sources: &[&'s Filesystem],
+    ) -> Result<Option<&'s Filesystem>> {
+        let own_uuid = self.subvol.as_ref().map(|s| s.uuid);
+        if own_uuid.is_none() || own_uuid == Some(uuid) {
+            return Ok(None);
+        }
+        sources
+            .iter()
+            .find(|s| s.subvol.as_ref().map(|s| s.uuid) == Some(uuid))
+            .copied()
+            .map(Some)
+            .ok_or(Error::MissingCloneSource(uuid))
+    }
+
+    fn mkspecial(&mut self, m: &crate::Mkspecial, kind: InodeKind) -> Result<()> {
+        let mut inode = Inode::new(Some(m.ino), kind);
+        inode.mode = Some(Mode(m.mode.0 & 0o7777));
+        self.create(&m.path, inode)?;
+        Ok(())
+    }
+
+    fn resolve(&self, path: &Path) -> Result<InodeId> {
+        let mut cur = ROOT;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    cur = self
+                        .inodes
+                        .get(&cur)
+                        .and_then(Inode::entries)
+                        .ok_or_else(|| Error::NotADirectory(path.to_path_buf()))?
+                        .get(name)
+                        .copied()
+                        .ok_or_else(|| Error::NotFound(path.to_path_buf()))?;
+                }
+                Component::CurDir => (),
+                _ => return Err(Error::InvalidPath(path.to_path_buf())),
+            }
+        }
+        Ok(cur)
+    }
+
+    /// Split `path` into its (existing) parent directory and file name
+    fn entry(&self, path: &Path) -> Result<(InodeId, OsString)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::InvalidPath(path.to_path_buf()))?;
+        let parent = self.resolve(path.parent().unwrap_or_else(|| Path::new("")))?;
+        if self.inodes[&parent].entries().is_none() {
+            return Err(Error::NotADirectory(path.to_path_buf()));
+        }
+        Ok((parent, name.to_owned()))
+    }
+
+    fn child(&self, dir: InodeId, name: &OsStr, path: &Path) -> Result<InodeId> {
+        self.inodes[&dir]
+            .entries()
+            .and_then(|e| e.get(name))
+            .copied()
+            .ok_or_else(|| Error::NotFound(path.to_path_buf()))
+    }
+
+    fn entries_mut(&mut self, dir: InodeId) -> &mut BTreeMap<OsString, InodeId> {
+        match self.inodes.get_mut(&dir).map(|i| &mut i.kind) {
+            Some(InodeKind::Directory(e)) => e,
+            _ => unreachable!("{dir:?} was already checked to be a directory"),
+        }
+    }
+
+    fn inode_mut(&mut self, path: &Path) -> Result<&mut Inode> {
+        let id = self.resolve(path)?;
+        self.inodes
+            .get_mut(&id)
+            .ok_or_else(|| Error::NotFound(path.to_path_buf()))
+    }
+
+    fn contents(&self, path: &Path) -> Result<&FileContents> {
+        self.get(path)
+            .and_then(Inode::contents)
+            .ok_or_else(|| Error::NotAFile(path.to_path_buf()))
+    }
+
+    pub(crate) fn contents_of_mut(&mut self, id: InodeId) -> Option<&mut FileContents> {
+        match &mut self.inodes.get_mut(&id)?.kind {
+            InodeKind::File(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn contents_mut(&mut self, path: &Path) -> Result<&mut FileContents> {
+        match &mut self.inode_mut(path)?.kind {
+            InodeKind::File(c) => Ok(c),
+            _ => Err(Error::NotAFile(path.to_path_buf())),
+        }
+    }
+
+    fn create(&mut self, path: &Path, inode: Inode) -> Result<InodeId> {
+        let (dir, name) = self.entry(path)?;
+        if self.child(dir, &name, path).is_ok() {
+            return Err(Error::Exists(path.to_path_buf()));
+        }
+        let id = InodeId(self.next_id);
+        self.next_id += 1;
+        self.inodes.insert(id, inode);
+        self.link(path, id)?;
+        Ok(id)
+    }
+
+    fn link(&mut self, path: &Path, id: InodeId) -> Result<()> {
+        let (dir, name) = self.entry(path)?;
+        if self.child(dir, &name, path).is_ok() {
+            return Err(Error::Exists(path.to_path_buf()));
+        }
+        self.entries_mut(dir).insert(name, id);
+        if let Some(inode) = self.inodes.get_mut(&id) {
+            inode.nlink += 1;
+        }
+        Ok(())
+    }
+
+    /// Remove a directory entry, dropping the inode (and anything below it)
+    /// when its last link is removed.
+    fn remove_entry(&mut self, dir: InodeId, name: &OsStr) {
+        let Some(id) = self.entries_mut(dir).remove(name) else {
+            return;
+        };
+        let mut orphans = Vec::new();
+        if let Some(inode) = self.inodes.get_mut(&id) {
+            inode.nlink -= 1;
+            if inode.nlink == 0 {
+                orphans.push(id);
+            }
+        }
+        while let Some(id) = orphans.pop() {
+            if let Some(inode) = self.inodes.remove(&id) {
+                if let InodeKind::Directory(entries) = inode.kind {
+                    for child in entries.into_values() {
+                        if let Some(c) = self.inodes.get_mut(&child) {
+                            c.nlink -= 1;
+                            if c.nlink == 0 {
+                                orphans.push(child);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let (from_dir, from_name) = self.entry(from)?;
+        let id = self.child(from_dir, &from_name, from)?;
+        let is_dir = self.inodes[&id].entries().is_some();
+        if is_dir && to.starts_with(from) && to != from {
+            return Err(Error::InvalidPath(to.to_path_buf()));
+        }
+        let (to_dir, to_name) = self.entry(to)?;
+        if let Ok(existing) = self.child(to_dir, &to_name, to) {
+            if existing == id {
+                return Ok(());
+            }
+            match (is_dir, self.inodes[&existing].entries()) {
+                (true, Some(e)) if !e.is_empty() => {
+                    return Err(Error::NotEmpty(to.to_path_buf()));
+                }
+                (true, None) => return Err(Error::NotADirectory(to.to_path_buf())),
+                (false, Some(_)) => return Err(Error::IsADirectory(to.to_path_buf())),
+                _ => (),
+            }
+            self.remove_entry(to_dir, &to_name);
+        }
+        self.entries_mut(from_dir).remove(&from_name);
+        self.entries_mut(to_dir).insert(to_name, id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let msg = demo.get(Path::new("hello/msg")).expect("hello/msg missing");
+        assert_eq!(2, msg.nlink());
+        assert_eq!(
+            Some(&b"Hello world!\n"[..]),
+            msg.contents().map(|c| c.to_vec()).as_deref()
+        );
+        assert_eq!(
+            demo.get(Path::new("hello/lorem")).and_then(Inode::contents),
+            demo.get(Path::new("hello/lorem-reflinked"))
+                .and_then(Inode::contents),
+        );
+        assert_eq!(
+            Some(107374182400),
+            demo.get(Path::new("huge-empty-file"))
+                .and_then(Inode::contents)
+                .map(FileContents::len)
+        );
+
+        let undo = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        assert!(undo.get(Path::new("to-be-deleted")).is_none());
+        assert!(undo.get(Path::new("dir-to-be-deleted")).is_none());
+        let msg = undo.get(Path::new("hello/msg")).expect("hello/msg missing");
+        assert!(msg.xattrs().is_empty());
+        assert_eq!(
+            Some(&b"Goodbye!\n"[..]),
+            msg.contents().map(|c| c.to_vec()).as_deref()
+        );
+    }
+
+    #[test]
+    fn replay_chain() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let chain = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let undo = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        assert_eq!(vec![demo, undo], chain);
+
+        assert!(matches!(
+            Filesystem::from_chain(sendstreams.iter().rev()),
+            Err(Error::WrongParent { actual: None, .. })
+        ));
+    }
+
+    #[test]
+    fn replay_compressed() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let mut stream = sendstreams[0].clone();
+        let end = stream.commands.len() - 1;
+        stream.commands.insert(
+            end,
+            crate::EncodedWrite {
+                path: std::borrow::Cow::Borrowed(Path::new("hello/msg")),
+                offset: crate::FileOffset(4),
+                unencoded_file_len: 4096,
+                unencoded_len: 8192,
+                unencoded_offset: 0,
+                compression: crate::Compression::Zstd,
+                encryption: 0,
+                data: crate::Data(std::borrow::Cow::Borrowed(b"compressed")),
+            }
+            .into(),
+        );
+        let fs = Filesystem::from_sendstream(&stream).expect("failed to replay");
+        let msg = fs
+            .get(Path::new("hello/msg"))
+            .and_then(Inode::contents)
+            .expect("hello/msg missing");
+        assert!(msg.undecodable());
+        assert_eq!(4100, msg.len());
+        assert_eq!(b"Hell\0\0".as_slice(), msg.read(0, 6));
+    }
+}
@@ -0,0 +1,525 @@
+//! Generate sendstreams from directory trees on any filesystem, so that they
+//! can be "sent" without btrfs: full ones with [from_directory], or
+//! incremental ones against another tree with [from_directories], against an
+//! earlier read of a tree with [incremental_from_directory], or against a
+//! stored [Manifest] with [incremental_from_manifest].
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use nix::libc;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use sha2::Sha256;
+
+use crate::compare::data_ranges;
+use crate::compare::disk_inode;
+use crate::compare::walk_dir;
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::fs::InodeKind;
+use crate::hash::hash_contents;
+use crate::incremental::diff_renaming;
+use crate::incremental::emit_full;
+use crate::incremental::MAX_WRITE_LEN;
+use crate::manifest::FileType;
+use crate::manifest::Manifest;
+use crate::Command;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to read {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn owned(path: &Path) -> Cow<'static, Path> {
+    Cow::Owned(path.to_path_buf())
+}
+
+fn ctime(meta: &std::fs::Metadata) -> SystemTime {
+    let since = Duration::new(meta.ctime().unsigned_abs(), meta.ctime_nsec() as u32);
+    match meta.ctime() < 0 {
+        true => SystemTime::UNIX_EPOCH - since,
+        false => SystemTime::UNIX_EPOCH + since,
+    }
+}
+
+/// Model the directory tree at `dir` as the subvolume that `header` starts,
+/// with everything in it (including the data of every file) read into
+/// memory. Inode numbers are the ones on disk, so that reading the same tree
+/// again later lets [crate::incremental::diff] recognize the files that are
+/// still there.
+pub fn read_directory(dir: &Path, header: crate::Subvol<'static>) -> Result<Filesystem> {
+    let mut fs = Filesystem::new();
+    fs.apply(&header.into())?;
+    // hard links are the same inode on the same device
+    let mut linked: BTreeMap<(u64, u64), PathBuf> = BTreeMap::new();
+    let entries = walk_dir(dir).map_err(|error| Error::Io {
+        path: dir.to_path_buf(),
+        error,
+    })?;
+    for (path, meta) in entries {
+        let full = dir.join(&path);
+        let io = |error| Error::Io {
+            path: full.clone(),
+            error,
+        };
+        let inode = disk_inode(&full, &meta).map_err(io)?;
+        let ino = crate::Ino(meta.ino());
+        let tmp = || crate::TemporaryPath(owned(&path));
+        let mkspecial = |ty: u32| crate::Mkspecial {
+            path: tmp(),
+            ino,
+            rdev: crate::Rdev(meta.rdev()),
+            mode: crate::Mode(ty | (meta.mode() & 0o7777)),
+        };
+        let mut cmds: Vec<Command> = Vec::new();
+        if !path.as_os_str().is_empty() {
+            if !meta.is_dir() && meta.nlink() > 1 {
+                if let Some(target) = linked.get(&(meta.dev(), meta.ino())) {
+                    fs.apply(
+                        &crate::Link {
+                            link_name: owned(&path),
+                            target: crate::LinkTarget(owned(target)),
+                        }
+                        .into(),
+                    )?;
+                    continue;
+                }
+                linked.insert((meta.dev(), meta.ino()), path.clone());
+            }
+            cmds.push(match inode.kind() {
+                InodeKind::Directory(_) => crate::Mkdir { path: tmp(), ino }.into(),
+                InodeKind::File(_) => crate::Mkfile { path: tmp(), ino }.into(),
+                InodeKind::Symlink(target) => crate::Symlink {
+                    link_name: owned(&path),
+                    ino,
+                    target: crate::LinkTarget(owned(target)),
+                }
+                .into(),
+                InodeKind::Fifo => crate::Mkfifo(mkspecial(libc::S_IFIFO)).into(),
+                InodeKind::Socket => crate::Mksock(mkspecial(libc::S_IFSOCK)).into(),
+                InodeKind::CharDevice(_) => crate::Mknod(mkspecial(libc::S_IFCHR)).into(),
+                InodeKind::BlockDevice(_) => crate::Mknod(mkspecial(libc::S_IFBLK)).into(),
+            });
+        }
+        if meta.is_file() {
+            let file = File::open(&full).map_err(io)?;
+            // only what is not a hole is read, so sparse files stay sparse
+            for (start, end) in data_ranges(&file, meta.len()) {
+                let mut off = start;
+                while off < end {
+                    let mut data = vec![0; MAX_WRITE_LEN.min(end - off) as usize];
+                    file.read_exact_at(&mut data, off).map_err(io)?;
+                    cmds.push(
+                        crate::Write {
+                            path: owned(&path),
+                            offset: crate::FileOffset(off),
+                            data: crate::Data(Cow::Owned(data)),
+                        }
+                        .into(),
+                    );
+                    off += MAX_WRITE_LEN.min(end - off);
+                }
+            }
+            cmds.push(
+                crate::Truncate {
+                    path: owned(&path),
+                    size: meta.len(),
+                }
+                .into(),
+            );
+        }
+        for (name, value) in inode.xattrs() {
+            cmds.push(
+                crate::SetXattr {
+                    path: owned(&path),
+                    name: crate::XattrName(Cow::Owned(name.clone())),
+                    data: crate::XattrData(Cow::Owned(value.clone())),
+                }
+                .into(),
+            );
+        }
+        if let (Some(uid), Some(gid)) = (inode.uid(), inode.gid()) {
+            cmds.push(
+                crate::Chown {
+                    path: owned(&path),
+                    uid,
+                    gid,
+                }
+                .into(),
+            );
+        }
+        if let (Some(mode), false) = (inode.mode(), meta.is_symlink()) {
+            cmds.push(
+                crate::Chmod {
+                    path: owned(&path),
+                    mode,
+                }
+                .into(),
+            );
+        }
+        if let (Some(atime), Some(mtime)) = (inode.atime(), inode.mtime()) {
+            cmds.push(
+                crate::Utimes {
+                    path: owned(&path),
+                    atime,
+                    mtime,
+                    ctime: crate::Ctime(ctime(&meta)),
+                }
+                .into(),
+            );
+        }
+        for cmd in &cmds {
+            fs.apply(cmd)?;
+        }
+    }
+    Ok(fs)
+}
+
+/// Generate a full sendstream, starting with `header`, that creates the
+/// directory tree at `dir`: directories, files (keeping holes), symlinks,
+/// special files and hard links, with their xattrs, ownership, permissions
+/// and timestamps. Like `btrfs send`, every inode is created under a
+/// temporary name and then renamed into place.
+///
+/// Symlinks are not followed and mount points are crossed. Whatever changes
+/// in the tree while it is read may or may not be in the stream.
+pub fn from_directory(dir: &Path, header: crate::Subvol<'static>) -> Result<Sendstream<'static>> {
+    let fs = read_directory(dir, header.clone())?;
+    Ok(emit_full(&fs, header))
+}
+
+/// Generate an incremental sendstream that turns `parent` into the directory
+/// tree at `dir`, where `parent` is an earlier [read_directory] of the same
+/// tree or of another one (or anything else with a subvolume header, like
+/// what a stream produces). `header` is for the new subvolume, whose parent
+/// is `parent`'s.
+///
+/// Files that are still there are only written where their data changed.
+/// Inodes that moved (as identified by their inode numbers, so only for trees
+/// that share them, like an earlier read of the same tree or a snapshot that
+/// another filesystem took) are renamed, and anything else that went away is
+/// unlinked.
+pub fn incremental_from_directory(
+    parent: &Filesystem,
+    dir: &Path,
+    header: crate::Subvol<'static>,
+) -> Result<Sendstream<'static>> {
+    let child = read_directory(dir, header)?;
+    Ok(diff_renaming(parent, &child, &BTreeSet::new())?)
+}
+
+/// Generate an incremental sendstream that turns the directory tree at
+/// `parent` (with `parent_header`) into the one at `dir` (with `header`), see
+/// [incremental_from_directory].
+pub fn from_directories(
+    parent: &Path,
+    parent_header: crate::Subvol<'static>,
+    dir: &Path,
+    header: crate::Subvol<'static>,
+) -> Result<Sendstream<'static>> {
+    incremental_from_directory(&read_directory(parent, parent_header)?, dir, header)
+}
+
+/// Model of the subvolume that `manifest` lists, along with the files whose
+/// contents it does not say enough about. Those are the ones that do not
+/// have the same hash as the file at the same path in `child`, which the
+/// others get the contents of.
+fn manifest_parent(
+    manifest: &Manifest,
+    header: crate::Subvol<'static>,
+    child: &Filesystem,
+) -> Result<(Filesystem, BTreeSet<PathBuf>)> {
+    let mut fs = Filesystem::new();
+    fs.apply(&header.into())?;
+    let mut unknown = BTreeSet::new();
+    // inode numbers that nothing in the child has, so that nothing is
+    // mistaken for having moved
+    let mut ino = child
+        .walk()
+        .into_iter()
+        .filter_map(|(_, id)| child[id].ino())
+        .map(|ino| ino.0)
+        .max()
+        .unwrap_or(256);
+    for entry in &manifest.entries {
+        ino += 1;
+        let path = entry.path.as_path();
+        let tmp = || crate::TemporaryPath(owned(path));
+        let mkspecial = |ty: u32| crate::Mkspecial {
+            path: tmp(),
+            ino: crate::Ino(ino),
+            rdev: crate::Rdev(entry.rdev.unwrap_or(0)),
+            mode: crate::Mode(ty | entry.mode.unwrap_or(0)),
+        };
+        let mut cmds: Vec<Command> = vec![match entry.file_type {
+            FileType::Directory => crate::Mkdir {
+                path: tmp(),
+                ino: crate::Ino(ino),
+            }
+            .into(),
+            FileType::File => crate::Mkfile {
+                path: tmp(),
+                ino: crate::Ino(ino),
+            }
+            .into(),
+            FileType::Symlink => crate::Symlink {
+                link_name: owned(path),
+                ino: crate::Ino(ino),
+                target: crate::LinkTarget(owned(entry.target.as_deref().unwrap_or(Path::new("")))),
+            }
+            .into(),
+            FileType::Fifo => crate::Mkfifo(mkspecial(libc::S_IFIFO)).into(),
+            FileType::Socket => crate::Mksock(mkspecial(libc::S_IFSOCK)).into(),
+            FileType::CharDevice => crate::Mknod(mkspecial(libc::S_IFCHR)).into(),
+            FileType::BlockDevice => crate::Mknod(mkspecial(libc::S_IFBLK)).into(),
+        }];
+        if let FileType::File = entry.file_type {
+            let same = child.get(path).and_then(|i| i.contents()).filter(|c| {
+                entry.size == Some(c.len())
+                    && entry.sha256.as_deref()
+                        == Some(hex::encode(hash_contents::<Sha256>(c)).as_str())
+            });
+            match same {
+                Some(contents) => {
+                    for (offset, data) in contents.extents() {
+                        cmds.push(
+                            crate::Write {
+                                path: owned(path),
+                                offset: crate::FileOffset(offset),
+                                data: crate::Data(Cow::Owned(data.to_vec())),
+                            }
+                            .into(),
+                        );
+                    }
+                    cmds.push(
+                        crate::Truncate {
+                            path: owned(path),
+                            size: contents.len(),
+                        }
+                        .into(),
+                    );
+                }
+                None => {
+                    unknown.insert(path.to_path_buf());
+                }
+            }
+        }
+        for (name, value) in &entry.xattrs {
+            cmds.push(
+                crate::SetXattr {
+                    path: owned(path),
+                    name: crate::XattrName(Cow::Owned(name.as_bytes().to_vec())),
+                    data: crate::XattrData(Cow::Owned(value.clone())),
+                }
+                .into(),
+            );
+        }
+        if let (Some(uid), Some(gid)) = (entry.uid, entry.gid) {
+            cmds.push(
+                crate::Chown {
+                    path: owned(path),
+                    uid: Uid::from_raw(uid),
+                    gid: Gid::from_raw(gid),
+                }
+                .into(),
+            );
+        }
+        if let (Some(mode), false) = (entry.mode, entry.file_type == FileType::Symlink) {
+            cmds.push(
+                crate::Chmod {
+                    path: owned(path),
+                    mode: crate::Mode(mode),
+                }
+                .into(),
+            );
+        }
+        for cmd in &cmds {
+            fs.apply(cmd)?;
+        }
+    }
+    Ok((fs, unknown))
+}
+
+/// Generate an incremental sendstream that turns the subvolume that
+/// `manifest` lists (and that `parent_header` is for) into the directory tree
+/// at `dir`, for when all that was kept of the parent is its manifest.
+///
+/// The manifest has no data or inode numbers, so this is not as frugal as
+/// [incremental_from_directory]: files are kept as they are if their size
+/// and [sha256](crate::manifest::ManifestEntry::sha256) are unchanged, and
+/// are replaced (with all of their data) otherwise, including when the
+/// manifest has no hashes at all. Files that moved are removed and added.
+/// Timestamps are not in the manifest either, so they are set on everything.
+pub fn incremental_from_manifest(
+    manifest: &Manifest,
+    parent_header: crate::Subvol<'static>,
+    dir: &Path,
+    header: crate::Subvol<'static>,
+) -> Result<Sendstream<'static>> {
+    let child = read_directory(dir, header)?;
+    let (parent, unknown) = manifest_parent(manifest, parent_header, &child)?;
+    Ok(diff_renaming(&parent, &child, &unknown)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::diff;
+    use crate::compare::diff_directory;
+
+    #[test]
+    fn from_directory() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dir = std::env::temp_dir().join(format!("generate.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        crate::extract::extract_tree_to(&sendstreams[0], Path::new(""), &dir)
+            .expect("failed to extract");
+        let header = crate::Subvol::new(
+            Path::new("generated"),
+            uuid::Uuid::from_u128(1),
+            crate::Ctransid(3),
+        );
+        let stream = super::from_directory(&dir, header).expect("failed to generate");
+        let generated = Filesystem::from_sendstream(&stream).expect("failed to replay");
+        let on_disk = diff_directory(&generated, Path::new(""), &dir).expect("failed to compare");
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+        assert!(on_disk.is_empty(), "{on_disk:#?}");
+        let diff = diff(&sendstreams[0], &stream).expect("failed to replay");
+        assert!(diff.is_empty(), "{diff:#?}");
+        assert!(
+            matches!(&stream.commands()[0], Command::Subvol(s) if s.path() == Path::new("generated"))
+        );
+        // the 100G file is all hole, so none of it is written
+        assert!(!stream.commands().iter().any(|c| matches!(
+            c,
+            Command::Write(w) if w.path() == Path::new("huge-empty-file")
+        )));
+        assert!(stream.commands().iter().any(|c| matches!(
+            c,
+            Command::Rename(r) if r.to() == Path::new("hello/msg")
+                && r.from().to_string_lossy().ends_with("-3-0")
+        )));
+    }
+
+    /// Extract the demo into a new directory named `name`, and read it in
+    fn demo_dir(name: &str) -> (PathBuf, Filesystem) {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dir = std::env::temp_dir().join(format!("{name}.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).expect("failed to create dir");
+        crate::extract::extract_tree_to(&sendstreams[0], Path::new(""), &dir)
+            .expect("failed to extract");
+        // the manifest would have to hash all 100G of it
+        std::fs::remove_file(dir.join("huge-empty-file")).expect("failed to remove");
+        let header = crate::Subvol::new(
+            Path::new("parent"),
+            uuid::Uuid::from_u128(1),
+            crate::Ctransid(1),
+        );
+        let parent = read_directory(&dir, header).expect("failed to read");
+        std::fs::rename(dir.join("hello/lorem"), dir.join("lorem-moved")).expect("failed to move");
+        std::fs::rename(dir.join("dir-to-be-deleted"), dir.join("dir-moved"))
+            .expect("failed to move");
+        std::fs::remove_file(dir.join("to-be-deleted")).expect("failed to remove");
+        std::fs::write(dir.join("hello/lorem-reflinked"), b"changed").expect("failed to write");
+        std::fs::create_dir(dir.join("new-dir")).expect("failed to create dir");
+        (dir, parent)
+    }
+
+    fn child_header() -> crate::Subvol<'static> {
+        crate::Subvol::new(
+            Path::new("child"),
+            uuid::Uuid::from_u128(2),
+            crate::Ctransid(2),
+        )
+    }
+
+    #[test]
+    fn incremental_from_directory() {
+        let (dir, parent) = demo_dir("generate_incremental");
+        let stream = super::incremental_from_directory(&parent, &dir, child_header())
+            .expect("failed to generate");
+        let child = Filesystem::from_incremental(&parent, &stream).expect("failed to replay");
+        let diff = diff_directory(&child, Path::new(""), &dir).expect("failed to compare");
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+        assert!(diff.is_empty(), "{diff:#?}");
+        let renamed: BTreeSet<_> = stream
+            .commands()
+            .iter()
+            .filter_map(|c| match c {
+                Command::Rename(r) => Some(r.to()),
+                _ => None,
+            })
+            .filter(|p| !p.to_string_lossy().starts_with('o'))
+            .collect();
+        assert_eq!(
+            BTreeSet::from([Path::new("dir-moved"), Path::new("lorem-moved")]),
+            renamed
+        );
+        let written: BTreeSet<_> = stream
+            .commands()
+            .iter()
+            .filter_map(|c| match c {
+                Command::Write(w) => Some(w.path()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            BTreeSet::from([Path::new("hello/lorem-reflinked")]),
+            written
+        );
+        assert!(stream.commands().iter().any(|c| matches!(
+            c,
+            Command::Unlink(u) if u.path() == Path::new("to-be-deleted")
+        )));
+    }
+
+    #[test]
+    fn incremental_from_manifest() {
+        let (dir, parent) = demo_dir("generate_manifest");
+        let manifest = Manifest::from_filesystem(&parent, true);
+        let parent_header = crate::Subvol::new(
+            Path::new("parent"),
+            uuid::Uuid::from_u128(1),
+            crate::Ctransid(1),
+        );
+        let stream =
+            super::incremental_from_manifest(&manifest, parent_header, &dir, child_header())
+                .expect("failed to generate");
+        let child = Filesystem::from_incremental(&parent, &stream).expect("failed to replay");
+        let diff = diff_directory(&child, Path::new(""), &dir).expect("failed to compare");
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+        assert!(diff.is_empty(), "{diff:#?}");
+        // unchanged files are left alone, even without their data
+        assert!(!stream.commands().iter().any(|c| match c {
+            Command::Write(w) => w.path() == Path::new("hello/msg"),
+            Command::Unlink(u) => u.path() == Path::new("hello/msg"),
+            _ => false,
+        }));
+        assert!(stream.commands().iter().any(|c| matches!(
+            c,
+            Command::Write(w) if w.path() == Path::new("hello/lorem-reflinked")
+        )));
+    }
+}
@@ -0,0 +1,229 @@
+//! Search the file data in a sendstream, for example to find out which files
+//! in an archive of old sendstreams ever contained some leaked secret.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// Data held in memory at once for each file when the [Pattern] has a
+/// [Pattern::max_len]
+const CHUNK: usize = 1024 * 1024;
+
+/// Something to search for with [Sendstream::grep]. This is implemented for
+/// byte strings, and can be implemented for a regex engine as well.
+pub trait Pattern {
+    /// Range of every match in `haystack`, in order
+    fn find_all(&self, haystack: &[u8]) -> Vec<Range<usize>>;
+
+    /// Longest match that this pattern can have, so that only that much data
+    /// has to be kept around to find matches that span multiple writes. If
+    /// this is `None`, each run of contiguous writes is searched as a whole.
+    fn max_len(&self) -> Option<usize>;
+}
+
+impl Pattern for [u8] {
+    fn find_all(&self, haystack: &[u8]) -> Vec<Range<usize>> {
+        memchr::memmem::find_iter(haystack, self)
+            .map(|start| start..start + self.len())
+            .collect()
+    }
+
+    fn max_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl Pattern for str {
+    fn find_all(&self, haystack: &[u8]) -> Vec<Range<usize>> {
+        self.as_bytes().find_all(haystack)
+    }
+
+    fn max_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// A match found by [Sendstream::grep]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Hit {
+    /// Final path of the file (see [Sendstream::final_paths]), or `None` if
+    /// it does not survive to the end of the stream
+    pub path: Option<PathBuf>,
+    /// Where the match starts in the file
+    pub offset: u64,
+    pub len: u64,
+    /// Index of the write that the match starts in
+    pub index: usize,
+}
+
+/// Data of contiguous writes to one file that has not been searched yet
+struct Run {
+    /// File offset of the start of `data`
+    offset: u64,
+    data: Vec<u8>,
+    /// Indices of the writes that make up `data`, with where they start in it
+    writes: Vec<(usize, usize)>,
+    path: Option<PathBuf>,
+}
+
+impl Run {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    /// Report every match, except for ones that start in the last `keep`
+    /// bytes, which are kept so they can be searched again along with
+    /// whatever comes next
+    fn search<P: Pattern + ?Sized>(&mut self, pattern: &P, keep: usize, hits: &mut Vec<Hit>) {
+        let cut = self.data.len().saturating_sub(keep);
+        for m in pattern.find_all(&self.data) {
+            if keep > 0 && m.start >= cut {
+                break;
+            }
+            let write = self
+                .writes
+                .partition_point(|(_, start)| *start <= m.start)
+                .saturating_sub(1);
+            hits.push(Hit {
+                path: self.path.clone(),
+                offset: self.offset + m.start as u64,
+                len: m.len() as u64,
+                index: self.writes[write].0,
+            });
+        }
+        if keep > 0 {
+            self.data.drain(..cut);
+            self.offset += cut as u64;
+            let first = self
+                .writes
+                .partition_point(|(_, start)| *start <= cut)
+                .saturating_sub(1);
+            self.writes.drain(..first);
+            for (_, start) in &mut self.writes {
+                *start = start.saturating_sub(cut);
+            }
+        }
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Search the data of every [crate::Write] (and every
+    /// [crate::EncodedWrite] that is not compressed) for `pattern`, in
+    /// stream order. Writes that continue exactly where the previous write
+    /// to the same file left off are searched together, so matches that
+    /// span them are found too. Data is searched as it is sent, even if it
+    /// is overwritten or truncated away later on.
+    pub fn grep<P: Pattern + ?Sized>(&self, pattern: &P) -> Vec<Hit> {
+        let finals = self.final_paths();
+        let keep = pattern.max_len().map(|l| l.saturating_sub(1));
+        let mut runs: BTreeMap<usize, Run> = BTreeMap::new();
+        let mut hits = Vec::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let (offset, data) = match cmd {
+                Command::Write(w) => (w.offset.as_u64(), &w.data[..]),
+                Command::EncodedWrite(w) => match w.decoded() {
+                    Some(data) => (w.offset.as_u64(), data),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let Some(inode) = finals.inode(index) else {
+                continue;
+            };
+            let run = match runs.remove(&inode) {
+                Some(run) if run.end() == offset => Some(run),
+                Some(mut run) => {
+                    run.search(pattern, 0, &mut hits);
+                    None
+                }
+                None => None,
+            };
+            let mut run = run.unwrap_or_else(|| Run {
+                offset,
+                data: Vec::new(),
+                writes: Vec::new(),
+                path: finals.get(index).map(|p| p.to_path_buf()),
+            });
+            run.writes.push((index, run.data.len()));
+            run.data.extend_from_slice(data);
+            if let Some(keep) = keep.filter(|_| run.data.len() >= CHUNK) {
+                run.search(pattern, keep, &mut hits);
+            }
+            runs.insert(inode, run);
+        }
+        for run in runs.values_mut() {
+            run.search(pattern, 0, &mut hits);
+        }
+        hits.sort_by_key(|h| (h.index, h.offset));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+    use crate::Data;
+    use crate::FileOffset;
+
+    #[test]
+    fn grep_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let s = &sendstreams[0];
+        let hits = s.grep("Hello world");
+        let msg = s
+            .commands()
+            .iter()
+            .position(|c| matches!(c, Command::Write(w) if w.path().ends_with("msg")))
+            .expect("demo writes msg");
+        assert!(hits.contains(&Hit {
+            path: Some(PathBuf::from("hello/msg")),
+            offset: 0,
+            len: 11,
+            index: msg,
+        }));
+        assert_eq!(Vec::<Hit>::new(), s.grep(&b"not in the demo"[..]));
+
+        // a match that is split across two writes
+        let mut commands = s.commands().to_vec();
+        let end = commands.pop().expect("demo ends");
+        let write = |offset: u64, data: Vec<u8>| {
+            Command::from(crate::Write {
+                path: Cow::Borrowed(Path::new("hello/msg")),
+                offset: FileOffset(offset),
+                data: Data(Cow::Owned(data)),
+            })
+        };
+        let first = commands.len();
+        // the second one is searched in pieces, since it is so large
+        let mut large = vec![0; CHUNK - 3];
+        large.extend_from_slice(b"sec");
+        commands.extend([
+            write(100, b"top se".to_vec()),
+            write(106, b"cret".to_vec()),
+            write(1000, large),
+            write(1000 + CHUNK as u64, b"ret".to_vec()),
+            end,
+        ]);
+        let hit = |offset, index| Hit {
+            path: Some(PathBuf::from("hello/msg")),
+            offset,
+            len: 6,
+            index,
+        };
+        assert_eq!(
+            vec![hit(104, first), hit(997 + CHUNK as u64, first + 2)],
+            Sendstream { commands }.grep("secret")
+        );
+    }
+}
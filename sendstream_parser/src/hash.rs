@@ -0,0 +1,338 @@
+//! Hash the contents of every file that a sendstream produces.
+
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use sha2::digest::Output;
+use sha2::Digest;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::Command;
+use crate::Sendstream;
+
+/// How much file data is hashed at once
+const HASH_CHUNK: u64 = 1 << 20;
+/// Blocks of this size that are entirely zero are left out of [hash_sparse]
+const SPARSE_BLOCK: u64 = 4096;
+
+/// Hash the full contents of a file (holes included) with `D`
+pub(crate) fn hash_contents<D: Digest>(contents: &FileContents) -> Output<D> {
+    let mut h = D::new();
+    let mut off = 0;
+    while off < contents.len() {
+        let chunk = HASH_CHUNK.min(contents.len() - off);
+        h.update(contents.read(off, chunk));
+        off += chunk;
+    }
+    h.finalize()
+}
+
+/// Hash the logical contents of a file without reading its holes. Every
+/// block that is not all zeroes is hashed along with its position, so the
+/// result only depends on the bytes of the file and not on which parts of it
+/// are holes.
+pub(crate) fn hash_sparse<D: Digest>(contents: &FileContents) -> Output<D> {
+    let ranges = contents
+        .extents()
+        .map(|(offset, data)| (offset, offset + data.len() as u64));
+    let read = |off, len| Ok::<_, std::convert::Infallible>(contents.read(off, len));
+    match hash_sparse_by::<D, _>(contents.len(), ranges, read) {
+        Ok(h) => h,
+        Err(e) => match e {},
+    }
+}
+
+/// [hash_sparse] of a file of `len` bytes that only has data in `ranges`
+/// (sorted start and end offsets), reading it with `read(offset, len)`
+pub(crate) fn hash_sparse_by<D: Digest, E>(
+    len: u64,
+    ranges: impl IntoIterator<Item = (u64, u64)>,
+    mut read: impl FnMut(u64, u64) -> std::result::Result<Vec<u8>, E>,
+) -> std::result::Result<Output<D>, E> {
+    let mut h = D::new();
+    let mut next = 0;
+    for (start, end) in ranges {
+        let end = end.min(len);
+        let mut block = (start / SPARSE_BLOCK).max(next);
+        while block * SPARSE_BLOCK < end {
+            let start = block * SPARSE_BLOCK;
+            let data = read(start, SPARSE_BLOCK.min(len - start))?;
+            if data.iter().any(|b| *b != 0) {
+                h.update(block.to_le_bytes());
+                h.update(&data);
+            }
+            block += 1;
+        }
+        next = block;
+    }
+    h.update(len.to_le_bytes());
+    Ok(h.finalize())
+}
+
+/// Length-prefixed, so that records can not run into each other
+fn update_bytes(h: &mut impl Digest, bytes: &[u8]) {
+    h.update((bytes.len() as u64).to_le_bytes());
+    h.update(bytes);
+}
+
+fn update_opt(h: &mut impl Digest, v: Option<u64>) {
+    match v {
+        Some(v) => {
+            h.update([1]);
+            h.update(v.to_le_bytes());
+        }
+        None => h.update([0]),
+    }
+}
+
+fn digest_inode<D: Digest>(
+    fs: &Filesystem,
+    id: InodeId,
+    cache: &mut BTreeMap<InodeId, Output<D>>,
+) -> Output<D> {
+    if let Some(d) = cache.get(&id) {
+        return d.clone();
+    }
+    let inode = &fs[id];
+    let mut h = D::new();
+    match inode.kind() {
+        InodeKind::Directory(entries) => {
+            h.update(b"d");
+            h.update((entries.len() as u64).to_le_bytes());
+            for (name, child) in entries {
+                update_bytes(&mut h, name.as_bytes());
+                h.update(digest_inode::<D>(fs, *child, cache));
+            }
+        }
+        InodeKind::File(c) => {
+            h.update(b"f");
+            h.update(hash_sparse::<D>(c));
+        }
+        InodeKind::Symlink(target) => {
+            h.update(b"l");
+            update_bytes(&mut h, target.as_os_str().as_bytes());
+        }
+        InodeKind::Fifo => h.update(b"p"),
+        InodeKind::Socket => h.update(b"s"),
+        InodeKind::CharDevice(r) => {
+            h.update(b"c");
+            h.update(r.as_u64().to_le_bytes());
+        }
+        InodeKind::BlockDevice(r) => {
+            h.update(b"b");
+            h.update(r.as_u64().to_le_bytes());
+        }
+    }
+    h.update((inode.nlink() as u64).to_le_bytes());
+    update_opt(&mut h, inode.mode().map(|m| u64::from(m.0)));
+    update_opt(&mut h, inode.uid().map(|u| u64::from(u.as_raw())));
+    update_opt(&mut h, inode.gid().map(|g| u64::from(g.as_raw())));
+    h.update((inode.xattrs().len() as u64).to_le_bytes());
+    for (name, value) in inode.xattrs() {
+        update_bytes(&mut h, name);
+        update_bytes(&mut h, value);
+    }
+    let d = h.finalize();
+    cache.insert(id, d.clone());
+    d
+}
+
+/// Digest of the whole tree in `fs`, built up from a digest of each file and
+/// directory so that it does not depend on the order anything was created
+/// in. File types, contents, symlink targets, device numbers, link counts,
+/// permissions, ownership and xattrs all count, but times do not, since
+/// receiving a sendstream can not reproduce the ctime of a file anyway.
+pub fn tree_digest<D: Digest>(fs: &Filesystem) -> Output<D> {
+    digest_inode::<D>(fs, fs.root(), &mut BTreeMap::new())
+}
+
+impl<'a> Sendstream<'a> {
+    /// The [tree_digest] of the subvolume that this (full) sendstream
+    /// produces, so that two sendstreams can be checked for equivalence
+    /// without receiving either. Holes are never read, so this is fast even
+    /// for huge sparse files.
+    pub fn content_digest<D: Digest>(&self) -> fs::Result<Output<D>> {
+        Filesystem::from_sendstream(self).map(|fs| tree_digest::<D>(&fs))
+    }
+}
+
+/// Computes a digest of the final contents of every regular file, one
+/// [Command] at a time, so that it can be fed directly from a
+/// [crate::CommandReader] without collecting a whole [crate::Sendstream]
+/// first.
+///
+/// Any [Digest] implementation can be used, such as [sha2::Sha256] or
+/// `blake3::Hasher` (with blake3's `traits-preview` feature). Writes can
+/// arrive in any order and files can be truncated or cloned into, so file data
+/// is kept until [FileHasher::finish] is called.
+pub struct FileHasher<'p, D> {
+    fs: Filesystem,
+    parent: Option<&'p Filesystem>,
+    digest: std::marker::PhantomData<D>,
+}
+
+impl<'p, D: Digest> FileHasher<'p, D> {
+    /// Hash the files of a full sendstream
+    pub fn new() -> Self {
+        Self {
+            fs: Filesystem::new(),
+            parent: None,
+            digest: std::marker::PhantomData,
+        }
+    }
+
+    /// Hash the files of an incremental sendstream sent relative to `parent`
+    pub fn with_parent(parent: &'p Filesystem) -> Self {
+        Self {
+            fs: parent.clone(),
+            parent: Some(parent),
+            digest: std::marker::PhantomData,
+        }
+    }
+
+    pub fn apply(&mut self, cmd: &Command) -> fs::Result<()> {
+        match &self.parent {
+            Some(parent) => self.fs.apply_with_sources(cmd, &[parent]),
+            None => self.fs.apply(cmd),
+        }
+    }
+
+    /// Digest of every regular file, by path. Files with more than one hard
+    /// link appear once for each path.
+    pub fn finish(self) -> BTreeMap<PathBuf, Output<D>> {
+        self.fs
+            .walk()
+            .into_iter()
+            .filter_map(|(path, id)| {
+                self.fs[id]
+                    .contents()
+                    .map(|c| (path, hash_contents::<D>(c)))
+            })
+            .collect()
+    }
+
+    /// The [tree_digest] of everything that has been applied
+    pub fn finish_tree(self) -> Output<D> {
+        tree_digest::<D>(&self.fs)
+    }
+}
+
+impl<D: Digest> Default for FileHasher<'_, D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use sha2::Sha256;
+    use sha2::Sha512;
+
+    use super::*;
+    use crate::CommandReader;
+    use crate::Data;
+    use crate::FileOffset;
+
+    #[test]
+    fn hash_files() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let sendstreams = Sendstream::parse_all(input).expect("failed to parse demo.sendstream");
+        // skip the 100G sparse file, which takes too long to hash
+        let hello = sendstreams[0]
+            .extract_paths(&[PathBuf::from("hello")])
+            .expect("failed to extract");
+        let mut hasher = FileHasher::<Sha256>::new();
+        for cmd in hello.commands() {
+            hasher.apply(cmd).expect("failed to apply");
+        }
+        let hashes = hasher.finish();
+        assert_eq!(
+            Some(&Sha256::digest(b"Hello world!\n")),
+            hashes.get(Path::new("hello/msg"))
+        );
+        assert_eq!(
+            hashes.get(Path::new("hello/lorem")),
+            hashes.get(Path::new("hello/lorem-reflinked"))
+        );
+
+        // an incremental, straight from a reader
+        let parent = Filesystem::from_sendstream(&hello).expect("failed to replay");
+        let encoded = sendstreams[1]
+            .extract_paths(&[PathBuf::from("hello")])
+            .expect("failed to extract")
+            .to_bytes()
+            .expect("failed to encode");
+        let mut hasher = FileHasher::<Sha512>::with_parent(&parent);
+        for cmd in CommandReader::new(encoded.as_slice()) {
+            hasher
+                .apply(&cmd.expect("failed to parse"))
+                .expect("failed to apply");
+        }
+        assert_eq!(
+            Some(&Sha512::digest(b"Goodbye!\n")),
+            hasher.finish().get(Path::new("hello/msg"))
+        );
+    }
+
+    #[test]
+    fn content_digest() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = &sendstreams[0];
+        let digest = demo.content_digest::<Sha256>().expect("failed to digest");
+        let reordered = demo.reorder().expect("failed to reorder");
+        assert_eq!(
+            digest,
+            reordered
+                .content_digest::<Sha256>()
+                .expect("failed to digest")
+        );
+        let mut hasher = FileHasher::<Sha256>::new();
+        for cmd in demo.commands() {
+            hasher.apply(cmd).expect("failed to apply");
+        }
+        assert_eq!(digest, hasher.finish_tree());
+
+        let mut commands = demo.commands().to_vec();
+        let end = commands.pop().expect("demo ends");
+        let with = |extra: Command<'static>| {
+            let mut commands = commands.clone();
+            commands.extend([extra, end.clone()]);
+            Sendstream { commands }
+                .content_digest::<Sha256>()
+                .expect("failed to digest")
+        };
+        let path = || Cow::Borrowed(Path::new("huge-empty-file"));
+        // writing zeroes into a hole changes nothing, and neither do times
+        let zeroes = crate::Write {
+            path: path(),
+            offset: FileOffset(1 << 30),
+            data: Data(Cow::Owned(vec![0; 10000])),
+        };
+        assert_eq!(digest, with(zeroes.into()));
+        let utimes = commands
+            .iter()
+            .find(|c| matches!(c, Command::Utimes(_)))
+            .cloned()
+            .expect("demo sets times");
+        let Command::Utimes(mut utimes) = utimes else {
+            unreachable!()
+        };
+        utimes.mtime = crate::Mtime(std::time::SystemTime::UNIX_EPOCH);
+        assert_eq!(digest, with(utimes.into()));
+        let write = crate::Write {
+            path: path(),
+            offset: FileOffset(1 << 30),
+            data: Data(Cow::Borrowed(b"not a hole")),
+        };
+        assert_ne!(digest, with(write.into()));
+    }
+}
@@ -0,0 +1,94 @@
+//! Replay part of a sendstream, to find out where something went wrong.
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::Sendstream;
+
+impl<'a> Sendstream<'a> {
+    /// State of the subvolume after receiving only the first `n` commands of
+    /// this sendstream (or all of them, if there are fewer than `n`).
+    /// Incremental sendstreams are replayed on top of `parent`, which full
+    /// sendstreams ignore.
+    pub fn state_at(&self, n: usize, parent: Option<&Filesystem>) -> fs::Result<Filesystem> {
+        let (mut fs, sources) = Filesystem::replay_start(self, parent)?;
+        for cmd in self.commands.iter().take(n) {
+            fs.apply_with_sources(cmd, &sources)?;
+        }
+        Ok(fs)
+    }
+
+    /// Find the command that first makes `pred` hold, by checking the state
+    /// of the subvolume after each command (see [Sendstream::state_at]).
+    /// Returns the index of that command, or `None` if `pred` never holds.
+    ///
+    /// Replaying the stream once and checking along the way is much cheaper
+    /// than an actual bisection, which would have to replay from the start
+    /// for every step.
+    pub fn bisect(
+        &self,
+        parent: Option<&Filesystem>,
+        mut pred: impl FnMut(&Filesystem) -> bool,
+    ) -> fs::Result<Option<usize>> {
+        let (mut fs, sources) = Filesystem::replay_start(self, parent)?;
+        for (index, cmd) in self.commands.iter().enumerate() {
+            fs.apply_with_sources(cmd, &sources)?;
+            if pred(&fs) {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::Command;
+
+    #[test]
+    fn state_at() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let stream = &sendstreams[0];
+        assert_eq!(
+            Filesystem::from_sendstream(stream).expect("failed to replay"),
+            stream.state_at(usize::MAX, None).expect("failed to replay")
+        );
+        let empty = stream.state_at(0, None).expect("failed to replay");
+        assert!(empty.walk().is_empty());
+        assert!(empty.subvolume().is_none());
+
+        let msg = Path::new("hello/msg");
+        let index = stream
+            .bisect(None, |fs| fs.get(msg).is_some())
+            .expect("failed to replay")
+            .expect("msg exists in the end");
+        assert!(matches!(stream.commands()[index], Command::Rename(_)));
+        assert!(stream
+            .state_at(index, None)
+            .expect("failed to replay")
+            .get(msg)
+            .is_none());
+        assert!(stream
+            .state_at(index + 1, None)
+            .expect("failed to replay")
+            .get(msg)
+            .is_some());
+
+        let demo = Filesystem::from_sendstream(stream).expect("failed to replay");
+        let undo = &sendstreams[1];
+        let deleted = undo
+            .bisect(Some(&demo), |fs| {
+                fs.get(Path::new("to-be-deleted")).is_none()
+            })
+            .expect("failed to replay")
+            .expect("to-be-deleted is deleted");
+        assert!(matches!(undo.commands()[deleted], Command::Unlink(_)));
+        assert!(matches!(
+            undo.state_at(1, None),
+            Err(fs::Error::Incremental)
+        ));
+    }
+}
@@ -0,0 +1,711 @@
+//! Convert full sendstreams into incremental ones by comparing the
+//! filesystems that they produce.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::Inode;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::Command;
+use crate::Ino;
+use crate::Mode;
+use crate::Sendstream;
+
+/// Largest amount of data that will be put into a single [crate::Write],
+/// matching what the kernel emits.
+pub(crate) const MAX_WRITE_LEN: u64 = 48 * 1024;
+
+impl<'a> Sendstream<'a> {
+    /// Compute an incremental sendstream that, when received on top of
+    /// `parent`, produces the same subvolume as this (full) sendstream.
+    /// Both `self` and `parent` must be full sendstreams.
+    ///
+    /// Data that is unchanged from `parent` is not repeated, and files that
+    /// moved between the two (as identified by their inode numbers) are cloned
+    /// from `parent` instead of being written again.
+    pub fn incremental_from(&self, parent: &Sendstream) -> fs::Result<Sendstream<'static>> {
+        let parent = Filesystem::from_sendstream(parent)?;
+        let child = Filesystem::from_sendstream(self)?;
+        diff(&parent, &child)
+    }
+
+    /// Generate a sendstream that reverts the changes made by this incremental
+    /// sendstream. When received on top of the subvolume produced by `self`,
+    /// it recreates `parent` (with `parent`'s uuid and ctransid): deleted files
+    /// are recreated from `parent`'s contents and changed metadata is restored.
+    pub fn undo(&self, parent: &Filesystem) -> fs::Result<Sendstream<'static>> {
+        let child = Filesystem::from_incremental(parent, self)?;
+        diff(&child, parent)
+    }
+}
+
+/// Produce an incremental sendstream that transforms `parent` into `child`.
+pub fn diff(parent: &Filesystem, child: &Filesystem) -> fs::Result<Sendstream<'static>> {
+    Ok(emit(parent, child, snapshot_header(parent, child)?))
+}
+
+/// Like [diff], but inodes that moved are renamed into place instead of
+/// being recreated, and the parent's inodes at `unknown` paths (whose
+/// contents are not actually known) are replaced rather than updated.
+pub(crate) fn diff_renaming(
+    parent: &Filesystem,
+    child: &Filesystem,
+    unknown: &BTreeSet<PathBuf>,
+) -> fs::Result<Sendstream<'static>> {
+    let header = snapshot_header(parent, child)?;
+    let generation = child.subvolume().map(|s| s.ctransid().0);
+    let strategy = Strategy {
+        renames: generation,
+        unknown: Some(unknown),
+        ..Default::default()
+    };
+    Ok(emit_with(parent, child, header, strategy))
+}
+
+fn snapshot_header(parent: &Filesystem, child: &Filesystem) -> fs::Result<Command<'static>> {
+    let p_sub = parent.subvolume().ok_or(fs::Error::MissingHeader)?;
+    let c_sub = child.subvolume().ok_or(fs::Error::MissingHeader)?;
+    Ok(crate::Snapshot {
+        path: Cow::Owned(c_sub.path().to_path_buf()),
+        uuid: c_sub.uuid(),
+        ctransid: c_sub.ctransid(),
+        clone_uuid: p_sub.uuid(),
+        clone_ctransid: p_sub.ctransid(),
+    }
+    .into())
+}
+
+fn owned(path: &Path) -> Cow<'static, Path> {
+    Cow::Owned(path.to_path_buf())
+}
+
+/// Two inodes are compatible if one can be turned into the other without
+/// recreating it
+fn compatible(p: &Inode, c: &Inode) -> bool {
+    match (p.kind(), c.kind()) {
+        (InodeKind::Directory(_), InodeKind::Directory(_))
+        | (InodeKind::File(_), InodeKind::File(_))
+        | (InodeKind::Fifo, InodeKind::Fifo)
+        | (InodeKind::Socket, InodeKind::Socket) => true,
+        (InodeKind::Symlink(p), InodeKind::Symlink(c)) => p == c,
+        (InodeKind::CharDevice(p), InodeKind::CharDevice(c))
+        | (InodeKind::BlockDevice(p), InodeKind::BlockDevice(c)) => p == c,
+        _ => false,
+    }
+}
+
+/// How [emit_with] goes about turning the parent into the child
+#[derive(Default)]
+struct Strategy<'s> {
+    /// Generation to create new inodes under temporary `o<ino>-<gen>-0` names
+    /// with, renaming them into place right away, like the kernel does
+    temporary: Option<u64>,
+    /// Generation to move inodes out of the way under temporary names with,
+    /// so that they can be renamed to where they are in the child
+    renames: Option<u64>,
+    /// Paths in the parent whose contents are not known
+    unknown: Option<&'s BTreeSet<PathBuf>>,
+}
+
+struct Emitter<'f> {
+    /// The parent after any renames
+    parent: &'f Filesystem,
+    /// The parent as it was, which is where clones come from
+    source: &'f Filesystem,
+    /// First path of every file in `source`, by inode number
+    source_files: BTreeMap<Ino, (PathBuf, &'f FileContents)>,
+    child: &'f Filesystem,
+    cmds: Vec<Command<'static>>,
+    /// Directories (in the child) whose entries were changed
+    touched_dirs: BTreeSet<PathBuf>,
+    next_ino: u64,
+    temporary: Option<u64>,
+    /// Directories (in the parent) that renames moved entries into or out of
+    renamed_in: BTreeSet<InodeId>,
+    /// Paths in the parent that are replaced no matter what is there
+    unknown: &'f BTreeSet<PathBuf>,
+}
+
+fn parent_subvol(fs: &Filesystem) -> Option<(uuid::Uuid, crate::Ctransid)> {
+    fs.subvolume().map(|s| (s.uuid(), s.ctransid()))
+}
+
+/// Emit all the commands necessary to transform `parent` into `child`,
+/// starting with `header`.
+pub(crate) fn emit(
+    parent: &Filesystem,
+    child: &Filesystem,
+    header: Command<'static>,
+) -> Sendstream<'static> {
+    emit_with(parent, child, header, Strategy::default())
+}
+
+/// Emit a full sendstream that creates `child`, giving every inode a
+/// temporary name first like `btrfs send` does, so that nothing tells it
+/// apart from one that the kernel produced
+pub(crate) fn emit_full(child: &Filesystem, header: crate::Subvol<'static>) -> Sendstream<'static> {
+    let strategy = Strategy {
+        temporary: Some(header.ctransid.0),
+        ..Default::default()
+    };
+    emit_with(&Filesystem::new(), child, header.into(), strategy)
+}
+
+fn emit_with(
+    parent: &Filesystem,
+    child: &Filesystem,
+    header: Command<'static>,
+    strategy: Strategy,
+) -> Sendstream<'static> {
+    let max_ino = child
+        .walk()
+        .into_iter()
+        .filter_map(|(_, id)| child.inode(id).and_then(Inode::ino))
+        .map(|ino| ino.0)
+        .max()
+        .unwrap_or(256);
+    let mut cmds = vec![header];
+    let mut renamed_in = BTreeSet::new();
+    let moved = strategy.renames.map(|generation| {
+        let (renames, moved, dirs) = renames(parent, child, generation);
+        cmds.extend(renames);
+        renamed_in = dirs;
+        moved
+    });
+    let mut source_files = BTreeMap::new();
+    for (path, id) in parent.walk() {
+        let inode = &parent[id];
+        if let (Some(ino), Some(c)) = (inode.ino(), inode.contents()) {
+            source_files.entry(ino).or_insert((path, c));
+        }
+    }
+    let empty = BTreeSet::new();
+    let mut e = Emitter {
+        parent: moved.as_ref().unwrap_or(parent),
+        source: parent,
+        source_files,
+        child,
+        cmds,
+        touched_dirs: BTreeSet::new(),
+        next_ino: max_ino + 1,
+        temporary: strategy.temporary,
+        renamed_in,
+        unknown: strategy.unknown.unwrap_or(&empty),
+    };
+    e.run();
+    Sendstream { commands: e.cmds }
+}
+
+/// Inodes that are at one path in `parent` and another in `child`, as
+/// identified by their inode numbers, where neither path can simply stay.
+/// Hard links are left alone, since on their own the inode numbers do not say
+/// which name went where.
+fn moved(parent: &Filesystem, child: &Filesystem) -> Vec<((PathBuf, InodeId), (PathBuf, InodeId))> {
+    let by_ino = |fs: &Filesystem| {
+        let mut names: BTreeMap<Ino, Vec<(PathBuf, InodeId)>> = BTreeMap::new();
+        for (path, id) in fs.walk() {
+            if let Some(ino) = fs[id].ino() {
+                names.entry(ino).or_default().push((path, id));
+            }
+        }
+        names
+    };
+    // `other` has something at `path` that could be kept there
+    let stays = |fs: &Filesystem, id: InodeId, other: &Filesystem, path: &Path| {
+        other.get(path).is_some_and(|o| compatible(&fs[id], o))
+    };
+    let (p_names, mut c_names) = (by_ino(parent), by_ino(child));
+    p_names
+        .into_iter()
+        .filter_map(|(ino, mut p)| {
+            let mut c = c_names.remove(&ino)?;
+            let (p, c) = match (p.len(), c.len()) {
+                (1, 1) => (p.remove(0), c.remove(0)),
+                _ => return None,
+            };
+            let moves = p.0 != c.0
+                && compatible(&parent[p.1], &child[c.1])
+                && !stays(parent, p.1, child, &p.0)
+                && !stays(child, c.1, parent, &c.0);
+            moves.then_some((p, c))
+        })
+        .collect()
+}
+
+/// Rename the inodes that moved between `parent` and `child` to where they
+/// are in the child. Like the kernel, everything is first moved out of the
+/// way under a temporary name, so that the order does not matter. Whatever
+/// can not go to its new path yet (because something else is there, or its
+/// new directory does not exist) is left under its temporary name, to be
+/// removed and recreated like it would have been without renames.
+///
+/// Returns the renames along with `parent` as it looks after them, and the
+/// directories that they changed.
+fn renames(
+    parent: &Filesystem,
+    child: &Filesystem,
+    generation: u64,
+) -> (Vec<Command<'static>>, Filesystem, BTreeSet<InodeId>) {
+    let mut fs = parent.clone();
+    let mut cmds = Vec::new();
+    let mut dirs = BTreeSet::new();
+    let mut rename = |fs: &mut Filesystem, from: &Path, to: &Path| {
+        let cmd: Command = crate::Rename {
+            from: owned(from),
+            to: owned(to),
+        }
+        .into();
+        for path in [from, to] {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            dirs.extend(fs.lookup(dir));
+        }
+        let ok = fs.apply(&cmd).is_ok();
+        if ok {
+            cmds.push(cmd);
+        }
+        ok
+    };
+    let mut pending = moved(parent, child);
+    // deepest first, so that nothing has moved out from under the inode yet
+    pending.sort_by(|a, b| {
+        let depth = |p: &Path| p.components().count();
+        depth(&b.0 .0).cmp(&depth(&a.0 .0))
+    });
+    let mut orphans = Vec::new();
+    for ((from, _), (to, cid)) in pending {
+        let Some(ino) = child[cid].ino() else {
+            continue;
+        };
+        let orphan = PathBuf::from(format!("o{}-{generation}-0", ino.0));
+        if fs.lookup(&orphan).is_none() && rename(&mut fs, &from, &orphan) {
+            orphans.push((to, orphan));
+        }
+    }
+    // parents first, so that directories that moved are there for what
+    // moved into them
+    orphans.sort();
+    for (to, orphan) in orphans {
+        let dir = to.parent().unwrap_or_else(|| Path::new(""));
+        let into_dir = fs
+            .get(dir)
+            .is_some_and(|d| matches!(d.kind(), InodeKind::Directory(_)));
+        if into_dir && fs.lookup(&to).is_none() {
+            rename(&mut fs, &orphan, &to);
+        }
+    }
+    (cmds, fs, dirs)
+}
+
+impl<'f> Emitter<'f> {
+    fn run(&mut self) {
+        let p_walk = self.parent.walk();
+        let c_walk = self.child.walk();
+        let p_paths: BTreeMap<&Path, InodeId> =
+            p_walk.iter().map(|(p, id)| (p.as_path(), *id)).collect();
+
+        // Figure out which paths can stay where they are. An inode is only
+        // ever matched with one other inode, so that changes to hardlinks are
+        // handled correctly.
+        let mut c2p = BTreeMap::from([(self.child.root(), self.parent.root())]);
+        let mut p2c = BTreeMap::from([(self.parent.root(), self.child.root())]);
+        let mut existing_path: BTreeMap<InodeId, PathBuf> =
+            BTreeMap::from([(self.child.root(), PathBuf::new())]);
+        let mut kept = BTreeSet::new();
+        for (path, cid) in &c_walk {
+            let Some(pid) = p_paths.get(path.as_path()) else {
+                continue;
+            };
+            if self.unknown.contains(path) {
+                continue;
+            }
+            if !compatible(&self.parent[*pid], &self.child[*cid]) {
+                continue;
+            }
+            let consistent = match (c2p.get(cid), p2c.get(pid)) {
+                (None, None) => true,
+                (Some(p), Some(c)) => p == pid && c == cid,
+                _ => false,
+            };
+            if consistent {
+                c2p.insert(*cid, *pid);
+                p2c.insert(*pid, *cid);
+                existing_path.entry(*cid).or_insert_with(|| path.clone());
+                kept.insert(path.as_path());
+            }
+        }
+
+        for pid in &self.renamed_in {
+            if let Some(path) = p2c.get(pid).and_then(|cid| existing_path.get(cid)) {
+                self.touched_dirs.insert(path.clone());
+            }
+        }
+
+        // Remove everything that isn't staying, deepest paths first
+        for (path, pid) in p_walk.iter().rev() {
+            if kept.contains(path.as_path()) {
+                continue;
+            }
+            let path = owned(path);
+            self.touch_parent(&path);
+            self.cmds.push(match self.parent[*pid].kind() {
+                InodeKind::Directory(_) => crate::Rmdir { path }.into(),
+                _ => crate::Unlink { path }.into(),
+            });
+        }
+
+        // Create new inodes and links in depth-first order
+        for (path, cid) in &c_walk {
+            if kept.contains(path.as_path()) {
+                continue;
+            }
+            self.touch_parent(path);
+            match existing_path.get(cid) {
+                Some(target) => self.cmds.push(
+                    crate::Link {
+                        link_name: owned(path),
+                        target: crate::LinkTarget(owned(target)),
+                    }
+                    .into(),
+                ),
+                None => {
+                    self.create(path, &self.child[*cid]);
+                    existing_path.insert(*cid, path.clone());
+                }
+            }
+        }
+
+        // Bring the contents and metadata of each inode up to date
+        let mut utimes = Vec::new();
+        let mut seen = BTreeSet::new();
+        let all_inodes = std::iter::once(self.child.root()).chain(c_walk.iter().map(|(_, id)| *id));
+        for cid in all_inodes {
+            if !seen.insert(cid) {
+                continue;
+            }
+            let path = &existing_path[&cid];
+            let base = c2p.get(&cid).map(|pid| &self.parent[*pid]);
+            let new = base.is_none();
+            let changed = self.update(path, &self.child[cid], base);
+            let inode = &self.child[cid];
+            let times_differ = base.is_none_or(|b| {
+                (b.atime(), b.mtime(), b.ctime()) != (inode.atime(), inode.mtime(), inode.ctime())
+            });
+            if new || changed || times_differ || self.touched_dirs.contains(path) {
+                utimes.push((path.clone(), cid));
+            }
+        }
+        // times are set last, since every other change would affect them
+        for (path, cid) in utimes {
+            let inode = &self.child[cid];
+            if let (Some(atime), Some(mtime), Some(ctime)) =
+                (inode.atime(), inode.mtime(), inode.ctime())
+            {
+                self.cmds.push(
+                    crate::Utimes {
+                        path: owned(&path),
+                        atime,
+                        mtime,
+                        ctime,
+                    }
+                    .into(),
+                );
+            }
+        }
+        self.cmds.push(Command::End);
+    }
+
+    fn touch_parent(&mut self, path: &Path) {
+        self.touched_dirs
+            .insert(path.parent().unwrap_or_else(|| Path::new("")).to_path_buf());
+    }
+
+    fn create(&mut self, path: &Path, inode: &Inode) {
+        let ino = inode.ino().unwrap_or_else(|| {
+            self.next_ino += 1;
+            Ino(self.next_ino - 1)
+        });
+        let final_path = path;
+        let temporary = self
+            .temporary
+            .map(|generation| PathBuf::from(format!("o{}-{generation}-0", ino.0)));
+        let path = temporary.as_deref().unwrap_or(path);
+        let mkspecial = |ty: u32, rdev: crate::Rdev| crate::Mkspecial {
+            path: crate::TemporaryPath(owned(path)),
+            ino,
+            rdev,
+            mode: Mode(ty | inode.mode().map_or(0, |m| m.0)),
+        };
+        self.cmds.push(match inode.kind() {
+            InodeKind::Directory(_) => crate::Mkdir {
+                path: crate::TemporaryPath(owned(path)),
+                ino,
+            }
+            .into(),
+            InodeKind::File(_) => crate::Mkfile {
+                path: crate::TemporaryPath(owned(path)),
+                ino,
+            }
+            .into(),
+            InodeKind::Symlink(target) => crate::Symlink {
+                link_name: owned(path),
+                ino,
+                target: crate::LinkTarget(owned(target)),
+            }
+            .into(),
+            InodeKind::Fifo => crate::Mkfifo(mkspecial(nix::libc::S_IFIFO, crate::Rdev(0))).into(),
+            InodeKind::Socket => {
+                crate::Mksock(mkspecial(nix::libc::S_IFSOCK, crate::Rdev(0))).into()
+            }
+            InodeKind::CharDevice(rdev) => {
+                crate::Mknod(mkspecial(nix::libc::S_IFCHR, *rdev)).into()
+            }
+            InodeKind::BlockDevice(rdev) => {
+                crate::Mknod(mkspecial(nix::libc::S_IFBLK, *rdev)).into()
+            }
+        });
+        if temporary.is_some() {
+            self.cmds.push(
+                crate::Rename {
+                    from: owned(path),
+                    to: owned(final_path),
+                }
+                .into(),
+            );
+        }
+    }
+
+    /// Look for a file in the parent with the same inode number, which means
+    /// that it was moved (or replaced by a hardlink).
+    fn moved_from(&self, inode: &Inode) -> Option<(PathBuf, &'f FileContents)> {
+        let (path, c) = self.source_files.get(&inode.ino()?)?;
+        Some((path.clone(), *c))
+    }
+
+    /// Emit commands to make `path` look like `inode`, assuming it currently
+    /// looks like `base` (or is freshly created if `base` is None). Returns
+    /// true if any commands were emitted.
+    fn update(&mut self, path: &Path, inode: &Inode, base: Option<&Inode>) -> bool {
+        let start = self.cmds.len();
+        if let Some(contents) = inode.contents() {
+            let mut base_contents = base.and_then(Inode::contents);
+            if base.is_none() {
+                if let (Some((src_path, src)), Some((uuid, ctransid))) = (
+                    self.moved_from(inode).filter(|(_, c)| c.len() > 0),
+                    parent_subvol(self.source),
+                ) {
+                    self.cmds.push(
+                        crate::Clone {
+                            src_offset: crate::FileOffset(0),
+                            len: crate::CloneLen(src.len()),
+                            src_path: owned(&src_path),
+                            uuid,
+                            ctransid,
+                            dst_path: owned(path),
+                            dst_offset: crate::FileOffset(0),
+                        }
+                        .into(),
+                    );
+                    base_contents = Some(src);
+                }
+            }
+            self.update_contents(path, contents, base_contents);
+        }
+
+        let empty = BTreeMap::new();
+        let base_xattrs = base.map_or(&empty, Inode::xattrs);
+        for name in base_xattrs.keys() {
+            if !inode.xattrs().contains_key(name) {
+                self.cmds.push(
+                    crate::RemoveXattr {
+                        path: owned(path),
+                        name: crate::XattrName(Cow::Owned(name.clone())),
+                    }
+                    .into(),
+                );
+            }
+        }
+        for (name, data) in inode.xattrs() {
+            if base_xattrs.get(name) != Some(data) {
+                self.cmds.push(
+                    crate::SetXattr {
+                        path: owned(path),
+                        name: crate::XattrName(Cow::Owned(name.clone())),
+                        data: crate::XattrData(Cow::Owned(data.clone())),
+                    }
+                    .into(),
+                );
+            }
+        }
+
+        if let (Some(uid), Some(gid)) = (inode.uid(), inode.gid()) {
+            if base.is_none_or(|b| (b.uid(), b.gid()) != (Some(uid), Some(gid))) {
+                self.cmds.push(
+                    crate::Chown {
+                        path: owned(path),
+                        uid,
+                        gid,
+                    }
+                    .into(),
+                );
+            }
+        }
+        // symlink permissions are meaningless, and btrfs never sends them
+        if !matches!(inode.kind(), InodeKind::Symlink(_)) {
+            if let Some(mode) = inode.mode() {
+                if base.is_none_or(|b| b.mode() != Some(mode)) {
+                    self.cmds.push(
+                        crate::Chmod {
+                            path: owned(path),
+                            mode,
+                        }
+                        .into(),
+                    );
+                }
+            }
+        }
+        self.cmds.len() != start
+    }
+
+    fn update_contents(&mut self, path: &Path, target: &FileContents, base: Option<&FileContents>) {
+        let empty = FileContents::default();
+        let base = base.unwrap_or(&empty);
+        // only ranges that have data on at least one side can differ
+        let mut ranges: Vec<(u64, u64)> = target
+            .extents()
+            .chain(base.extents())
+            .map(|(off, data)| (off, (off + data.len() as u64).min(target.len())))
+            .filter(|(start, end)| start < end)
+            .collect();
+        ranges.sort_unstable();
+        let mut size = base.len();
+        let mut covered = 0;
+        for (start, end) in ranges {
+            let mut off = start.max(covered);
+            while off < end {
+                let len = MAX_WRITE_LEN.min(end - off);
+                let new = target.read(off, len);
+                let mut old = base.read(off, len);
+                old.resize(new.len(), 0);
+                if new != old {
+                    size = size.max(off + new.len() as u64);
+                    self.cmds.push(
+                        crate::Write {
+                            path: owned(path),
+                            offset: crate::FileOffset(off),
+                            data: crate::Data(Cow::Owned(new)),
+                        }
+                        .into(),
+                    );
+                }
+                off += len;
+            }
+            covered = covered.max(end);
+        }
+        if size != target.len() {
+            self.cmds.push(
+                crate::Truncate {
+                    path: owned(path),
+                    size: target.len(),
+                }
+                .into(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::Ctransid;
+
+    /// Everything observable about a filesystem, independent of the internal
+    /// inode identities.
+    fn observe(fs: &Filesystem) -> Vec<(String, Option<&FileContents>)> {
+        fs.walk()
+            .into_iter()
+            .map(|(path, id)| {
+                let i = &fs[id];
+                let kind = match i.kind() {
+                    InodeKind::Directory(_) => "dir".to_owned(),
+                    InodeKind::File(_) => "file".to_owned(),
+                    k => format!("{k:?}"),
+                };
+                let desc = format!(
+                    "{path:?} {kind} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+                    i.nlink(),
+                    i.mode(),
+                    i.uid(),
+                    i.gid(),
+                    i.mtime(),
+                    i.atime(),
+                    i.xattrs()
+                );
+                (desc, i.contents())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_to_incremental() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let undo = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        // pretend that demo-undo was sent in full by starting from nothing
+        let undo_full = emit(
+            &Filesystem::new(),
+            &undo,
+            crate::Subvol {
+                path: owned(Path::new("demo-undo")),
+                uuid: Uuid::from_u128(1),
+                ctransid: Ctransid(2),
+            }
+            .into(),
+        );
+        let incremental = undo_full
+            .incremental_from(&sendstreams[0])
+            .expect("failed to diff");
+        let written: u64 = incremental
+            .commands()
+            .iter()
+            .filter_map(|c| match c {
+                Command::Write(w) => Some(w.data().len() as u64),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(b"Goodbye!\n".len() as u64, written);
+        let bytes = incremental.to_bytes().expect("failed to encode");
+        let reparsed = Sendstream::parse_all(&bytes).expect("failed to parse");
+        let received = Filesystem::from_incremental(&demo, &reparsed[0]).expect("failed to replay");
+        similar_asserts::assert_eq!(observe(&undo), observe(&received));
+    }
+
+    #[test]
+    fn undo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let changed =
+            Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        let undo = sendstreams[1].undo(&demo).expect("failed to undo");
+        let bytes = undo.to_bytes().expect("failed to encode");
+        let reparsed = Sendstream::parse_all(&bytes).expect("failed to parse");
+        let reverted =
+            Filesystem::from_incremental(&changed, &reparsed[0]).expect("failed to replay");
+        let sub = reverted.subvolume().expect("missing subvolume");
+        assert_eq!(
+            demo.subvolume().map(|s| (s.uuid(), s.ctransid())),
+            Some((sub.uuid(), sub.ctransid()))
+        );
+        similar_asserts::assert_eq!(observe(&demo), observe(&reverted));
+    }
+}
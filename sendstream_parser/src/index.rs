@@ -0,0 +1,188 @@
+//! Index of the commands that affect each path, for answering many questions
+//! about the same sendstream.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::query::removed_path;
+use crate::Command;
+use crate::Sendstream;
+
+/// How a command affects the file at an indexed path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Role {
+    /// Creates the file, or links it at this path
+    Create,
+    /// Changes the contents of the file
+    Data,
+    /// Changes the mode, ownership, times or xattrs of the file
+    Metadata,
+    /// Renames or removes this directory entry
+    Namespace,
+}
+
+impl Role {
+    pub(crate) fn of(cmd: &Command) -> Option<Self> {
+        match cmd {
+            Command::Mkdir(_)
+            | Command::Mkfile(_)
+            | Command::Mkfifo(_)
+            | Command::Mknod(_)
+            | Command::Mksock(_)
+            | Command::Symlink(_)
+            | Command::Link(_) => Some(Self::Create),
+            Command::Write(_)
+            | Command::EncodedWrite(_)
+            | Command::Fallocate(_)
+            | Command::Clone(_)
+            | Command::Truncate(_)
+            | Command::UpdateExtent(_) => Some(Self::Data),
+            Command::Chmod(_)
+            | Command::Chown(_)
+            | Command::Fileattr(_)
+            | Command::Utimes(_)
+            | Command::SetXattr(_)
+            | Command::RemoveXattr(_) => Some(Self::Metadata),
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_) => Some(Self::Namespace),
+            Command::Subvol(_) | Command::Snapshot(_) | Command::End => None,
+        }
+    }
+}
+
+/// Reference to a command in the indexed sendstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CommandRef {
+    /// Index of the command in the sendstream
+    pub index: usize,
+    pub role: Role,
+}
+
+/// Every command that affects each final path of a sendstream, see
+/// [Sendstream::path_index]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathIndex {
+    paths: HashMap<PathBuf, Vec<CommandRef>>,
+}
+
+impl PathIndex {
+    /// Commands that affect `path`, in stream order. Empty if the sendstream
+    /// does not touch `path` at all.
+    pub fn get(&self, path: impl AsRef<Path>) -> &[CommandRef] {
+        self.paths.get(path.as_ref()).map_or(&[], Vec::as_slice)
+    }
+
+    /// Index of the command that creates the file at `path`, or links it
+    /// there
+    pub fn creation(&self, path: impl AsRef<Path>) -> Option<usize> {
+        self.get(path)
+            .iter()
+            .find(|r| r.role == Role::Create)
+            .map(|r| r.index)
+    }
+
+    pub fn contains(&self, path: impl AsRef<Path>) -> bool {
+        self.paths.contains_key(path.as_ref())
+    }
+
+    /// Every indexed path, in no particular order
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.keys().map(PathBuf::as_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Build a [PathIndex] of the commands that affect each final path (see
+    /// [Sendstream::final_paths]), so repeated lookups do not have to scan the
+    /// whole stream. Changes to the contents and metadata of a file with
+    /// several hard links are listed under every one of its paths. Paths that
+    /// are removed by the end of the stream are indexed by the path that they
+    /// are removed from, like [Sendstream::query].
+    pub fn path_index(&self) -> PathIndex {
+        let finals = self.final_paths();
+        let mut paths: HashMap<PathBuf, Vec<CommandRef>> = HashMap::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let Some(role) = Role::of(cmd) else {
+                continue;
+            };
+            let r = CommandRef { index, role };
+            match (role, finals.inode(index)) {
+                (Role::Data | Role::Metadata, Some(inode)) if !finals.links(inode).is_empty() => {
+                    for link in finals.links(inode) {
+                        paths.entry(link.clone()).or_default().push(r);
+                    }
+                }
+                _ => {
+                    if let Some(path) = finals.get(index).or_else(|| removed_path(cmd)) {
+                        paths.entry(path.to_path_buf()).or_default().push(r);
+                    }
+                }
+            }
+        }
+        PathIndex { paths }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_index() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let index = sendstreams[0].path_index();
+        let commands = sendstreams[0].commands();
+        let msg = index.get("hello/msg");
+        assert!(matches!(
+            commands[index.creation("hello/msg").expect("msg is created")],
+            Command::Mkfile(_)
+        ));
+        assert!(msg.iter().any(|r| r.role == Role::Data));
+        assert_eq!(
+            msg.iter()
+                .filter(|r| matches!(r.role, Role::Data | Role::Metadata))
+                .collect::<Vec<_>>(),
+            index
+                .get("hello/msg-hard")
+                .iter()
+                .filter(|r| matches!(r.role, Role::Data | Role::Metadata))
+                .collect::<Vec<_>>(),
+            "hard links share their contents and metadata"
+        );
+        assert!(matches!(
+            commands[index
+                .creation("hello/msg-hard")
+                .expect("msg-hard is linked")],
+            Command::Link(_)
+        ));
+        assert!(index.get("nope").is_empty());
+        for file in sendstreams[0].files() {
+            assert!(file.links.iter().all(|l| index.contains(l)));
+        }
+
+        let index = sendstreams[1].path_index();
+        assert_eq!(
+            vec![Role::Namespace],
+            index
+                .get("to-be-deleted")
+                .iter()
+                .map(|r| r.role)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Find commands that would make `btrfs receive` fail, without stopping at
+//! the first one.
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::Command;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Problem {
+    /// The command cannot be applied to the subvolume as it is at that point
+    /// in the stream, like a write to a path that was never created or an
+    /// rmdir of a directory that still has something in it
+    #[error(transparent)]
+    Fs(fs::Error),
+    #[error("{0:?} can only be the first command")]
+    MisplacedHeader(&'static str),
+    #[error("command after the End of the stream")]
+    AfterEnd,
+    #[error("stream does not finish with an End command")]
+    MissingEnd,
+}
+
+/// A [Problem] with the command at `index`
+#[derive(Debug)]
+pub struct Finding {
+    pub index: usize,
+    pub problem: Problem,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Check every command of this sendstream against a replay of the
+    /// subvolume being received, reporting each command that would fail.
+    /// Commands that fail are skipped, so a single mistake early on can cause
+    /// more findings later (for example every write to a file that could not
+    /// be created).
+    ///
+    /// Incremental sendstreams require a model of their `parent`, full
+    /// sendstreams ignore it. A missing header is an error, since nothing
+    /// else can be checked without it.
+    pub fn lint(&self, parent: Option<&Filesystem>) -> fs::Result<Vec<Finding>> {
+        let (mut model, sources) = Filesystem::replay_start(self, parent)?;
+        let mut findings = Vec::new();
+        let mut ended = false;
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let problem = match cmd {
+                _ if ended => Some(Problem::AfterEnd),
+                Command::Subvol(_) if index > 0 => Some(Problem::MisplacedHeader("Subvol")),
+                Command::Snapshot(_) if index > 0 => Some(Problem::MisplacedHeader("Snapshot")),
+                Command::End => {
+                    ended = true;
+                    None
+                }
+                cmd => model
+                    .apply_with_sources(cmd, &sources)
+                    .err()
+                    .map(Problem::Fs),
+            };
+            if let Some(problem) = problem {
+                findings.push(Finding { index, problem });
+            }
+        }
+        if !ended {
+            findings.push(Finding {
+                index: self.commands.len(),
+                problem: Problem::MissingEnd,
+            });
+        }
+        Ok(findings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+    use crate::Mode;
+
+    #[test]
+    fn lint() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        assert!(sendstreams[0].lint(None).expect("has a header").is_empty());
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        assert!(sendstreams[1]
+            .lint(Some(&demo))
+            .expect("has a header")
+            .is_empty());
+        assert!(matches!(
+            sendstreams[1].lint(None),
+            Err(fs::Error::Incremental)
+        ));
+
+        let mut commands = sendstreams[0].commands().to_vec();
+        commands.pop();
+        let chmod = |path: &'static str| {
+            Command::from(crate::Chmod {
+                path: Cow::Borrowed(Path::new(path)),
+                mode: Mode(0o644),
+            })
+        };
+        let broken = commands.len();
+        commands.extend([
+            chmod("nope"),
+            crate::Rmdir {
+                path: Cow::Borrowed(Path::new("hello")),
+            }
+            .into(),
+            crate::Unlink {
+                path: Cow::Borrowed(Path::new("myfifo")),
+            }
+            .into(),
+            chmod("myfifo"),
+            crate::Mkdir {
+                path: crate::TemporaryPath(Cow::Borrowed(Path::new("hello"))),
+                ino: crate::Ino(1000),
+            }
+            .into(),
+        ]);
+        let stream = Sendstream { commands };
+        let findings = stream.lint(None).expect("has a header");
+        assert_eq!(
+            vec![broken, broken + 1, broken + 3, broken + 4, broken + 5],
+            findings.iter().map(|f| f.index).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            findings[0].problem,
+            Problem::Fs(fs::Error::NotFound(_))
+        ));
+        assert!(matches!(
+            findings[1].problem,
+            Problem::Fs(fs::Error::NotEmpty(_))
+        ));
+        assert!(matches!(
+            findings[2].problem,
+            Problem::Fs(fs::Error::NotFound(_))
+        ));
+        assert!(matches!(
+            findings[3].problem,
+            Problem::Fs(fs::Error::Exists(_))
+        ));
+        assert!(matches!(findings[4].problem, Problem::MissingEnd));
+    }
+}
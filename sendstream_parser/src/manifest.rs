@@ -0,0 +1,252 @@
+//! Structured listing of the subvolume that a sendstream produces, for audits
+//! and drift detection without having to receive it.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use base64ct::Base64;
+use base64ct::Encoding;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::InodeKind;
+use crate::hash::hash_contents;
+use crate::Sendstream;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FileType {
+    Directory,
+    File,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub file_type: FileType,
+    /// Size of a regular file
+    pub size: Option<u64>,
+    /// Permission bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Target of a symlink
+    pub target: Option<PathBuf>,
+    /// Device number of a character or block device
+    pub rdev: Option<u64>,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Hex-encoded SHA-256 of the contents of a regular file, if requested
+    pub sha256: Option<String>,
+}
+
+/// Every path in a subvolume, in sorted depth-first order (see
+/// [Filesystem::walk]). Hard links show up once per path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// List everything in `fs`, optionally hashing the contents of every
+    /// regular file (which reads every byte of every file, including holes).
+    pub fn from_filesystem(fs: &Filesystem, hash: bool) -> Self {
+        let entries = fs
+            .walk()
+            .into_iter()
+            .map(|(path, id)| {
+                let inode = &fs[id];
+                let (file_type, content, target, rdev) = match inode.kind() {
+                    InodeKind::Directory(_) => (FileType::Directory, None, None, None),
+                    InodeKind::File(c) => (FileType::File, Some(c), None, None),
+                    InodeKind::Symlink(t) => (FileType::Symlink, None, Some(t.clone()), None),
+                    InodeKind::Fifo => (FileType::Fifo, None, None, None),
+                    InodeKind::Socket => (FileType::Socket, None, None, None),
+                    InodeKind::CharDevice(r) => {
+                        (FileType::CharDevice, None, None, Some(r.as_u64()))
+                    }
+                    InodeKind::BlockDevice(r) => {
+                        (FileType::BlockDevice, None, None, Some(r.as_u64()))
+                    }
+                };
+                ManifestEntry {
+                    path,
+                    file_type,
+                    size: content.map(FileContents::len),
+                    mode: inode.mode().map(|m| m.0),
+                    uid: inode.uid().map(|u| u.as_raw()),
+                    gid: inode.gid().map(|g| g.as_raw()),
+                    target,
+                    rdev,
+                    xattrs: inode
+                        .xattrs()
+                        .iter()
+                        .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.clone()))
+                        .collect(),
+                    sha256: content
+                        .filter(|_| hash)
+                        .map(|c| hex::encode(hash_contents::<Sha256>(c))),
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Render this manifest as a BSD mtree spec, with one line for each path
+    /// (in the "full path" form that starts every path with `./`). Xattrs
+    /// use the `xattr.<name>=<base64>` keyword that go-mtree understands.
+    pub fn to_mtree(&self) -> String {
+        let mut out = String::from("#mtree\n");
+        for e in &self.entries {
+            let mut line = format!("./{}", mtree_escape(e.path.as_os_str().as_bytes()));
+            let ty = match e.file_type {
+                FileType::Directory => "dir",
+                FileType::File => "file",
+                FileType::Symlink => "link",
+                FileType::Fifo => "fifo",
+                FileType::Socket => "socket",
+                FileType::CharDevice => "char",
+                FileType::BlockDevice => "block",
+            };
+            // writing to a String can not fail
+            let _ = write!(line, " type={ty}");
+            if let Some(mode) = e.mode {
+                let _ = write!(line, " mode={:04o}", mode & 0o7777);
+            }
+            if let Some(uid) = e.uid {
+                let _ = write!(line, " uid={uid}");
+            }
+            if let Some(gid) = e.gid {
+                let _ = write!(line, " gid={gid}");
+            }
+            if let Some(size) = e.size {
+                let _ = write!(line, " size={size}");
+            }
+            if let Some(target) = &e.target {
+                let _ = write!(
+                    line,
+                    " link={}",
+                    mtree_escape(target.as_os_str().as_bytes())
+                );
+            }
+            if let Some(rdev) = e.rdev {
+                let _ = write!(
+                    line,
+                    " device=native,{},{}",
+                    nix::sys::stat::major(rdev),
+                    nix::sys::stat::minor(rdev)
+                );
+            }
+            if let Some(sha256) = &e.sha256 {
+                let _ = write!(line, " sha256digest={sha256}");
+            }
+            for (name, value) in &e.xattrs {
+                let _ = write!(
+                    line,
+                    " xattr.{}={}",
+                    mtree_escape(name.as_bytes()),
+                    Base64::encode_string(value)
+                );
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Escape everything but printable ASCII (and the characters that mean
+/// something in an mtree spec) as `\ooo`, like `vis(3)` does for mtree
+pub(crate) fn mtree_escape(name: &[u8]) -> String {
+    let mut out = String::with_capacity(name.len());
+    for b in name {
+        match b {
+            b'\\' | b'#' | b'=' | b'*' | b'?' | b'[' => {
+                let _ = write!(out, "\\{b:03o}");
+            }
+            b if b.is_ascii_graphic() => out.push(char::from(*b)),
+            b => {
+                let _ = write!(out, "\\{b:03o}");
+            }
+        }
+    }
+    out
+}
+
+impl<'a> Sendstream<'a> {
+    /// Build a [Manifest] of the subvolume that this full sendstream
+    /// produces. Incremental sendstreams need to be replayed on top of their
+    /// parent first, after which [Manifest::from_filesystem] can be used.
+    pub fn manifest(&self, hash: bool) -> fs::Result<Manifest> {
+        Ok(Manifest::from_filesystem(
+            &Filesystem::from_sendstream(self)?,
+            hash,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sha2::Digest;
+
+    use super::*;
+
+    #[test]
+    fn manifest_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let manifest = sendstreams[0].manifest(false).expect("failed to replay");
+        let entry = |p: &str| {
+            manifest
+                .entries
+                .iter()
+                .find(|e| e.path == Path::new(p))
+                .unwrap_or_else(|| panic!("{p} missing"))
+        };
+        assert_eq!(FileType::Directory, entry("hello").file_type);
+        assert_eq!(Some(107374182400), entry("huge-empty-file").size);
+        assert_eq!(FileType::CharDevice, entry("null").file_type);
+        assert!(manifest.entries.iter().all(|e| e.sha256.is_none()));
+
+        // hashing the 100G sparse file takes too long, so only hash hello/
+        let hello = sendstreams[0]
+            .extract_paths(&[PathBuf::from("hello")])
+            .expect("failed to extract");
+        let manifest = hello.manifest(true).expect("failed to replay");
+        let msg = manifest
+            .entries
+            .iter()
+            .find(|e| e.path == Path::new("hello/msg"))
+            .expect("hello/msg missing");
+        assert_eq!(
+            Some(hex::encode(Sha256::digest(b"Hello world!\n"))),
+            msg.sha256
+        );
+        assert_eq!(Some(13), msg.size);
+
+        let mtree = manifest.to_mtree();
+        assert!(mtree.starts_with("#mtree\n./hello type=dir "));
+        assert!(mtree.contains(&format!(
+            "\n./hello/msg type=file mode=0400 uid=0 gid=0 size=13 sha256digest={} \
+             xattr.user.antlir.demo=eyJoZWxsbyI6ICJ3b3JsZCJ9\n",
+            hex::encode(Sha256::digest(b"Hello world!\n"))
+        )));
+        assert!(mtree.contains("\n./hello/msg-sym type=link uid=0 gid=0 link=hello/msg\n"));
+        assert_eq!("a\\040b\\075c\\012", mtree_escape(b"a b=c\n"));
+    }
+}
@@ -0,0 +1,114 @@
+//! Canonical form of a sendstream, for comparing or caching streams that
+//! describe the same subvolume.
+
+use std::borrow::Cow;
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::incremental;
+use crate::Sendstream;
+
+impl<'a> Sendstream<'a> {
+    /// Rewrite this sendstream into a canonical form that depends only on the
+    /// subvolume it produces, not on the order the kernel happened to send
+    /// things in. Two sends of the same subvolume produce byte-identical
+    /// normalized streams.
+    ///
+    /// Files are created directly at their final paths (so there are no
+    /// temporary `o<ino>-<gen>-<seq>` names), in sorted path order, with data
+    /// written in fixed-size chunks and each piece of metadata set exactly
+    /// once. Clones within the subvolume are replaced by the data they copy.
+    ///
+    /// Incremental sendstreams require a model of their `parent`, and are
+    /// normalized into the canonical diff from `parent`. Full sendstreams
+    /// ignore `parent`.
+    pub fn normalize(&self, parent: Option<&Filesystem>) -> fs::Result<Sendstream<'static>> {
+        match (self.commands.first(), parent) {
+            (Some(crate::Command::Snapshot(_)), Some(parent)) => {
+                let child = Filesystem::from_incremental(parent, self)?;
+                incremental::diff(parent, &child)
+            }
+            (Some(crate::Command::Snapshot(_)), None) => Err(fs::Error::Incremental),
+            _ => {
+                let fs = Filesystem::from_sendstream(self)?;
+                let sub = fs.subvolume().ok_or(fs::Error::MissingHeader)?;
+                let header = crate::Subvol {
+                    path: Cow::Owned(sub.path().to_path_buf()),
+                    uuid: sub.uuid(),
+                    ctransid: sub.ctransid(),
+                };
+                Ok(incremental::emit(&Filesystem::new(), &fs, header.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+
+    #[test]
+    fn normalize() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let normalized = sendstreams[0].normalize(None).expect("failed to normalize");
+
+        // the same subvolume, with a different order of operations and some
+        // redundant metadata changes
+        let mut shuffled = Vec::new();
+        for cmd in sendstreams[0].commands() {
+            match cmd {
+                Command::Mkfile(c) if c.path.as_os_str() == "o258-720050-0" => {
+                    shuffled.push(cmd.clone());
+                    shuffled.push(
+                        crate::Rename {
+                            from: c.path.0.clone(),
+                            to: Cow::Borrowed("o258-1-1".as_ref()),
+                        }
+                        .into(),
+                    );
+                }
+                Command::Rename(c) if c.from.as_os_str() == "o258-720050-0" => {
+                    shuffled.push(
+                        crate::Rename {
+                            from: Cow::Borrowed("o258-1-1".as_ref()),
+                            to: c.to.clone(),
+                        }
+                        .into(),
+                    );
+                }
+                Command::Chown(_) | Command::Utimes(_) => {
+                    shuffled.push(cmd.clone());
+                    shuffled.push(cmd.clone());
+                }
+                _ => shuffled.push(cmd.clone()),
+            }
+        }
+        let shuffled = Sendstream { commands: shuffled };
+        assert_ne!(sendstreams[0], shuffled);
+        assert_eq!(
+            normalized.to_bytes().expect("failed to encode"),
+            shuffled
+                .normalize(None)
+                .expect("failed to normalize")
+                .to_bytes()
+                .expect("failed to encode"),
+        );
+        assert!(!normalized
+            .commands()
+            .iter()
+            .any(|c| matches!(c, Command::Rename(_))));
+
+        let parent = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let incremental = sendstreams[1]
+            .normalize(Some(&parent))
+            .expect("failed to normalize");
+        assert_eq!(
+            incremental,
+            incremental
+                .normalize(Some(&parent))
+                .expect("failed to normalize")
+        );
+    }
+}
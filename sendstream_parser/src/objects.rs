@@ -0,0 +1,268 @@
+//! Land sendstreams in an object store (like S3) instead of a filesystem:
+//! file contents become content-addressed blobs, and everything else about
+//! the subvolume goes in a manifest next to them.
+//!
+//! For a subvolume with uuid `U` the layout is
+//!  * `blobs/<sha256>`: a piece of file data, at most [CHUNK_LEN] bytes
+//!  * `manifests/U.mtree`: every path with its metadata, see
+//!    [Manifest::to_mtree]
+//!  * `manifests/U.chunks`: which blobs make up each file, one line of
+//!    `<path> <offset> <len> <sha256>` per blob. Anything in a file that is
+//!    not covered by a blob is a hole.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use sha2::Digest;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::InodeKind;
+use crate::manifest::mtree_escape;
+use crate::manifest::Manifest;
+use crate::receive::MemoryReceiver;
+use crate::receive::Receiver;
+use crate::Command;
+
+/// Data is split into blobs at multiples of this many bytes
+pub const CHUNK_LEN: u64 = 4 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to store {key}: {error}")]
+    Store { key: String, error: std::io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Somewhere to put objects, keyed by `/`-separated names
+pub trait ObjectStore {
+    fn contains(&mut self, key: &str) -> std::io::Result<bool>;
+
+    /// Store `data` under `key`, replacing whatever was there
+    fn put(&mut self, key: &str, data: &[u8]) -> std::io::Result<()>;
+}
+
+/// [ObjectStore] that keeps every object as a file under a directory, which
+/// is also handy for staging an upload
+pub struct DirStore {
+    root: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn contains(&mut self, key: &str) -> std::io::Result<bool> {
+        self.root.join(key).try_exists()
+    }
+
+    fn put(&mut self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // readers never see a partial object
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+/// What was stored for one subvolume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub uuid: Uuid,
+    /// Key of the `.mtree` manifest
+    pub manifest: String,
+    /// Key of the `.chunks` index
+    pub chunks: String,
+    /// Blobs that were uploaded
+    pub new_blobs: usize,
+    /// Blobs that the store already had, from this or an earlier subvolume
+    pub existing_blobs: usize,
+    /// Bytes of blob data that were uploaded
+    pub bytes: u64,
+}
+
+/// [Receiver] that replays each stream in memory and, at its end, writes the
+/// subvolume to an [ObjectStore]. Received subvolumes are kept around as
+/// parents (and clone sources) of the incremental streams that follow them.
+pub struct ObjectReceiver<S> {
+    store: S,
+    memory: MemoryReceiver,
+    stored: Vec<Stored>,
+}
+
+/// Offset and length of each blob of `contents`: contiguous data cut at
+/// multiples of [CHUNK_LEN], so that the same data ends up in the same blobs
+/// no matter how it was written
+fn pieces(contents: &FileContents) -> Vec<(u64, u64)> {
+    let mut out = Vec::new();
+    for range in contents.data_ranges() {
+        let mut start = range.start;
+        while start < range.end {
+            let stop = range.end.min((start / CHUNK_LEN + 1) * CHUNK_LEN);
+            out.push((start, stop - start));
+            start = stop;
+        }
+    }
+    out
+}
+
+fn put(store: &mut impl ObjectStore, key: String, data: &[u8]) -> Result<String> {
+    match store.put(&key, data) {
+        Ok(()) => Ok(key),
+        Err(error) => Err(Error::Store { key, error }),
+    }
+}
+
+/// Upload the blobs of `fs`, then the manifests that refer to them
+fn upload(store: &mut impl ObjectStore, fs: &Filesystem) -> Result<Stored> {
+    let uuid = fs.subvolume().map(|s| s.uuid).unwrap_or_default();
+    let mut stored = Stored {
+        uuid,
+        manifest: String::new(),
+        chunks: String::new(),
+        new_blobs: 0,
+        existing_blobs: 0,
+        bytes: 0,
+    };
+    let mut chunks = String::new();
+    // hard links only need to be uploaded once
+    let mut seen = BTreeMap::new();
+    for (path, id) in fs.walk() {
+        let InodeKind::File(contents) = fs[id].kind() else {
+            continue;
+        };
+        let path = mtree_escape(path.as_os_str().as_bytes());
+        if let Some(lines) = seen.get(&id) {
+            for line in lines {
+                let _ = writeln!(chunks, "{path} {line}");
+            }
+            continue;
+        }
+        let mut lines = Vec::new();
+        for (offset, len) in pieces(contents) {
+            let piece = contents.read(offset, len);
+            let hash = hex::encode(Sha256::digest(&piece));
+            let key = format!("blobs/{hash}");
+            let exists = store.contains(&key).map_err(|error| Error::Store {
+                key: key.clone(),
+                error,
+            })?;
+            if exists {
+                stored.existing_blobs += 1;
+            } else {
+                put(store, key, &piece)?;
+                stored.new_blobs += 1;
+                stored.bytes += len;
+            }
+            lines.push(format!("{offset} {len} {hash}"));
+        }
+        for line in &lines {
+            let _ = writeln!(chunks, "{path} {line}");
+        }
+        seen.insert(id, lines);
+    }
+    stored.chunks = put(store, format!("manifests/{uuid}.chunks"), chunks.as_bytes())?;
+    let mtree = Manifest::from_filesystem(fs, false).to_mtree();
+    stored.manifest = put(store, format!("manifests/{uuid}.mtree"), mtree.as_bytes())?;
+    Ok(stored)
+}
+
+impl<S: ObjectStore> ObjectReceiver<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            memory: MemoryReceiver::new(),
+            stored: Vec::new(),
+        }
+    }
+
+    /// Make `parent` available to incremental streams that were sent relative
+    /// to it
+    pub fn parent(mut self, parent: Filesystem) -> Self {
+        self.memory = self.memory.parent(parent);
+        self
+    }
+
+    /// Every subvolume that has been stored so far, in order
+    pub fn stored(&self) -> &[Stored] {
+        &self.stored
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+}
+
+impl<S: ObjectStore> Receiver for ObjectReceiver<S> {
+    type Error = Error;
+
+    fn command(&mut self, cmd: &Command) -> Result<()> {
+        if let Some(fs) = self.memory.apply(cmd)? {
+            let stored = upload(&mut self.store, fs)?;
+            self.stored.push(stored);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receive::receive_all;
+    use crate::Sendstream;
+
+    #[test]
+    fn object_store() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dir = std::env::temp_dir().join(format!("object_store.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut receiver = ObjectReceiver::new(DirStore::new(&dir));
+        receive_all(&mut receiver, &sendstreams).expect("failed to receive");
+        let stored = receiver.stored().to_vec();
+        assert_eq!(2, stored.len());
+        let Command::Subvol(subvol) = &sendstreams[0].commands()[0] else {
+            panic!("demo does not start with a subvol");
+        };
+        assert_eq!(subvol.uuid(), stored[0].uuid);
+        // the clone in the first stream and the files that the second one
+        // inherits were already there
+        assert!(stored[0].existing_blobs > 0);
+        assert!(stored[1].existing_blobs >= stored[0].new_blobs);
+
+        let mtree = std::fs::read_to_string(dir.join(&stored[0].manifest)).expect("no manifest");
+        assert!(mtree.contains("./hello/msg type=file mode=0400"), "{mtree}");
+        let chunks = std::fs::read_to_string(dir.join(&stored[0].chunks)).expect("no chunks");
+        let msg: Vec<_> = chunks
+            .lines()
+            .filter(|l| l.starts_with("hello/msg "))
+            .collect();
+        assert_eq!(1, msg.len(), "{chunks}");
+        let hash = msg[0].rsplit(' ').next().expect("no hash");
+        let blob = std::fs::read(dir.join("blobs").join(hash)).expect("no blob");
+        assert_eq!(13, blob.len());
+        // the huge sparse file is nothing but holes
+        assert!(!chunks.contains("huge-empty-file "));
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,215 @@
+//! Write sendstreams as OCI image layers, so that container build systems can
+//! use btrfs snapshots as image layers.
+//!
+//! What goes in a layer is the same as for an [overlay](crate::overlay)
+//! layer, but whiteouts are written the OCI way: an empty `.wh.<name>` file
+//! for each path that was removed, and an empty `.wh..wh..opq` file in each
+//! opaque directory.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::overlay::layer_entries;
+use crate::overlay::LayerEntry;
+use crate::tar::Entry;
+use crate::tar::EntryKind;
+use crate::tar::TarWriter;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to write layer: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Name of the file that marks its directory as opaque
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// Passes everything through to `w`, hashing it along the way
+struct Digesting<W> {
+    w: W,
+    sha: Sha256,
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.sha.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// `path` with `.wh.` put in front of its file name, or `name` in the
+/// directory `path`
+fn whiteout(path: &Path, name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => path.join(name),
+        None => {
+            let mut wh = OsString::from(".wh.");
+            wh.push(path.file_name().unwrap_or_default());
+            path.with_file_name(wh)
+        }
+    }
+}
+
+static NO_XATTRS: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+
+/// Empty file at `path`, which is what whiteouts are
+fn marker<'a>(path: &'a Path, no_data: &'a FileContents) -> Entry<'a> {
+    Entry {
+        path,
+        kind: EntryKind::File(no_data),
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        mtime: None,
+        xattrs: &NO_XATTRS,
+    }
+}
+
+/// Write the layer that `stream` adds on top of `parent` (the subvolume
+/// that it was sent relative to, which is `None` for a full stream) to `w`
+/// as an uncompressed OCI layer tarball. Returns `w` once the layer is
+/// complete, along with its `diff_id` (`sha256:<hex>` of the tarball), which
+/// is what goes in `rootfs.diff_ids` of the image config.
+///
+/// Everything that was added or modified goes in the layer in full, along
+/// with the directories that lead to it. Sockets are left out, like in
+/// [crate::tar::to_tar].
+pub fn to_layer<W: Write>(
+    parent: Option<&Filesystem>,
+    stream: &Sendstream,
+    w: W,
+) -> Result<(W, String)> {
+    let empty = Filesystem::new();
+    let (old, new) = match parent {
+        Some(parent) => (parent, Filesystem::from_incremental(parent, stream)?),
+        None => (&empty, Filesystem::from_sendstream(stream)?),
+    };
+    let mut tar = TarWriter::new(Digesting {
+        w,
+        sha: Sha256::new(),
+    });
+    let no_data = FileContents::default();
+    // first path that each hard-linked file went in the layer at
+    let mut linked: BTreeMap<InodeId, PathBuf> = BTreeMap::new();
+    let mut put = |tar: &mut TarWriter<_>, path: &Path, id: InodeId| -> Result<()> {
+        let inode = &new[id];
+        let kind = match inode.kind() {
+            InodeKind::Directory(_) => EntryKind::Directory,
+            _ if linked.contains_key(&id) => EntryKind::Hardlink(&linked[&id]),
+            InodeKind::File(c) => EntryKind::File(c),
+            InodeKind::Symlink(t) => EntryKind::Symlink(t),
+            InodeKind::Fifo => EntryKind::Fifo,
+            InodeKind::CharDevice(r) => EntryKind::CharDevice(r.as_u64()),
+            InodeKind::BlockDevice(r) => EntryKind::BlockDevice(r.as_u64()),
+            InodeKind::Socket => return Ok(()),
+        };
+        tar.entry(&Entry::new(path, inode, kind))?;
+        if inode.nlink() > 1 && !linked.contains_key(&id) {
+            linked.insert(id, path.to_path_buf());
+        }
+        Ok(())
+    };
+    put(&mut tar, Path::new(""), new.root())?;
+    for entry in layer_entries(old, &new) {
+        match entry {
+            LayerEntry::File(path, id) => put(&mut tar, &path, id)?,
+            LayerEntry::Whiteout(path) => {
+                tar.entry(&marker(&whiteout(&path, None), &no_data))?;
+            }
+            LayerEntry::Opaque(path, id) => {
+                put(&mut tar, &path, id)?;
+                tar.entry(&marker(&whiteout(&path, Some(OPAQUE)), &no_data))?;
+            }
+        }
+    }
+    tar.finish()?;
+    let Digesting { w, sha } = tar.into_inner();
+    Ok((w, format!("sha256:{}", hex::encode(sha.finalize()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::tests::headers;
+
+    #[test]
+    fn to_layer() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let (base, base_id) =
+            super::to_layer(None, &sendstreams[0], Vec::new()).expect("failed to write layer");
+        assert_eq!(
+            format!("sha256:{}", hex::encode(Sha256::digest(&base))),
+            base_id
+        );
+        assert!(headers(&base).iter().any(|h| h.0 == "./hello/msg"));
+
+        let parent = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let (layer, layer_id) = super::to_layer(Some(&parent), &sendstreams[1], Vec::new())
+            .expect("failed to write layer");
+        assert_ne!(base_id, layer_id);
+        let names: Vec<_> = headers(&layer).into_iter().map(|h| h.0).collect();
+        for name in [
+            "./",
+            "./hello/",
+            "./hello/msg",
+            "./.wh.to-be-deleted",
+            "./.wh.dir-to-be-deleted",
+        ] {
+            assert!(
+                names.iter().any(|n| n == name),
+                "{name} is not in {names:?}"
+            );
+        }
+        // nothing that stayed the same is in there
+        assert!(!names
+            .iter()
+            .any(|n| n.contains("huge-empty-file") || n.contains("lorem")));
+
+        // a directory that replaces a file hides whatever was there before
+        let replaced = crate::incremental::diff(&parent, &{
+            let mut fs = parent.clone();
+            for cmd in [
+                crate::Command::Unlink(crate::Unlink {
+                    path: std::borrow::Cow::Borrowed(Path::new("to-be-deleted")),
+                }),
+                crate::Command::Mkdir(crate::Mkdir {
+                    path: crate::TemporaryPath(std::borrow::Cow::Borrowed(Path::new(
+                        "to-be-deleted",
+                    ))),
+                    ino: crate::Ino(1000),
+                }),
+            ] {
+                fs.apply(&cmd).expect("failed to apply");
+            }
+            fs
+        })
+        .expect("failed to diff");
+        let (layer, _) =
+            super::to_layer(Some(&parent), &replaced, Vec::new()).expect("failed to write layer");
+        assert!(headers(&layer)
+            .iter()
+            .any(|h| h.0 == "./to-be-deleted/.wh..wh..opq"));
+    }
+}
@@ -0,0 +1,207 @@
+//! Find files that are never moved out of the temporary `o<ino>-<gen>-<seq>`
+//! names that the kernel creates them under, or that are moved out of them
+//! twice, which means that a stream has been truncated or reordered.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Sendstream;
+
+/// Whether `name` looks like one of the temporary names that the kernel gives
+/// files before they are renamed into place, `o<ino>-<gen>-<seq>`
+pub(crate) fn is_temporary_name(name: &OsStr) -> bool {
+    let Some(rest) = name.to_str().and_then(|n| n.strip_prefix('o')) else {
+        return false;
+    };
+    let parts: Vec<_> = rest.split('-').collect();
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn is_temporary(path: &Path) -> bool {
+    path.file_name().is_some_and(is_temporary_name)
+}
+
+/// Path of the new directory entry that `cmd` creates, if it creates one
+fn created_path<'c>(cmd: &'c Command) -> Option<&'c Path> {
+    match cmd {
+        Command::Mkdir(c) => Some(c.path.as_path()),
+        Command::Mkfile(c) => Some(c.path.as_path()),
+        Command::Mkfifo(c) => Some(c.path().as_path()),
+        Command::Mknod(c) => Some(c.path().as_path()),
+        Command::Mksock(c) => Some(c.path().as_path()),
+        Command::Symlink(c) => Some(c.link_name.as_ref()),
+        Command::Link(c) => Some(c.link_name.as_ref()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OrphanKind {
+    /// Still at its temporary name at the end of the stream
+    NeverRenamed,
+    /// Renamed away from its temporary name again, after it had already been
+    /// renamed once
+    RenamedTwice,
+}
+
+/// A temporary path that is not renamed into place exactly once, see
+/// [Sendstream::orphans]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Orphan {
+    /// The temporary path, as it is at the last command in `indices`
+    pub path: PathBuf,
+    pub kind: OrphanKind,
+    /// Index of the command that created the temporary path, followed by
+    /// every rename away from it
+    pub indices: Vec<usize>,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Find temporary paths that are created (or renamed to) but never
+    /// renamed to their final location, or that are renamed away from twice.
+    /// A temporary path that is unlinked or removed is fine, since that is
+    /// how incrementals get rid of directories that have been orphaned.
+    pub fn orphans(&self) -> Vec<Orphan> {
+        // every temporary path that currently exists, with the index of its
+        // creation, and every one that has been renamed away from
+        let mut live: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+        let mut gone: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+        let mut orphans = Vec::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            if let Some(path) = created_path(cmd).filter(|p| is_temporary(p)) {
+                live.insert(path.to_path_buf(), vec![index]);
+                gone.remove(path);
+                continue;
+            }
+            match cmd {
+                Command::Rename(r) => {
+                    if let Some(mut indices) = live.remove(r.from.as_ref()) {
+                        indices.push(index);
+                        gone.insert(r.from.to_path_buf(), indices);
+                    } else if let Some(mut indices) = gone.remove(r.from.as_ref()) {
+                        indices.push(index);
+                        orphans.push(Orphan {
+                            path: r.from.to_path_buf(),
+                            kind: OrphanKind::RenamedTwice,
+                            indices,
+                        });
+                    }
+                    // temporary paths beneath a renamed directory move with it
+                    let moved: Vec<_> = live
+                        .keys()
+                        .filter(|p| p.starts_with(&r.from))
+                        .cloned()
+                        .collect();
+                    for path in moved {
+                        if let (Some(indices), Some(to)) =
+                            (live.remove(&path), renamed(&path, &r.from, &r.to))
+                        {
+                            live.insert(to, indices);
+                        }
+                    }
+                    if is_temporary(&r.to) {
+                        live.insert(r.to.to_path_buf(), vec![index]);
+                        gone.remove(r.to.as_ref());
+                    }
+                }
+                Command::Unlink(u) => {
+                    live.remove(u.path.as_ref());
+                }
+                Command::Rmdir(r) => {
+                    live.remove(r.path.as_ref());
+                }
+                _ => (),
+            }
+        }
+        orphans.extend(live.into_iter().map(|(path, indices)| Orphan {
+            path,
+            kind: OrphanKind::NeverRenamed,
+            indices,
+        }));
+        orphans.sort_by_key(|o| o.indices[0]);
+        orphans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn temporary_names() {
+        assert!(is_temporary_name(OsStr::new("o258-720050-0")));
+        assert!(!is_temporary_name(OsStr::new("o258-720050")));
+        assert!(!is_temporary_name(OsStr::new("o258-x-0")));
+        assert!(!is_temporary_name(OsStr::new("hello")));
+    }
+
+    #[test]
+    fn orphans() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        for s in &sendstreams {
+            assert_eq!(Vec::<Orphan>::new(), s.orphans());
+        }
+
+        let commands = sendstreams[0].commands();
+        let (index, rename) = commands
+            .iter()
+            .enumerate()
+            .find(|(_, c)| matches!(c, Command::Rename(r) if is_temporary(&r.from)))
+            .expect("demo renames a temporary path");
+        let Command::Rename(r) = rename else {
+            unreachable!()
+        };
+        let created = commands
+            .iter()
+            .position(|c| created_path(c) == Some(r.from.as_ref()))
+            .expect("temporary path is created");
+
+        // truncated before the rename
+        let truncated = Sendstream {
+            commands: commands[..index].to_vec(),
+        };
+        assert_eq!(
+            vec![Orphan {
+                path: r.from.to_path_buf(),
+                kind: OrphanKind::NeverRenamed,
+                indices: vec![created],
+            }],
+            truncated.orphans()
+        );
+
+        // the rename repeated
+        let mut repeated = commands.to_vec();
+        repeated.insert(
+            index + 1,
+            crate::Rename {
+                from: r.from.clone(),
+                to: Cow::Borrowed(Path::new("again")),
+            }
+            .into(),
+        );
+        assert_eq!(
+            vec![Orphan {
+                path: r.from.to_path_buf(),
+                kind: OrphanKind::RenamedTwice,
+                indices: vec![created, index, index + 1],
+            }],
+            Sendstream { commands: repeated }.orphans()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Check that the data commands for each file fit together, which a
+//! well-behaved generator always gets right. Anything reported here can
+//! still be received, but usually points at a bug in whatever produced the
+//! stream.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// Something odd about the data commands of a file, see
+/// [Sendstream::overlaps]. Indices are of commands in the sendstream, and
+/// `path` is the final path of the file (see [Sendstream::final_paths]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Anomaly {
+    /// A [crate::Write] puts data past the size that a later
+    /// [crate::Truncate] cuts the file down to, so that data was never
+    /// needed
+    WriteTruncated {
+        path: Option<PathBuf>,
+        write: usize,
+        truncate: usize,
+    },
+    /// Two [crate::Clone]s copy into overlapping ranges of the same file
+    OverlappingClones {
+        path: Option<PathBuf>,
+        first: usize,
+        second: usize,
+    },
+    /// A [crate::Write] overlaps the range of an [crate::UpdateExtent], but
+    /// does not fit inside it
+    WritePastUpdateExtent {
+        path: Option<PathBuf>,
+        update: usize,
+        write: usize,
+    },
+}
+
+/// Data commands seen so far for one file, with the range each one covers
+#[derive(Default)]
+struct FileRanges {
+    /// Writes since the last truncate
+    writes: Vec<(usize, Range<u64>)>,
+    clones: Vec<(usize, Range<u64>)>,
+    updates: Vec<(usize, Range<u64>)>,
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl<'a> Sendstream<'a> {
+    /// Find writes that are cut off by a later truncate, clones into the same
+    /// part of a file and writes that spill over the edge of an
+    /// [crate::UpdateExtent], in stream order. Files are followed through
+    /// temporary names and hard links.
+    pub fn overlaps(&self) -> Vec<Anomaly> {
+        let finals = self.final_paths();
+        let mut files: BTreeMap<usize, FileRanges> = BTreeMap::new();
+        let mut anomalies = Vec::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let Some(inode) = finals.inode(index) else {
+                continue;
+            };
+            let path = || finals.get(index).map(|p| p.to_path_buf());
+            let file = files.entry(inode).or_default();
+            match cmd {
+                Command::Write(w) => {
+                    let start = w.offset.as_u64();
+                    let range = start..start + w.data.len() as u64;
+                    for (update, u) in &file.updates {
+                        if overlaps(u, &range) && (range.start < u.start || range.end > u.end) {
+                            anomalies.push(Anomaly::WritePastUpdateExtent {
+                                path: path(),
+                                update: *update,
+                                write: index,
+                            });
+                        }
+                    }
+                    file.writes.push((index, range));
+                }
+                Command::Clone(c) => {
+                    let start = c.dst_offset.as_u64();
+                    let range = start..start + c.len.as_u64();
+                    for (first, other) in &file.clones {
+                        if overlaps(other, &range) {
+                            anomalies.push(Anomaly::OverlappingClones {
+                                path: path(),
+                                first: *first,
+                                second: index,
+                            });
+                        }
+                    }
+                    file.clones.push((index, range));
+                }
+                Command::UpdateExtent(u) => {
+                    let start = u.offset.as_u64();
+                    file.updates.push((index, start..start + u.len));
+                }
+                Command::Truncate(t) => {
+                    for (write, range) in file.writes.drain(..) {
+                        if range.end > t.size {
+                            anomalies.push(Anomaly::WriteTruncated {
+                                path: path(),
+                                write,
+                                truncate: index,
+                            });
+                        }
+                    }
+                    for (_, range) in file.clones.iter_mut().chain(&mut file.updates) {
+                        range.end = range.end.min(t.size);
+                    }
+                    file.clones.retain(|(_, r)| !r.is_empty());
+                    file.updates.retain(|(_, r)| !r.is_empty());
+                }
+                _ => (),
+            }
+        }
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    use super::*;
+    use crate::Data;
+    use crate::FileOffset;
+
+    #[test]
+    fn overlaps_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        for s in &sendstreams {
+            assert_eq!(Vec::<Anomaly>::new(), s.overlaps());
+        }
+
+        let mut commands = sendstreams[0].commands().to_vec();
+        let end = commands.pop().expect("demo ends");
+        let clone = commands
+            .iter()
+            .find(|c| matches!(c, Command::Clone(_)))
+            .cloned()
+            .expect("demo has a clone");
+        let write = |path: &'static str, offset: u64, data: &'static [u8]| {
+            Command::from(crate::Write {
+                path: Cow::Borrowed(Path::new(path)),
+                offset: FileOffset(offset),
+                data: Data(Cow::Borrowed(data)),
+            })
+        };
+        let first = commands.len();
+        commands.extend([
+            write("hello/msg", 0, b"Hello world, again!\n"),
+            crate::Truncate {
+                path: Cow::Borrowed(Path::new("hello/msg")),
+                size: 15,
+            }
+            .into(),
+            clone,
+            crate::UpdateExtent {
+                path: Cow::Borrowed(Path::new("hello/lorem")),
+                offset: FileOffset(0),
+                len: 10,
+            }
+            .into(),
+            write("hello/lorem", 2, b"fits"),
+            write("hello/lorem", 5, b"does not fit"),
+            end,
+        ]);
+        let reflinked = commands
+            .iter()
+            .position(|c| matches!(c, Command::Clone(_)))
+            .expect("demo has a clone");
+        let path = |p: &str| Some(PathBuf::from(p));
+        assert_eq!(
+            vec![
+                Anomaly::WriteTruncated {
+                    path: path("hello/msg"),
+                    write: first,
+                    truncate: first + 1,
+                },
+                Anomaly::OverlappingClones {
+                    path: path("hello/lorem-reflinked"),
+                    first: reflinked,
+                    second: first + 2,
+                },
+                Anomaly::WritePastUpdateExtent {
+                    path: path("hello/lorem"),
+                    update: first + 3,
+                    write: first + 5,
+                },
+            ],
+            Sendstream { commands }.overlaps()
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Materialize sendstreams as overlayfs layers, so that a chain of
+//! incremental streams can be mounted by overlay-based container runtimes
+//! without receiving it first.
+//!
+//! A full stream becomes a layer holding the whole subvolume. An incremental
+//! stream becomes an upper layer on top of the layer of its parent:
+//!  * paths that were removed become whiteouts (`0:0` character devices)
+//!  * directories that replaced something else at the same path are opaque,
+//!    and hold everything beneath them
+//!  * everything that was added or modified is copied up in full, along
+//!    with the directories that lead to it
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::sys::stat::Mode;
+use nix::sys::stat::SFlag;
+use nix::unistd::Uid;
+
+use crate::compare::diff_filesystems;
+use crate::compare::Change;
+use crate::extract::create;
+use crate::extract::set_metadata;
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::receive::MemoryReceiver;
+use crate::receive::Receiver;
+use crate::Command;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to write {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// One entry of a layer, see [layer_entries]
+pub(crate) enum LayerEntry {
+    /// The file at this path in the new subvolume
+    File(PathBuf, InodeId),
+    /// Whatever was at this path is gone
+    Whiteout(PathBuf),
+    /// The directory at this path in the new subvolume, which hides whatever
+    /// was beneath it before. Everything beneath it now follows as
+    /// [LayerEntry::File]s.
+    Opaque(PathBuf, InodeId),
+}
+
+/// Everything in a layer that turns `old` into `new` (see the
+/// [module docs](self)), with directories before anything in them
+pub(crate) fn layer_entries(old: &Filesystem, new: &Filesystem) -> Vec<LayerEntry> {
+    let mut entries = Vec::new();
+    // directories that are in the layer already
+    let mut dirs: BTreeSet<PathBuf> = BTreeSet::new();
+    let put = |entries: &mut Vec<LayerEntry>, dirs: &mut BTreeSet<PathBuf>, path: &Path, id| {
+        if matches!(new[id].kind(), InodeKind::Directory(_)) {
+            dirs.insert(path.to_path_buf());
+        }
+        entries.push(LayerEntry::File(path.to_path_buf(), id));
+    };
+    // paths beneath which everything has been taken care of
+    let mut done: BTreeSet<PathBuf> = BTreeSet::new();
+    for d in diff_filesystems(old, new).paths {
+        if d.path.ancestors().skip(1).any(|a| done.contains(a)) {
+            continue;
+        }
+        // copy up the directories that lead to the path
+        let mut ancestors: Vec<_> = d
+            .path
+            .ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty() && !dirs.contains(*a))
+            .map(Path::to_path_buf)
+            .collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            if let Some(id) = new.lookup(&dir) {
+                put(&mut entries, &mut dirs, &dir, id);
+            }
+        }
+        let Some(id) = new.lookup(&d.path) else {
+            entries.push(LayerEntry::Whiteout(d.path.clone()));
+            done.insert(d.path);
+            continue;
+        };
+        let was = old.lookup(&d.path);
+        let is_dir = matches!(new[id].kind(), InodeKind::Directory(_));
+        let was_dir = was.is_some_and(|w| matches!(old[w].kind(), InodeKind::Directory(_)));
+        match d.change {
+            // a directory that took the place of another file or directory
+            // hides everything below it
+            Change::Modified(_) if is_dir && was != Some(id) => {
+                entries.push(LayerEntry::Opaque(d.path.clone(), id));
+                for (p, id) in new.walk() {
+                    if p.starts_with(&d.path) && p != d.path {
+                        put(&mut entries, &mut dirs, &p, id);
+                    }
+                }
+                done.insert(d.path);
+            }
+            _ => {
+                put(&mut entries, &mut dirs, &d.path, id);
+                if was_dir && !is_dir {
+                    done.insert(d.path);
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// Everything that goes in one layer, as it is being written
+struct Layer<'f> {
+    dir: &'f Path,
+    new: &'f Filesystem,
+    /// Everything that was written, in order, to set the metadata of
+    /// children before that of their parents
+    written: Vec<(PathBuf, InodeId)>,
+    /// First path that each hard-linked file was written at
+    linked: BTreeMap<InodeId, PathBuf>,
+    opaque: String,
+}
+
+impl Layer<'_> {
+    fn io(&self, path: &Path) -> impl Fn(std::io::Error) -> Error {
+        let path = self.dir.join(path);
+        move |error| Error::Io {
+            path: path.clone(),
+            error,
+        }
+    }
+
+    /// Write the file at `path` in the new subvolume
+    fn put(&mut self, path: &Path, id: InodeId) -> Result<()> {
+        let inode = &self.new[id];
+        let dst = self.dir.join(path);
+        let dir = matches!(inode.kind(), InodeKind::Directory(_));
+        if inode.nlink() > 1 && !dir {
+            if let Some(first) = self.linked.get(&id) {
+                return std::fs::hard_link(first, &dst).map_err(self.io(path));
+            }
+            self.linked.insert(id, dst.clone());
+        }
+        create(inode, &dst).map_err(self.io(path))?;
+        self.written.push((path.to_path_buf(), id));
+        Ok(())
+    }
+
+    fn whiteout(&mut self, path: &Path) -> Result<()> {
+        nix::sys::stat::mknod(&self.dir.join(path), SFlag::S_IFCHR, Mode::empty(), 0)
+            .map_err(|e| self.io(path)(e.into()))
+    }
+
+    /// Write `path` as an opaque directory
+    fn opaque(&mut self, path: &Path, id: InodeId) -> Result<()> {
+        self.put(path, id)?;
+        xattr::set(self.dir.join(path), &self.opaque, b"y").map_err(self.io(path))
+    }
+}
+
+/// Write what changed from `old` to `new` as a layer in `dir`
+fn write_layer(dir: &Path, old: &Filesystem, new: &Filesystem, prefix: &str) -> Result<()> {
+    std::fs::create_dir_all(dir).map_err(|error| Error::Io {
+        path: dir.to_path_buf(),
+        error,
+    })?;
+    let mut layer = Layer {
+        dir,
+        new,
+        written: vec![(PathBuf::new(), new.root())],
+        linked: BTreeMap::new(),
+        opaque: format!("{prefix}opaque"),
+    };
+    for entry in layer_entries(old, new) {
+        match entry {
+            LayerEntry::File(path, id) => layer.put(&path, id)?,
+            LayerEntry::Whiteout(path) => layer.whiteout(&path)?,
+            LayerEntry::Opaque(path, id) => layer.opaque(&path, id)?,
+        }
+    }
+    // children first, so that directory times are not disturbed
+    let root = Uid::effective().is_root();
+    for (path, id) in layer.written.iter().rev() {
+        set_metadata(&new[*id], &dir.join(path), root).map_err(layer.io(path))?;
+    }
+    Ok(())
+}
+
+/// [Receiver] that writes each subvolume as an overlayfs layer in a
+/// directory of its own (named after its uuid) under one directory. See the
+/// [module docs](self) for what goes in each layer.
+///
+/// Whiteouts and opaque directories need privileges to create, as does
+/// restoring ownership (which is only done when running as root).
+pub struct OverlayReceiver {
+    dir: PathBuf,
+    memory: MemoryReceiver,
+    /// The subvolume that the current stream started from
+    parent: Option<Filesystem>,
+    prefix: &'static str,
+    layers: Vec<PathBuf>,
+}
+
+impl OverlayReceiver {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            memory: MemoryReceiver::new(),
+            parent: None,
+            prefix: "trusted.overlay.",
+            layers: Vec::new(),
+        }
+    }
+
+    /// Make `parent` available to incremental streams that were sent relative
+    /// to it, whose layers then go on top of a layer of `parent`
+    pub fn parent(mut self, parent: Filesystem) -> Self {
+        self.memory = self.memory.parent(parent);
+        self
+    }
+
+    /// Mark opaque directories with `user.overlay.` xattrs instead of
+    /// `trusted.overlay.` ones, for mounting with `-o userxattr`
+    pub fn user_xattrs(mut self) -> Self {
+        self.prefix = "user.overlay.";
+        self
+    }
+
+    /// Directory of every layer that has been written, in the order that
+    /// they were received (which for a chain is bottom first)
+    pub fn layers(&self) -> &[PathBuf] {
+        &self.layers
+    }
+}
+
+impl Receiver for OverlayReceiver {
+    type Error = Error;
+
+    fn command(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Subvol(_) => self.parent = None,
+            Command::Snapshot(_) => {
+                self.memory.apply(cmd)?;
+                self.parent = self.memory.current().cloned();
+                return Ok(());
+            }
+            _ => (),
+        }
+        let Some(fs) = self.memory.apply(cmd)? else {
+            return Ok(());
+        };
+        let uuid = fs.subvolume().map(|s| s.uuid).unwrap_or_default();
+        let dir = self.dir.join(uuid.to_string());
+        let old = self.parent.take().unwrap_or_default();
+        write_layer(&dir, &old, fs, self.prefix)?;
+        self.layers.push(dir);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::receive::receive_all;
+    use crate::Sendstream;
+
+    #[test]
+    fn overlay_layers() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let fs = Filesystem::from_chain(&sendstreams).expect("failed to replay");
+        let dir = std::env::temp_dir().join(format!("overlay.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut receiver = OverlayReceiver::new(&dir);
+        receive_all(&mut receiver, &sendstreams).expect("failed to receive");
+        let layers = receiver.layers().to_vec();
+        assert_eq!(2, layers.len());
+
+        // the bottom layer is everything
+        let diff = diff_directory(&fs[0], Path::new(""), &layers[0]).expect("failed to diff");
+        assert!(diff.is_empty(), "{diff:?}");
+
+        let upper = &layers[1];
+        for removed in ["to-be-deleted", "dir-to-be-deleted"] {
+            let meta = std::fs::symlink_metadata(upper.join(removed)).expect("no whiteout");
+            assert!(meta.file_type().is_char_device(), "{removed}");
+            assert_eq!(0, meta.rdev());
+        }
+        assert_eq!(
+            b"Goodbye!\n".as_slice(),
+            std::fs::read(upper.join("hello/msg")).expect("msg is not copied up")
+        );
+        // nothing that stayed the same is in there
+        assert!(!upper.join("huge-empty-file").exists());
+        assert!(!upper.join("hello/lorem").exists());
+        std::fs::remove_dir_all(&dir).expect("failed to clean up");
+    }
+}
@@ -0,0 +1,111 @@
+//! Report of the users and groups that a sendstream gives files to, for
+//! checking that they exist on the system that will receive it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use nix::unistd::Gid;
+use nix::unistd::Group;
+use nix::unistd::Uid;
+use nix::unistd::User;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// Every uid and gid that a sendstream [crate::Chown]s files to, see
+/// [Sendstream::ownership]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct OwnershipReport {
+    /// Number of files chowned to each uid
+    pub uids: BTreeMap<u32, u64>,
+    /// Number of files chowned to each gid
+    pub gids: BTreeMap<u32, u64>,
+}
+
+impl OwnershipReport {
+    /// Uids that have no user in the user database of the running system
+    pub fn unknown_uids(&self) -> nix::Result<Vec<u32>> {
+        let mut unknown = Vec::new();
+        for uid in self.uids.keys() {
+            if User::from_uid(Uid::from_raw(*uid))?.is_none() {
+                unknown.push(*uid);
+            }
+        }
+        Ok(unknown)
+    }
+
+    /// Gids that have no group in the group database of the running system
+    pub fn unknown_gids(&self) -> nix::Result<Vec<u32>> {
+        let mut unknown = Vec::new();
+        for gid in self.gids.keys() {
+            if Group::from_gid(Gid::from_raw(*gid))?.is_none() {
+                unknown.push(*gid);
+            }
+        }
+        Ok(unknown)
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Count the files that are [crate::Chown]ed to each uid and gid. Files
+    /// are identified through all of their names (see
+    /// [Sendstream::final_paths]), so a file that is chowned more than once
+    /// is only counted once for each id, and files that do not survive to
+    /// the end of the stream are counted too.
+    pub fn ownership(&self) -> OwnershipReport {
+        let finals = self.final_paths();
+        let mut uids: BTreeMap<u32, BTreeSet<usize>> = BTreeMap::new();
+        let mut gids: BTreeMap<u32, BTreeSet<usize>> = BTreeMap::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let (Command::Chown(c), Some(inode)) = (cmd, finals.inode(index)) else {
+                continue;
+            };
+            uids.entry(c.uid.as_raw()).or_default().insert(inode);
+            gids.entry(c.gid.as_raw()).or_default().insert(inode);
+        }
+        let count = |ids: BTreeMap<u32, BTreeSet<usize>>| {
+            ids.into_iter()
+                .map(|(id, files)| (id, files.len() as u64))
+                .collect()
+        };
+        OwnershipReport {
+            uids: count(uids),
+            gids: count(gids),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ownership_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let report = sendstreams[0].ownership();
+        let chowned = sendstreams[0]
+            .files()
+            .iter()
+            .filter(|f| {
+                f.commands
+                    .iter()
+                    .any(|i| matches!(sendstreams[0].commands()[*i], Command::Chown(_)))
+            })
+            .count() as u64;
+        // everything in the demo belongs to root
+        assert_eq!(BTreeMap::from([(0, chowned)]), report.uids);
+        assert_eq!(BTreeMap::from([(0, chowned)]), report.gids);
+        assert_eq!(Vec::<u32>::new(), report.unknown_uids().expect("lookup"));
+
+        let report = OwnershipReport {
+            uids: BTreeMap::from([(0, 1), (4000000000, 1)]),
+            gids: BTreeMap::from([(4000000000, 1)]),
+        };
+        assert_eq!(vec![4000000000], report.unknown_uids().expect("lookup"));
+        assert_eq!(vec![4000000000], report.unknown_gids().expect("lookup"));
+    }
+}
@@ -0,0 +1,58 @@
+//! Identify a sendstream without parsing all of it.
+
+use std::io::Read;
+
+use crate::fs::SubvolumeInfo;
+use crate::CommandReader;
+use crate::Error;
+use crate::Result;
+use crate::Sendstream;
+
+/// Read just the stream header and the first command of a sendstream to find
+/// out which subvolume it produces (and which parent an incremental
+/// sendstream is relative to). Nothing past the first command is read, so
+/// this is cheap even for huge sendstreams, and `r` is left positioned right
+/// after the first command.
+pub fn peek<R: Read>(r: R) -> Result<'static, SubvolumeInfo> {
+    let first = CommandReader::new(r).next().ok_or(Error::Incomplete)??;
+    SubvolumeInfo::from_command(&first).ok_or(Error::MissingHeader)
+}
+
+impl<'a> Sendstream<'a> {
+    /// Identifying information of the subvolume that this sendstream
+    /// produces, from its first command
+    pub fn subvolume(&self) -> Option<SubvolumeInfo> {
+        self.commands.first().and_then(SubvolumeInfo::from_command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn peek_demo() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let demo = peek(input.as_slice()).expect("failed to peek");
+        assert_eq!(Path::new("demo"), demo.path());
+        assert_eq!(
+            Uuid::parse_str("0fbf2b5f-ff82-a748-8b41-e35aec190b49").expect("valid"),
+            demo.uuid()
+        );
+        assert_eq!(None, demo.parent_uuid());
+
+        let sendstreams = Sendstream::parse_all(input).expect("failed to parse demo.sendstream");
+        let undo = sendstreams[1].to_bytes().expect("failed to encode");
+        // only the start of the stream is needed
+        let undo = peek(&undo[..undo.len() / 2]).expect("failed to peek");
+        assert_eq!(Path::new("demo-undo"), undo.path());
+        assert_eq!(Some(demo.uuid()), undo.parent_uuid());
+        assert_eq!(Some(demo.ctransid()), undo.parent_ctransid());
+
+        assert!(matches!(peek([].as_slice()), Err(Error::Incomplete)));
+    }
+}
@@ -0,0 +1,155 @@
+//! Composable transformations that stream [Command]s from a source (usually a
+//! [crate::CommandReader]) through user-provided functions and into an
+//! [Encoder], without ever collecting the whole sendstream in memory.
+
+use std::io::Write;
+
+use crate::Command;
+use crate::Encoder;
+use crate::Result;
+
+enum Stage<'a, 'f> {
+    Filter(Box<dyn FnMut(&Command<'a>) -> bool + 'f>),
+    Map(Box<dyn FnMut(Command<'a>) -> Command<'a> + 'f>),
+}
+
+/// An ordered sequence of filters and maps that is applied to each command.
+#[derive(Default)]
+pub struct Pipeline<'a, 'f> {
+    stages: Vec<Stage<'a, 'f>>,
+}
+
+impl<'a, 'f> Pipeline<'a, 'f> {
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Drop any command for which `f` returns `false`.
+    pub fn filter(mut self, f: impl FnMut(&Command<'a>) -> bool + 'f) -> Self {
+        self.stages.push(Stage::Filter(Box::new(f)));
+        self
+    }
+
+    /// Replace each command with the result of `f`.
+    pub fn map(mut self, f: impl FnMut(Command<'a>) -> Command<'a> + 'f) -> Self {
+        self.stages.push(Stage::Map(Box::new(f)));
+        self
+    }
+
+    /// Run a single command through every stage, returning `None` if it was
+    /// filtered out.
+    pub fn apply(&mut self, mut cmd: Command<'a>) -> Option<Command<'a>> {
+        for stage in &mut self.stages {
+            match stage {
+                Stage::Filter(f) => {
+                    if !f(&cmd) {
+                        return None;
+                    }
+                }
+                Stage::Map(f) => cmd = f(cmd),
+            }
+        }
+        Some(cmd)
+    }
+
+    /// Send the output of this pipeline to `encoder`.
+    pub fn sink<W: Write>(self, encoder: Encoder<W>) -> Sink<'a, 'f, W> {
+        Sink {
+            pipeline: self,
+            encoder,
+        }
+    }
+}
+
+/// A [Pipeline] connected to an [Encoder].
+pub struct Sink<'a, 'f, W: Write> {
+    pipeline: Pipeline<'a, 'f>,
+    encoder: Encoder<W>,
+}
+
+impl<'a, 'f, W: Write> Sink<'a, 'f, W> {
+    /// Transform `cmd` and write out the result (if any).
+    pub fn write_command(&mut self, cmd: Command<'a>) -> std::io::Result<()> {
+        match self.pipeline.apply(cmd) {
+            Some(cmd) => self.encoder.write_command(&cmd),
+            None => Ok(()),
+        }
+    }
+
+    /// Transform and write every command from `commands`, stopping at the
+    /// first error, then return the underlying writer.
+    pub fn run<'e, I>(mut self, commands: I) -> Result<'e, W>
+    where
+        I: IntoIterator<Item = Result<'e, Command<'a>>>,
+    {
+        for cmd in commands {
+            self.write_command(cmd?)?;
+        }
+        Ok(self.into_inner())
+    }
+
+    /// Get the underlying writer back.
+    pub fn into_inner(self) -> W {
+        self.encoder.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Uid;
+
+    use super::*;
+    use crate::CommandReader;
+    use crate::Sendstream;
+
+    #[test]
+    fn pipeline() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let mut count = 0;
+        let out = Pipeline::new()
+            .filter(|c| !matches!(c, Command::SetXattr(_)))
+            .map(|c| match c {
+                Command::Chown(c) => crate::Chown {
+                    uid: Uid::from_raw(1234),
+                    ..c
+                }
+                .into(),
+                c => c,
+            })
+            .map(|c| {
+                count += 1;
+                c
+            })
+            .sink(Encoder::new(Vec::new()).expect("failed to create encoder"))
+            .run(CommandReader::new(&input[..]))
+            .expect("failed to run pipeline");
+
+        let expected: Vec<Sendstream> = Sendstream::parse_all(input)
+            .expect("failed to parse demo.sendstream")
+            .into_iter()
+            .map(|s| Sendstream {
+                commands: s
+                    .into_commands()
+                    .into_iter()
+                    .filter(|c| !matches!(c, Command::SetXattr(_)))
+                    .map(|c| match c {
+                        Command::Chown(c) => crate::Chown {
+                            uid: Uid::from_raw(1234),
+                            ..c
+                        }
+                        .into(),
+                        c => c,
+                    })
+                    .collect(),
+            })
+            .collect();
+        assert_eq!(
+            expected,
+            Sendstream::parse_all(&out).expect("failed to parse pipeline output")
+        );
+        assert_eq!(
+            expected.iter().map(|s| s.commands.len()).sum::<usize>(),
+            count
+        );
+    }
+}
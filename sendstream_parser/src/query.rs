@@ -0,0 +1,127 @@
+//! Find the commands that affect paths matching a glob.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use glob::MatchOptions;
+use glob::Pattern;
+pub use glob::PatternError;
+
+use crate::Command;
+use crate::Sendstream;
+
+/// A command that affects a path matching the query, see [Sendstream::query]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryMatch<'s, 'a> {
+    /// Index of the command in the sendstream
+    pub index: usize,
+    pub command: &'s Command<'a>,
+    /// Path that matched the query
+    pub path: PathBuf,
+}
+
+/// Path that a command removes, which has no final path
+pub(crate) fn removed_path<'c>(cmd: &'c Command) -> Option<&'c Path> {
+    match cmd {
+        Command::Unlink(u) => Some(&u.path),
+        Command::Rmdir(r) => Some(&r.path),
+        _ => None,
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Every command (in stream order) that affects a file whose final path
+    /// matches the glob `pattern`, like `/etc/**` or `usr/lib/*.so`. Paths
+    /// are relative to the root of the subvolume, so a leading `/` in the
+    /// pattern is ignored. `*` and `?` do not match `/`, but `**` matches
+    /// any number of directories.
+    ///
+    /// Commands are matched by their final path (see
+    /// [Sendstream::final_paths]), so temporary names and renames are taken
+    /// care of. [crate::Unlink] and [crate::Rmdir] of files that do not
+    /// survive to the end of the stream are matched by the path they remove.
+    pub fn query(&self, pattern: &str) -> Result<Vec<QueryMatch<'_, 'a>>, PatternError> {
+        self.query_any(&[pattern])
+    }
+
+    /// Like [Sendstream::query], but matching commands that affect a path
+    /// matching any of `patterns`
+    pub fn query_any(&self, patterns: &[&str]) -> Result<Vec<QueryMatch<'_, 'a>>, PatternError> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Pattern::new(p.trim_start_matches('/')))
+            .collect::<Result<Vec<_>, _>>()?;
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        Ok(self
+            .resolved()
+            .enumerate()
+            .filter_map(|(index, (command, path))| {
+                let path = path.or_else(|| removed_path(command).map(Path::to_path_buf))?;
+                patterns
+                    .iter()
+                    .any(|p| p.matches_path_with(&path, options))
+                    .then_some(QueryMatch {
+                        index,
+                        command,
+                        path,
+                    })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn query() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let paths =
+            |matches: Vec<QueryMatch>| matches.into_iter().map(|m| m.path).collect::<BTreeSet<_>>();
+        assert_eq!(
+            BTreeSet::from([
+                PathBuf::from("hello/lorem"),
+                PathBuf::from("hello/lorem-reflinked"),
+                PathBuf::from("hello/msg"),
+                PathBuf::from("hello/msg-hard"),
+                PathBuf::from("hello/msg-sym"),
+            ]),
+            paths(sendstreams[0].query("/hello/**").expect("valid glob"))
+        );
+        assert_eq!(
+            BTreeSet::from([PathBuf::from("hello/msg"), PathBuf::from("hello/msg-hard")]),
+            paths(
+                sendstreams[0]
+                    .query_any(&["hello/msg", "*/*-hard"])
+                    .expect("valid glob")
+            )
+        );
+        // * does not cross directories
+        assert!(sendstreams[0]
+            .query("hello*")
+            .expect("valid glob")
+            .iter()
+            .all(|m| m.path == Path::new("hello")));
+
+        let writes = sendstreams[0].query("hello/lorem").expect("valid glob");
+        assert!(writes
+            .iter()
+            .all(|m| std::ptr::eq(m.command, &sendstreams[0].commands()[m.index])));
+        assert!(writes
+            .iter()
+            .any(|m| matches!(m.command, Command::Mkfile(_))));
+
+        let removed = sendstreams[1].query("to-be-deleted").expect("valid glob");
+        assert_eq!(1, removed.len());
+        assert!(matches!(removed[0].command, Command::Unlink(_)));
+
+        assert!(sendstreams[0].query("[").is_err());
+    }
+}
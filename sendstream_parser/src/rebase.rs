@@ -0,0 +1,220 @@
+//! Rewrite an incremental sendstream so that it can be received on top of a
+//! different parent subvolume.
+
+use std::borrow::Cow;
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::incremental::MAX_WRITE_LEN;
+use crate::Command;
+use crate::Sendstream;
+
+impl<'a> Sendstream<'a> {
+    /// Rewrite this incremental sendstream so that it refers to `new_parent`
+    /// instead of `old_parent` (the subvolume it was originally sent against).
+    ///
+    /// The [crate::Snapshot] header and every [crate::Clone] out of
+    /// `old_parent` are changed to reference `new_parent`. If the cloned range
+    /// does not have identical contents in `new_parent`, the clone is replaced
+    /// by [crate::Write]s of the data from `old_parent` instead.
+    ///
+    /// This does not check that the rest of `new_parent` matches `old_parent`,
+    /// it is up to the caller to make sure that the new parent is a suitable
+    /// base for the changes in this sendstream.
+    pub fn rebase(
+        &self,
+        old_parent: &Filesystem,
+        new_parent: &Filesystem,
+    ) -> fs::Result<Sendstream<'a>> {
+        let old = old_parent.subvolume().ok_or(fs::Error::MissingHeader)?;
+        let new = new_parent.subvolume().ok_or(fs::Error::MissingHeader)?;
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            match cmd {
+                Command::Snapshot(s) => {
+                    if s.clone_uuid != old.uuid() {
+                        return Err(fs::Error::WrongParent {
+                            expected: s.clone_uuid,
+                            actual: Some(old.uuid()),
+                        });
+                    }
+                    commands.push(
+                        crate::Snapshot {
+                            clone_uuid: new.uuid(),
+                            clone_ctransid: new.ctransid(),
+                            ..s.clone()
+                        }
+                        .into(),
+                    );
+                }
+                Command::Clone(c) if c.uuid == old.uuid() => {
+                    let src = old_parent
+                        .get(&c.src_path)
+                        .and_then(fs::Inode::contents)
+                        .ok_or_else(|| fs::Error::NotAFile(c.src_path.to_path_buf()))?;
+                    let available = new_parent
+                        .get(&c.src_path)
+                        .and_then(fs::Inode::contents)
+                        .is_some_and(|n| same_range(src, n, c.src_offset.0, c.len.0));
+                    if available {
+                        commands.push(
+                            crate::Clone {
+                                uuid: new.uuid(),
+                                ctransid: new.ctransid(),
+                                ..c.clone()
+                            }
+                            .into(),
+                        );
+                    } else {
+                        materialize(c, src, &mut commands);
+                    }
+                }
+                _ => commands.push(cmd.clone()),
+            }
+        }
+        Ok(Sendstream { commands })
+    }
+}
+
+/// Check that `[offset, offset+len)` reads back the same in both files
+fn same_range(a: &fs::FileContents, b: &fs::FileContents, offset: u64, len: u64) -> bool {
+    let end = offset.saturating_add(len);
+    let mut off = offset;
+    while off < end {
+        let chunk = MAX_WRITE_LEN.min(end - off);
+        if a.read(off, chunk) != b.read(off, chunk) {
+            return false;
+        }
+        off += chunk;
+    }
+    true
+}
+
+/// Replace a clone with writes of the data that it would have copied
+fn materialize<'a>(c: &crate::Clone<'a>, src: &fs::FileContents, out: &mut Vec<Command<'a>>) {
+    let end = c.src_offset.0.saturating_add(c.len.0).min(src.len());
+    let mut off = c.src_offset.0;
+    while off < end {
+        let chunk = MAX_WRITE_LEN.min(end - off);
+        out.push(
+            crate::Write {
+                path: c.dst_path.clone(),
+                offset: crate::FileOffset(c.dst_offset.0 + (off - c.src_offset.0)),
+                data: crate::Data(Cow::Owned(src.read(off, chunk))),
+            }
+            .into(),
+        );
+        off += chunk;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn with_uuid<'a>(s: &Sendstream<'a>, uuid: Uuid) -> Sendstream<'a> {
+        let mut s = s.clone();
+        let mut old = None;
+        for cmd in &mut s.commands {
+            match cmd {
+                Command::Subvol(sv) => old = Some(std::mem::replace(&mut sv.uuid, uuid)),
+                Command::Clone(c) if Some(c.uuid) == old => c.uuid = uuid,
+                _ => (),
+            }
+        }
+        s
+    }
+
+    /// Incremental stream that creates a copy of hello/lorem by cloning it
+    /// from the parent
+    fn clone_lorem(parent: &Filesystem) -> Sendstream<'static> {
+        let sub = parent.subvolume().expect("parent is a full subvol");
+        let path = |p: &str| Cow::Owned(Path::new(p).to_path_buf());
+        Sendstream {
+            commands: vec![
+                crate::Snapshot {
+                    path: path("copy"),
+                    uuid: Uuid::from_u128(2),
+                    ctransid: crate::Ctransid(2),
+                    clone_uuid: sub.uuid(),
+                    clone_ctransid: sub.ctransid(),
+                }
+                .into(),
+                crate::Mkfile {
+                    path: crate::TemporaryPath(path("o300-1-0")),
+                    ino: crate::Ino(300),
+                }
+                .into(),
+                crate::Rename {
+                    from: path("o300-1-0"),
+                    to: path("lorem-copy"),
+                }
+                .into(),
+                crate::Clone {
+                    src_offset: crate::FileOffset(0),
+                    len: crate::CloneLen(131072),
+                    src_path: path("hello/lorem"),
+                    uuid: sub.uuid(),
+                    ctransid: sub.ctransid(),
+                    dst_path: path("lorem-copy"),
+                    dst_offset: crate::FileOffset(0),
+                }
+                .into(),
+                Command::End,
+            ],
+        }
+    }
+
+    #[test]
+    fn rebase() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let stream = clone_lorem(&demo);
+        let expected = Filesystem::from_incremental(&demo, &stream).expect("failed to replay");
+
+        // identical content, so the clone is kept
+        let same = Filesystem::from_sendstream(&with_uuid(&sendstreams[0], Uuid::from_u128(3)))
+            .expect("failed to replay");
+        let rebased = stream.rebase(&demo, &same).expect("failed to rebase");
+        assert!(matches!(
+            &rebased.commands[3],
+            Command::Clone(c) if c.uuid == Uuid::from_u128(3)
+        ));
+        let received = Filesystem::from_incremental(&same, &rebased).expect("failed to replay");
+        assert_eq!(
+            expected
+                .get(Path::new("lorem-copy"))
+                .map(fs::Inode::contents),
+            received
+                .get(Path::new("lorem-copy"))
+                .map(fs::Inode::contents),
+        );
+
+        // hello/lorem is missing from the new parent, so the data gets written
+        let mut missing = with_uuid(&sendstreams[0], Uuid::from_u128(4));
+        missing.commands.retain(|c| match c {
+            Command::Write(w) => w.path() != Path::new("hello/lorem"),
+            _ => true,
+        });
+        let missing = Filesystem::from_sendstream(&missing).expect("failed to replay");
+        let rebased = stream.rebase(&demo, &missing).expect("failed to rebase");
+        assert!(!rebased
+            .commands
+            .iter()
+            .any(|c| matches!(c, Command::Clone(_))));
+        let received = Filesystem::from_incremental(&missing, &rebased).expect("failed to replay");
+        assert_eq!(
+            expected
+                .get(Path::new("lorem-copy"))
+                .map(fs::Inode::contents),
+            received
+                .get(Path::new("lorem-copy"))
+                .map(fs::Inode::contents),
+        );
+    }
+}
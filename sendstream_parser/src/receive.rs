@@ -0,0 +1,308 @@
+//! Pluggable backends for replaying sendstreams. A [Receiver] gets every
+//! command as it is parsed, so the same driver can land a stream in a
+//! directory ([crate::apply::Applier]), a btrfs subvolume, a database or a
+//! virtual filesystem.
+
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::Chmod;
+use crate::Chown;
+use crate::Clone;
+use crate::Command;
+use crate::EncodedWrite;
+use crate::Fallocate;
+use crate::Fileattr;
+use crate::Link;
+use crate::Mkdir;
+use crate::Mkfifo;
+use crate::Mkfile;
+use crate::Mknod;
+use crate::Mksock;
+use crate::RemoveXattr;
+use crate::Rename;
+use crate::Rmdir;
+use crate::Sendstream;
+use crate::SetXattr;
+use crate::Snapshot;
+use crate::Subvol;
+use crate::Symlink;
+use crate::Truncate;
+use crate::Unlink;
+use crate::UpdateExtent;
+use crate::Utimes;
+use crate::Write;
+
+macro_rules! receiver {
+    ($($(#[$doc:meta])* $f:ident($t:ident)),+ $(,)?) => {
+        /// Has one method per [Command] type, each of which does nothing by
+        /// default, plus hooks for the start and end of each stream (which
+        /// are [Receiver::subvol] or [Receiver::snapshot], and
+        /// [Receiver::end]) and for the end of the input. Implementors
+        /// override the ones they care about and pass themselves to
+        /// [receive] or [receive_all].
+        pub trait Receiver {
+            /// Parse errors are turned into this
+            type Error: From<crate::Error<'static>>;
+
+            $(
+                $(#[$doc])*
+                fn $f(&mut self, _cmd: &$t) -> Result<(), Self::Error> {
+                    Ok(())
+                }
+            )+
+
+            /// End of one stream, after which another one may follow
+            fn end(&mut self) -> Result<(), Self::Error> {
+                Ok(())
+            }
+
+            /// There are no more commands
+            fn close(&mut self) -> Result<(), Self::Error> {
+                Ok(())
+            }
+
+            /// Handle any command, which goes to the matching method unless
+            /// this is overridden
+            fn command(&mut self, cmd: &Command) -> Result<(), Self::Error> {
+                dispatch(self, cmd)
+            }
+        }
+
+        /// Pass a single command to the matching method of `receiver`
+        pub fn dispatch<R: Receiver + ?Sized>(
+            receiver: &mut R,
+            cmd: &Command,
+        ) -> Result<(), R::Error> {
+            match cmd {
+                $(Command::$t(c) => receiver.$f(c),)+
+                Command::End => receiver.end(),
+            }
+        }
+    };
+}
+
+receiver!(
+    chmod(Chmod),
+    chown(Chown),
+    /// Named so that it does not clash with [std::clone::Clone::clone]
+    clone_range(Clone),
+    encoded_write(EncodedWrite),
+    fallocate(Fallocate),
+    fileattr(Fileattr),
+    link(Link),
+    mkdir(Mkdir),
+    mkfifo(Mkfifo),
+    mkfile(Mkfile),
+    mknod(Mknod),
+    mksock(Mksock),
+    remove_xattr(RemoveXattr),
+    rename(Rename),
+    rmdir(Rmdir),
+    set_xattr(SetXattr),
+    /// Start of an incremental stream
+    snapshot(Snapshot),
+    /// Start of a full stream
+    subvol(Subvol),
+    symlink(Symlink),
+    truncate(Truncate),
+    unlink(Unlink),
+    update_extent(UpdateExtent),
+    utimes(Utimes),
+    write(Write),
+);
+
+/// Feed every command from `commands` (for example a [crate::CommandReader],
+/// so that nothing but the current command is held in memory) to
+/// `receiver` and then [Receiver::close] it, stopping at the first error.
+pub fn receive<'e, 'a, R, I>(receiver: &mut R, commands: I) -> Result<(), R::Error>
+where
+    R: Receiver + ?Sized,
+    I: IntoIterator<Item = crate::Result<'e, Command<'a>>>,
+{
+    for cmd in commands {
+        receiver.command(&cmd.map_err(crate::Error::into_owned)?)?;
+    }
+    receiver.close()
+}
+
+/// [receive] sendstreams that have already been parsed, in order
+pub fn receive_all<R: Receiver + ?Sized>(
+    receiver: &mut R,
+    sendstreams: &[Sendstream],
+) -> Result<(), R::Error> {
+    for cmd in sendstreams.iter().flat_map(Sendstream::commands) {
+        receiver.command(cmd)?;
+    }
+    receiver.close()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+}
+
+/// [Receiver] that replays every stream into a [Filesystem], so that code
+/// which generates sendstreams can be tested against the filesystem it
+/// should produce without touching disk. Each incremental stream starts from
+/// whichever earlier subvolume (or [MemoryReceiver::parent]) it was sent
+/// relative to, and can clone from any of them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReceiver {
+    parents: Vec<Filesystem>,
+    received: Vec<Filesystem>,
+    current: Option<Filesystem>,
+}
+
+impl MemoryReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `parent` available to incremental streams that were sent relative
+    /// to it
+    pub fn parent(mut self, parent: Filesystem) -> Self {
+        self.parents.push(parent);
+        self
+    }
+
+    /// Every subvolume that has been received completely, in order
+    pub fn subvolumes(&self) -> &[Filesystem] {
+        &self.received
+    }
+
+    pub fn into_subvolumes(self) -> Vec<Filesystem> {
+        self.received
+    }
+
+    /// Subvolume of the stream that is being received, if it has not ended
+    pub fn current(&self) -> Option<&Filesystem> {
+        self.current.as_ref()
+    }
+
+    /// Replay a single command, returning the subvolume that it finished if
+    /// it was the end of a stream
+    pub fn apply(&mut self, cmd: &Command) -> fs::Result<Option<&Filesystem>> {
+        match cmd {
+            Command::Subvol(_) => self.current = Some(Filesystem::new()),
+            Command::Snapshot(s) => {
+                let uuid = |fs: &Filesystem| fs.subvolume().map(|s| s.uuid);
+                let parent = self
+                    .received
+                    .iter()
+                    .rev()
+                    .chain(self.parents.iter().rev())
+                    .find(|fs| uuid(fs) == Some(s.clone_uuid()))
+                    .ok_or_else(|| fs::Error::WrongParent {
+                        expected: s.clone_uuid(),
+                        actual: self.received.last().and_then(uuid),
+                    })?;
+                self.current = Some(parent.clone());
+            }
+            Command::End => {
+                let fs = self.current.take().ok_or(fs::Error::MissingHeader)?;
+                self.received.push(fs);
+                return Ok(self.received.last());
+            }
+            _ => (),
+        }
+        let fs = self.current.as_mut().ok_or(fs::Error::MissingHeader)?;
+        let sources: Vec<_> = self.received.iter().chain(&self.parents).collect();
+        fs.apply_with_sources(cmd, &sources)?;
+        Ok(None)
+    }
+}
+
+impl Receiver for MemoryReceiver {
+    type Error = Error;
+
+    fn command(&mut self, cmd: &Command) -> Result<(), Error> {
+        self.apply(cmd)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::CommandReader;
+
+    #[derive(Default)]
+    struct Files {
+        created: Vec<PathBuf>,
+        streams: usize,
+        closed: bool,
+    }
+
+    impl Receiver for Files {
+        type Error = crate::Error<'static>;
+
+        fn mkfile(&mut self, cmd: &Mkfile) -> Result<(), Self::Error> {
+            self.created.push(cmd.path().to_path_buf());
+            Ok(())
+        }
+
+        fn end(&mut self) -> Result<(), Self::Error> {
+            self.streams += 1;
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<(), Self::Error> {
+            self.closed = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn receive_demo() {
+        let bytes = include_bytes!("../testdata/demo.sendstream");
+        let sendstreams = Sendstream::parse_all(bytes).expect("failed to parse demo.sendstream");
+        let mut parsed = Files::default();
+        receive_all(&mut parsed, &sendstreams).expect("failed to receive");
+        let mut read = Files::default();
+        receive(&mut read, CommandReader::new(&bytes[..])).expect("failed to receive");
+        assert_eq!(parsed.created, read.created);
+        assert_eq!(
+            sendstreams
+                .iter()
+                .flat_map(Sendstream::commands)
+                .filter(|c| matches!(c, Command::Mkfile(_)))
+                .count(),
+            read.created.len()
+        );
+        assert_eq!(2, read.streams);
+        assert!(read.closed);
+
+        // parse errors end up as the receiver's errors
+        let mut truncated = Files::default();
+        assert!(receive(&mut truncated, CommandReader::new(&bytes[..100])).is_err());
+        assert!(!truncated.closed);
+    }
+
+    #[test]
+    fn memory() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let mut memory = MemoryReceiver::new();
+        receive_all(&mut memory, &sendstreams).expect("failed to receive");
+        assert!(memory.current().is_none());
+        assert_eq!(
+            Filesystem::from_chain(&sendstreams).expect("failed to replay"),
+            memory.subvolumes()
+        );
+
+        // the incremental on its own needs to be given its parent
+        let mut incremental = MemoryReceiver::new();
+        assert!(matches!(
+            receive_all(&mut incremental, &sendstreams[1..]),
+            Err(Error::Fs(fs::Error::WrongParent { .. }))
+        ));
+        let mut incremental = MemoryReceiver::new().parent(memory.subvolumes()[0].clone());
+        receive_all(&mut incremental, &sendstreams[1..]).expect("failed to receive");
+        assert_eq!(memory.subvolumes()[1..], incremental.into_subvolumes());
+    }
+}
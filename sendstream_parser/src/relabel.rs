@@ -0,0 +1,111 @@
+//! Rewrite SELinux labels, for moving subvolumes between systems with
+//! different policies.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::Command;
+use crate::Sendstream;
+use crate::XattrData;
+
+/// What `labels` turns the value of a `security.selinux` xattr into: `None`
+/// if it is removed, and `data` itself if it is not in the table
+pub(crate) fn relabel<'d>(
+    labels: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    data: &'d [u8],
+) -> Option<Cow<'d, [u8]>> {
+    let (label, nul) = match data.strip_suffix(b"\0") {
+        Some(label) => (label, true),
+        None => (data, false),
+    };
+    match labels.get(label) {
+        None => Some(Cow::Borrowed(data)),
+        Some(None) => None,
+        Some(Some(new)) => {
+            let mut data = new.clone();
+            if nul {
+                data.push(0);
+            }
+            Some(Cow::Owned(data))
+        }
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Replace the value of every `security.selinux` xattr according to
+    /// `labels`. A label mapped to `None` is removed entirely (the receiver
+    /// will then apply its default context), and labels that do not appear in
+    /// the table are kept as-is.
+    ///
+    /// Labels are looked up without the trailing NUL that the kernel stores
+    /// them with, and are written back with one if the original had it.
+    pub fn relabel_selinux(&self, labels: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Sendstream<'a> {
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            match cmd {
+                Command::SetXattr(x) if x.name.is_selinux() => match relabel(labels, &x.data) {
+                    None => (),
+                    Some(Cow::Borrowed(_)) => commands.push(cmd.clone()),
+                    Some(Cow::Owned(data)) => {
+                        let mut x = x.clone();
+                        x.data = XattrData(Cow::Owned(data));
+                        commands.push(x.into());
+                    }
+                },
+                _ => commands.push(cmd.clone()),
+            }
+        }
+        Sendstream { commands }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::XattrName;
+
+    fn label(path: &'static str, label: &'static [u8]) -> Command<'static> {
+        crate::SetXattr {
+            path: Cow::Borrowed(Path::new(path)),
+            name: XattrName(Cow::Borrowed(b"security.selinux")),
+            data: XattrData(Cow::Borrowed(label)),
+        }
+        .into()
+    }
+
+    #[test]
+    fn relabel_selinux() {
+        let stream = Sendstream {
+            commands: vec![
+                label("etc", b"system_u:object_r:etc_t:s0\0"),
+                label("tmp", b"system_u:object_r:tmp_t:s0"),
+                label("home", b"system_u:object_r:home_root_t:s0\0"),
+                crate::SetXattr {
+                    path: Cow::Borrowed(Path::new("etc")),
+                    name: XattrName(Cow::Borrowed(b"user.comment")),
+                    data: XattrData(Cow::Borrowed(b"system_u:object_r:etc_t:s0\0")),
+                }
+                .into(),
+                Command::End,
+            ],
+        };
+        let labels = BTreeMap::from([
+            (
+                b"system_u:object_r:etc_t:s0".to_vec(),
+                Some(b"system_u:object_r:container_file_t:s0".to_vec()),
+            ),
+            (b"system_u:object_r:tmp_t:s0".to_vec(), None),
+        ]);
+        assert_eq!(
+            vec![
+                label("etc", b"system_u:object_r:container_file_t:s0\0"),
+                stream.commands[2].clone(),
+                stream.commands[3].clone(),
+                Command::End,
+            ],
+            stream.relabel_selinux(&labels).commands
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Reorder a sendstream into filesystem order, with all the commands for
+//! each file grouped together, for exporters and human-readable dumps that
+//! would rather not deal with the inode order that the kernel sends in.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use crate::fs;
+use crate::index::Role;
+use crate::resolve::FinalPaths;
+use crate::Command;
+use crate::Link;
+use crate::LinkTarget;
+use crate::Sendstream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    /// A [crate::Clone] copies data that is changed again later in the
+    /// stream (or copies from a file that is itself cloned into from the
+    /// destination), so there is no order with each file's commands grouped
+    /// together that copies the same data
+    #[error("cannot reorder {path:?} without changing the data cloned from it")]
+    Unorderable { path: PathBuf },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Commands that affect one inode, by index
+#[derive(Default)]
+struct Group {
+    create: Option<usize>,
+    data: Vec<usize>,
+    metadata: Vec<usize>,
+    /// Inodes that this one clones data from
+    clone_sources: BTreeSet<usize>,
+}
+
+struct Reorder<'s, 'a> {
+    commands: &'s [Command<'a>],
+    finals: FinalPaths,
+    groups: BTreeMap<usize, Group>,
+    /// Path that each inode is created at (the first of its final paths)
+    primary: BTreeMap<usize, PathBuf>,
+    /// Inode at every final path
+    inodes: BTreeMap<PathBuf, usize>,
+    visiting: BTreeSet<usize>,
+    done: BTreeSet<usize>,
+    out: Vec<Command<'a>>,
+}
+
+impl<'s, 'a> Reorder<'s, 'a> {
+    fn is_dir(&self, inode: usize) -> bool {
+        self.primary[&inode].as_os_str().is_empty()
+            || self.groups[&inode]
+                .create
+                .is_some_and(|i| matches!(self.commands[i], Command::Mkdir(_)))
+    }
+
+    /// Copy of the command at `index`, changed to operate on final paths
+    fn rewritten(&self, index: usize) -> Command<'a> {
+        let mut cmd = self.commands[index].clone();
+        let inode = self.finals.inode(index).map(|i| &self.primary[&i]);
+        let source = self
+            .finals
+            .clone_source(index)
+            .and_then(|p| self.inodes.get(p))
+            .map(|i| &self.primary[i]);
+        let mut paths = cmd.paths_mut();
+        if let (Command::Clone(_), Some(source)) = (&self.commands[index], source) {
+            *paths[0] = Cow::Owned(source.clone());
+        }
+        if let (Some(path), Some(last)) = (inode, paths.last_mut()) {
+            **last = Cow::Owned(path.clone());
+        }
+        cmd
+    }
+
+    fn emit(&mut self, indices: impl IntoIterator<Item = usize>) {
+        for index in indices {
+            let cmd = self.rewritten(index);
+            self.out.push(cmd);
+        }
+    }
+
+    /// Emit the creation and data of `inode`, after everything it depends
+    /// on. Directory metadata is left until everything beneath them has been
+    /// created, since that would change their mtime again.
+    fn visit(&mut self, inode: usize) -> Result<()> {
+        if self.done.contains(&inode) {
+            return Ok(());
+        }
+        let path = self.primary[&inode].clone();
+        if !self.visiting.insert(inode) {
+            return Err(Error::Unorderable { path });
+        }
+        if let Some(parent) = path.parent().and_then(|p| self.inodes.get(p)) {
+            self.visit(*parent)?;
+        }
+        let sources: Vec<_> = self.groups[&inode]
+            .clone_sources
+            .iter()
+            .copied()
+            .filter(|s| *s != inode)
+            .collect();
+        for source in sources {
+            self.visit(source)?;
+        }
+        let group = &self.groups[&inode];
+        let (create, data) = (group.create, group.data.clone());
+        match create {
+            Some(index) => self.emit([index]),
+            None if path.as_os_str().is_empty() => (),
+            None => return Err(fs::Error::NotFound(path).into()),
+        }
+        self.emit(data);
+        if !self.is_dir(inode) {
+            self.emit(self.groups[&inode].metadata.clone());
+        }
+        self.visiting.remove(&inode);
+        self.done.insert(inode);
+        Ok(())
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Reorder this sendstream so that files are created in path order, each
+    /// directly at its final path and followed by all of its data and then
+    /// all of its metadata (in the same relative order as before).
+    /// Directories are created before anything inside them, but their
+    /// metadata comes after everything inside them. Extra hard links are
+    /// created in path order as well.
+    ///
+    /// Renames are not needed any more and are dropped, as is everything to
+    /// do with files that do not survive to the end of the stream. Files that
+    /// are [crate::Clone]d from are written before any file that clones from
+    /// them, even if that is out of path order.
+    ///
+    /// Only full sendstreams can be reordered, since incrementals depend on
+    /// the order that existing files are moved around in.
+    pub fn reorder(&self) -> Result<Sendstream<'a>> {
+        let finals = self.final_paths();
+        let mut groups: BTreeMap<usize, Group> = BTreeMap::new();
+        let mut header = None;
+        let mut end = None;
+        let mut later_data: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut clones = Vec::new();
+        let uuid = self.subvolume().map(|s| s.uuid());
+        for (index, cmd) in self.commands.iter().enumerate() {
+            match cmd {
+                Command::Snapshot(_) => return Err(fs::Error::Incremental.into()),
+                Command::Subvol(_) => header = Some(index),
+                Command::End => end = Some(index),
+                _ => (),
+            }
+            let (Some(inode), Some(_)) = (finals.inode(index), finals.get(index)) else {
+                continue;
+            };
+            let group = groups.entry(inode).or_default();
+            match (Role::of(cmd), cmd) {
+                (Some(Role::Create), Command::Link(_)) => (),
+                (Some(Role::Create), _) => {
+                    group.create.get_or_insert(index);
+                }
+                (Some(Role::Data), _) => {
+                    group.data.push(index);
+                    later_data.insert(inode, index);
+                }
+                (Some(Role::Metadata), _) => group.metadata.push(index),
+                _ => (),
+            }
+            if let Command::Clone(c) = cmd {
+                if uuid == Some(c.uuid) {
+                    clones.push((index, inode));
+                }
+            }
+        }
+
+        let mut primary = BTreeMap::new();
+        let mut inodes = BTreeMap::new();
+        for inode in groups.keys() {
+            let links = finals.links(*inode);
+            if let Some(first) = links.first() {
+                primary.insert(*inode, first.clone());
+            }
+            for link in links {
+                inodes.insert(link.clone(), *inode);
+            }
+        }
+        for (index, inode) in clones {
+            let Some(source) = finals.clone_source(index).and_then(|p| inodes.get(p)) else {
+                let Command::Clone(c) = &self.commands[index] else {
+                    continue;
+                };
+                return Err(Error::Unorderable {
+                    path: c.src_path.to_path_buf(),
+                });
+            };
+            if *source != inode && later_data.get(source).is_some_and(|last| *last > index) {
+                return Err(Error::Unorderable {
+                    path: primary[source].clone(),
+                });
+            }
+            if let Some(group) = groups.get_mut(&inode) {
+                group.clone_sources.insert(*source);
+            }
+        }
+
+        let mut r = Reorder {
+            commands: &self.commands,
+            finals,
+            groups,
+            primary,
+            inodes,
+            visiting: BTreeSet::new(),
+            done: BTreeSet::new(),
+            out: Vec::with_capacity(self.commands.len()),
+        };
+        r.out.extend(header.map(|i| self.commands[i].clone()));
+        let mut open_dirs: Vec<(PathBuf, usize)> = Vec::new();
+        let paths: Vec<_> = r.inodes.iter().map(|(p, i)| (p.clone(), *i)).collect();
+        for (path, inode) in paths {
+            while let Some((dir, _)) = open_dirs.last() {
+                if path.starts_with(dir) {
+                    break;
+                }
+                if let Some((_, dir)) = open_dirs.pop() {
+                    r.emit(r.groups[&dir].metadata.clone());
+                }
+            }
+            r.visit(inode)?;
+            if r.primary[&inode] != path {
+                r.out.push(
+                    Link {
+                        link_name: Cow::Owned(path),
+                        target: LinkTarget(Cow::Owned(r.primary[&inode].clone())),
+                    }
+                    .into(),
+                );
+            } else if r.is_dir(inode) {
+                open_dirs.push((path, inode));
+            }
+        }
+        while let Some((_, dir)) = open_dirs.pop() {
+            r.emit(r.groups[&dir].metadata.clone());
+        }
+        r.out.extend(end.map(|i| self.commands[i].clone()));
+        Ok(Sendstream { commands: r.out })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::compare::equivalent;
+    use crate::fs::Filesystem;
+
+    #[test]
+    fn reorder_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let reordered = sendstreams[0].reorder().expect("failed to reorder");
+        assert!(equivalent(
+            &Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay"),
+            &Filesystem::from_sendstream(&reordered).expect("failed to replay reordered"),
+        ));
+        assert!(!reordered.commands().iter().any(|c| matches!(
+            c,
+            Command::Rename(_) | Command::Unlink(_) | Command::Rmdir(_)
+        )));
+
+        let created: Vec<&Path> = reordered
+            .commands()
+            .iter()
+            .filter_map(|c| match c {
+                Command::Mkdir(c) => Some(c.path().as_path()),
+                Command::Mkfile(c) => Some(c.path().as_path()),
+                Command::Symlink(c) => Some(c.link_name()),
+                Command::Link(c) => Some(c.link_name()),
+                _ => None,
+            })
+            .collect();
+        let mut sorted = created.clone();
+        sorted.sort();
+        assert_eq!(sorted, created);
+
+        // all of hello/msg's commands are together
+        let msg: Vec<_> = reordered
+            .resolved()
+            .enumerate()
+            .filter(|(_, (_, p))| p.as_deref() == Some(Path::new("hello/msg")))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(msg.len(), msg[msg.len() - 1] - msg[0] + 1);
+
+        assert!(matches!(
+            sendstreams[1].reorder(),
+            Err(Error::Fs(fs::Error::Incremental))
+        ));
+    }
+
+    #[test]
+    fn reorder_clone_of_changed_data() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let mut commands = sendstreams[0].commands().to_vec();
+        // overwrite hello/lorem after hello/lorem-reflinked is cloned from it
+        let end = commands.len() - 1;
+        commands.insert(
+            end,
+            crate::Write {
+                path: Cow::Borrowed(Path::new("hello/lorem")),
+                offset: crate::FileOffset(0),
+                data: crate::Data(Cow::Borrowed(b"overwritten")),
+            }
+            .into(),
+        );
+        let err = Sendstream { commands }
+            .reorder()
+            .expect_err("cannot group lorem");
+        assert!(matches!(err, Error::Unorderable { path } if path == Path::new("hello/lorem")));
+    }
+}
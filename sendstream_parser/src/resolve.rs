@@ -0,0 +1,411 @@
+//! Resolve the temporary names that the kernel creates files under (like
+//! `o257-12-0`) into the paths that those files end up at.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::Ino;
+use crate::Sendstream;
+
+/// Where `path` ends up once `from` is renamed to `to`, if it is at or
+/// beneath `from`. Unlike `to.join("")`, the renamed path itself does not get
+/// a trailing slash.
+pub(crate) fn renamed(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let rel = path.strip_prefix(from).ok()?;
+    if rel.as_os_str().is_empty() {
+        Some(to.to_path_buf())
+    } else {
+        Some(to.join(rel))
+    }
+}
+
+/// Tracks which directory entry (identified by an arbitrary counter) is at
+/// each path, and which inode each entry links to.
+#[derive(Default)]
+struct Entries {
+    paths: BTreeMap<PathBuf, usize>,
+    /// Inode of each entry, indexed by entry
+    inodes: Vec<usize>,
+    next_inode: usize,
+}
+
+impl Entries {
+    /// Entry at `path`, assuming that paths that have not been seen yet
+    /// already existed in the parent subvolume.
+    fn at(&mut self, path: &Path) -> usize {
+        match self.paths.get(path) {
+            Some(e) => *e,
+            None => self.create(path, None),
+        }
+    }
+
+    /// New entry at `path`, linking to `inode` or a brand new inode
+    fn create(&mut self, path: &Path, inode: Option<usize>) -> usize {
+        let inode = inode.unwrap_or_else(|| {
+            self.next_inode += 1;
+            self.next_inode
+        });
+        let entry = self.inodes.len();
+        self.inodes.push(inode);
+        self.paths.insert(path.to_path_buf(), entry);
+        entry
+    }
+
+    /// Every entry at or beneath `dir`
+    fn under(&self, dir: &Path) -> Vec<PathBuf> {
+        self.paths
+            .range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+            .take_while(|(p, _)| p.starts_with(dir))
+            .map(|(p, _)| p.clone())
+            .collect()
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<_> = self
+            .under(from)
+            .into_iter()
+            .filter_map(|p| self.paths.remove(&p).map(|e| (p, e)))
+            .collect();
+        for p in self.under(to) {
+            self.paths.remove(&p);
+        }
+        for (p, e) in moved {
+            let dst = renamed(&p, from, to).unwrap_or(p);
+            self.paths.insert(dst, e);
+        }
+    }
+}
+
+/// The path that the file affected by each command of a [Sendstream] has at
+/// the end of the stream, see [Sendstream::final_paths].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FinalPaths {
+    paths: Vec<Option<PathBuf>>,
+    /// Arbitrary identity of the inode that each command affects
+    inodes: Vec<Option<usize>>,
+    /// Every path that each inode is linked at in the end, in sorted order
+    links: BTreeMap<usize, Vec<PathBuf>>,
+    /// Final path of the source of each [crate::Clone] that copies from the
+    /// subvolume being sent, by command index
+    clone_sources: BTreeMap<usize, PathBuf>,
+}
+
+impl FinalPaths {
+    /// Final path of the file affected by the command at `index`. `None` if
+    /// that file no longer exists at the end of the stream, or if the command
+    /// does not apply to a file (like [Command::End]).
+    pub fn get(&self, index: usize) -> Option<&Path> {
+        self.paths.get(index).and_then(Option::as_deref)
+    }
+
+    /// Final path of every command, in stream order
+    pub fn iter(&self) -> impl Iterator<Item = Option<&Path>> {
+        self.paths.iter().map(Option::as_deref)
+    }
+
+    /// Identity of the inode affected by the command at `index`, which is
+    /// shared by all the commands that affect the same file (through any of
+    /// its hard links).
+    pub(crate) fn inode(&self, index: usize) -> Option<usize> {
+        self.inodes.get(index).copied().flatten()
+    }
+
+    /// Every final path of `inode`, in sorted order
+    pub(crate) fn links(&self, inode: usize) -> &[PathBuf] {
+        self.links.get(&inode).map_or(&[], Vec::as_slice)
+    }
+
+    /// Final path of the file that the [crate::Clone] at `index` copies from,
+    /// if it copies from the subvolume being sent and that file still exists
+    /// at the end of the stream. Clones from other subvolumes (including the
+    /// parent of an incremental) refer to paths in that subvolume instead.
+    pub fn clone_source(&self, index: usize) -> Option<&Path> {
+        self.clone_sources.get(&index).map(PathBuf::as_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+/// Every final path of a file with more than one hard link, see
+/// [Sendstream::hardlinks]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HardlinkGroup {
+    /// Inode number, if the file is created by the sendstream
+    pub ino: Option<Ino>,
+    /// Every path of the file, in sorted order
+    pub paths: Vec<PathBuf>,
+}
+
+/// Iterator over the commands of a [Sendstream] alongside the final path of
+/// the file each one affects, see [Sendstream::resolved].
+pub struct Resolved<'s, 'a> {
+    commands: std::slice::Iter<'s, Command<'a>>,
+    paths: std::vec::IntoIter<Option<PathBuf>>,
+}
+
+impl<'s, 'a> Iterator for Resolved<'s, 'a> {
+    type Item = (&'s Command<'a>, Option<PathBuf>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((self.commands.next()?, self.paths.next()?))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.commands.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Resolved<'_, '_> {}
+
+impl<'a> Sendstream<'a> {
+    /// Iterate over every command in stream order, paired with the final path
+    /// (from [Sendstream::final_paths]) of the file that it affects, so that
+    /// consumers never have to deal with temporary names.
+    ///
+    /// Final paths depend on renames that may come at the very end of the
+    /// stream, so this needs the whole sendstream to have been parsed first.
+    pub fn resolved(&self) -> Resolved<'_, 'a> {
+        Resolved {
+            commands: self.commands.iter(),
+            paths: self.final_paths().paths.into_iter(),
+        }
+    }
+
+    /// Group the final paths of every file that ends up with more than one
+    /// hard link, in order of their first path. Every [crate::Link] adds a
+    /// path to the file that it links to, which is identified by the inode
+    /// number it was created with (if it is created in this sendstream).
+    ///
+    /// For incremental sendstreams, only the links that the sendstream itself
+    /// touches are known, so files in the parent may have more links than are
+    /// listed here (or have links that are not listed at all).
+    pub fn hardlinks(&self) -> Vec<HardlinkGroup> {
+        let finals = self.final_paths();
+        let mut inos = BTreeMap::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            if let (Some(inode), Some(ino)) = (finals.inode(index), cmd.ino()) {
+                inos.insert(inode, ino);
+            }
+        }
+        let mut groups: Vec<_> = finals
+            .links
+            .iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(inode, paths)| HardlinkGroup {
+                ino: inos.get(inode).copied(),
+                paths: paths.clone(),
+            })
+            .collect();
+        groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+        groups
+    }
+
+    /// Work out where the file that each command affects ends up once the
+    /// whole sendstream has been received, following temporary names through
+    /// every [crate::Rename] (including renames of the directories above it).
+    ///
+    /// The affected file is the one being created, changed or moved: the
+    /// destination of a [crate::Clone], the new name of a [crate::Link] and
+    /// the source of a [crate::Rename]. If that directory entry is removed
+    /// later but the file is still reachable through another hard link, the
+    /// (lexicographically) first remaining link is used instead.
+    pub fn final_paths(&self) -> FinalPaths {
+        let mut e = Entries::default();
+        let mut subjects = Vec::with_capacity(self.commands.len());
+        let mut uuid = None;
+        let mut sources = Vec::new();
+        for (index, cmd) in self.commands.iter().enumerate() {
+            let subject = match cmd {
+                Command::Subvol(s) => {
+                    uuid = Some(s.uuid);
+                    None
+                }
+                Command::Snapshot(s) => {
+                    uuid = Some(s.uuid);
+                    None
+                }
+                Command::End => None,
+                Command::Mkdir(c) => Some(e.create(&c.path, None)),
+                Command::Mkfile(c) => Some(e.create(&c.path, None)),
+                Command::Mkfifo(c) => Some(e.create(&c.path, None)),
+                Command::Mknod(c) => Some(e.create(&c.path, None)),
+                Command::Mksock(c) => Some(e.create(&c.path, None)),
+                Command::Symlink(c) => Some(e.create(&c.link_name, None)),
+                Command::Link(c) => {
+                    let target = e.at(&c.target);
+                    Some(e.create(&c.link_name, Some(e.inodes[target])))
+                }
+                Command::Rename(c) => {
+                    let entry = e.at(&c.from);
+                    e.rename(&c.from, &c.to);
+                    Some(entry)
+                }
+                Command::Unlink(c) => {
+                    let entry = e.at(&c.path);
+                    e.paths.remove(c.path.as_ref());
+                    Some(entry)
+                }
+                Command::Rmdir(c) => {
+                    let entry = e.at(&c.path);
+                    e.paths.remove(c.path.as_ref());
+                    Some(entry)
+                }
+                Command::Clone(c) => {
+                    if uuid == Some(c.uuid) {
+                        sources.push((index, e.at(&c.src_path)));
+                    }
+                    Some(e.at(&c.dst_path))
+                }
+                Command::Chmod(c) => Some(e.at(&c.path)),
+                Command::Chown(c) => Some(e.at(&c.path)),
+                Command::EncodedWrite(c) => Some(e.at(&c.path)),
+                Command::Fallocate(c) => Some(e.at(&c.path)),
+                Command::Fileattr(c) => Some(e.at(&c.path)),
+                Command::RemoveXattr(c) => Some(e.at(&c.path)),
+                Command::SetXattr(c) => Some(e.at(&c.path)),
+                Command::Truncate(c) => Some(e.at(&c.path)),
+                Command::UpdateExtent(c) => Some(e.at(&c.path)),
+                Command::Utimes(c) => Some(e.at(&c.path)),
+                Command::Write(c) => Some(e.at(&c.path)),
+            };
+            subjects.push(subject);
+        }
+
+        let mut by_entry = vec![None; e.inodes.len()];
+        let mut links: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+        for (path, entry) in &e.paths {
+            by_entry[*entry] = Some(path.clone());
+            links
+                .entry(e.inodes[*entry])
+                .or_default()
+                .push(path.clone());
+        }
+        let final_path = |entry: usize| {
+            by_entry[entry]
+                .clone()
+                .or_else(|| links.get(&e.inodes[entry]).and_then(|p| p.first().cloned()))
+        };
+        let paths = subjects.iter().map(|s| s.and_then(final_path)).collect();
+        let clone_sources = sources
+            .into_iter()
+            .filter_map(|(index, entry)| Some((index, final_path(entry)?)))
+            .collect();
+        let inodes = subjects
+            .into_iter()
+            .map(|s| s.map(|entry| e.inodes[entry]))
+            .collect();
+        FinalPaths {
+            paths,
+            inodes,
+            links,
+            clone_sources,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::Filesystem;
+
+    #[test]
+    fn final_paths_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let undo = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+
+        for (s, fs) in sendstreams.iter().zip([&demo, &undo]) {
+            let finals = s.final_paths();
+            assert_eq!(s.commands().len(), finals.len());
+            for (cmd, path) in s.commands().iter().zip(finals.iter()) {
+                match cmd {
+                    Command::Subvol(_) | Command::Snapshot(_) | Command::End => {
+                        assert_eq!(None, path)
+                    }
+                    Command::Unlink(_) | Command::Rmdir(_) => (),
+                    _ => {
+                        let path = path.unwrap_or_else(|| panic!("{cmd:?} has no final path"));
+                        assert!(fs.get(path).is_some(), "{path:?} does not exist");
+                    }
+                }
+            }
+        }
+
+        let finals = sendstreams[0].final_paths();
+        assert!(
+            finals
+                .iter()
+                .flatten()
+                .all(|p| !p.as_os_str().to_string_lossy().ends_with('/')),
+            "renamed paths should not have a trailing slash"
+        );
+        let (index, _) = sendstreams[0]
+            .commands()
+            .iter()
+            .enumerate()
+            .find(
+                |(_, c)| matches!(c, Command::Mkfile(m) if m.path().as_os_str() == "o258-720050-0"),
+            )
+            .expect("missing hello/msg");
+        assert_eq!(Some(Path::new("hello/msg")), finals.get(index));
+
+        let finals = sendstreams[1].final_paths();
+        let (index, _) = sendstreams[1]
+            .commands()
+            .iter()
+            .enumerate()
+            .find(
+                |(_, c)| matches!(c, Command::Unlink(u) if u.path() == Path::new("to-be-deleted")),
+            )
+            .expect("missing unlink");
+        assert_eq!(None, finals.get(index));
+    }
+
+    #[test]
+    fn hardlinks() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        assert_eq!(
+            vec![HardlinkGroup {
+                ino: Some(Ino(258)),
+                paths: vec![PathBuf::from("hello/msg"), PathBuf::from("hello/msg-hard")],
+            }],
+            sendstreams[0].hardlinks()
+        );
+        assert!(sendstreams[1].hardlinks().is_empty());
+    }
+
+    #[test]
+    fn resolved() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let resolved: Vec<_> = sendstreams[0].resolved().collect();
+        assert_eq!(sendstreams[0].commands().len(), resolved.len());
+        let finals = sendstreams[0].final_paths();
+        for (i, (cmd, path)) in resolved.into_iter().enumerate() {
+            assert_eq!(&sendstreams[0].commands()[i], cmd);
+            assert_eq!(finals.get(i), path.as_deref());
+            if let Some(path) = path {
+                let temporary = path.iter().any(|c| {
+                    let c = c.to_string_lossy();
+                    c.starts_with('o') && c.matches('-').count() == 2
+                });
+                assert!(!temporary, "{path:?} is a temporary name");
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Rewrite the targets of symlinks and hard links.
+
+use std::borrow::Cow;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::fs;
+use crate::Command;
+use crate::LinkTarget;
+use crate::Sendstream;
+
+/// Which kind of link a target belongs to, see [Sendstream::retarget_links].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkKind {
+    /// Target of a [crate::Symlink], which can be any path at all
+    Symlink,
+    /// Target of a [crate::Link], which is an existing path within the
+    /// subvolume
+    Hardlink,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Rewrite the target of every [crate::Symlink] and [crate::Link] with
+    /// `f`, which is called with the kind of link, its path and its current
+    /// target, and returns the new target (or `None` to leave it as-is).
+    ///
+    /// Every new target is checked so that the result is still a sendstream
+    /// that can be received: symlink targets must not be empty, and hard link
+    /// targets must be plain relative paths within the subvolume. Whether the
+    /// new hard link target actually exists is up to the caller.
+    pub fn retarget_links<F>(&self, mut f: F) -> fs::Result<Sendstream<'a>>
+    where
+        F: FnMut(LinkKind, &Path, &Path) -> Option<PathBuf>,
+    {
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            let mut cmd = cmd.clone();
+            let (kind, link_name, target) = match &mut cmd {
+                Command::Symlink(s) => (LinkKind::Symlink, &s.link_name, &mut s.target),
+                Command::Link(l) => (LinkKind::Hardlink, &l.link_name, &mut l.target),
+                _ => {
+                    commands.push(cmd);
+                    continue;
+                }
+            };
+            if let Some(new) = f(kind, link_name, target.as_path()) {
+                let valid = match kind {
+                    LinkKind::Symlink => !new.as_os_str().is_empty(),
+                    LinkKind::Hardlink => {
+                        new.components().next().is_some()
+                            && new.components().all(|c| matches!(c, Component::Normal(_)))
+                    }
+                };
+                if !valid {
+                    return Err(fs::Error::InvalidPath(new));
+                }
+                *target = LinkTarget(Cow::Owned(new));
+            }
+            commands.push(cmd);
+        }
+        Ok(Sendstream { commands })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &str) -> Cow<'static, Path> {
+        Cow::Owned(PathBuf::from(p))
+    }
+
+    fn symlink(name: &str, target: &str) -> Command<'static> {
+        crate::Symlink {
+            link_name: path(name),
+            ino: crate::Ino(257),
+            target: LinkTarget(path(target)),
+        }
+        .into()
+    }
+
+    #[test]
+    fn retarget_links() {
+        let stream = Sendstream {
+            commands: vec![
+                symlink("lib", "/usr/lib"),
+                symlink("bin/sh", "bash"),
+                crate::Link {
+                    link_name: path("bin/rbash"),
+                    target: LinkTarget(path("bin/bash")),
+                }
+                .into(),
+                Command::End,
+            ],
+        };
+        let chroot = |kind, _: &Path, target: &Path| match kind {
+            LinkKind::Symlink => target
+                .strip_prefix("/")
+                .ok()
+                .map(|t| Path::new("/chroot").join(t)),
+            LinkKind::Hardlink => None,
+        };
+        let retargeted = stream.retarget_links(chroot).expect("valid targets");
+        assert_eq!(
+            vec![
+                symlink("lib", "/chroot/usr/lib"),
+                stream.commands[1].clone(),
+                stream.commands[2].clone(),
+                Command::End,
+            ],
+            retargeted.commands
+        );
+
+        assert!(matches!(
+            stream.retarget_links(|kind, _, target| match kind {
+                LinkKind::Hardlink => Some(Path::new("/").join(target)),
+                LinkKind::Symlink => None,
+            }),
+            Err(fs::Error::InvalidPath(p)) if p == Path::new("/bin/bash")
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+//! Harden sendstreams from untrusted sources before they are received.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::resolve::renamed;
+use crate::Command;
+use crate::Mode;
+use crate::Sendstream;
+
+/// What [sanitize] is allowed to let through unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Keep character and block devices instead of replacing them with empty
+    /// regular files
+    pub allow_devices: bool,
+    /// Keep setuid and setgid bits instead of clearing them
+    pub allow_setuid: bool,
+    /// Largest xattr value that will be kept
+    pub max_xattr_len: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            allow_devices: false,
+            allow_setuid: false,
+            // the most that ext4 (with 4k blocks) can store for a single xattr
+            max_xattr_len: 4096,
+        }
+    }
+}
+
+/// Something in the sendstream that violated the [Policy].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Issue {
+    /// Path was absolute and has been made relative to the subvolume root
+    AbsolutePath(PathBuf),
+    /// Path contained `.` or `..` components that have been resolved
+    DotComponents(PathBuf),
+    /// Path resolves to somewhere outside of the subvolume
+    EscapesRoot(PathBuf),
+    /// Path goes through a symlink created by this sendstream, which the
+    /// receiver would follow (potentially out of the subvolume)
+    ThroughSymlink(PathBuf),
+    /// Character or block device node was replaced by an empty file
+    DeviceNode(PathBuf),
+    /// Setuid/setgid bits were cleared
+    Setuid { path: PathBuf, mode: u32 },
+    /// Xattr value was larger than [Policy::max_xattr_len]
+    OversizedXattr {
+        path: PathBuf,
+        name: Vec<u8>,
+        len: usize,
+    },
+}
+
+/// What was done about an [Issue].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Action {
+    /// The command was changed to comply with the [Policy]
+    Rewritten,
+    /// The command was removed from the sendstream
+    Dropped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Finding {
+    /// Index of the offending command in the original sendstream
+    pub index: usize,
+    pub issue: Issue,
+    pub action: Action,
+}
+
+/// Everything that [sanitize] changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// True if the sendstream was already compliant and nothing was changed
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Lexically resolve `.` and `..` and strip any leading `/`. Returns `None`
+/// if the path would escape the root.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                if !out.pop() {
+                    return None;
+                }
+            }
+            Component::Normal(n) => out.push(n),
+        }
+    }
+    Some(out)
+}
+
+/// Every path of `cmd`, including the name of the subvolume that it creates,
+/// which is relative to the directory that the sendstream is received into
+fn paths_mut<'c, 'a>(cmd: &'c mut Command<'a>) -> Vec<&'c mut Cow<'a, Path>> {
+    match cmd {
+        Command::Subvol(s) => vec![&mut s.path],
+        Command::Snapshot(s) => vec![&mut s.path],
+        cmd => cmd.paths_mut(),
+    }
+}
+
+const SETID: u32 = nix::libc::S_ISUID | nix::libc::S_ISGID;
+
+/// Rewrite or drop anything in `sendstream` that does not comply with
+/// `policy`, returning the sanitized sendstream and a [Report] of every
+/// change. Callers that would rather reject non-compliant sendstreams
+/// outright can check [Report::is_clean].
+pub fn sanitize<'a>(sendstream: &Sendstream<'a>, policy: &Policy) -> (Sendstream<'a>, Report) {
+    let mut report = Report::default();
+    let mut symlinks = BTreeSet::new();
+    let mut commands = Vec::with_capacity(sendstream.commands.len());
+    'cmds: for (index, cmd) in sendstream.commands.iter().enumerate() {
+        let mut cmd = cmd.clone();
+        let mut found = |issue, action| {
+            report.findings.push(Finding {
+                index,
+                issue,
+                action,
+            })
+        };
+
+        for path in paths_mut(&mut cmd) {
+            let Some(normal) = normalize(path) else {
+                found(Issue::EscapesRoot(path.to_path_buf()), Action::Dropped);
+                continue 'cmds;
+            };
+            if normal.as_path() != path.as_ref() {
+                let issue = if path.has_root() {
+                    Issue::AbsolutePath(path.to_path_buf())
+                } else {
+                    Issue::DotComponents(path.to_path_buf())
+                };
+                found(issue, Action::Rewritten);
+                *path = Cow::Owned(normal);
+            }
+            if path.ancestors().skip(1).any(|a| symlinks.contains(a)) {
+                found(Issue::ThroughSymlink(path.to_path_buf()), Action::Dropped);
+                continue 'cmds;
+            }
+        }
+
+        match &mut cmd {
+            Command::Symlink(s) => {
+                symlinks.insert(s.link_name.to_path_buf());
+            }
+            // a hard link to a symlink is just as much of a symlink
+            Command::Link(l) if symlinks.contains(l.target.as_path()) => {
+                symlinks.insert(l.link_name.to_path_buf());
+            }
+            Command::Rename(r) => {
+                let moved: Vec<_> = symlinks
+                    .iter()
+                    .filter(|s| s.starts_with(&r.from))
+                    .cloned()
+                    .collect();
+                symlinks.retain(|s| !s.starts_with(&r.to));
+                for s in moved {
+                    symlinks.remove(&s);
+                    symlinks.insert(renamed(&s, &r.from, &r.to).unwrap_or(s));
+                }
+            }
+            Command::Unlink(u) => {
+                symlinks.remove(u.path.as_ref());
+            }
+            Command::Mknod(n) if !policy.allow_devices => {
+                found(Issue::DeviceNode(n.0.path.to_path_buf()), Action::Rewritten);
+                cmd = crate::Mkfile {
+                    path: n.0.path.clone(),
+                    ino: n.0.ino,
+                }
+                .into();
+            }
+            Command::Chmod(c) if !policy.allow_setuid && c.mode.0 & SETID != 0 => {
+                found(
+                    Issue::Setuid {
+                        path: c.path.to_path_buf(),
+                        mode: c.mode.0,
+                    },
+                    Action::Rewritten,
+                );
+                c.mode = Mode(c.mode.0 & !SETID);
+            }
+            Command::SetXattr(x) if x.data.len() > policy.max_xattr_len => {
+                found(
+                    Issue::OversizedXattr {
+                        path: x.path.to_path_buf(),
+                        name: x.name.to_vec(),
+                        len: x.data.len(),
+                    },
+                    Action::Dropped,
+                );
+                continue 'cmds;
+            }
+            _ => (),
+        }
+        commands.push(cmd);
+    }
+    (Sendstream { commands }, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &str) -> Cow<'static, Path> {
+        Cow::Owned(PathBuf::from(p))
+    }
+
+    #[test]
+    fn sanitize_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let (sanitized, report) = sanitize(&sendstreams[0], &Policy::default());
+        assert_eq!(1, report.findings.len());
+        assert!(matches!(
+            &report.findings[0],
+            Finding { issue: Issue::DeviceNode(p), action: Action::Rewritten, .. } if p == Path::new("o266-720050-0")
+        ));
+        assert!(!sanitized
+            .commands()
+            .iter()
+            .any(|c| matches!(c, Command::Mknod(_))));
+
+        let (unchanged, report) = sanitize(
+            &sendstreams[0],
+            &Policy {
+                allow_devices: true,
+                ..Default::default()
+            },
+        );
+        assert!(report.is_clean());
+        assert_eq!(sendstreams[0], unchanged);
+    }
+
+    #[test]
+    fn sanitize_malicious() {
+        let stream = Sendstream {
+            commands: vec![
+                crate::Subvol {
+                    path: path("/demo"),
+                    uuid: uuid::Uuid::nil(),
+                    ctransid: crate::Ctransid(1),
+                }
+                .into(),
+                crate::Symlink {
+                    link_name: path("etc"),
+                    ino: crate::Ino(257),
+                    target: crate::LinkTarget(path("/etc")),
+                }
+                .into(),
+                crate::Link {
+                    link_name: path("etc2"),
+                    target: crate::LinkTarget(path("etc")),
+                }
+                .into(),
+                crate::Write {
+                    path: path("etc2/shadow"),
+                    offset: crate::FileOffset(0),
+                    data: crate::Data(Cow::Borrowed(b"root::0:0:::::\n")),
+                }
+                .into(),
+                crate::Write {
+                    path: path("etc/passwd"),
+                    offset: crate::FileOffset(0),
+                    data: crate::Data(Cow::Borrowed(b"root::0:0::/:/bin/sh\n")),
+                }
+                .into(),
+                crate::Unlink {
+                    path: path("../../etc/shadow"),
+                }
+                .into(),
+                crate::Chmod {
+                    path: path("/bin/./sh"),
+                    mode: Mode(0o4755),
+                }
+                .into(),
+                Command::End,
+            ],
+        };
+        let (sanitized, report) = sanitize(&stream, &Policy::default());
+        assert_eq!(
+            vec![
+                Finding {
+                    index: 0,
+                    issue: Issue::AbsolutePath(PathBuf::from("/demo")),
+                    action: Action::Rewritten,
+                },
+                Finding {
+                    index: 3,
+                    issue: Issue::ThroughSymlink(PathBuf::from("etc2/shadow")),
+                    action: Action::Dropped,
+                },
+                Finding {
+                    index: 4,
+                    issue: Issue::ThroughSymlink(PathBuf::from("etc/passwd")),
+                    action: Action::Dropped,
+                },
+                Finding {
+                    index: 5,
+                    issue: Issue::EscapesRoot(PathBuf::from("../../etc/shadow")),
+                    action: Action::Dropped,
+                },
+                Finding {
+                    index: 6,
+                    issue: Issue::AbsolutePath(PathBuf::from("/bin/./sh")),
+                    action: Action::Rewritten,
+                },
+                Finding {
+                    index: 6,
+                    issue: Issue::Setuid {
+                        path: PathBuf::from("bin/sh"),
+                        mode: 0o4755,
+                    },
+                    action: Action::Rewritten,
+                },
+            ],
+            report.findings
+        );
+        assert_eq!(
+            vec![
+                crate::Subvol {
+                    path: path("demo"),
+                    uuid: uuid::Uuid::nil(),
+                    ctransid: crate::Ctransid(1),
+                }
+                .into(),
+                stream.commands[1].clone(),
+                stream.commands[2].clone(),
+                crate::Chmod {
+                    path: path("bin/sh"),
+                    mode: Mode(0o755),
+                }
+                .into(),
+                Command::End,
+            ],
+            sanitized.commands
+        );
+    }
+}
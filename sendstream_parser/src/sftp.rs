@@ -0,0 +1,1018 @@
+//! [SftpReceiver], which applies sendstreams to a directory on another host
+//! over SFTP, for hosts that have no btrfs tooling (or no way to run this
+//! crate) but do run an SSH server.
+//!
+//! This speaks version 3 of the SFTP protocol, which is what OpenSSH
+//! implements, over any pair of pipes: usually the standard input and output
+//! of `ssh -s <host> sftp` (see [SftpReceiver::ssh]), but a local
+//! `sftp-server` works just as well. SFTP has no way to create device nodes,
+//! fifos or sockets or to set xattrs, so those are left out, and times only
+//! have a resolution of seconds.
+
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::ChildStdout;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::receive::Receiver;
+use crate::resolve::renamed;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error("SFTP connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("SFTP server sent a malformed reply: {0}")]
+    Protocol(&'static str),
+    #[error("SFTP server failed on {path:?} with status {code}: {message}")]
+    Status {
+        path: PathBuf,
+        code: u32,
+        message: String,
+    },
+    #[error("SFTP server does not support {0}")]
+    Unsupported(&'static str),
+    #[error("clone source subvolume {0} is not on the server")]
+    CloneSource(Uuid),
+    #[error("data written to {0:?} is compressed or encrypted")]
+    Undecodable(PathBuf),
+    #[error("{0:?} is outside of the directory that is received into")]
+    Outside(PathBuf),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const VERSION: u32 = 3;
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_RMDIR: u8 = 15;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_SYMLINK: u8 = 20;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_ATTRS: u8 = 105;
+const FXP_EXTENDED: u8 = 200;
+
+const FXF_READ: u32 = 0x1;
+const FXF_WRITE: u32 = 0x2;
+const FXF_CREAT: u32 = 0x8;
+const FXF_EXCL: u32 = 0x20;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+const HARDLINK: &str = "hardlink@openssh.com";
+const LSETSTAT: &str = "lsetstat@openssh.com";
+
+/// Writes are sent in pieces of at most this size, which every server takes
+const WRITE_LEN: usize = 32 * 1024;
+
+/// Largest packet that is accepted from the server, which is what OpenSSH
+/// limits itself to
+const MAX_PACKET: usize = 256 * 1024;
+
+/// Writes that are sent before waiting for the replies to earlier ones
+const WINDOW: usize = 64;
+
+/// Body of a packet that is being put together
+#[derive(Default)]
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    fn string(self, s: &[u8]) -> Self {
+        let mut m = self.u32(s.len() as u32);
+        m.0.extend_from_slice(s);
+        m
+    }
+
+    fn attrs(self, a: &Attrs) -> Self {
+        let flags = [
+            (a.size.is_some(), ATTR_SIZE),
+            (a.ids.is_some(), ATTR_UIDGID),
+            (a.perms.is_some(), ATTR_PERMISSIONS),
+            (a.times.is_some(), ATTR_ACMODTIME),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |f, (_, bit)| f | bit);
+        let mut m = self.u32(flags);
+        if let Some(size) = a.size {
+            m = m.u64(size);
+        }
+        if let Some((uid, gid)) = a.ids {
+            m = m.u32(uid).u32(gid);
+        }
+        if let Some(perms) = a.perms {
+            m = m.u32(perms);
+        }
+        if let Some((atime, mtime)) = a.times {
+            m = m.u32(atime).u32(mtime);
+        }
+        m
+    }
+}
+
+/// Reads the fields of a reply one at a time
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(Error::Protocol("packet is too short"));
+        }
+        let (field, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// File attributes that are set, where `None` leaves one alone
+#[derive(Default)]
+struct Attrs {
+    size: Option<u64>,
+    ids: Option<(u32, u32)>,
+    perms: Option<u32>,
+    times: Option<(u32, u32)>,
+}
+
+fn seconds(t: SystemTime) -> u32 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// [Receiver] that applies each stream to a directory on an SFTP server,
+/// like [crate::apply::Applier] does locally. Incremental streams are applied
+/// onto the directory itself, which should hold their parent, and so are
+/// clones from the parent. Ownership is only applied with
+/// [SftpReceiver::preserve_owners], since it needs the server to run as root.
+pub struct SftpReceiver<R, W> {
+    r: R,
+    w: W,
+    child: Option<Child>,
+    dir: Vec<u8>,
+    next_id: u32,
+    extensions: BTreeSet<Vec<u8>>,
+    /// Writes that have been sent, and the paths that they are for
+    pending: VecDeque<(u32, PathBuf)>,
+    /// Handle of the last file that was written to
+    file: Option<(PathBuf, Vec<u8>)>,
+    /// The subvolume that is being received, and the parent of an
+    /// incremental one, which clones can read from
+    uuids: Vec<Uuid>,
+    /// Special files that SFTP can not create, along with everything done to
+    /// them
+    skipped: BTreeSet<PathBuf>,
+    /// Symlinks that the stream created, which the server would follow
+    symlinks: BTreeSet<PathBuf>,
+    owners: bool,
+}
+
+impl SftpReceiver<ChildStdout, ChildStdin> {
+    /// Run `ssh` with the `sftp` subsystem on `destination` (anything that
+    /// `ssh` takes, like `user@host`) and apply streams to `dir` there
+    pub fn ssh(destination: &str, dir: impl Into<PathBuf>) -> Result<Self> {
+        let mut child = std::process::Command::new("ssh")
+            .args(["-s", "--", destination, "sftp"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(w), Some(r)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(Error::Protocol("ssh has no pipes"));
+        };
+        let mut receiver = Self::new(r, w, dir)?;
+        receiver.child = Some(child);
+        Ok(receiver)
+    }
+}
+
+impl<R: Read, W: Write> SftpReceiver<R, W> {
+    /// Start an SFTP session over `r` and `w`, which are connected to the
+    /// server, and apply streams to `dir` on it
+    pub fn new(r: R, w: W, dir: impl Into<PathBuf>) -> Result<Self> {
+        let mut receiver = Self {
+            r,
+            w,
+            child: None,
+            dir: dir.into().into_os_string().into_vec(),
+            next_id: 0,
+            extensions: BTreeSet::new(),
+            pending: VecDeque::new(),
+            file: None,
+            uuids: Vec::new(),
+            skipped: BTreeSet::new(),
+            symlinks: BTreeSet::new(),
+            owners: false,
+        };
+        receiver.packet(FXP_INIT, &Msg::default().u32(VERSION).0)?;
+        let (ty, body) = receiver.recv()?;
+        if ty != FXP_VERSION {
+            return Err(Error::Protocol("expected a version"));
+        }
+        let mut f = Fields(&body);
+        if f.u32()? < VERSION {
+            return Err(Error::Unsupported("SFTP version 3"));
+        }
+        while !f.0.is_empty() {
+            let name = f.string()?.to_vec();
+            f.string()?;
+            receiver.extensions.insert(name);
+        }
+        Ok(receiver)
+    }
+
+    /// Apply [crate::Chown]s, which only works if the server runs as root
+    pub fn preserve_owners(mut self) -> Self {
+        self.owners = true;
+        self
+    }
+
+    fn has(&self, extension: &str) -> bool {
+        self.extensions.contains(extension.as_bytes())
+    }
+
+    fn remote(&self, path: &Path) -> Result<Vec<u8>> {
+        // the server resolves whatever it is sent, so paths from the
+        // sendstream must not lead anywhere but into the directory
+        if !path.components().all(|c| matches!(c, Component::Normal(_)))
+            || path.ancestors().skip(1).any(|a| self.symlinks.contains(a))
+        {
+            return Err(Error::Outside(path.to_path_buf()));
+        }
+        let mut remote = self.dir.clone();
+        if !path.as_os_str().is_empty() {
+            remote.push(b'/');
+            remote.extend_from_slice(path.as_os_str().as_bytes());
+        }
+        Ok(remote)
+    }
+
+    fn packet(&mut self, ty: u8, body: &[u8]) -> Result<()> {
+        let len = body.len() as u32 + 1;
+        self.w.write_all(&len.to_be_bytes())?;
+        self.w.write_all(&[ty])?;
+        self.w.write_all(body)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<(u8, Vec<u8>)> {
+        self.w.flush()?;
+        let mut len = [0; 4];
+        self.r.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Err(Error::Protocol("empty packet"));
+        }
+        if len > MAX_PACKET {
+            return Err(Error::Protocol("packet is too large"));
+        }
+        let mut body = vec![0; len];
+        self.r.read_exact(&mut body)?;
+        let ty = body.remove(0);
+        Ok((ty, body))
+    }
+
+    /// Send a request, returning its id
+    fn send(&mut self, ty: u8, body: Msg) -> Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&body.0);
+        self.packet(ty, &packet)?;
+        Ok(id)
+    }
+
+    /// Wait for the reply to request `id`, returning its type and what comes
+    /// after the id, failing on any status other than ok (or end of file,
+    /// for reads)
+    fn reply(&mut self, id: u32, path: &Path) -> Result<(u8, Vec<u8>)> {
+        let (ty, body) = self.recv()?;
+        let mut f = Fields(&body);
+        if f.u32()? != id {
+            return Err(Error::Protocol("reply to the wrong request"));
+        }
+        let rest = f.0.to_vec();
+        if ty == FXP_STATUS {
+            let code = f.u32()?;
+            if code != FX_OK && code != FX_EOF {
+                let message = String::from_utf8_lossy(f.string().unwrap_or_default()).into_owned();
+                return Err(Error::Status {
+                    path: path.to_path_buf(),
+                    code,
+                    message,
+                });
+            }
+        }
+        Ok((ty, rest))
+    }
+
+    /// Wait for every write that has been sent
+    fn drain(&mut self) -> Result<()> {
+        while let Some((id, path)) = self.pending.pop_front() {
+            self.reply(id, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Send a request once every write is done, and wait for its reply
+    fn call(&mut self, path: &Path, ty: u8, body: Msg) -> Result<(u8, Vec<u8>)> {
+        self.drain()?;
+        let id = self.send(ty, body)?;
+        self.reply(id, path)
+    }
+
+    fn extended(&mut self, path: &Path, name: &str, body: Msg) -> Result<()> {
+        let mut m = Msg::default().string(name.as_bytes());
+        m.0.extend_from_slice(&body.0);
+        self.call(path, FXP_EXTENDED, m).map(drop)
+    }
+
+    fn open(&mut self, path: &Path, flags: u32) -> Result<Vec<u8>> {
+        if self.symlinks.contains(path) {
+            return Err(Error::Outside(path.to_path_buf()));
+        }
+        let remote = self.remote(path)?;
+        let body = Msg::default()
+            .string(&remote)
+            .u32(flags)
+            .attrs(&Attrs::default());
+        match self.call(path, FXP_OPEN, body)? {
+            (FXP_HANDLE, rest) => Ok(Fields(&rest).string()?.to_vec()),
+            _ => Err(Error::Protocol("expected a handle")),
+        }
+    }
+
+    fn close(&mut self, path: &Path, handle: &[u8]) -> Result<()> {
+        self.call(path, FXP_CLOSE, Msg::default().string(handle))
+            .map(drop)
+    }
+
+    /// Handle of the regular file at `path`, reusing the last one if it is
+    /// the same
+    fn handle(&mut self, path: &Path) -> Result<Vec<u8>> {
+        if let Some((p, h)) = &self.file {
+            if p == path {
+                return Ok(h.clone());
+            }
+        }
+        self.close_file()?;
+        let handle = self.open(path, FXF_WRITE)?;
+        self.file = Some((path.to_path_buf(), handle.clone()));
+        Ok(handle)
+    }
+
+    fn close_file(&mut self) -> Result<()> {
+        self.drain()?;
+        match self.file.take() {
+            Some((path, handle)) => self.close(&path, &handle),
+            None => Ok(()),
+        }
+    }
+
+    fn write_at(&mut self, path: &Path, offset: u64, data: &[u8]) -> Result<()> {
+        let handle = self.handle(path)?;
+        for (i, piece) in data.chunks(WRITE_LEN).enumerate() {
+            let body = Msg::default()
+                .string(&handle)
+                .u64(offset + (i * WRITE_LEN) as u64)
+                .string(piece);
+            let id = self.send(FXP_WRITE, body)?;
+            self.pending.push_back((id, path.to_path_buf()));
+            if self.pending.len() > WINDOW {
+                if let Some((id, path)) = self.pending.pop_front() {
+                    self.reply(id, &path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&mut self, path: &Path) -> Result<u64> {
+        let handle = self.handle(path)?;
+        match self.call(path, FXP_FSTAT, Msg::default().string(&handle))? {
+            (FXP_ATTRS, rest) => {
+                let mut f = Fields(&rest);
+                match f.u32()? & ATTR_SIZE {
+                    0 => Err(Error::Protocol("attributes have no size")),
+                    _ => f.u64(),
+                }
+            }
+            _ => Err(Error::Protocol("expected attributes")),
+        }
+    }
+
+    /// Set attributes of `path`, without following a symlink there if the
+    /// server can do that
+    fn setstat(&mut self, path: &Path, attrs: Attrs) -> Result<()> {
+        let remote = self.remote(path)?;
+        let body = Msg::default().string(&remote).attrs(&attrs);
+        if self.has(LSETSTAT) {
+            return self.extended(path, LSETSTAT, body);
+        }
+        // which would change whatever the symlink points to instead
+        if self.symlinks.contains(path) {
+            return Ok(());
+        }
+        self.call(path, FXP_SETSTAT, body).map(drop)
+    }
+
+    fn setstat_file(&mut self, path: &Path, attrs: Attrs) -> Result<()> {
+        let handle = self.handle(path)?;
+        let body = Msg::default().string(&handle).attrs(&attrs);
+        self.call(path, FXP_FSETSTAT, body).map(drop)
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool> {
+        let remote = self.remote(path)?;
+        match self.call(path, FXP_STAT, Msg::default().string(&remote)) {
+            Ok(_) => Ok(true),
+            Err(Error::Status { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether commands for `path` are left out, because it is a special
+    /// file that SFTP could not create
+    fn skip(&self, path: &Path) -> bool {
+        self.skipped.contains(path)
+    }
+}
+
+/// Move the paths in `set` that are at or below `from` to below `to`
+fn move_paths(set: &mut BTreeSet<PathBuf>, from: &Path, to: &Path) {
+    let moved: Vec<_> = set
+        .range(from.to_path_buf()..)
+        .take_while(|p| p.starts_with(from))
+        .cloned()
+        .collect();
+    for p in moved {
+        set.remove(&p);
+        set.extend(renamed(&p, from, to));
+    }
+}
+
+impl<R, W> Drop for SftpReceiver<R, W> {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl<R: Read, W: Write> Receiver for SftpReceiver<R, W> {
+    type Error = Error;
+
+    fn subvol(&mut self, cmd: &crate::Subvol) -> Result<()> {
+        self.uuids = vec![cmd.uuid];
+        if self.exists(Path::new(""))? {
+            return Ok(());
+        }
+        let remote = self.remote(Path::new(""))?;
+        let body = Msg::default().string(&remote).attrs(&Attrs::default());
+        self.call(Path::new(""), FXP_MKDIR, body).map(drop)
+    }
+
+    fn snapshot(&mut self, cmd: &crate::Snapshot) -> Result<()> {
+        self.uuids = vec![cmd.uuid, cmd.clone_uuid];
+        Ok(())
+    }
+
+    fn mkfile(&mut self, cmd: &crate::Mkfile) -> Result<()> {
+        let path = cmd.path.as_path();
+        let handle = self.open(path, FXF_WRITE | FXF_CREAT | FXF_EXCL)?;
+        self.close(path, &handle)
+    }
+
+    fn mkdir(&mut self, cmd: &crate::Mkdir) -> Result<()> {
+        let path = cmd.path.as_path();
+        let remote = self.remote(path)?;
+        let body = Msg::default().string(&remote).attrs(&Attrs::default());
+        self.call(path, FXP_MKDIR, body).map(drop)
+    }
+
+    fn mknod(&mut self, cmd: &crate::Mknod) -> Result<()> {
+        self.skipped.insert(cmd.path.to_path_buf());
+        Ok(())
+    }
+
+    fn mkfifo(&mut self, cmd: &crate::Mkfifo) -> Result<()> {
+        self.skipped.insert(cmd.path.to_path_buf());
+        Ok(())
+    }
+
+    fn mksock(&mut self, cmd: &crate::Mksock) -> Result<()> {
+        self.skipped.insert(cmd.path.to_path_buf());
+        Ok(())
+    }
+
+    fn symlink(&mut self, cmd: &crate::Symlink) -> Result<()> {
+        // OpenSSH takes the target first, unlike what the protocol says
+        let remote = self.remote(&cmd.link_name)?;
+        let body = Msg::default()
+            .string(cmd.target.as_os_str().as_bytes())
+            .string(&remote);
+        self.call(&cmd.link_name, FXP_SYMLINK, body)?;
+        self.symlinks.insert(cmd.link_name.to_path_buf());
+        Ok(())
+    }
+
+    fn link(&mut self, cmd: &crate::Link) -> Result<()> {
+        if self.skip(cmd.target.as_path()) {
+            self.skipped.insert(cmd.link_name.to_path_buf());
+            return Ok(());
+        }
+        if !self.has(HARDLINK) {
+            return Err(Error::Unsupported(HARDLINK));
+        }
+        let body = Msg::default()
+            .string(&self.remote(cmd.target.as_path())?)
+            .string(&self.remote(&cmd.link_name)?);
+        self.extended(&cmd.link_name, HARDLINK, body)?;
+        if self.symlinks.contains(cmd.target.as_path()) {
+            self.symlinks.insert(cmd.link_name.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, cmd: &crate::Rename) -> Result<()> {
+        self.close_file()?;
+        // a symlink that is replaced is gone, one that is moved is not
+        self.symlinks.remove(cmd.to.as_ref());
+        move_paths(&mut self.symlinks, &cmd.from, &cmd.to);
+        if self.skip(&cmd.from) {
+            self.skipped.remove(cmd.from.as_ref());
+            self.skipped.insert(cmd.to.to_path_buf());
+            return Ok(());
+        }
+        move_paths(&mut self.skipped, &cmd.from, &cmd.to);
+        // whatever was skipped is not there to be replaced
+        self.skipped.remove(cmd.to.as_ref());
+        let body = Msg::default()
+            .string(&self.remote(&cmd.from)?)
+            .string(&self.remote(&cmd.to)?);
+        if self.has(POSIX_RENAME) {
+            return self.extended(&cmd.from, POSIX_RENAME, body);
+        }
+        // plain renames do not replace anything
+        if self.exists(&cmd.to)? {
+            let remote = self.remote(&cmd.to)?;
+            self.call(&cmd.to, FXP_REMOVE, Msg::default().string(&remote))?;
+        }
+        self.call(&cmd.from, FXP_RENAME, body).map(drop)
+    }
+
+    fn unlink(&mut self, cmd: &crate::Unlink) -> Result<()> {
+        self.close_file()?;
+        if self.skipped.remove(cmd.path.as_ref()) {
+            return Ok(());
+        }
+        let remote = self.remote(&cmd.path)?;
+        self.call(&cmd.path, FXP_REMOVE, Msg::default().string(&remote))?;
+        self.symlinks.remove(cmd.path.as_ref());
+        Ok(())
+    }
+
+    fn rmdir(&mut self, cmd: &crate::Rmdir) -> Result<()> {
+        self.close_file()?;
+        let remote = self.remote(&cmd.path)?;
+        self.call(&cmd.path, FXP_RMDIR, Msg::default().string(&remote))
+            .map(drop)
+    }
+
+    fn write(&mut self, cmd: &crate::Write) -> Result<()> {
+        self.write_at(&cmd.path, cmd.offset.as_u64(), &cmd.data)
+    }
+
+    fn encoded_write(&mut self, cmd: &crate::EncodedWrite) -> Result<()> {
+        let data = cmd
+            .decoded()
+            .ok_or_else(|| Error::Undecodable(cmd.path.to_path_buf()))?;
+        self.write_at(&cmd.path, cmd.offset.as_u64(), data)
+    }
+
+    fn clone_range(&mut self, cmd: &crate::Clone) -> Result<()> {
+        if !self.uuids.contains(&cmd.uuid) {
+            return Err(Error::CloneSource(cmd.uuid));
+        }
+        let src = self.open(&cmd.src_path, FXF_READ)?;
+        let (mut done, len) = (0, cmd.len.as_u64());
+        while done < len {
+            let want = (len - done).min(WRITE_LEN as u64) as u32;
+            let body = Msg::default()
+                .string(&src)
+                .u64(cmd.src_offset.as_u64() + done)
+                .u32(want);
+            let data = match self.call(&cmd.src_path, FXP_READ, body)? {
+                (FXP_DATA, rest) => Fields(&rest).string()?.to_vec(),
+                // the end of the source
+                _ => break,
+            };
+            if data.is_empty() {
+                break;
+            }
+            self.write_at(&cmd.dst_path, cmd.dst_offset.as_u64() + done, &data)?;
+            done += data.len() as u64;
+        }
+        self.close(&cmd.src_path, &src)
+    }
+
+    fn truncate(&mut self, cmd: &crate::Truncate) -> Result<()> {
+        let attrs = Attrs {
+            size: Some(cmd.size),
+            ..Default::default()
+        };
+        self.setstat_file(&cmd.path, attrs)
+    }
+
+    fn fallocate(&mut self, cmd: &crate::Fallocate) -> Result<()> {
+        let (offset, end) = (cmd.offset.as_u64(), cmd.offset.as_u64() + cmd.len);
+        let size = self.size(&cmd.path)?;
+        if cmd.mode.punch_hole() && offset < size {
+            let zeroes = vec![0; (end.min(size) - offset) as usize];
+            self.write_at(&cmd.path, offset, &zeroes)?;
+        }
+        if !cmd.mode.keep_size() && end > size {
+            let attrs = Attrs {
+                size: Some(end),
+                ..Default::default()
+            };
+            self.setstat_file(&cmd.path, attrs)?;
+        }
+        Ok(())
+    }
+
+    fn chmod(&mut self, cmd: &crate::Chmod) -> Result<()> {
+        if self.skip(&cmd.path) {
+            return Ok(());
+        }
+        let attrs = Attrs {
+            perms: Some(cmd.mode.0 & 0o7777),
+            ..Default::default()
+        };
+        self.drain()?;
+        self.setstat(&cmd.path, attrs)
+    }
+
+    fn chown(&mut self, cmd: &crate::Chown) -> Result<()> {
+        if !self.owners || self.skip(&cmd.path) {
+            return Ok(());
+        }
+        let attrs = Attrs {
+            ids: Some((cmd.uid.as_raw(), cmd.gid.as_raw())),
+            ..Default::default()
+        };
+        self.setstat(&cmd.path, attrs)
+    }
+
+    fn utimes(&mut self, cmd: &crate::Utimes) -> Result<()> {
+        if self.skip(&cmd.path) {
+            return Ok(());
+        }
+        // the times of the file that is written to would change again
+        self.close_file()?;
+        let attrs = Attrs {
+            times: Some((seconds(*cmd.atime), seconds(*cmd.mtime))),
+            ..Default::default()
+        };
+        self.setstat(&cmd.path, attrs)
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.close_file()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.close_file()?;
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::compare::diff_directory;
+    use crate::compare::Change;
+    use crate::compare::Delta;
+    use crate::fs::Filesystem;
+    use crate::fs::InodeKind;
+    use crate::receive::receive_all;
+    use crate::Sendstream;
+
+    fn reply(s: &mut UnixStream, ty: u8, id: u32, body: Msg) {
+        let mut packet = Msg::default().u32(id).0;
+        packet.extend_from_slice(&body.0);
+        let len = packet.len() as u32 + 1;
+        s.write_all(&len.to_be_bytes()).expect("failed to reply");
+        s.write_all(&[ty]).expect("failed to reply");
+        s.write_all(&packet).expect("failed to reply");
+    }
+
+    fn status(res: std::io::Result<()>) -> Msg {
+        let code = match res {
+            Ok(()) => FX_OK,
+            Err(_) => 4,
+        };
+        Msg::default().u32(code).string(b"").string(b"")
+    }
+
+    fn set(path: &Path, f: &mut Fields, follow: bool) -> std::io::Result<()> {
+        let flags = f.u32().map_err(|_| std::io::ErrorKind::InvalidData)?;
+        let field = |f: &mut Fields| f.u32().map_err(|_| std::io::ErrorKind::InvalidData);
+        if flags & ATTR_SIZE != 0 {
+            let size = f.u64().map_err(|_| std::io::ErrorKind::InvalidData)?;
+            OpenOptions::new().write(true).open(path)?.set_len(size)?;
+        }
+        if flags & ATTR_UIDGID != 0 {
+            let (uid, gid) = (field(f)?, field(f)?);
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            let perms = std::fs::Permissions::from_mode(field(f)?);
+            std::fs::set_permissions(path, perms)?;
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            let (atime, mtime) = (field(f)?, field(f)?);
+            let t = |s| nix::sys::time::TimeSpec::new(s as i64, 0);
+            let follow = match follow {
+                true => nix::sys::stat::UtimensatFlags::FollowSymlink,
+                false => nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+            };
+            nix::sys::stat::utimensat(None, path, &t(atime), &t(mtime), follow)?;
+        }
+        Ok(())
+    }
+
+    /// Just enough of an SFTP server for [SftpReceiver], on top of std::fs
+    fn serve(mut s: UnixStream) {
+        let mut files: Vec<Option<File>> = Vec::new();
+        let path = |b: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(b));
+        loop {
+            let mut len = [0; 4];
+            if s.read_exact(&mut len).is_err() {
+                return;
+            }
+            let mut body = vec![0; u32::from_be_bytes(len) as usize];
+            s.read_exact(&mut body).expect("failed to read request");
+            let ty = body.remove(0);
+            let mut f = Fields(&body);
+            if ty == FXP_INIT {
+                let version = Msg::default().u32(VERSION);
+                let version = [POSIX_RENAME, HARDLINK, LSETSTAT]
+                    .into_iter()
+                    .fold(version, |m, e| m.string(e.as_bytes()).string(b"1"));
+                let len = version.0.len() as u32 + 1;
+                s.write_all(&len.to_be_bytes()).expect("failed to reply");
+                s.write_all(&[FXP_VERSION]).expect("failed to reply");
+                s.write_all(&version.0).expect("failed to reply");
+                continue;
+            }
+            let id = f.u32().expect("no id");
+            let mut string = || path(f.string().expect("no string"));
+            let res = match ty {
+                FXP_OPEN => {
+                    let p = string();
+                    let flags = f.u32().expect("no flags");
+                    let file = OpenOptions::new()
+                        .read(flags & FXF_READ != 0)
+                        .write(flags & FXF_WRITE != 0)
+                        .create_new(flags & FXF_EXCL != 0)
+                        .open(p);
+                    match file {
+                        Ok(file) => {
+                            files.push(Some(file));
+                            let handle = (files.len() - 1).to_string();
+                            reply(
+                                &mut s,
+                                FXP_HANDLE,
+                                id,
+                                Msg::default().string(handle.as_bytes()),
+                            );
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                FXP_CLOSE | FXP_READ | FXP_WRITE | FXP_FSTAT | FXP_FSETSTAT => {
+                    let handle: usize = std::str::from_utf8(f.string().expect("no handle"))
+                        .expect("bad handle")
+                        .parse()
+                        .expect("bad handle");
+                    let file = files[handle].as_ref().expect("closed handle");
+                    match ty {
+                        FXP_CLOSE => {
+                            files[handle] = None;
+                            Ok(())
+                        }
+                        FXP_READ => {
+                            let offset = f.u64().expect("no offset");
+                            let mut buf = vec![0; f.u32().expect("no length") as usize];
+                            let n = file.read_at(&mut buf, offset).expect("failed to read");
+                            match n {
+                                0 => reply(&mut s, FXP_STATUS, id, Msg::default().u32(FX_EOF)),
+                                n => reply(&mut s, FXP_DATA, id, Msg::default().string(&buf[..n])),
+                            }
+                            continue;
+                        }
+                        FXP_WRITE => {
+                            let offset = f.u64().expect("no offset");
+                            file.write_all_at(f.string().expect("no data"), offset)
+                        }
+                        FXP_FSTAT => {
+                            let size = file.metadata().expect("failed to stat").len();
+                            let attrs = Attrs {
+                                size: Some(size),
+                                ..Default::default()
+                            };
+                            reply(&mut s, FXP_ATTRS, id, Msg::default().attrs(&attrs));
+                            continue;
+                        }
+                        _ => {
+                            let fd = format!("/proc/self/fd/{}", file.as_raw_fd());
+                            std::fs::read_link(fd).and_then(|p| set(&p, &mut f, true))
+                        }
+                    }
+                }
+                FXP_MKDIR => std::fs::create_dir(string()),
+                FXP_RMDIR => std::fs::remove_dir(string()),
+                FXP_REMOVE => std::fs::remove_file(string()),
+                FXP_STAT => std::fs::metadata(string()).map(drop),
+                FXP_SETSTAT => {
+                    let p = string();
+                    set(&p, &mut f, true)
+                }
+                FXP_SYMLINK => {
+                    let target = string();
+                    std::os::unix::fs::symlink(target, string())
+                }
+                FXP_EXTENDED => {
+                    let name = f.string().expect("no extension").to_vec();
+                    let p = path(f.string().expect("no path"));
+                    match std::str::from_utf8(&name).expect("bad extension") {
+                        POSIX_RENAME => std::fs::rename(p, path(f.string().expect("no path"))),
+                        HARDLINK => std::fs::hard_link(p, path(f.string().expect("no path"))),
+                        LSETSTAT => set(&p, &mut f, false),
+                        _ => Err(std::io::ErrorKind::Unsupported.into()),
+                    }
+                }
+                _ => Err(std::io::ErrorKind::Unsupported.into()),
+            };
+            reply(&mut s, FXP_STATUS, id, status(res));
+        }
+    }
+
+    #[test]
+    fn sftp() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let dest = std::env::temp_dir().join(format!("sftp.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        let (client, server) = UnixStream::pair().expect("failed to create socket pair");
+        let server = std::thread::spawn(move || serve(server));
+        let r = client.try_clone().expect("failed to clone socket");
+        let mut receiver = SftpReceiver::new(r, client, &dest)
+            .expect("failed to start session")
+            .preserve_owners();
+        receive_all(&mut receiver, &sendstreams).expect("failed to receive");
+        for path in ["../outside", "/etc/hostname"] {
+            assert!(matches!(
+                receiver.remote(Path::new(path)),
+                Err(Error::Outside(_))
+            ));
+        }
+        // the server follows symlinks, so nothing may be done through them,
+        // wherever they are moved or linked to
+        let path = |p| Cow::Borrowed(Path::new(p));
+        let write = |p| crate::Write {
+            path: path(p),
+            offset: crate::FileOffset(0),
+            data: crate::Data(Cow::Borrowed(b"ssh-ed25519")),
+        };
+        receiver
+            .symlink(&crate::Symlink {
+                link_name: path("x"),
+                ino: crate::Ino(1000),
+                target: crate::LinkTarget(path("/root/.ssh")),
+            })
+            .expect("failed to create symlink");
+        receiver
+            .rename(&crate::Rename {
+                from: path("x"),
+                to: path("y"),
+            })
+            .expect("failed to rename symlink");
+        receiver
+            .link(&crate::Link {
+                link_name: path("z"),
+                target: crate::LinkTarget(path("y")),
+            })
+            .expect("failed to link symlink");
+        for p in ["y/authorized_keys", "z/authorized_keys", "z"] {
+            assert!(
+                matches!(receiver.write(&write(p)), Err(Error::Outside(_))),
+                "{p}"
+            );
+        }
+        for p in ["y", "z"] {
+            receiver
+                .unlink(&crate::Unlink { path: path(p) })
+                .expect("failed to unlink symlink");
+        }
+        drop(receiver);
+        server.join().expect("server panicked");
+
+        let demo = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let fs = Filesystem::from_incremental(&demo, &sendstreams[1]).expect("failed to replay");
+        let diff = diff_directory(&fs, Path::new(""), &dest).expect("failed to diff");
+        // only what SFTP can not do is missing
+        for p in &diff.paths {
+            match &p.change {
+                Change::Removed => assert!(
+                    fs.lookup(&p.path).is_some_and(|id| !matches!(
+                        fs[id].kind(),
+                        InodeKind::Directory(_) | InodeKind::File(_) | InodeKind::Symlink(_)
+                    )),
+                    "{p:?}"
+                ),
+                Change::Modified(deltas) => assert!(
+                    deltas
+                        .iter()
+                        .all(|d| matches!(d, Delta::Xattr { .. } | Delta::Mtime { .. })),
+                    "{p:?}"
+                ),
+                Change::Added => panic!("{p:?}"),
+            }
+        }
+        std::fs::remove_dir_all(&dest).expect("failed to clean up");
+
+        // a server that claims to send 4G is not believed
+        let huge = u32::MAX.to_be_bytes();
+        assert!(matches!(
+            SftpReceiver::new(&huge[..], Vec::new(), &dest),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
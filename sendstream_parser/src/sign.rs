@@ -0,0 +1,417 @@
+//! Authenticate sendstreams end-to-end with ed25519 signatures.
+//!
+//! The signed digest is the SHA-256 of the wire encoding of the sendstream
+//! (stream header included), as produced by [crate::Encoder]. A signature can
+//! be stored separately from the stream, or appended to the end of it as a
+//! trailing record that [split_trailing] can remove again before parsing.
+
+use std::io::Read;
+use std::io::Write;
+
+use ed25519_dalek::Signer;
+pub use ed25519_dalek::SigningKey;
+use ed25519_dalek::Verifier;
+pub use ed25519_dalek::VerifyingKey;
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::Command;
+use crate::CommandReader;
+use crate::Encoder;
+use crate::Sendstream;
+
+/// Marks the start of a serialized [Signature]
+static MAGIC: &[u8] = b"btrfs-stream-sig\0";
+const VERSION: u32 = 1;
+/// Prepended to the digest before signing, so that these signatures can never
+/// be confused with signatures over some other kind of data.
+static CONTEXT: &[u8] = b"sendstream_parser signature v1\0";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed signature record")]
+    Malformed,
+    #[error("Signed by an untrusted key")]
+    UntrustedKey,
+    #[error("Signature does not match the sendstream")]
+    Mismatch,
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// SHA-256 digest of an encoded sendstream.
+pub type Digest = [u8; 32];
+
+/// Hashes everything written to it, to compute a [Digest] without buffering
+/// the encoded sendstream.
+#[derive(Debug, Clone, Default)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn finish(self) -> Digest {
+        self.0.finalize().into()
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compute the [Digest] of `sendstream`'s wire encoding.
+pub fn digest(sendstream: &Sendstream) -> std::io::Result<Digest> {
+    Ok(sendstream.write_to(Hasher::new())?.finish())
+}
+
+/// Compute the [Digest] of a sendstream that is already encoded.
+pub fn digest_bytes(encoded: &[u8]) -> Digest {
+    Sha256::digest(encoded).into()
+}
+
+/// A detached signature over a sendstream [Digest], along with the public key
+/// that made it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    key: VerifyingKey,
+    sig: ed25519_dalek::Signature,
+}
+
+impl Signature {
+    /// Size of the serialized signature record
+    pub const LEN: usize = MAGIC.len() + 4 + 32 + 64;
+
+    /// Sign a precomputed [Digest].
+    pub fn sign_digest(digest: &Digest, key: &SigningKey) -> Self {
+        Self {
+            key: key.verifying_key(),
+            sig: key.sign(&message(digest)),
+        }
+    }
+
+    /// The public key that made this signature. This is not trusted in any
+    /// way, verification always requires the caller to provide a trusted key.
+    pub fn key(&self) -> &VerifyingKey {
+        &self.key
+    }
+
+    /// Check that this signature was made by `trusted` over `digest`.
+    pub fn verify_digest(&self, digest: &Digest, trusted: &VerifyingKey) -> Result<()> {
+        if &self.key != trusted {
+            return Err(Error::UntrustedKey);
+        }
+        trusted
+            .verify(&message(digest), &self.sig)
+            .map_err(|_| Error::Mismatch)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::LEN);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(self.key.as_bytes());
+        out.extend_from_slice(&self.sig.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Self::LEN || !bytes.starts_with(MAGIC) {
+            return Err(Error::Malformed);
+        }
+        let (version, rest) = bytes[MAGIC.len()..].split_at(4);
+        if version != VERSION.to_le_bytes() {
+            return Err(Error::Malformed);
+        }
+        let (key, sig) = rest.split_at(32);
+        let key: &[u8; 32] = key.try_into().map_err(|_| Error::Malformed)?;
+        let sig: &[u8; 64] = sig.try_into().map_err(|_| Error::Malformed)?;
+        Ok(Self {
+            key: VerifyingKey::from_bytes(key).map_err(|_| Error::Malformed)?,
+            sig: ed25519_dalek::Signature::from_bytes(sig),
+        })
+    }
+}
+
+fn message(digest: &Digest) -> Vec<u8> {
+    [CONTEXT, digest].concat()
+}
+
+/// Sign `sendstream` with `key`.
+pub fn sign(sendstream: &Sendstream, key: &SigningKey) -> std::io::Result<Signature> {
+    Ok(Signature::sign_digest(&digest(sendstream)?, key))
+}
+
+/// Check that `signature` was made by `trusted` over `sendstream`.
+pub fn verify(
+    sendstream: &Sendstream,
+    signature: &Signature,
+    trusted: &VerifyingKey,
+) -> Result<()> {
+    signature.verify_digest(&digest(sendstream)?, trusted)
+}
+
+/// Encode `sendstream` with its signature appended as a trailing record.
+pub fn write_signed<W: Write>(
+    sendstream: &Sendstream,
+    key: &SigningKey,
+    w: W,
+) -> std::io::Result<W> {
+    let mut enc = Encoder::with_version(
+        Tee {
+            w,
+            hasher: Hasher::new(),
+        },
+        sendstream.version(),
+    )?;
+    for cmd in sendstream.commands() {
+        enc.write_command(cmd)?;
+    }
+    let Tee { mut w, hasher } = enc.into_inner();
+    w.write_all(&Signature::sign_digest(&hasher.finish(), key).to_bytes())?;
+    Ok(w)
+}
+
+/// Split a trailing signature record (as written by [write_signed]) off of
+/// the end of `input`, returning the encoded sendstream(s) before it.
+pub fn split_trailing(input: &[u8]) -> Option<(&[u8], Signature)> {
+    let split = input.len().checked_sub(Signature::LEN)?;
+    let (stream, record) = input.split_at(split);
+    Signature::from_bytes(record).ok().map(|sig| (stream, sig))
+}
+
+/// Writes to both `w` and a [Hasher]
+struct Tee<W> {
+    w: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Hashes everything that is read through it
+struct HashingReader<R> {
+    r: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.hasher.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Parses [Command]s from a [Read] (like [CommandReader]) while checking
+/// them against a detached [Signature], without buffering the sendstream.
+///
+/// Commands are yielded as soon as they are parsed, except for the very last
+/// [Command::End], which is held back until the whole input has been read and
+/// the signature checked. If the signature does not match, an error is
+/// yielded instead, so a relay that forwards commands as it receives them can
+/// refuse to complete a tampered sendstream. Likewise, a command that fails to
+/// parse is reported as a bad signature if the rest of the input shows that
+/// it was tampered with.
+pub struct VerifyingReader<R: Read> {
+    inner: CommandReader<HashingReader<R>>,
+    signature: Signature,
+    trusted: VerifyingKey,
+    held_end: bool,
+    stashed: Option<crate::Result<'static, Command<'static>>>,
+    done: bool,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(r: R, signature: Signature, trusted: VerifyingKey) -> Self {
+        Self {
+            inner: CommandReader::new(HashingReader {
+                r,
+                hasher: Hasher::new(),
+            }),
+            signature,
+            trusted,
+            held_end: false,
+            stashed: None,
+            done: false,
+        }
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        self.done = true;
+        let digest = self.inner.get_ref().hasher.clone().finish();
+        self.signature.verify_digest(&digest, &self.trusted)
+    }
+
+    /// A signed stream that fails to parse was most likely tampered with, so
+    /// read the rest of it and report a bad signature before the parse error
+    fn fail(&mut self, e: crate::Error<'static>) -> Error {
+        self.done = true;
+        if matches!(e, crate::Error::Cancelled) {
+            return e.into();
+        }
+        match std::io::copy(self.inner.get_mut(), &mut std::io::sink()) {
+            Ok(_) => self.verify().err().unwrap_or_else(|| e.into()),
+            Err(_) => e.into(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for VerifyingReader<R> {
+    type Item = Result<Command<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.held_end {
+            self.held_end = false;
+            return match self.inner.next() {
+                // that was the last command, so only release it if the whole
+                // input was signed
+                None => Some(self.verify().map(|_| Command::End)),
+                Some(Ok(next)) => {
+                    self.stashed = Some(Ok(next));
+                    Some(Ok(Command::End))
+                }
+                // whatever follows could not be parsed, so the signature has
+                // to be checked before the stream is allowed to end
+                Some(Err(e)) => Some(Err(self.fail(e))),
+            };
+        }
+        match self.stashed.take().or_else(|| self.inner.next()) {
+            None => self.verify().err().map(Err),
+            Some(Ok(Command::End)) => {
+                self.held_end = true;
+                self.next()
+            }
+            Some(Ok(cmd)) => Some(Ok(cmd)),
+            Some(Err(e)) => Some(Err(self.fail(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let sendstreams = Sendstream::parse_all(input).expect("failed to parse demo.sendstream");
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+
+        let sig = sign(&sendstreams[0], &key).expect("failed to sign");
+        let sig = Signature::from_bytes(&sig.to_bytes()).expect("failed to roundtrip");
+        verify(&sendstreams[0], &sig, &key.verifying_key()).expect("signature is valid");
+        assert!(matches!(
+            verify(&sendstreams[1], &sig, &key.verifying_key()),
+            Err(Error::Mismatch)
+        ));
+        assert!(matches!(
+            verify(&sendstreams[0], &sig, &other.verifying_key()),
+            Err(Error::UntrustedKey)
+        ));
+
+        let signed = write_signed(&sendstreams[0], &key, Vec::new()).expect("failed to encode");
+        let (stream, trailing) = split_trailing(&signed).expect("missing signature record");
+        assert_eq!(sig, trailing);
+        assert_eq!(
+            digest_bytes(stream),
+            digest(&sendstreams[0]).expect("failed to hash")
+        );
+        assert!(split_trailing(input).is_none());
+
+        // commands that need a newer protocol version are signed as they
+        // would be written
+        let mut commands = sendstreams[0].commands().to_vec();
+        let end = commands.pop();
+        commands.push(
+            crate::Fallocate {
+                path: std::borrow::Cow::Borrowed(std::path::Path::new("hello/msg")),
+                mode: crate::FallocateMode(nix::libc::FALLOC_FL_KEEP_SIZE as u32),
+                offset: crate::FileOffset(0),
+                len: 4096,
+            }
+            .into(),
+        );
+        commands.extend(end);
+        let v2 = Sendstream { commands };
+        assert_eq!(2, v2.version());
+        let signed = write_signed(&v2, &key, Vec::new()).expect("failed to encode");
+        let (stream, trailing) = split_trailing(&signed).expect("missing signature record");
+        verify(&v2, &trailing, &key.verifying_key()).expect("signature is valid");
+        assert_eq!(
+            vec![v2],
+            Sendstream::parse_all(stream).expect("failed to parse")
+        );
+    }
+
+    #[test]
+    fn verify_while_reading() {
+        let input = include_bytes!("../testdata/demo.sendstream");
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sig = Signature::sign_digest(&digest_bytes(input), &key);
+
+        let read: Vec<_> = VerifyingReader::new(&input[..], sig.clone(), key.verifying_key())
+            .collect::<Result<_>>()
+            .expect("signature is valid");
+        let expected: Vec<_> = Sendstream::parse_all(input)
+            .expect("failed to parse demo.sendstream")
+            .into_iter()
+            .flat_map(Sendstream::into_commands)
+            .collect();
+        assert_eq!(expected, read);
+
+        // flip a bit in the last Write of the second stream
+        let mut tampered = input.to_vec();
+        let pos = tampered
+            .windows(8)
+            .rposition(|w| w == b"Goodbye!")
+            .expect("missing write data");
+        tampered[pos] ^= 1;
+        let read: Vec<_> =
+            VerifyingReader::new(&tampered[..], sig.clone(), key.verifying_key()).collect();
+        // everything up to the final End is still parsed, but the End itself
+        // is replaced by the verification failure
+        assert_eq!(expected.len(), read.len());
+        assert!(read[..read.len() - 1].iter().all(|r| r.is_ok()));
+        assert!(matches!(read.last(), Some(Err(Error::Mismatch))));
+
+        // trailing garbage that fails to parse must not let the End through
+        tampered.extend_from_slice(&[0xff; 24]);
+        let read: Vec<_> =
+            VerifyingReader::new(&tampered[..], sig.clone(), key.verifying_key()).collect();
+        assert_eq!(expected.len(), read.len());
+        assert!(matches!(read.last(), Some(Err(Error::Mismatch))));
+
+        // a corrupted command in the middle is a bad signature, not a parse error
+        let mut tampered = input.to_vec();
+        // the type of the first command, right after the stream header
+        tampered[b"btrfs-stream\0".len() + 8..][..2].copy_from_slice(&999u16.to_le_bytes());
+        let read: Vec<_> = VerifyingReader::new(&tampered[..], sig, key.verifying_key()).collect();
+        assert!(matches!(read[..], [Err(Error::Mismatch)]));
+    }
+}
@@ -0,0 +1,145 @@
+//! Estimate how much space receiving a sendstream takes up.
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::extents::Source;
+use crate::files::created_type;
+use crate::Command;
+use crate::Sendstream;
+
+/// Data blocks are allocated in units of this size
+const BLOCK_SIZE: u64 = 4096;
+/// Largest file that btrfs stores inline in its metadata by default (the
+/// `max_inline` mount option)
+const MAX_INLINE: u64 = 2048;
+
+// Sizes of the btrfs metadata items that each file needs, without their
+// variable-length names or data
+const ITEM_HEADER: u64 = 25;
+const INODE_ITEM: u64 = 160;
+const INODE_REF: u64 = 10;
+const DIR_ITEM: u64 = 30;
+const FILE_EXTENT_ITEM: u64 = 53;
+const INLINE_EXTENT_ITEM: u64 = 21;
+
+/// How much space a sendstream would allocate when it is received, see
+/// [Sendstream::space_estimate]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SpaceEstimate {
+    /// New data extents, in whole blocks
+    pub data_bytes: u64,
+    /// Data of files small enough to be stored inline in the metadata
+    /// (included in `metadata_bytes`)
+    pub inline_bytes: u64,
+    /// Data that is shared with other files through [crate::Clone]s, which
+    /// does not allocate anything new
+    pub shared_bytes: u64,
+    /// Holes, which do not allocate anything either
+    pub hole_bytes: u64,
+    /// Rough estimate of the metadata needed for inodes, directory entries,
+    /// xattrs and extents
+    pub metadata_bytes: u64,
+}
+
+impl SpaceEstimate {
+    /// Everything that is newly allocated. Metadata is counted once, so
+    /// multiply it for profiles that store more than one copy (like `dup`).
+    pub fn total_bytes(&self) -> u64 {
+        self.data_bytes + self.metadata_bytes
+    }
+}
+
+impl<'a> Sendstream<'a> {
+    /// Estimate the space that receiving this sendstream would allocate,
+    /// from the final layout of every file (see [Sendstream::extent_map]).
+    /// Data that is cloned, left unchanged from the parent of an incremental
+    /// or not sent at all ([crate::UpdateExtent]) does not allocate new
+    /// blocks. Version 1 sendstreams have no compression information, so all
+    /// data is assumed to be stored uncompressed.
+    pub fn space_estimate(&self) -> SpaceEstimate {
+        let mut est = SpaceEstimate::default();
+        for file in self.extent_map() {
+            let inline = file.size.is_some_and(|s| s <= MAX_INLINE)
+                && file
+                    .extents
+                    .iter()
+                    .all(|e| matches!(e.source, Source::Write | Source::Hole));
+            if inline {
+                let size = file.size.unwrap_or_default();
+                est.inline_bytes += size;
+                est.metadata_bytes += ITEM_HEADER + INLINE_EXTENT_ITEM + size;
+                continue;
+            }
+            for e in &file.extents {
+                match e.source {
+                    Source::Write => {
+                        est.data_bytes += e.len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+                    }
+                    Source::Clone { .. } => est.shared_bytes += e.len,
+                    Source::Hole => {
+                        est.hole_bytes += e.len;
+                        continue;
+                    }
+                    Source::Updated | Source::Unchanged => continue,
+                }
+                est.metadata_bytes += ITEM_HEADER + FILE_EXTENT_ITEM;
+            }
+        }
+        for file in self.files() {
+            let created = file
+                .commands
+                .iter()
+                .find(|i| created_type(&self.commands[**i]).is_some())
+                .map(|i| &self.commands[*i]);
+            if let Some(cmd) = created {
+                est.metadata_bytes += ITEM_HEADER + INODE_ITEM;
+                // an inode ref, plus a dir item and dir index in the parent
+                for link in &file.links {
+                    let name = link.file_name().map_or(0, |n| n.len() as u64);
+                    est.metadata_bytes += 3 * (ITEM_HEADER + name) + INODE_REF + 2 * DIR_ITEM;
+                }
+                if let Command::Symlink(s) = cmd {
+                    est.metadata_bytes +=
+                        ITEM_HEADER + INLINE_EXTENT_ITEM + s.target.as_os_str().len() as u64;
+                }
+            }
+            for (name, value) in &file.xattrs {
+                if let Some(value) = value {
+                    est.metadata_bytes +=
+                        ITEM_HEADER + DIR_ITEM + (name.len() + value.len()) as u64;
+                }
+            }
+        }
+        est
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_estimate_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let est = sendstreams[0].space_estimate();
+        // hello/lorem is written in full, hello/lorem-reflinked shares its
+        // first 128K with it
+        let blocks = |len: u64| len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        assert_eq!(
+            3 * 49152 + 32768 + blocks(43222) + 49152 + blocks(43222),
+            est.data_bytes
+        );
+        assert_eq!(131072, est.shared_bytes);
+        assert_eq!(13, est.inline_bytes, "only hello/msg is inline");
+        assert_eq!(107374182400, est.hole_bytes);
+        assert!(est.metadata_bytes > est.inline_bytes);
+        assert_eq!(est.data_bytes + est.metadata_bytes, est.total_bytes());
+
+        let undo = sendstreams[1].space_estimate();
+        assert_eq!(0, undo.data_bytes);
+        assert_eq!(9, undo.inline_bytes);
+    }
+}
@@ -0,0 +1,317 @@
+//! Summary statistics of a sendstream, computed in a single pass over its
+//! commands.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::manifest::FileType;
+use crate::Command;
+use crate::Sendstream;
+
+/// Report of what a sendstream contains. Counts of created files only include
+/// files that the sendstream creates, not files that an incremental sendstream
+/// changes in its parent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Stats {
+    /// Number of each type of command, keyed by its name (like `Write`)
+    pub commands: BTreeMap<String, u64>,
+    /// Bytes of file data sent in [crate::Write]s
+    pub write_bytes: u64,
+    /// Bytes of file data shared with [crate::Clone]s
+    pub clone_bytes: u64,
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Fifos, sockets and device nodes
+    pub specials: u64,
+    pub xattrs_set: u64,
+    /// Total length of names and values of every [crate::SetXattr]
+    pub xattr_bytes: u64,
+    pub xattrs_removed: u64,
+    pub renames: u64,
+    pub links: u64,
+    pub unlinks: u64,
+    pub rmdirs: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for one more command, so that [Stats] can be computed while
+    /// reading commands from a [crate::CommandReader].
+    pub fn add(&mut self, cmd: &Command) {
+        *self
+            .commands
+            .entry(format!("{:?}", cmd.command_type()))
+            .or_default() += 1;
+        match cmd {
+            Command::Write(w) => self.write_bytes += w.data().len() as u64,
+            Command::Clone(c) => self.clone_bytes += c.len().as_u64(),
+            Command::Mkfile(_) => self.files += 1,
+            Command::Mkdir(_) => self.directories += 1,
+            Command::Symlink(_) => self.symlinks += 1,
+            Command::Mknod(_) | Command::Mkfifo(_) | Command::Mksock(_) => self.specials += 1,
+            Command::SetXattr(x) => {
+                self.xattrs_set += 1;
+                self.xattr_bytes += (x.name().len() + x.data().len()) as u64;
+            }
+            Command::RemoveXattr(_) => self.xattrs_removed += 1,
+            Command::Rename(_) => self.renames += 1,
+            Command::Link(_) => self.links += 1,
+            Command::Unlink(_) => self.unlinks += 1,
+            Command::Rmdir(_) => self.rmdirs += 1,
+            _ => (),
+        }
+    }
+
+    /// Total number of commands
+    pub fn total_commands(&self) -> u64 {
+        self.commands.values().sum()
+    }
+}
+
+/// Number of files of each type that a sendstream creates, see
+/// [Sendstream::census]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Census {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub fifos: u64,
+    pub sockets: u64,
+    pub char_devices: u64,
+    pub block_devices: u64,
+    /// Files with more than one hard link
+    pub hardlinked_files: u64,
+    /// Hard links beyond the first path of each of those files
+    pub extra_links: u64,
+}
+
+impl Census {
+    /// Total number of files of any type (not counting extra hard links)
+    pub fn total(&self) -> u64 {
+        self.files
+            + self.directories
+            + self.symlinks
+            + self.fifos
+            + self.sockets
+            + self.char_devices
+            + self.block_devices
+    }
+}
+
+/// How much data a sendstream carries for a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileSize {
+    /// Final path of the file (see [Sendstream::final_paths])
+    pub path: PathBuf,
+    pub write_bytes: u64,
+    pub clone_bytes: u64,
+    /// Size set by the last [crate::Truncate], if any
+    pub size: Option<u64>,
+}
+
+/// Number of files whose [FileSize::write_bytes] falls within
+/// `[min_bytes, max_bytes)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Bucket {
+    pub min_bytes: u64,
+    pub max_bytes: u64,
+    pub files: u64,
+    /// Sum of the [FileSize::write_bytes] of the files in this bucket
+    pub bytes: u64,
+}
+
+/// Which files account for the data in a sendstream, see
+/// [Sendstream::size_report]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SizeReport {
+    /// Files with the most written bytes, largest first
+    pub largest: Vec<FileSize>,
+    /// Power-of-two buckets of the written bytes of every file that is
+    /// written to, smallest first. Empty buckets are left out.
+    pub histogram: Vec<Bucket>,
+}
+
+impl<'a> Sendstream<'a> {
+    /// Compute [Stats] over every command in this sendstream
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for cmd in &self.commands {
+            stats.add(cmd);
+        }
+        stats
+    }
+
+    /// Count the files that this sendstream creates by type, telling
+    /// character and block devices apart by the mode of their
+    /// [crate::Mknod]. Unlike [Stats], this only counts files that still
+    /// exist at the end of the stream (see [Sendstream::inode_table]), and
+    /// counts each file once no matter how many hard links it has.
+    pub fn census(&self) -> Census {
+        let mut census = Census::default();
+        for entry in self.inode_table().values() {
+            let count = match entry.file_type {
+                FileType::File => &mut census.files,
+                FileType::Directory => &mut census.directories,
+                FileType::Symlink => &mut census.symlinks,
+                FileType::Fifo => &mut census.fifos,
+                FileType::Socket => &mut census.sockets,
+                FileType::CharDevice => &mut census.char_devices,
+                FileType::BlockDevice => &mut census.block_devices,
+            };
+            *count += 1;
+            if entry.file.links.len() > 1 {
+                census.hardlinked_files += 1;
+                census.extra_links += entry.file.links.len() as u64 - 1;
+            }
+        }
+        census
+    }
+
+    /// Account for the data sent for each file, keeping the `top` largest
+    /// files. Files that do not exist at the end of the sendstream are left
+    /// out (see [Sendstream::files]).
+    pub fn size_report(&self, top: usize) -> SizeReport {
+        let mut sizes: Vec<_> = self
+            .files()
+            .into_iter()
+            .map(|f| FileSize {
+                write_bytes: f.writes.iter().map(|w| w.data().len() as u64).sum(),
+                clone_bytes: f.clones.iter().map(|c| c.len().as_u64()).sum(),
+                size: f.size,
+                path: f.final_path,
+            })
+            .collect();
+
+        let mut histogram: BTreeMap<u32, Bucket> = BTreeMap::new();
+        for f in sizes.iter().filter(|f| f.write_bytes > 0) {
+            let bits = u64::BITS - f.write_bytes.leading_zeros();
+            let b = histogram.entry(bits).or_insert_with(|| Bucket {
+                min_bytes: 1 << (bits - 1),
+                max_bytes: 1u64.checked_shl(bits).unwrap_or(u64::MAX),
+                files: 0,
+                bytes: 0,
+            });
+            b.files += 1;
+            b.bytes += f.write_bytes;
+        }
+
+        sizes.sort_by(|a, b| {
+            b.write_bytes
+                .cmp(&a.write_bytes)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        sizes.truncate(top);
+        SizeReport {
+            largest: sizes,
+            histogram: histogram.into_values().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let stats = sendstreams[0].stats();
+        assert_eq!(
+            sendstreams[0].commands().len() as u64,
+            stats.total_commands()
+        );
+        assert_eq!(Some(&1), stats.commands.get("Subvol"));
+        assert_eq!(Some(&1), stats.commands.get("End"));
+        assert_eq!(131072, stats.clone_bytes);
+        assert_eq!(
+            13 + 49152 * 4 + 32768 + 43222 * 2,
+            stats.write_bytes,
+            "hello/msg, hello/lorem and the unshared tail of hello/lorem-reflinked"
+        );
+        assert_eq!(1, stats.links);
+        assert_eq!(1, stats.symlinks);
+        assert_eq!(3, stats.specials, "fifo, socket and null");
+
+        let undo = sendstreams[1].stats();
+        assert_eq!(1, undo.unlinks);
+        assert_eq!(1, undo.rmdirs);
+        assert_eq!(1, undo.xattrs_removed);
+        assert_eq!(0, undo.files);
+    }
+
+    #[test]
+    fn census_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let census = sendstreams[0].census();
+        assert_eq!(
+            Census {
+                files: 5,
+                directories: 2,
+                symlinks: 1,
+                fifos: 1,
+                sockets: 1,
+                char_devices: 1,
+                block_devices: 0,
+                hardlinked_files: 1,
+                extra_links: 1,
+            },
+            census
+        );
+        assert_eq!(
+            census.total() + 1,
+            sendstreams[0].files().len() as u64,
+            "plus the root"
+        );
+        assert_eq!(Census::default(), sendstreams[1].census());
+    }
+
+    #[test]
+    fn size_report() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let report = sendstreams[0].size_report(2);
+        assert_eq!(
+            vec![
+                FileSize {
+                    path: PathBuf::from("hello/lorem"),
+                    write_bytes: 223446,
+                    clone_bytes: 0,
+                    size: None,
+                },
+                FileSize {
+                    path: PathBuf::from("hello/lorem-reflinked"),
+                    write_bytes: 49152 + 43222,
+                    clone_bytes: 131072,
+                    size: None,
+                },
+            ],
+            report.largest
+        );
+        assert_eq!(
+            vec![(8, 16, 1), (65536, 131072, 1), (131072, 262144, 1)],
+            report
+                .histogram
+                .iter()
+                .map(|b| (b.min_bytes, b.max_bytes, b.files))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            sendstreams[0].stats().write_bytes,
+            report.histogram.iter().map(|b| b.bytes).sum::<u64>()
+        );
+    }
+}
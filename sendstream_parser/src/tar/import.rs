@@ -0,0 +1,608 @@
+//! Reading tarballs into full sendstreams, see [from_tar].
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use nix::libc;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+
+use super::Error;
+use super::Result;
+use super::BLOCK;
+use crate::fs::Filesystem;
+use crate::fs::InodeKind;
+use crate::incremental::emit_full;
+use crate::incremental::MAX_WRITE_LEN;
+use crate::Command;
+use crate::Sendstream;
+
+/// Number in a header field, which is octal or (as GNU tar writes numbers
+/// that do not fit) base-256 if the top bit is set
+fn number(field: &[u8]) -> Result<u64> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        let mut v = u64::from(field[0] & 0x7f);
+        for b in &field[1..] {
+            v = v
+                .checked_shl(8)
+                .ok_or(Error::Malformed("number is too large"))?
+                | u64::from(*b);
+        }
+        return Ok(v);
+    }
+    let digits = field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| (b'0'..=b'7').contains(b));
+    let mut v: u64 = 0;
+    for b in digits {
+        v = v
+            .checked_mul(8)
+            .ok_or(Error::Malformed("number is too large"))?
+            + u64::from(b - b'0');
+    }
+    Ok(v)
+}
+
+/// Nul-terminated string in a header field
+fn string(field: &[u8]) -> &[u8] {
+    &field[..field.iter().position(|b| *b == 0).unwrap_or(field.len())]
+}
+
+/// `<len> <key>=<value>\n` records of a pax header
+fn pax_records(data: &[u8], into: &mut BTreeMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+    let malformed = || Error::Malformed("bad pax record");
+    let mut rest = data;
+    while !rest.is_empty() && rest[0] != 0 {
+        let space = rest.iter().position(|b| *b == b' ').ok_or_else(malformed)?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .filter(|l| *l > space && *l <= rest.len())
+            .ok_or_else(malformed)?;
+        let record = &rest[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|b| *b == b'=')
+            .ok_or_else(malformed)?;
+        into.insert(record[..eq].to_vec(), record[eq + 1..].to_vec());
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+/// A pax time, which is seconds with an optional fraction
+fn pax_time(value: &[u8]) -> Option<SystemTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, value),
+    };
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let mut nanos = format!("{frac:0<9}");
+    nanos.truncate(9);
+    let d = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    match negative {
+        true => SystemTime::UNIX_EPOCH.checked_sub(d),
+        false => SystemTime::UNIX_EPOCH.checked_add(d),
+    }
+}
+
+/// Path within the subvolume of a name in the archive, which may start with
+/// `/` or `./` but not lead out of it
+fn subvolume_path(name: &[u8]) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for c in Path::new(OsStr::from_bytes(name)).components() {
+        match c {
+            Component::Normal(c) => path.push(c),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::Outside(PathBuf::from(OsStr::from_bytes(name))))
+            }
+        }
+    }
+    Ok(path)
+}
+
+fn owned(path: &Path) -> Cow<'static, Path> {
+    Cow::Owned(path.to_path_buf())
+}
+
+enum Kind {
+    File,
+    Hardlink(PathBuf),
+    Symlink(PathBuf),
+    Directory,
+    Fifo,
+    Device(u32, u64),
+}
+
+/// Everything about one member of the archive, after its pax and GNU
+/// headers were applied
+struct Member {
+    path: PathBuf,
+    kind: Kind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Where each piece of data goes, for sparse files
+    map: Option<Vec<(u64, u64)>>,
+    size: u64,
+}
+
+/// `<count>\n` followed by `<offset>\n<len>\n` for each piece, which GNU
+/// sparse format 1.0 puts in front of the data
+fn sparse_map(data: &[u8]) -> Result<(Vec<(u64, u64)>, usize)> {
+    let malformed = || Error::Malformed("bad sparse map");
+    let mut pos = 0;
+    let mut next = || -> Result<u64> {
+        let end = data[pos..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(malformed)?;
+        let n = std::str::from_utf8(&data[pos..pos + end])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(malformed)?;
+        pos += end + 1;
+        Ok(n)
+    };
+    let count = next()?;
+    let mut map = Vec::new();
+    for _ in 0..count {
+        map.push((next()?, next()?));
+    }
+    Ok((map, pos.div_ceil(BLOCK as usize) * BLOCK as usize))
+}
+
+/// Replays the members of an archive onto a [Filesystem]
+struct Importer {
+    fs: Filesystem,
+    next_ino: u64,
+}
+
+impl Importer {
+    fn apply(&mut self, cmd: Command) -> Result<()> {
+        Ok(self.fs.apply(&cmd)?)
+    }
+
+    fn ino(&mut self) -> crate::Ino {
+        self.next_ino += 1;
+        crate::Ino(self.next_ino - 1)
+    }
+
+    /// Create the parents of `path` that the archive left out
+    fn parents(&mut self, path: &Path) -> Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        if parent.as_os_str().is_empty() || self.fs.lookup(parent).is_some() {
+            return Ok(());
+        }
+        self.parents(parent)?;
+        let ino = self.ino();
+        self.apply(
+            crate::Mkdir {
+                path: crate::TemporaryPath(owned(parent)),
+                ino,
+            }
+            .into(),
+        )
+    }
+
+    /// Remove whatever is at `path`, which a later member replaces
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        let Some(id) = self.fs.lookup(path) else {
+            return Ok(());
+        };
+        if !matches!(self.fs[id].kind(), InodeKind::Directory(_)) {
+            return self.apply(crate::Unlink { path: owned(path) }.into());
+        }
+        let below: Vec<_> = self
+            .fs
+            .walk()
+            .into_iter()
+            .filter(|(p, _)| p.starts_with(path))
+            .collect();
+        for (p, id) in below.into_iter().rev() {
+            let cmd = match self.fs[id].kind() {
+                InodeKind::Directory(_) => crate::Rmdir { path: owned(&p) }.into(),
+                _ => crate::Unlink { path: owned(&p) }.into(),
+            };
+            self.apply(cmd)?;
+        }
+        Ok(())
+    }
+
+    fn member(&mut self, m: Member, data: &[u8]) -> Result<()> {
+        let path = m.path.as_path();
+        let existing = self.fs.lookup(path).map(|id| self.fs[id].kind());
+        let merge = matches!(m.kind, Kind::Directory)
+            && (path.as_os_str().is_empty() || matches!(existing, Some(InodeKind::Directory(_))));
+        if !merge {
+            if path.as_os_str().is_empty() {
+                return Err(Error::Malformed("subvolume root is not a directory"));
+            }
+            self.remove(path)?;
+            self.parents(path)?;
+        }
+        let mkspecial = |ino, ty: u32, rdev| crate::Mkspecial {
+            path: crate::TemporaryPath(owned(path)),
+            ino,
+            rdev: crate::Rdev(rdev),
+            mode: crate::Mode(ty | m.mode),
+        };
+        let create: Option<Command> = match &m.kind {
+            Kind::Directory if merge => None,
+            Kind::Directory => Some(
+                crate::Mkdir {
+                    path: crate::TemporaryPath(owned(path)),
+                    ino: self.ino(),
+                }
+                .into(),
+            ),
+            Kind::File => Some(
+                crate::Mkfile {
+                    path: crate::TemporaryPath(owned(path)),
+                    ino: self.ino(),
+                }
+                .into(),
+            ),
+            Kind::Hardlink(target) => {
+                // the metadata already belongs to the file that is linked to
+                return self.apply(
+                    crate::Link {
+                        link_name: owned(path),
+                        target: crate::LinkTarget(owned(target)),
+                    }
+                    .into(),
+                );
+            }
+            Kind::Symlink(target) => Some(
+                crate::Symlink {
+                    link_name: owned(path),
+                    ino: self.ino(),
+                    target: crate::LinkTarget(owned(target)),
+                }
+                .into(),
+            ),
+            Kind::Fifo => Some(crate::Mkfifo(mkspecial(self.ino(), libc::S_IFIFO, 0)).into()),
+            Kind::Device(ty, rdev) => Some(crate::Mknod(mkspecial(self.ino(), *ty, *rdev)).into()),
+        };
+        if let Some(cmd) = create {
+            self.apply(cmd)?;
+        }
+        if let Kind::File = m.kind {
+            let map = m
+                .map
+                .clone()
+                .unwrap_or_else(|| vec![(0, data.len() as u64)]);
+            let mut at = 0;
+            for (offset, len) in map {
+                let piece = data
+                    .get(at..at + len as usize)
+                    .ok_or(Error::Malformed("sparse map is larger than the data"))?;
+                at += len as usize;
+                for (i, chunk) in piece.chunks(MAX_WRITE_LEN as usize).enumerate() {
+                    self.apply(
+                        crate::Write {
+                            path: owned(path),
+                            offset: crate::FileOffset(offset + i as u64 * MAX_WRITE_LEN),
+                            data: crate::Data(Cow::Owned(chunk.to_vec())),
+                        }
+                        .into(),
+                    )?;
+                }
+            }
+            if m.map.is_some() {
+                self.apply(
+                    crate::Truncate {
+                        path: owned(path),
+                        size: m.size,
+                    }
+                    .into(),
+                )?;
+            }
+        }
+        for (name, value) in m.xattrs {
+            self.apply(
+                crate::SetXattr {
+                    path: owned(path),
+                    name: crate::XattrName(Cow::Owned(name)),
+                    data: crate::XattrData(Cow::Owned(value)),
+                }
+                .into(),
+            )?;
+        }
+        self.apply(
+            crate::Chown {
+                path: owned(path),
+                uid: Uid::from_raw(m.uid),
+                gid: Gid::from_raw(m.gid),
+            }
+            .into(),
+        )?;
+        if !matches!(m.kind, Kind::Symlink(_)) {
+            self.apply(
+                crate::Chmod {
+                    path: owned(path),
+                    mode: crate::Mode(m.mode),
+                }
+                .into(),
+            )?;
+        }
+        self.apply(
+            crate::Utimes {
+                path: owned(path),
+                atime: crate::Atime(m.atime),
+                mtime: crate::Mtime(m.mtime),
+                ctime: crate::Ctime(m.ctime),
+            }
+            .into(),
+        )
+    }
+}
+
+/// Read a tarball (ustar, pax or GNU, including sparse files in any of
+/// GNU's formats) from `archive` and turn it into a full sendstream that
+/// creates the same files, starting with `header`. Like `btrfs send`, every
+/// inode is created under a temporary name and then renamed into place.
+///
+/// Members replace whatever an earlier member put at the same path, and
+/// directories that the archive leaves out are created with default
+/// metadata. Ownership is always taken from the numeric ids, since the
+/// stream has nowhere to put user and group names.
+pub fn from_tar<R: Read>(
+    mut archive: R,
+    header: crate::Subvol<'static>,
+) -> Result<Sendstream<'static>> {
+    let mut importer = Importer {
+        fs: Filesystem::new(),
+        next_ino: 257,
+    };
+    importer.apply(header.clone().into())?;
+    let mut global = BTreeMap::new();
+    let mut local = BTreeMap::new();
+    let (mut long_name, mut long_link) = (None, None);
+    let mut block = [0; BLOCK as usize];
+    loop {
+        match archive.read_exact(&mut block) {
+            // some writers leave out the second empty block, or both
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            res => res?,
+        }
+        if block.iter().all(|b| *b == 0) {
+            break;
+        }
+        let mut sum = block.iter().map(|b| u64::from(*b)).sum::<u64>();
+        sum -= block[148..156].iter().map(|b| u64::from(*b)).sum::<u64>();
+        sum += 8 * u64::from(b' ');
+        if number(&block[148..156])? != sum {
+            return Err(Error::Malformed("bad header checksum"));
+        }
+        let typeflag = block[156];
+        let mut size = number(&block[124..136])?;
+        // old GNU sparse files have their map in the header
+        let mut old_sparse = None;
+        if typeflag == b'S' {
+            let mut map = Vec::new();
+            let entry = |e: &[u8]| -> Result<Option<(u64, u64)>> {
+                match e[0] {
+                    0 => Ok(None),
+                    _ => Ok(Some((number(&e[..12])?, number(&e[12..24])?))),
+                }
+            };
+            for e in block[386..482].chunks(24) {
+                map.extend(entry(e)?);
+            }
+            let mut extended = block[482] != 0;
+            let mut ext = [0; BLOCK as usize];
+            while extended {
+                archive.read_exact(&mut ext)?;
+                for e in ext[..504].chunks(24) {
+                    map.extend(entry(e)?);
+                }
+                extended = ext[504] != 0;
+            }
+            old_sparse = Some((map, number(&block[483..495])?));
+        }
+        let mut data = Vec::new();
+        data.try_reserve(size as usize)
+            .map_err(|_| Error::Malformed("member is too large"))?;
+        (&mut archive).take(size).read_to_end(&mut data)?;
+        if (data.len() as u64) < size {
+            return Err(Error::Malformed("archive ends in the middle of a member"));
+        }
+        let padding = size.div_ceil(BLOCK) * BLOCK - size;
+        std::io::copy(&mut (&mut archive).take(padding), &mut std::io::sink())?;
+
+        match typeflag {
+            b'x' => {
+                pax_records(&data, &mut local)?;
+                continue;
+            }
+            b'g' => {
+                pax_records(&data, &mut global)?;
+                continue;
+            }
+            b'L' => {
+                long_name = Some(string(&data).to_vec());
+                continue;
+            }
+            b'K' => {
+                long_link = Some(string(&data).to_vec());
+                continue;
+            }
+            _ => (),
+        }
+        let mut pax = global.clone();
+        pax.append(&mut local);
+
+        let mut name = string(&block[0..100]).to_vec();
+        if &block[257..262] == b"ustar" && block[345] != 0 {
+            let mut prefixed = string(&block[345..500]).to_vec();
+            prefixed.push(b'/');
+            prefixed.extend_from_slice(&name);
+            name = prefixed;
+        }
+        let name = pax
+            .remove(b"GNU.sparse.name".as_slice())
+            .or_else(|| pax.remove(b"path".as_slice()))
+            .or(long_name.take())
+            .unwrap_or(name);
+        let link = pax
+            .remove(b"linkpath".as_slice())
+            .or(long_link.take())
+            .unwrap_or_else(|| string(&block[157..257]).to_vec());
+        let field = |key: &[u8], default: u64| -> Result<u64> {
+            match pax.get(key) {
+                Some(v) => std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or(Error::Malformed("bad pax number")),
+                None => Ok(default),
+            }
+        };
+        size = field(b"size", size)?;
+        let mtime = pax
+            .get(b"mtime".as_slice())
+            .and_then(|t| pax_time(t))
+            .unwrap_or(SystemTime::UNIX_EPOCH + Duration::from_secs(number(&block[136..148])?));
+        let time = |key: &[u8]| pax.get(key).and_then(|t| pax_time(t)).unwrap_or(mtime);
+        let rdev = nix::sys::stat::makedev(number(&block[329..337])?, number(&block[337..345])?);
+        let kind = match typeflag {
+            b'0' | 0 | b'7' | b'S' => Kind::File,
+            b'1' => Kind::Hardlink(subvolume_path(&link)?),
+            b'2' => Kind::Symlink(PathBuf::from(OsStr::from_bytes(&link))),
+            b'3' => Kind::Device(libc::S_IFCHR, rdev),
+            b'4' => Kind::Device(libc::S_IFBLK, rdev),
+            b'5' => Kind::Directory,
+            b'6' => Kind::Fifo,
+            _ => return Err(Error::Unsupported(typeflag)),
+        };
+        let mut member = Member {
+            path: subvolume_path(&name)?,
+            kind,
+            mode: number(&block[100..108])? as u32 & 0o7777,
+            uid: field(b"uid", number(&block[108..116])?)? as u32,
+            gid: field(b"gid", number(&block[116..124])?)? as u32,
+            atime: time(b"atime"),
+            mtime,
+            ctime: time(b"ctime"),
+            xattrs: pax
+                .iter()
+                .filter_map(|(k, v)| Some((k.strip_prefix(b"SCHILY.xattr.")?.to_vec(), v.clone())))
+                .collect(),
+            map: None,
+            size,
+        };
+        if let Some((map, real)) = old_sparse {
+            member.map = Some(map);
+            member.size = real;
+        } else if let Some(real) = pax
+            .get(b"GNU.sparse.realsize".as_slice())
+            .or(pax.get(b"GNU.sparse.size".as_slice()))
+        {
+            member.size = std::str::from_utf8(real)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or(Error::Malformed("bad sparse size"))?;
+            if pax
+                .get(b"GNU.sparse.major".as_slice())
+                .is_some_and(|v| v == b"1")
+            {
+                let (map, len) = sparse_map(&data)?;
+                data.drain(..len.min(data.len()));
+                member.map = Some(map);
+            } else if let Some(map) = pax.get(b"GNU.sparse.map".as_slice()) {
+                let numbers = std::str::from_utf8(map)
+                    .ok()
+                    .and_then(|m| {
+                        m.split(',')
+                            .map(|n| n.parse().ok())
+                            .collect::<Option<Vec<u64>>>()
+                    })
+                    .ok_or(Error::Malformed("bad sparse map"))?;
+                member.map = Some(
+                    numbers
+                        .chunks(2)
+                        .filter_map(|c| Some((c[0], *c.get(1)?)))
+                        .collect(),
+                );
+            }
+        }
+        importer.member(member, &data)?;
+    }
+    Ok(emit_full(&importer.fs, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::compare::diff;
+    use crate::compare::Change;
+    use crate::tar::to_tar;
+
+    #[test]
+    fn from_tar() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let archive = to_tar(&sendstreams[0], Vec::new()).expect("failed to archive");
+        let header = crate::Subvol::new(
+            Path::new("demo"),
+            uuid::Uuid::from_u128(1),
+            crate::Ctransid(7),
+        );
+        let stream = super::from_tar(&archive[..], header).expect("failed to import");
+        assert!(matches!(stream.commands()[0], Command::Subvol(_)));
+        assert!(stream.commands().iter().any(|c| matches!(
+            c,
+            Command::Mkfile(m) if m.path().to_string_lossy().ends_with("-7-0")
+        )));
+        // tarballs have no sockets
+        let diff = diff(&sendstreams[0], &stream).expect("failed to replay");
+        assert_eq!(
+            vec![(Path::new("socket-node.sock"), &Change::Removed)],
+            diff.paths
+                .iter()
+                .map(|p| (p.path.as_path(), &p.change))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn outside() {
+        let mut evil = [0; 512];
+        evil[..4].copy_from_slice(b"../x");
+        evil[100..108].copy_from_slice(b"0000644\0");
+        evil[124..136].copy_from_slice(b"00000000000\0");
+        evil[156] = b'0';
+        evil[257..265].copy_from_slice(b"ustar\x0000");
+        evil[148..156].fill(b' ');
+        let sum: u32 = evil.iter().map(|b| u32::from(*b)).sum();
+        evil[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        let header = crate::Subvol::new(Path::new("x"), uuid::Uuid::nil(), crate::Ctransid(1));
+        let res = super::from_tar(&evil[..], header);
+        assert!(
+            matches!(
+                res,
+                Err(Error::Outside(ref p)) if p == Path::new("../x")
+            ),
+            "{res:?}"
+        );
+    }
+}
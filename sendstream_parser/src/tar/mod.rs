@@ -0,0 +1,511 @@
+//! Write the subvolumes that sendstreams produce as tarballs (POSIX pax,
+//! with GNU sparse files), without receiving them anywhere first: all at once
+//! with [to_tar], or as the stream is parsed with [TarReceiver]. Going the
+//! other way, [from_tar] turns a tarball into a full sendstream.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::fs;
+use crate::fs::FileContents;
+use crate::fs::Filesystem;
+use crate::fs::Inode;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::Sendstream;
+
+mod import;
+mod receiver;
+
+pub use import::from_tar;
+pub use receiver::TarReceiver;
+
+const BLOCK: u64 = 512;
+/// File data is copied into the archive in pieces of at most this size
+const COPY_LEN: u64 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] crate::Error<'static>),
+    #[error(transparent)]
+    Fs(#[from] fs::Error),
+    #[error("failed to write archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0:?} is already in the archive, so it can not be renamed or removed")]
+    Written(PathBuf),
+    #[error("malformed archive: {0}")]
+    Malformed(&'static str),
+    #[error("{0:?} leads outside of the archive")]
+    Outside(PathBuf),
+    #[error("members of type {:?} are not supported", char::from(*.0))]
+    Unsupported(u8),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where an archive is written. Some streams need data that is already in
+/// the archive again (for example when a file that was written out is the
+/// source of a clone), so it has to be possible to read it back.
+pub trait Archive: Write {
+    /// Read exactly `buf.len()` bytes starting `offset` bytes into the archive
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+}
+
+impl Archive for Vec<u8> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buf.len())?))
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// The file has to be opened for reading as well as writing, and the archive
+/// has to start at the beginning of it
+impl Archive for std::fs::File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+}
+
+/// [Archive] that can not be read back, like a pipe. Streams that need to
+/// read back from it fail.
+pub struct Unseekable<W>(pub W);
+
+impl<W: Write> Write for Unseekable<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Archive for Unseekable<W> {
+    fn read_at(&mut self, _offset: u64, _buf: &mut [u8]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "archive can not be read back",
+        ))
+    }
+}
+
+pub(crate) enum EntryKind<'a> {
+    File(&'a FileContents),
+    /// Another name for a path earlier in the archive
+    Hardlink(&'a Path),
+    Symlink(&'a Path),
+    Directory,
+    Fifo,
+    CharDevice(u64),
+    BlockDevice(u64),
+}
+
+pub(crate) struct Entry<'a> {
+    /// Relative to the root of the subvolume, which is the empty path
+    pub(crate) path: &'a Path,
+    pub(crate) kind: EntryKind<'a>,
+    /// Permission bits
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) mtime: Option<SystemTime>,
+    pub(crate) xattrs: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<'a> Entry<'a> {
+    /// Entry for `inode` at `path`, with the permissions that a file gets
+    /// when the stream never sets them
+    pub(crate) fn new(path: &'a Path, inode: &'a Inode, kind: EntryKind<'a>) -> Self {
+        let mode = match kind {
+            EntryKind::Directory => 0o755,
+            EntryKind::Symlink(_) => 0o777,
+            _ => 0o644,
+        };
+        Self {
+            path,
+            kind,
+            mode: inode.mode().map_or(mode, |m| m.0),
+            uid: inode.uid().map_or(0, |u| u.as_raw()),
+            gid: inode.gid().map_or(0, |g| g.as_raw()),
+            mtime: inode.mtime().map(|t| *t),
+            xattrs: inode.xattrs(),
+        }
+    }
+}
+
+/// Where a range of a file's data ended up in the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Region {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    /// Offset in the archive
+    pub(crate) at: u64,
+}
+
+/// Append a `<len> <key>=<value>\n` pax record, where `len` counts itself
+fn pax_record(out: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while base + len.to_string().len() != len {
+        len = base + len.to_string().len();
+    }
+    out.extend_from_slice(format!("{len} ").as_bytes());
+    out.extend_from_slice(key);
+    out.push(b'=');
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+/// Write `v` as a nul-terminated octal number filling `field`, or zero if it
+/// does not fit (in which case it has to go in a pax record instead)
+fn octal(field: &mut [u8], v: u64) -> bool {
+    let digits = field.len() - 1;
+    let s = format!("{v:0digits$o}");
+    let fits = s.len() == digits;
+    if fits {
+        field[..digits].copy_from_slice(s.as_bytes());
+    } else {
+        field[..digits].fill(b'0');
+    }
+    fits
+}
+
+/// Copy as much of `value` as fits in `field`, returning whether it all did
+fn text(field: &mut [u8], value: &[u8]) -> bool {
+    let n = value.len().min(field.len());
+    field[..n].copy_from_slice(&value[..n]);
+    n == value.len()
+}
+
+struct Header {
+    name: Vec<u8>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    typeflag: u8,
+    link: Vec<u8>,
+    rdev: u64,
+}
+
+impl Header {
+    /// The ustar header block, adding pax records to `pax` for anything that
+    /// does not fit in it
+    fn encode(&self, pax: &mut Vec<u8>) -> [u8; BLOCK as usize] {
+        let mut h = [0; BLOCK as usize];
+        if !text(&mut h[0..100], &self.name) {
+            pax_record(pax, b"path", &self.name);
+        }
+        octal(&mut h[100..108], self.mode.into());
+        if !octal(&mut h[108..116], self.uid.into()) {
+            pax_record(pax, b"uid", self.uid.to_string().as_bytes());
+        }
+        if !octal(&mut h[116..124], self.gid.into()) {
+            pax_record(pax, b"gid", self.gid.to_string().as_bytes());
+        }
+        if !octal(&mut h[124..136], self.size) {
+            pax_record(pax, b"size", self.size.to_string().as_bytes());
+        }
+        octal(&mut h[136..148], self.mtime);
+        h[156] = self.typeflag;
+        if !text(&mut h[157..257], &self.link) {
+            pax_record(pax, b"linkpath", &self.link);
+        }
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        if matches!(self.typeflag, b'3' | b'4') {
+            octal(&mut h[329..337], nix::sys::stat::major(self.rdev));
+            octal(&mut h[337..345], nix::sys::stat::minor(self.rdev));
+        }
+        h[148..156].fill(b' ');
+        let sum: u32 = h.iter().map(|b| u32::from(*b)).sum();
+        h[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+        h
+    }
+}
+
+/// Name of `path` in the archive, like `tar -C <subvolume> .` would have it
+fn archive_name(path: &Path, dir: bool) -> Vec<u8> {
+    let mut name = b"./".to_vec();
+    name.extend_from_slice(path.as_os_str().as_bytes());
+    if dir && !path.as_os_str().is_empty() {
+        name.push(b'/');
+    }
+    name
+}
+
+/// Appends entries to an archive, keeping track of how far into it they are
+pub(crate) struct TarWriter<W> {
+    w: W,
+    pos: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub(crate) fn new(w: W) -> Self {
+        Self { w, pos: 0 }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.w
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.w.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Zeroes up to the end of the current block
+    fn pad(&mut self) -> std::io::Result<()> {
+        let rem = self.pos % BLOCK;
+        if rem != 0 {
+            self.write(&[0; BLOCK as usize][..(BLOCK - rem) as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Append `entry`, returning where the data of a regular file went
+    pub(crate) fn entry(&mut self, entry: &Entry) -> std::io::Result<Vec<Region>> {
+        let dir = matches!(entry.kind, EntryKind::Directory);
+        let name = archive_name(entry.path, dir);
+        let mut pax = Vec::new();
+        let mut header = Header {
+            name: name.clone(),
+            mode: entry.mode & 0o7777,
+            uid: entry.uid,
+            gid: entry.gid,
+            size: 0,
+            mtime: 0,
+            typeflag: b'0',
+            link: Vec::new(),
+            rdev: 0,
+        };
+        let mut ranges = Vec::new();
+        let mut sparse_map = Vec::new();
+        match &entry.kind {
+            EntryKind::File(contents) => {
+                ranges = contents.data_ranges();
+                let data: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+                header.size = data;
+                if data < contents.len() {
+                    // GNU sparse format 1.0: the data is preceded by a map
+                    // of where it goes, and the real name is in pax records
+                    let mut map = ranges
+                        .iter()
+                        .map(|r| (r.start, r.end - r.start))
+                        .collect::<Vec<_>>();
+                    if ranges.last().is_none_or(|r| r.end < contents.len()) {
+                        map.push((contents.len(), 0));
+                    }
+                    sparse_map = format!("{}\n", map.len()).into_bytes();
+                    for (offset, len) in map {
+                        sparse_map.extend_from_slice(format!("{offset}\n{len}\n").as_bytes());
+                    }
+                    let padded = sparse_map.len().div_ceil(BLOCK as usize) * BLOCK as usize;
+                    sparse_map.resize(padded, 0);
+                    header.size += sparse_map.len() as u64;
+                    pax_record(&mut pax, b"GNU.sparse.major", b"1");
+                    pax_record(&mut pax, b"GNU.sparse.minor", b"0");
+                    pax_record(&mut pax, b"GNU.sparse.name", &name);
+                    pax_record(
+                        &mut pax,
+                        b"GNU.sparse.realsize",
+                        contents.len().to_string().as_bytes(),
+                    );
+                    let parent = entry.path.parent().unwrap_or(Path::new(""));
+                    let mut fake = archive_name(parent, true);
+                    fake.extend_from_slice(b"GNUSparseFile.0/");
+                    fake.extend_from_slice(
+                        entry
+                            .path
+                            .file_name()
+                            .map(|n| n.as_bytes())
+                            .unwrap_or_default(),
+                    );
+                    header.name = fake;
+                }
+            }
+            EntryKind::Hardlink(target) => {
+                header.typeflag = b'1';
+                header.link = archive_name(target, false);
+            }
+            EntryKind::Symlink(target) => {
+                header.typeflag = b'2';
+                header.link = target.as_os_str().as_bytes().to_vec();
+            }
+            EntryKind::Directory => header.typeflag = b'5',
+            EntryKind::Fifo => header.typeflag = b'6',
+            EntryKind::CharDevice(rdev) => {
+                header.typeflag = b'3';
+                header.rdev = *rdev;
+            }
+            EntryKind::BlockDevice(rdev) => {
+                header.typeflag = b'4';
+                header.rdev = *rdev;
+            }
+        }
+        match entry
+            .mtime
+            .map(|t| t.duration_since(SystemTime::UNIX_EPOCH))
+        {
+            Some(Ok(d)) if d.subsec_nanos() == 0 => header.mtime = d.as_secs(),
+            Some(Ok(d)) => {
+                header.mtime = d.as_secs();
+                let value = format!("{}.{:09}", d.as_secs(), d.subsec_nanos());
+                pax_record(&mut pax, b"mtime", value.as_bytes());
+            }
+            Some(Err(e)) => {
+                let d = e.duration();
+                let value = format!("-{}.{:09}", d.as_secs(), d.subsec_nanos());
+                pax_record(&mut pax, b"mtime", value.as_bytes());
+            }
+            None => (),
+        }
+        for (key, value) in entry.xattrs {
+            let mut k = b"SCHILY.xattr.".to_vec();
+            k.extend_from_slice(key);
+            pax_record(&mut pax, &k, value);
+        }
+
+        let block = header.encode(&mut pax);
+        if !pax.is_empty() {
+            let mut name = b"./PaxHeaders/".to_vec();
+            name.extend_from_slice(
+                entry
+                    .path
+                    .file_name()
+                    .map(|n| n.as_bytes())
+                    .unwrap_or_default(),
+            );
+            let pax_header = Header {
+                name,
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: pax.len() as u64,
+                mtime: 0,
+                typeflag: b'x',
+                link: Vec::new(),
+                rdev: 0,
+            };
+            // anything too long for the pax header itself just gets cut off
+            self.write(&pax_header.encode(&mut Vec::new()))?;
+            self.write(&pax)?;
+            self.pad()?;
+        }
+        self.write(&block)?;
+        self.write(&sparse_map)?;
+        let mut regions = Vec::new();
+        if let EntryKind::File(contents) = &entry.kind {
+            for range in ranges {
+                regions.push(Region {
+                    offset: range.start,
+                    len: range.end - range.start,
+                    at: self.pos,
+                });
+                let mut off = range.start;
+                while off < range.end {
+                    let len = COPY_LEN.min(range.end - off);
+                    self.write(&contents.read(off, len))?;
+                    off += len;
+                }
+            }
+        }
+        self.pad()?;
+        Ok(regions)
+    }
+
+    /// End the archive with two empty blocks
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        self.write(&[0; 2 * BLOCK as usize])?;
+        self.w.flush()
+    }
+}
+
+/// Write the subvolume that the full stream `stream` creates to `w` as a
+/// tarball, returning `w` once the archive is complete. Unlike
+/// [TarReceiver], this replays the whole stream in memory first, so nothing
+/// ever has to be read back from the archive. Every name of a file after the
+/// first is a hard link to the first one, and sockets are left out.
+pub fn to_tar<W: Write>(stream: &Sendstream, w: W) -> Result<W> {
+    let fs = Filesystem::from_sendstream(stream)?;
+    let mut tar = TarWriter::new(w);
+    let mut linked: BTreeMap<InodeId, PathBuf> = BTreeMap::new();
+    for (path, id) in std::iter::once((PathBuf::new(), fs.root())).chain(fs.walk()) {
+        let inode = &fs[id];
+        let kind = match inode.kind() {
+            InodeKind::Directory(_) => EntryKind::Directory,
+            _ if linked.contains_key(&id) => EntryKind::Hardlink(&linked[&id]),
+            InodeKind::File(c) => EntryKind::File(c),
+            InodeKind::Symlink(t) => EntryKind::Symlink(t),
+            InodeKind::Fifo => EntryKind::Fifo,
+            InodeKind::CharDevice(r) => EntryKind::CharDevice(r.as_u64()),
+            InodeKind::BlockDevice(r) => EntryKind::BlockDevice(r.as_u64()),
+            InodeKind::Socket => continue,
+        };
+        tar.entry(&Entry::new(&path, inode, kind))?;
+        if inode.nlink() > 1 && !linked.contains_key(&id) {
+            linked.insert(id, path);
+        }
+    }
+    tar.finish()?;
+    Ok(tar.into_inner())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Name, type and size field of every header in `archive`
+    pub(crate) fn headers(archive: &[u8]) -> Vec<(String, u8, u64)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos + 512 <= archive.len() && archive[pos] != 0 {
+            let h = &archive[pos..pos + 512];
+            let field = |r: std::ops::Range<usize>| {
+                let f = &h[r];
+                String::from_utf8_lossy(&f[..f.iter().position(|b| *b == 0).unwrap_or(f.len())])
+                    .into_owned()
+            };
+            let size = u64::from_str_radix(&field(124..135), 8).expect("bad size");
+            out.push((field(0..100), h[156], size));
+            pos += 512 + size.div_ceil(512) as usize * 512;
+        }
+        out
+    }
+
+    #[test]
+    fn to_tar() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let archive = super::to_tar(&sendstreams[0], Vec::new()).expect("failed to archive");
+        let mut entries = headers(&archive);
+        // the same entries as when the stream is received, in another order
+        let mut receiver = TarReceiver::new(Vec::new());
+        crate::receive::receive_all(&mut receiver, &sendstreams[..1]).expect("failed to receive");
+        let mut received = headers(&receiver.into_inner());
+        entries.sort();
+        received.sort();
+        assert_eq!(received, entries);
+        assert!(archive.ends_with(&[0; 1024]));
+        assert!(matches!(
+            super::to_tar(&sendstreams[1], Vec::new()),
+            Err(Error::Fs(fs::Error::Incremental))
+        ));
+    }
+}
@@ -0,0 +1,385 @@
+//! [TarReceiver], which turns a stream into a tarball as it is parsed.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::Archive;
+use super::Entry;
+use super::EntryKind;
+use super::Error;
+use super::Region;
+use super::Result;
+use super::TarWriter;
+use super::COPY_LEN;
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::fs::InodeId;
+use crate::fs::InodeKind;
+use crate::orphans::is_temporary_name;
+use crate::receive::Receiver;
+use crate::Command;
+
+/// A file that is already in the archive
+struct Written {
+    paths: Vec<PathBuf>,
+    regions: Vec<Region>,
+}
+
+/// [Receiver] that writes the subvolume of each full stream to a tar
+/// archive as it goes, instead of replaying the whole thing first. Each file
+/// is written out once the stream moves on to the next one (or has been
+/// renamed into place, if it starts out with a temporary name), after which
+/// its data is no longer held in memory. Directories are written at the end
+/// of each stream, since their times change with everything created in
+/// them. If a stream comes back to a file that was already written, the file
+/// is read back from the archive and written again, which extractors take
+/// as replacing it.
+///
+/// Sockets can not be archived and are left out. Incremental streams can not
+/// be expressed as a tarball, since it can not delete anything.
+pub struct TarReceiver<W> {
+    tar: TarWriter<W>,
+    fs: Filesystem,
+    /// Every name of each (non-directory) inode that is not in the archive
+    open: BTreeMap<InodeId, Vec<PathBuf>>,
+    /// The one that the stream is working on
+    current: Option<InodeId>,
+    written: BTreeMap<InodeId, Written>,
+    written_paths: BTreeSet<PathBuf>,
+}
+
+/// Existing path that `cmd` changes the inode of
+fn subject<'c>(cmd: &'c Command) -> Option<&'c Path> {
+    match cmd {
+        Command::Chmod(c) => Some(c.path()),
+        Command::Chown(c) => Some(c.path()),
+        Command::Clone(c) => Some(c.dst_path()),
+        Command::EncodedWrite(c) => Some(c.path()),
+        Command::Fallocate(c) => Some(c.path()),
+        Command::Fileattr(c) => Some(c.path()),
+        Command::Link(c) => Some(c.target().as_path()),
+        Command::RemoveXattr(c) => Some(c.path()),
+        Command::Rename(c) => Some(c.from()),
+        Command::SetXattr(c) => Some(c.path()),
+        Command::Truncate(c) => Some(c.path()),
+        Command::Unlink(c) => Some(c.path()),
+        Command::UpdateExtent(c) => Some(c.path()),
+        Command::Utimes(c) => Some(c.path()),
+        Command::Write(c) => Some(c.path()),
+        _ => None,
+    }
+}
+
+/// Path of the non-directory that `cmd` creates
+fn created<'c>(cmd: &'c Command) -> Option<&'c Path> {
+    match cmd {
+        Command::Mkfile(c) => Some(c.path().as_path()),
+        Command::Mkfifo(c) => Some(c.path().as_path()),
+        Command::Mknod(c) => Some(c.path().as_path()),
+        Command::Mksock(c) => Some(c.path().as_path()),
+        Command::Symlink(c) => Some(c.link_name()),
+        _ => None,
+    }
+}
+
+fn temporary(path: &Path) -> bool {
+    path.iter().any(is_temporary_name)
+}
+
+impl<W: Archive> TarReceiver<W> {
+    /// Write the archive to `archive`, starting at its beginning
+    pub fn new(archive: W) -> Self {
+        Self {
+            tar: TarWriter::new(archive),
+            fs: Filesystem::new(),
+            open: BTreeMap::new(),
+            current: None,
+            written: BTreeMap::new(),
+            written_paths: BTreeSet::new(),
+        }
+    }
+
+    /// The archive, which is only complete after [Receiver::close]
+    pub fn into_inner(self) -> W {
+        self.tar.into_inner()
+    }
+
+    fn is_dir(&self, id: InodeId) -> bool {
+        matches!(self.fs[id].kind(), InodeKind::Directory(_))
+    }
+
+    /// Non-directory at `path`
+    fn lookup(&self, path: &Path) -> Option<InodeId> {
+        self.fs.lookup(path).filter(|id| !self.is_dir(*id))
+    }
+
+    /// Whether anything at or below `path` is in the archive
+    fn written_below(&self, path: &Path) -> bool {
+        self.written_paths
+            .range(path.to_path_buf()..)
+            .next()
+            .is_some_and(|p| p.starts_with(path))
+    }
+
+    /// Put the data of a file that is in the archive back in memory
+    fn read_back(&mut self, id: InodeId) -> Result<()> {
+        let regions = match self.written.get(&id) {
+            Some(w) => w.regions.clone(),
+            None => return Ok(()),
+        };
+        for r in regions {
+            let mut done = 0;
+            while done < r.len {
+                let mut buf = vec![0; COPY_LEN.min(r.len - done) as usize];
+                self.tar.get_mut().read_at(r.at + done, &mut buf)?;
+                if let Some(c) = self.fs.contents_of_mut(id) {
+                    c.write(r.offset + done, &buf);
+                }
+                done += buf.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `id` to the archive under all of its names
+    fn emit(&mut self, id: InodeId) -> Result<()> {
+        let Some(paths) = self.open.remove(&id) else {
+            return Ok(());
+        };
+        let Some(inode) = self.fs.inode(id) else {
+            return Ok(());
+        };
+        let kind = match inode.kind() {
+            InodeKind::File(c) => EntryKind::File(c),
+            InodeKind::Symlink(t) => EntryKind::Symlink(t),
+            InodeKind::Fifo => EntryKind::Fifo,
+            InodeKind::CharDevice(r) => EntryKind::CharDevice(r.as_u64()),
+            InodeKind::BlockDevice(r) => EntryKind::BlockDevice(r.as_u64()),
+            InodeKind::Socket | InodeKind::Directory(_) => return Ok(()),
+        };
+        let Some((first, links)) = paths.split_first() else {
+            return Ok(());
+        };
+        let mut entry = Entry::new(first, inode, kind);
+        let regions = self.tar.entry(&entry)?;
+        for link in links {
+            entry.path = link;
+            entry.kind = EntryKind::Hardlink(first);
+            self.tar.entry(&entry)?;
+        }
+        if let Some(c) = self.fs.contents_of_mut(id) {
+            c.clear();
+        }
+        self.written_paths.extend(paths.iter().cloned());
+        self.written.insert(id, Written { paths, regions });
+        Ok(())
+    }
+
+    /// The stream moved on from `id`, so write it out unless it still has
+    /// a temporary name that it is going to be renamed from
+    fn set_aside(&mut self, id: InodeId) -> Result<()> {
+        match self.open.get(&id) {
+            Some(paths) if !paths.iter().any(|p| temporary(p)) => self.emit(id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Everything that is left at the end of a stream
+    fn end(&mut self) -> Result<()> {
+        let ids: Vec<_> = self.current.take().into_iter().collect();
+        for id in ids
+            .into_iter()
+            .chain(self.open.keys().copied().collect::<Vec<_>>())
+        {
+            self.emit(id)?;
+        }
+        let dirs = std::iter::once((PathBuf::new(), self.fs.root())).chain(self.fs.walk());
+        for (path, id) in dirs {
+            let inode = &self.fs[id];
+            if !matches!(inode.kind(), InodeKind::Directory(_)) {
+                continue;
+            }
+            self.tar
+                .entry(&Entry::new(&path, inode, EntryKind::Directory))?;
+        }
+        self.fs = Filesystem::new();
+        self.written.clear();
+        self.written_paths.clear();
+        Ok(())
+    }
+}
+
+impl<W: Archive> Receiver for TarReceiver<W> {
+    type Error = Error;
+
+    fn command(&mut self, cmd: &Command) -> Result<()> {
+        match cmd {
+            Command::Subvol(_) => {
+                self.fs = Filesystem::new();
+                self.fs.apply(cmd)?;
+                return Ok(());
+            }
+            Command::Snapshot(_) => return Err(fs::Error::Incremental.into()),
+            Command::End => return self.end(),
+            _ => (),
+        }
+        // tar can not take back anything that is already in the archive
+        let removed = match cmd {
+            Command::Rename(c) => [Some(c.from()), Some(c.to())],
+            Command::Unlink(c) => [Some(c.path()), None],
+            _ => [None, None],
+        };
+        for path in removed.into_iter().flatten() {
+            if self.written_below(path) {
+                return Err(Error::Written(path.to_path_buf()));
+            }
+        }
+
+        let subject = subject(cmd).and_then(|p| self.lookup(p));
+        if let Some(id) = subject {
+            if let Some(w) = self.written.get(&id) {
+                let paths = w.paths.clone();
+                self.read_back(id)?;
+                self.written.remove(&id);
+                self.open.insert(id, paths);
+            }
+        }
+        let source = match cmd {
+            Command::Clone(c) if self.fs.subvolume().map(|s| s.uuid) == Some(c.uuid()) => self
+                .lookup(c.src_path())
+                .filter(|id| self.written.contains_key(id)),
+            _ => None,
+        };
+        if let Some(id) = source {
+            self.read_back(id)?;
+        }
+        let replaced = match cmd {
+            Command::Rename(c) => self.lookup(c.to()).filter(|id| Some(*id) != subject),
+            _ => None,
+        };
+        let dir = match cmd {
+            Command::Rename(c) => self.fs.lookup(c.from()).filter(|id| self.is_dir(*id)),
+            _ => None,
+        };
+
+        self.fs.apply(cmd)?;
+
+        if let Some(c) = source.and_then(|id| self.fs.contents_of_mut(id)) {
+            c.clear();
+        }
+        let mut touched = subject;
+        match cmd {
+            Command::Link(c) => {
+                if let Some(paths) = subject.and_then(|id| self.open.get_mut(&id)) {
+                    paths.push(c.link_name().to_path_buf());
+                }
+            }
+            Command::Rename(c) => {
+                if let Some(paths) = replaced.and_then(|id| self.open.get_mut(&id)) {
+                    paths.retain(|p| p != c.to());
+                }
+                if let Some(paths) = subject.and_then(|id| self.open.get_mut(&id)) {
+                    for p in paths.iter_mut().filter(|p| p.as_path() == c.from()) {
+                        *p = c.to().to_path_buf();
+                    }
+                }
+                if dir.is_some() {
+                    for p in self.open.values_mut().flatten() {
+                        if let Ok(rest) = p.strip_prefix(c.from()) {
+                            *p = c.to().join(rest);
+                        }
+                    }
+                }
+            }
+            Command::Unlink(c) => {
+                if let Some(paths) = subject.and_then(|id| self.open.get_mut(&id)) {
+                    paths.retain(|p| p != c.path());
+                }
+            }
+            _ => {
+                if let Some(path) = created(cmd) {
+                    touched = self.lookup(path);
+                    if let Some(id) = touched {
+                        self.open.insert(id, vec![path.to_path_buf()]);
+                    }
+                }
+            }
+        }
+        for id in [subject, replaced].into_iter().flatten() {
+            if self.fs.inode(id).is_none() {
+                self.open.remove(&id);
+                if self.current == Some(id) {
+                    self.current = None;
+                }
+                if touched == Some(id) {
+                    touched = None;
+                }
+            }
+        }
+
+        if let Some(id) = touched {
+            if let Some(prev) = self.current.replace(id).filter(|prev| *prev != id) {
+                self.set_aside(prev)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.tar.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receive::receive;
+    use crate::receive::receive_all;
+    use crate::tar::tests::headers;
+    use crate::CommandReader;
+    use crate::Sendstream;
+
+    #[test]
+    fn tar_demo() {
+        let bytes = include_bytes!("../../testdata/demo.sendstream");
+        let sendstreams = Sendstream::parse_all(bytes).expect("failed to parse demo.sendstream");
+        let mut receiver = TarReceiver::new(Vec::new());
+        receive_all(&mut receiver, &sendstreams[..1]).expect("failed to receive");
+        let archive = receiver.into_inner();
+        // the huge sparse file only takes up its map
+        assert!(archive.len() < 1 << 20, "{}", archive.len());
+        let headers = headers(&archive);
+        let find = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _, _)| n == name)
+                .unwrap_or_else(|| panic!("{name} is missing from {headers:?}"))
+        };
+        assert_eq!(&("./hello/msg".to_owned(), b'0', 13), find("./hello/msg"));
+        assert_eq!(b'1', find("./hello/msg-hard").1);
+        assert_eq!(b'2', find("./hello/msg-sym").1);
+        assert_eq!(b'6', find("./myfifo").1);
+        assert_eq!(b'5', find("./").1);
+        assert_eq!(223446, find("./hello/lorem-reflinked").2);
+        assert_eq!(b'x', find("./PaxHeaders/huge-empty-file").1);
+        find("./GNUSparseFile.0/huge-empty-file");
+        assert!(!headers.iter().any(|(n, _, _)| n.contains("socket")));
+        assert!(archive.ends_with(&[0; 1024]));
+
+        // the same, without having to parse the whole stream first
+        let mut streamed = TarReceiver::new(Vec::new());
+        let end = sendstreams[0].commands().len();
+        receive(&mut streamed, CommandReader::new(&bytes[..]).take(end))
+            .expect("failed to receive");
+        assert_eq!(archive, streamed.into_inner());
+
+        let mut incremental = TarReceiver::new(Vec::new());
+        assert!(matches!(
+            receive_all(&mut incremental, &sendstreams),
+            Err(Error::Fs(fs::Error::Incremental))
+        ));
+    }
+}
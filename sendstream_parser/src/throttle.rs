@@ -0,0 +1,131 @@
+//! Capping how fast sendstreams are read and applied, so that a background
+//! restore does not saturate the disks or network of a busy host. See
+//! [crate::CommandReader::throttle] and [crate::apply::Applier::throttle].
+
+use std::io::Read;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Token bucket that refills at `rate` per second, and holds at most a
+/// second's worth
+#[derive(Debug, Clone)]
+struct Bucket {
+    rate: u64,
+    /// Tokens that are left, which goes negative when more was taken than
+    /// there was
+    level: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            level: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Take `n` tokens, returning how long to wait until they are paid for
+    fn take(&mut self, now: Instant, n: u64) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.level = (self.level + elapsed * self.rate as f64).min(self.rate as f64) - n as f64;
+        self.last = now;
+        match self.level < 0.0 {
+            true => Duration::from_secs_f64(-self.level / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Caps on bytes and commands per second, which allow bursts of up to a
+/// second's worth of either
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    bytes: Option<Bucket>,
+    commands: Option<Bucket>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bytes_per_sec(mut self, rate: u64) -> Self {
+        self.bytes = Some(Bucket::new(rate, Instant::now()));
+        self
+    }
+
+    pub fn commands_per_sec(mut self, rate: u64) -> Self {
+        self.commands = Some(Bucket::new(rate, Instant::now()));
+        self
+    }
+
+    /// How long to wait at `now` after `bytes` and `commands` went through
+    fn delay(&mut self, now: Instant, bytes: u64, commands: u64) -> Duration {
+        let bytes = self.bytes.as_mut().map(|b| b.take(now, bytes));
+        let commands = self.commands.as_mut().map(|c| c.take(now, commands));
+        bytes.max(commands).unwrap_or_default()
+    }
+
+    /// Account for `bytes` and `commands` going through, sleeping for as
+    /// long as it takes to get back under the caps
+    pub fn wait(&mut self, bytes: u64, commands: u64) {
+        let delay = self.delay(Instant::now(), bytes, commands);
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// [Read] that is held to the byte cap of a [Throttle], for anything else
+/// that reads sendstreams
+pub struct ThrottledReader<R> {
+    r: R,
+    throttle: Throttle,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(r: R, throttle: Throttle) -> Self {
+        Self { r, throttle }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.throttle.wait(n as u64, 0);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let start = Instant::now();
+        let mut throttle = Throttle {
+            bytes: Some(Bucket::new(1000, start)),
+            commands: Some(Bucket::new(10, start)),
+        };
+        // a second's worth goes through at once
+        assert_eq!(Duration::ZERO, throttle.delay(start, 1000, 1));
+        // and then everything has to wait for its share of a second
+        assert_eq!(Duration::from_millis(500), throttle.delay(start, 500, 1));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Duration::ZERO, throttle.delay(later, 0, 0));
+        // whichever cap is further behind decides
+        assert_eq!(Duration::from_millis(500), throttle.delay(later, 0, 15));
+        // unused time does not pile up past a second's worth
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(Duration::ZERO, throttle.delay(idle, 1000, 10));
+        assert_eq!(Duration::from_millis(250), throttle.delay(idle, 250, 0));
+    }
+}
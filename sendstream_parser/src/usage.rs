@@ -0,0 +1,189 @@
+//! Attribute the space used by a chain of sendstreams to each of the
+//! subvolumes they produce, like `btrfs qgroup show` would after receiving
+//! all of them.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::extents::Source;
+use crate::fs;
+use crate::fs::Filesystem;
+use crate::fs::InodeKind;
+use crate::Sendstream;
+
+/// Space used by one subvolume, see [usage]. Data is counted in bytes of
+/// data extents (without rounding up to whole blocks).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SubvolumeUsage {
+    /// Index of the sendstream that produces this subvolume
+    pub index: usize,
+    pub path: PathBuf,
+    pub uuid: Uuid,
+    /// Index of the sendstream that produces the parent of an incremental
+    pub parent: Option<usize>,
+    /// All the data in the subvolume, whether or not it is shared with other
+    /// subvolumes. Files with several hard links are counted once, but files
+    /// that share data through clones count it once for each file.
+    pub referenced_bytes: u64,
+    /// Data that only this subvolume has: written by its own sendstream,
+    /// and not kept by any snapshot of it later in the chain
+    pub exclusive_bytes: u64,
+    /// Everything else that this subvolume references: inherited from its
+    /// parent, kept by its snapshots, or cloned from other subvolumes
+    pub shared_bytes: u64,
+    /// Estimated metadata written by the sendstream, see
+    /// [Sendstream::space_estimate]
+    pub metadata_bytes: u64,
+}
+
+/// Total length of `ranges` after merging any overlaps
+fn union_len(mut ranges: Vec<Range<u64>>) -> u64 {
+    ranges.sort_by_key(|r| r.start);
+    let mut total = 0;
+    let mut end = 0;
+    for r in ranges {
+        let start = r.start.max(end);
+        if r.end > start {
+            total += r.end - start;
+            end = r.end;
+        }
+    }
+    total
+}
+
+/// The parts of `range` that are not in any of `minus` (in offset order)
+fn subtract(range: Range<u64>, minus: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut out = Vec::new();
+    let mut pos = range.start;
+    for m in minus {
+        if m.end <= pos || m.start >= range.end {
+            continue;
+        }
+        if m.start > pos {
+            out.push(pos..m.start);
+        }
+        pos = pos.max(m.end);
+    }
+    if pos < range.end {
+        out.push(pos..range.end);
+    }
+    out
+}
+
+/// Non-hole data of every regular file in `fs`
+fn referenced(fs: &Filesystem) -> u64 {
+    let inodes: BTreeSet<_> = fs.walk().into_iter().map(|(_, id)| id).collect();
+    inodes
+        .into_iter()
+        .filter_map(|id| match fs[id].kind() {
+            InodeKind::File(c) => Some(c.extents().map(|(_, d)| d.len() as u64).sum::<u64>()),
+            _ => None,
+        })
+        .sum()
+}
+
+/// Work out how much space each subvolume in `chain` (a full sendstream
+/// followed by incrementals, as accepted by [Filesystem::from_chain]) takes
+/// up once all of them are received.
+///
+/// Sharing between subvolumes is inferred by path: data that a sendstream
+/// writes counts as shared with a later snapshot if the snapshot still has
+/// the same bytes at the same offset of the file at the same path. Data that
+/// a snapshot only moves elsewhere is therefore (wrongly) treated as
+/// exclusive to both.
+pub fn usage<'s>(chain: &[Sendstream<'s>]) -> fs::Result<Vec<SubvolumeUsage>> {
+    let states = Filesystem::from_chain(chain)?;
+    let infos: Vec<_> = chain.iter().map(Sendstream::subvolume).collect();
+    let parents: Vec<Option<usize>> = infos
+        .iter()
+        .enumerate()
+        .map(|(index, info)| {
+            let parent = info.as_ref()?.parent_uuid()?;
+            (0..index)
+                .rev()
+                .find(|i| infos[*i].as_ref().map(fs::SubvolumeInfo::uuid) == Some(parent))
+        })
+        .collect();
+    let mut out = Vec::with_capacity(chain.len());
+    for (index, stream) in chain.iter().enumerate() {
+        let children: Vec<&Filesystem> = (index + 1..chain.len())
+            .filter(|c| parents[*c] == Some(index))
+            .map(|c| &states[c])
+            .collect();
+        let mut written = 0;
+        let mut kept = 0;
+        for file in stream.extent_map() {
+            let ours = states[index].get(&file.path).and_then(|i| i.contents());
+            let mut kept_ranges = Vec::new();
+            for e in file.extents.iter().filter(|e| e.source == Source::Write) {
+                written += e.len;
+                let range = e.offset..e.offset + e.len;
+                for child in &children {
+                    let theirs = child.get(&file.path).and_then(|i| i.contents());
+                    if let (Some(ours), Some(theirs)) = (ours, theirs) {
+                        kept_ranges.extend(subtract(range.clone(), &ours.changed_ranges(theirs)));
+                    }
+                }
+            }
+            kept += union_len(kept_ranges);
+        }
+        let referenced_bytes = referenced(&states[index]);
+        let exclusive_bytes = (written - kept).min(referenced_bytes);
+        out.push(SubvolumeUsage {
+            index,
+            path: infos[index]
+                .as_ref()
+                .map(|i| i.path().to_path_buf())
+                .unwrap_or_default(),
+            uuid: infos[index]
+                .as_ref()
+                .map(fs::SubvolumeInfo::uuid)
+                .unwrap_or_default(),
+            parent: parents[index],
+            referenced_bytes,
+            exclusive_bytes,
+            shared_bytes: referenced_bytes - exclusive_bytes,
+            metadata_bytes: stream.space_estimate().metadata_bytes,
+        });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let usage = usage(&sendstreams).expect("failed to replay");
+        let lorem = 223446;
+        let reflinked = lorem;
+        assert_eq!(
+            (PathBuf::from("demo"), None, 13 + lorem + reflinked),
+            (
+                usage[0].path.clone(),
+                usage[0].parent,
+                usage[0].referenced_bytes
+            )
+        );
+        // everything but hello/msg is kept by the snapshot
+        assert_eq!(13, usage[0].exclusive_bytes);
+        assert_eq!(lorem + reflinked, usage[0].shared_bytes);
+
+        assert_eq!(Some(0), usage[1].parent);
+        assert_eq!(9 + lorem + reflinked, usage[1].referenced_bytes);
+        assert_eq!(9, usage[1].exclusive_bytes);
+        assert!(usage[1].metadata_bytes > 0);
+
+        assert_eq!(7, union_len(vec![0..4, 2..6, 8..9]));
+        assert_eq!(vec![0..2, 5..10], subtract(0..10, &[2..5, 12..14]));
+    }
+}
@@ -0,0 +1,129 @@
+//! Visitor-style traversal of [Command]s, for analyses that are only
+//! interested in a few kinds of commands.
+
+use crate::Chmod;
+use crate::Chown;
+use crate::Clone;
+use crate::Command;
+use crate::EncodedWrite;
+use crate::Fallocate;
+use crate::Fileattr;
+use crate::Link;
+use crate::Mkdir;
+use crate::Mkfifo;
+use crate::Mkfile;
+use crate::Mknod;
+use crate::Mksock;
+use crate::RemoveXattr;
+use crate::Rename;
+use crate::Rmdir;
+use crate::Sendstream;
+use crate::SetXattr;
+use crate::Snapshot;
+use crate::Subvol;
+use crate::Symlink;
+use crate::Truncate;
+use crate::Unlink;
+use crate::UpdateExtent;
+use crate::Utimes;
+use crate::Write;
+
+macro_rules! visitor {
+    ($($f:ident($t:ident)),+ $(,)?) => {
+        /// Has one method per [Command] type, each of which does nothing by
+        /// default. Implementors override only the ones they care about and
+        /// pass themselves to [walk].
+        pub trait CommandVisitor<'a> {
+            $(
+                fn $f(&mut self, _cmd: &$t<'a>) {}
+            )+
+            fn visit_end(&mut self) {}
+        }
+
+        /// Dispatch a single command to the matching method of `visitor`.
+        pub fn visit_command<'a>(cmd: &Command<'a>, visitor: &mut impl CommandVisitor<'a>) {
+            match cmd {
+                $(Command::$t(c) => visitor.$f(c),)+
+                Command::End => visitor.visit_end(),
+            }
+        }
+    };
+}
+
+visitor!(
+    visit_chmod(Chmod),
+    visit_chown(Chown),
+    visit_clone(Clone),
+    visit_encoded_write(EncodedWrite),
+    visit_fallocate(Fallocate),
+    visit_fileattr(Fileattr),
+    visit_link(Link),
+    visit_mkdir(Mkdir),
+    visit_mkfifo(Mkfifo),
+    visit_mkfile(Mkfile),
+    visit_mknod(Mknod),
+    visit_mksock(Mksock),
+    visit_remove_xattr(RemoveXattr),
+    visit_rename(Rename),
+    visit_rmdir(Rmdir),
+    visit_set_xattr(SetXattr),
+    visit_snapshot(Snapshot),
+    visit_subvol(Subvol),
+    visit_symlink(Symlink),
+    visit_truncate(Truncate),
+    visit_unlink(Unlink),
+    visit_update_extent(UpdateExtent),
+    visit_utimes(Utimes),
+    visit_write(Write),
+);
+
+/// Visit every command in `sendstream`, in order.
+pub fn walk<'a>(sendstream: &Sendstream<'a>, visitor: &mut impl CommandVisitor<'a>) {
+    for cmd in sendstream.commands() {
+        visit_command(cmd, visitor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        writes: usize,
+        bytes: usize,
+        ends: usize,
+    }
+
+    impl<'a> CommandVisitor<'a> for Counter {
+        fn visit_write(&mut self, cmd: &Write<'a>) {
+            self.writes += 1;
+            self.bytes += cmd.data().len();
+        }
+
+        fn visit_end(&mut self) {
+            self.ends += 1;
+        }
+    }
+
+    #[test]
+    fn walk_demo() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let mut counter = Counter::default();
+        for s in &sendstreams {
+            walk(s, &mut counter);
+        }
+        let writes: Vec<_> = sendstreams
+            .iter()
+            .flat_map(|s| s.commands())
+            .filter_map(|c| match c {
+                Command::Write(w) => Some(w.data().len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(writes.len(), counter.writes);
+        assert_eq!(writes.iter().sum::<usize>(), counter.bytes);
+        assert_eq!(2, counter.ends);
+    }
+}
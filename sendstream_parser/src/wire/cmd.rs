@@ -1,6 +1,12 @@
+use std::io::Result;
+
 use nom::IResult;
 
 use crate::wire::tlv::attr_types;
+use crate::wire::tlv::encode_data_v2;
+use crate::wire::tlv::encode_tlv;
+use crate::wire::tlv::encode_tlv_with_attr;
+use crate::wire::tlv::parse_data_v2;
 use crate::wire::tlv::parse_tlv;
 use crate::wire::tlv::parse_tlv_with_attr;
 
@@ -15,6 +21,8 @@ pub(crate) struct CommandHeader {
 }
 
 impl CommandHeader {
+    pub(crate) const LEN: usize = 10;
+
     pub(crate) fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (input, len) = nom::number::complete::le_u32(input)?;
         let (input, ty) = CommandType::parse(input)?;
@@ -57,6 +65,13 @@ macro_rules! command_type {
                 }
             }
 
+            pub(crate) const fn as_u16(self) -> u16 {
+                match self {
+                    $(Self::$v => ${index()},)+
+                    Self::Unknown(u) => u,
+                }
+            }
+
             #[cfg(test)]
             pub(crate) fn iter() -> impl Iterator<Item = Self> {
                 [$(Self::$v,)+].into_iter()
@@ -90,7 +105,11 @@ command_type!(
     Chown,
     Utimes,
     End,
-    UpdateExtent
+    UpdateExtent,
+    // v2
+    Fallocate,
+    Fileattr,
+    EncodedWrite
 );
 
 impl CommandType {
@@ -100,31 +119,51 @@ impl CommandType {
     }
 }
 
+/// A command whose header was read but whose body is bad can not be the end
+/// of the stream, so report it as a [nom::Err::Failure]
+fn malformed(input: &[u8], kind: nom::error::ErrorKind) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Failure(nom::error::Error::new(input, kind))
+}
+
 macro_rules! parse_subtypes {
-    ($hdr: expr, $cmd_data:expr, $($t:ident),+) => {
+    ($cmd: expr, $hdr: expr, $cmd_data:expr, $version:expr, $($t:ident),+; $($v:ident),+) => {
         match $hdr.ty {
             $(CommandType::$t => {
-                let (remaining, cmd) = crate::$t::parse($cmd_data).expect(concat!("failed to parse ", stringify!($t)));
+                let (remaining, cmd) = crate::$t::parse($cmd_data)
+                    .map_err(|_| malformed($cmd, nom::error::ErrorKind::Verify))?;
+                (remaining, cmd.into())
+            }),+
+            // the encoding of these depends on the stream version
+            $(CommandType::$v => {
+                let (remaining, cmd) = crate::$v::parse($cmd_data, $version)
+                    .map_err(|_| malformed($cmd, nom::error::ErrorKind::Verify))?;
                 (remaining, cmd.into())
             }),+
             CommandType::End => ($cmd_data, crate::Command::End),
-            _ => {
-                unreachable!("all btrfs sendstream command types are covered, what is this? {:?}", $hdr)
+            CommandType::Unspecified | CommandType::Unknown(_) => {
+                return Err(malformed($cmd, nom::error::ErrorKind::Switch));
             }
         }
     }
 }
 
 impl<'a> crate::Command<'a> {
-    pub(crate) fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
+    /// Parse a single command from a stream of the given protocol `version`
+    pub(crate) fn parse(input: &'a [u8], version: u32) -> IResult<&'a [u8], Self> {
+        let start = input;
         let (input, hdr) = CommandHeader::parse(input)?;
         let (input, cmd_data) = nom::bytes::complete::take(hdr.len)(input)?;
+        let whole = &start[..CommandHeader::LEN + hdr.len];
         let (cmd_remaining, cmd): (_, crate::Command) = parse_subtypes!(
+            whole,
             hdr,
             cmd_data,
+            version,
             Chmod,
             Chown,
             Clone,
+            Fallocate,
+            Fileattr,
             Link,
             Mkdir,
             Mkfifo,
@@ -141,17 +180,95 @@ impl<'a> crate::Command<'a> {
             Truncate,
             Unlink,
             UpdateExtent,
-            Utimes,
+            Utimes;
+            EncodedWrite,
             Write
         );
 
-        assert!(cmd_remaining.is_empty(), "command length is wrong",);
+        if !cmd_remaining.is_empty() {
+            return Err(malformed(whole, nom::error::ErrorKind::Eof));
+        }
         Ok((input, cmd))
     }
 }
 
+macro_rules! encode_subtypes {
+    ($cmd: expr, $out:expr, $version:expr, $($t:ident),+; $($v:ident),+) => {
+        match $cmd {
+            $(crate::Command::$t(c) => {
+                c.encode($out)?;
+                CommandType::$t
+            }),+
+            $(crate::Command::$v(c) => {
+                c.encode($out, $version)?;
+                CommandType::$v
+            }),+
+            crate::Command::End => CommandType::End,
+        }
+    }
+}
+
+impl<'a> crate::Command<'a> {
+    /// Append the wire representation of this command (including its header)
+    /// to `out`, for a stream of the given protocol `version`.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>, version: u32) -> Result<()> {
+        if version < self.min_version() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} needs a version {} sendstream",
+                    self.command_type(),
+                    self.min_version()
+                ),
+            ));
+        }
+        let start = out.len();
+        out.extend_from_slice(&[0; CommandHeader::LEN]);
+        let ty = encode_subtypes!(
+            self,
+            out,
+            version,
+            Chmod,
+            Chown,
+            Clone,
+            Fallocate,
+            Fileattr,
+            Link,
+            Mkdir,
+            Mkfifo,
+            Mkfile,
+            Mknod,
+            Mksock,
+            RemoveXattr,
+            Rename,
+            Rmdir,
+            SetXattr,
+            Snapshot,
+            Subvol,
+            Symlink,
+            Truncate,
+            Unlink,
+            UpdateExtent,
+            Utimes;
+            EncodedWrite,
+            Write
+        );
+        let len: u32 = (out.len() - start - CommandHeader::LEN)
+            .try_into()
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "command is too large")
+            })?;
+        out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        out[start + 4..start + 6].copy_from_slice(&ty.as_u16().to_le_bytes());
+        // the checksum is computed with the crc field zeroed out
+        let crc = super::crc32c(&out[start..]);
+        out[start + 6..start + 10].copy_from_slice(&crc.to_le_bytes());
+        Ok(())
+    }
+}
+
 impl<'a> crate::Subvol<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, uuid) = parse_tlv(input)?;
         let (input, ctransid) = parse_tlv(input)?;
@@ -164,27 +281,44 @@ impl<'a> crate::Subvol<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.uuid, out)?;
+        encode_tlv(&self.ctransid, out)
+    }
 }
 
 impl<'a> crate::Chmod<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, mode) = parse_tlv(input)?;
         Ok((input, Self { path, mode }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.mode, out)
+    }
 }
 
 impl<'a> crate::Chown<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, uid) = parse_tlv(input)?;
         let (input, gid) = parse_tlv(input)?;
         Ok((input, Self { path, uid, gid }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.uid, out)?;
+        encode_tlv(&self.gid, out)
+    }
 }
 
 impl<'a> crate::Clone<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, dst_offset) = parse_tlv(input)?;
         let (input, len) = parse_tlv(input)?;
         let (input, dst_path) = parse_tlv(input)?;
@@ -205,18 +339,33 @@ impl<'a> crate::Clone<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.dst_offset, out)?;
+        encode_tlv(&self.len, out)?;
+        encode_tlv(&self.dst_path, out)?;
+        encode_tlv_with_attr::<_, attr_types::CloneUuid>(&self.uuid, out)?;
+        encode_tlv_with_attr::<_, attr_types::CloneCtransid>(&self.ctransid, out)?;
+        encode_tlv_with_attr::<_, attr_types::ClonePath>(&self.src_path, out)?;
+        encode_tlv_with_attr::<_, attr_types::CloneOffset>(&self.src_offset, out)
+    }
 }
 
 impl<'a> crate::Link<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, link_name) = parse_tlv(input)?;
         let (input, target) = parse_tlv(input)?;
         Ok((input, Self { target, link_name }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.link_name, out)?;
+        encode_tlv(&self.target, out)
+    }
 }
 
 impl<'a> crate::Symlink<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, link_name) = parse_tlv(input)?;
         let (input, ino) = parse_tlv(input)?;
         let (input, target) = parse_tlv(input)?;
@@ -229,26 +378,42 @@ impl<'a> crate::Symlink<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.link_name, out)?;
+        encode_tlv(&self.ino, out)?;
+        encode_tlv(&self.target, out)
+    }
 }
 
 impl<'a> crate::Mkdir<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, ino) = parse_tlv(input)?;
         Ok((input, Self { path, ino }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.ino, out)
+    }
 }
 
 impl<'a> crate::Mkfile<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, ino) = parse_tlv(input)?;
         Ok((input, Self { path, ino }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.ino, out)
+    }
 }
 
 impl<'a> crate::Mkspecial<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, ino) = parse_tlv(input)?;
         let (input, rdev) = parse_tlv(input)?;
@@ -263,14 +428,25 @@ impl<'a> crate::Mkspecial<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.ino, out)?;
+        encode_tlv(&self.rdev, out)?;
+        encode_tlv(&self.mode, out)
+    }
 }
 
 macro_rules! mkspecial {
     ($t:ident) => {
         impl<'a> crate::$t<'a> {
-            fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+            fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
                 crate::Mkspecial::parse(input).map(|(r, s)| (r, Self(s)))
             }
+
+            fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+                self.0.encode(out)
+            }
         }
     };
 }
@@ -280,47 +456,72 @@ mkspecial!(Mkfifo);
 mkspecial!(Mksock);
 
 impl<'a> crate::RemoveXattr<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, name) = parse_tlv(input)?;
         Ok((input, Self { path, name }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.name, out)
+    }
 }
 
 impl<'a> crate::Rename<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, from) = parse_tlv(input)?;
         let (input, to) = parse_tlv_with_attr::<_, 0, attr_types::PathTo>(input)?;
         Ok((input, Self { from, to }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.from, out)?;
+        encode_tlv_with_attr::<_, attr_types::PathTo>(&self.to, out)
+    }
 }
 
 impl<'a> crate::Rmdir<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         Ok((input, Self { path }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)
+    }
 }
 
 impl<'a> crate::SetXattr<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, name) = parse_tlv(input)?;
         let (input, data) = parse_tlv(input)?;
         Ok((input, Self { path, name, data }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.name, out)?;
+        encode_tlv(&self.data, out)
+    }
 }
 
 impl<'a> crate::Truncate<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, size) = parse_tlv(input)?;
         Ok((input, Self { path, size }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.size, out)
+    }
 }
 
 impl<'a> crate::Snapshot<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, uuid) = parse_tlv(input)?;
         let (input, ctransid) = parse_tlv(input)?;
@@ -338,26 +539,44 @@ impl<'a> crate::Snapshot<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.uuid, out)?;
+        encode_tlv(&self.ctransid, out)?;
+        encode_tlv_with_attr::<_, attr_types::CloneUuid>(&self.clone_uuid, out)?;
+        encode_tlv_with_attr::<_, attr_types::CloneCtransid>(&self.clone_ctransid, out)
+    }
 }
 
 impl<'a> crate::Unlink<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         Ok((input, Self { path }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)
+    }
 }
 
 impl<'a> crate::UpdateExtent<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, offset) = parse_tlv(input)?;
         let (input, len) = parse_tlv(input)?;
         Ok((input, Self { path, offset, len }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.offset, out)?;
+        encode_tlv(&self.len, out)
+    }
 }
 
 impl<'a> crate::Utimes<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, atime) = parse_tlv(input)?;
         let (input, mtime) = parse_tlv(input)?;
@@ -372,13 +591,116 @@ impl<'a> crate::Utimes<'a> {
             },
         ))
     }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.atime, out)?;
+        encode_tlv(&self.mtime, out)?;
+        encode_tlv(&self.ctime, out)
+    }
 }
 
 impl<'a> crate::Write<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&[u8], Self> {
+    fn parse(input: &'a [u8], version: u32) -> IResult<&'a [u8], Self> {
         let (input, path) = parse_tlv(input)?;
         let (input, offset) = parse_tlv(input)?;
-        let (input, data) = parse_tlv(input)?;
+        let (input, data) = match version {
+            1 => parse_tlv(input)?,
+            _ => parse_data_v2(input)?,
+        };
         Ok((input, Self { path, offset, data }))
     }
+
+    fn encode(&self, out: &mut Vec<u8>, version: u32) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.offset, out)?;
+        match version {
+            1 => encode_tlv(&self.data, out),
+            _ => {
+                encode_data_v2(&self.data, out);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> crate::EncodedWrite<'a> {
+    fn parse(input: &'a [u8], _version: u32) -> IResult<&'a [u8], Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, offset) = parse_tlv(input)?;
+        let (input, unencoded_file_len) =
+            parse_tlv_with_attr::<_, 8, attr_types::UnencodedFileLen>(input)?;
+        let (input, unencoded_len) = parse_tlv_with_attr::<_, 8, attr_types::UnencodedLen>(input)?;
+        let (input, unencoded_offset) =
+            parse_tlv_with_attr::<_, 8, attr_types::UnencodedOffset>(input)?;
+        let (input, compression) = parse_tlv(input)?;
+        // encryption is optional, and left out when there is none
+        let (input, encryption) = nom::combinator::opt(parse_tlv)(input)?;
+        let (input, data) = parse_data_v2(input)?;
+        Ok((
+            input,
+            Self {
+                path,
+                offset,
+                unencoded_file_len,
+                unencoded_len,
+                unencoded_offset,
+                compression,
+                encryption: encryption.unwrap_or_default(),
+                data,
+            },
+        ))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>, _version: u32) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.offset, out)?;
+        encode_tlv_with_attr::<_, attr_types::UnencodedFileLen>(&self.unencoded_file_len, out)?;
+        encode_tlv_with_attr::<_, attr_types::UnencodedLen>(&self.unencoded_len, out)?;
+        encode_tlv_with_attr::<_, attr_types::UnencodedOffset>(&self.unencoded_offset, out)?;
+        encode_tlv(&self.compression, out)?;
+        if self.encryption != 0 {
+            encode_tlv(&self.encryption, out)?;
+        }
+        encode_data_v2(&self.data, out);
+        Ok(())
+    }
+}
+
+impl<'a> crate::Fallocate<'a> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, mode) = parse_tlv(input)?;
+        let (input, offset) = parse_tlv(input)?;
+        let (input, len) = parse_tlv(input)?;
+        Ok((
+            input,
+            Self {
+                path,
+                mode,
+                offset,
+                len,
+            },
+        ))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv(&self.mode, out)?;
+        encode_tlv(&self.offset, out)?;
+        encode_tlv(&self.len, out)
+    }
+}
+
+impl<'a> crate::Fileattr<'a> {
+    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, attr) = parse_tlv_with_attr::<_, 8, attr_types::Fileattr>(input)?;
+        Ok((input, Self { path, attr }))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        encode_tlv(&self.path, out)?;
+        encode_tlv_with_attr::<_, attr_types::Fileattr>(&self.attr, out)
+    }
 }
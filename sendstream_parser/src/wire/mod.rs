@@ -1,25 +1,75 @@
+use std::io::Write;
+use std::ops::Range;
+
 use nom::IResult;
 
+use crate::Command;
 use crate::Sendstream;
 
+/// Byte range of a single encoded command (header included) within the
+/// buffer that it was parsed from.
+pub type Span = Range<usize>;
+
+fn offset(start: &[u8], rest: &[u8]) -> usize {
+    start.len() - rest.len()
+}
+
 static MAGIC_HEADER: &[u8] = b"btrfs-stream\0";
 
+/// Newest version of the sendstream protocol that can be parsed
+pub(crate) const MAX_VERSION: u32 = 2;
+
+/// Most data that a single (v1) TLV can hold
+pub(crate) const MAX_TLV_DATA: usize = u16::MAX as usize;
+
+/// Largest command (header included) that `btrfs send` produces for each
+/// protocol version, which is also the most that `btrfs receive` accepts
+pub(crate) fn max_command_len(version: u32) -> usize {
+    match version {
+        1 => 64 * 1024,
+        // room for the largest compressed extent and its metadata, which the
+        // kernel rounds up to the page size (up to 64K)
+        _ => (16 * 1024 + 128 * 1024usize).next_multiple_of(64 * 1024),
+    }
+}
+
 pub(crate) mod cmd;
+pub(crate) mod reader;
 mod tlv;
 use crate::Error;
 use crate::Result;
 
 impl<'a> Sendstream<'a> {
-    fn parse(input: &'a [u8]) -> IResult<&'a [u8], Self> {
+    fn parse(start: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], (Self, Vec<Span>)> {
         let (input, _) = nom::bytes::complete::tag(MAGIC_HEADER)(input)?;
-        let (input, version) = nom::number::complete::le_u32(input)?;
-        assert_eq!(1, version);
-        let (input, commands) = nom::multi::many1(crate::Command::parse)(input)?;
-        Ok((input, Self { commands }))
+        let (mut input, version) = nom::combinator::verify(nom::number::complete::le_u32, |v| {
+            (1..=MAX_VERSION).contains(v)
+        })(input)?;
+        let mut commands = Vec::new();
+        let mut spans = Vec::new();
+        loop {
+            match crate::Command::parse(input, version) {
+                Ok((rest, cmd)) => {
+                    spans.push(offset(start, input)..offset(start, rest));
+                    commands.push(cmd);
+                    input = rest;
+                }
+                Err(nom::Err::Error(_)) if !commands.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((input, (Self { commands }, spans)))
     }
 
-    pub fn parse_all(input: &'a [u8]) -> Result<Vec<Self>> {
-        match nom::combinator::complete(nom::multi::many1(Sendstream::parse))(input) {
+    pub fn parse_all(input: &'a [u8]) -> Result<'a, Vec<Self>> {
+        Self::parse_all_with_spans(input).map(|s| s.into_iter().map(|(s, _)| s).collect())
+    }
+
+    /// Parse all the sendstreams in `input`, along with the [Span] of each
+    /// command so that they can later be copied verbatim with [splice].
+    pub fn parse_all_with_spans(input: &'a [u8]) -> Result<'a, Vec<(Self, Vec<Span>)>> {
+        let parse = |i| Sendstream::parse(input, i);
+        match nom::combinator::complete(nom::multi::many1(parse))(input) {
             Ok((left, sendstreams)) => {
                 if !left.is_empty() {
                     Err(Error::TrailingData(left.to_vec()))
@@ -27,10 +77,206 @@ impl<'a> Sendstream<'a> {
                     Ok(sendstreams)
                 }
             }
-            Err(e) => match e {
-                nom::Err::Error(e) | nom::Err::Failure(e) => Err(e.into()),
-                nom::Err::Incomplete(_) => Err(Error::Incomplete),
-            },
+            Err(e) => Err(Error::from_nom(e)),
+        }
+    }
+
+    /// Oldest version of the sendstream protocol that can carry all of the
+    /// commands in this sendstream, which is what [Sendstream::write_to]
+    /// writes.
+    pub fn version(&self) -> u32 {
+        self.commands
+            .iter()
+            .map(Command::min_version)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Serialize this sendstream (including the stream header) to `w`.
+    pub fn write_to<W: Write>(&self, w: W) -> std::io::Result<W> {
+        let mut enc = Encoder::with_version(w, self.version())?;
+        for cmd in &self.commands {
+            enc.write_command(cmd)?;
+        }
+        Ok(enc.into_inner())
+    }
+
+    /// Serialize this sendstream (including the stream header) into a new
+    /// buffer that can be read by `btrfs receive` or [Sendstream::parse_all].
+    pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+        self.write_to(Vec::new())
+    }
+}
+
+/// Writes [Command]s in the sendstream wire format. Each command is written
+/// as soon as it is received, so arbitrarily large streams can be produced
+/// without buffering more than a single command.
+/// Writing another command after an [Command::End] starts a new sendstream
+/// (with its own header) in the same output.
+pub struct Encoder<W: Write> {
+    w: W,
+    buf: Vec<u8>,
+    ended: bool,
+    version: u32,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Start a new (version 1) sendstream by writing the stream header to `w`.
+    pub fn new(w: W) -> std::io::Result<Self> {
+        Self::with_version(w, 1)
+    }
+
+    /// Start a new sendstream of the given protocol version. Commands that
+    /// need a newer version than this (see [Command::min_version]) can not be
+    /// written.
+    pub fn with_version(mut w: W, version: u32) -> std::io::Result<Self> {
+        if !(1..=MAX_VERSION).contains(&version) {
+            return Err(invalid("unsupported sendstream version"));
+        }
+        w.write_all(MAGIC_HEADER)?;
+        w.write_all(&version.to_le_bytes())?;
+        Ok(Self {
+            w,
+            buf: Vec::new(),
+            ended: false,
+            version,
+        })
+    }
+
+    /// Write a new stream header if the previous command ended a stream.
+    fn start(&mut self, end: bool) -> std::io::Result<()> {
+        if self.ended {
+            self.w.write_all(MAGIC_HEADER)?;
+            self.w.write_all(&self.version.to_le_bytes())?;
+        }
+        self.ended = end;
+        Ok(())
+    }
+
+    pub fn write_command(&mut self, cmd: &Command) -> std::io::Result<()> {
+        self.buf.clear();
+        cmd.encode(&mut self.buf, self.version)?;
+        self.start(matches!(cmd, Command::End))?;
+        self.w.write_all(&self.buf)
+    }
+
+    /// Copy a command that is already encoded (for example a [Span] of a
+    /// parsed buffer) without decoding and re-encoding it.
+    pub fn write_raw(&mut self, raw: &[u8]) -> std::io::Result<()> {
+        let (data, hdr) = match cmd::CommandHeader::parse(raw) {
+            Ok(parsed) => parsed,
+            Err(_) => return Err(invalid("truncated command header")),
+        };
+        if hdr.len != data.len() {
+            return Err(invalid("command length does not match header"));
+        }
+        self.start(hdr.ty == cmd::CommandType::End)?;
+        self.w.write_all(raw)
+    }
+
+    /// Get the underlying writer back. This does not implicitly write an
+    /// [Command::End], that is up to the caller.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+fn invalid(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+/// Offset and protocol version of each sendstream in `input`, found by
+/// skipping from one header to the next
+fn stream_versions(input: &[u8]) -> Vec<(usize, u32)> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while input.get(pos..pos + MAGIC_HEADER.len()) == Some(MAGIC_HEADER) {
+        let Some(version) = input
+            .get(pos + MAGIC_HEADER.len()..pos + MAGIC_HEADER.len() + 4)
+            .and_then(|v| v.try_into().ok())
+        else {
+            break;
+        };
+        out.push((pos, u32::from_le_bytes(version)));
+        pos += MAGIC_HEADER.len() + 4;
+        loop {
+            let Some(Ok((_, hdr))) = input.get(pos..).map(cmd::CommandHeader::parse) else {
+                return out;
+            };
+            pos += cmd::CommandHeader::LEN + hdr.len;
+            if hdr.ty == cmd::CommandType::End {
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// Copy the commands at `spans` of `input` into a new sendstream written to
+/// `w`. The commands are not decoded, so this is cheap even for huge streams.
+/// The stream header is written fresh, and if the last command copied is not
+/// an [Command::End], one is appended so that the result is a valid stream.
+/// The new stream has the same version as the stream(s) that the commands
+/// are copied from, which must all be of the same version.
+pub fn splice<W: Write>(
+    input: &[u8],
+    spans: impl IntoIterator<Item = Span>,
+    w: W,
+) -> std::io::Result<W> {
+    let streams = stream_versions(input);
+    let version_at = |offset: usize| match streams.partition_point(|(s, _)| *s <= offset) {
+        0 => 1,
+        i => streams[i - 1].1,
+    };
+    let spans: Vec<_> = spans.into_iter().collect();
+    let version = spans.first().map_or(1, |s| version_at(s.start));
+    let mut enc = Encoder::with_version(w, version)?;
+    let mut ended = false;
+    for span in spans {
+        if version_at(span.start) != version {
+            return Err(invalid("spans are from sendstreams of different versions"));
+        }
+        let raw = input
+            .get(span)
+            .ok_or_else(|| invalid("span out of bounds"))?;
+        enc.write_raw(raw)?;
+        ended = matches!(
+            cmd::CommandHeader::parse(raw),
+            Ok((_, hdr)) if hdr.ty == cmd::CommandType::End
+        );
+    }
+    if !ended {
+        enc.write_command(&Command::End)?;
+    }
+    Ok(enc.into_inner())
+}
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
         }
+        table[i] = crc;
+        i += 1;
     }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// btrfs uses the raw crc32c (seeded with 0, no final inversion) to checksum
+/// each command.
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, b| {
+        CRC32C_TABLE[((crc ^ u32::from(*b)) & 0xff) as usize] ^ (crc >> 8)
+    })
 }
@@ -0,0 +1,237 @@
+use std::io::ErrorKind;
+use std::io::Read;
+
+use super::cmd::CommandHeader;
+use super::cmd::CommandType;
+use super::max_command_len;
+use super::MAGIC_HEADER;
+use super::MAX_VERSION;
+use crate::cancel::CancelToken;
+use crate::throttle::Throttle;
+use crate::Command;
+use crate::Error;
+use crate::Result;
+
+/// Parses [Command]s one at a time from any [Read], so that arbitrarily large
+/// sendstreams can be processed while only holding a single command in memory.
+/// Concatenated sendstreams (as produced by `btrfs send` with multiple
+/// subvolumes) are read back to back.
+pub struct CommandReader<R: Read> {
+    r: R,
+    buf: Vec<u8>,
+    in_stream: bool,
+    /// Protocol version of the current stream
+    version: u32,
+    failed: bool,
+    throttle: Option<Throttle>,
+    cancel: Option<CancelToken>,
+}
+
+impl<R: Read> CommandReader<R> {
+    pub fn new(r: R) -> Self {
+        Self {
+            r,
+            buf: Vec::new(),
+            in_stream: false,
+            version: 1,
+            failed: false,
+            throttle: None,
+            cancel: None,
+        }
+    }
+
+    /// Read no faster than `throttle` allows, counting every command and
+    /// all of its bytes
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Stop with [Error::Cancelled] before reading the next command once
+    /// `token` is cancelled, leaving the underlying reader right after the
+    /// last command that was returned
+    pub fn cancel_on(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Get a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    /// Get a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.r
+    }
+
+    /// Get the underlying reader back.
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// Read the stream header, or return `false` if the input is exhausted.
+    fn start_stream(&mut self) -> Result<'static, bool> {
+        let mut header = [0; MAGIC_HEADER.len() + 4];
+        match read_exact_or_eof(&mut self.r, &mut header)? {
+            0 => return Ok(false),
+            n if n < header.len() => return Err(Error::Incomplete),
+            _ => (),
+        }
+        if &header[..MAGIC_HEADER.len()] != MAGIC_HEADER {
+            return Err(Error::ParseOwned(nom::error::Error::new(
+                header.to_vec(),
+                nom::error::ErrorKind::Tag,
+            )));
+        }
+        let version = u32::from_le_bytes([header[13], header[14], header[15], header[16]]);
+        if !(1..=MAX_VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        self.version = version;
+        Ok(true)
+    }
+
+    fn read_command(&mut self) -> Result<'static, Option<Command<'static>>> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(Error::Cancelled);
+        }
+        if !self.in_stream {
+            if !self.start_stream()? {
+                return Ok(None);
+            }
+            self.in_stream = true;
+        }
+        self.buf.resize(CommandHeader::LEN, 0);
+        if read_exact_or_eof(&mut self.r, &mut self.buf)? < CommandHeader::LEN {
+            return Err(Error::Incomplete);
+        }
+        let hdr = match CommandHeader::parse(&self.buf) {
+            Ok((_, hdr)) => hdr,
+            Err(e) => return Err(Error::from_nom(e).into_owned()),
+        };
+        // the length is not trusted to allocate more than any real command
+        // could need
+        if CommandHeader::LEN + hdr.len > max_command_len(self.version) {
+            return Err(Error::ParseOwned(nom::error::Error::new(
+                self.buf.clone(),
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
+        self.buf.resize(CommandHeader::LEN + hdr.len, 0);
+        if read_exact_or_eof(&mut self.r, &mut self.buf[CommandHeader::LEN..])? < hdr.len {
+            return Err(Error::Incomplete);
+        }
+        if hdr.ty == CommandType::End {
+            self.in_stream = false;
+        }
+        if let Some(t) = &mut self.throttle {
+            t.wait(self.buf.len() as u64, 1);
+        }
+        match Command::parse(&self.buf, self.version) {
+            Ok((_, cmd)) => Ok(Some(cmd.into_owned())),
+            Err(e) => Err(Error::from_nom(e).into_owned()),
+        }
+    }
+}
+
+/// Like [Read::read_exact], but returns how many bytes were read if the input
+/// ends early.
+fn read_exact_or_eof(r: &mut impl Read, mut buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = buf.len();
+    while !buf.is_empty() {
+        match r.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len - buf.len())
+}
+
+impl<R: Read> Iterator for CommandReader<R> {
+    type Item = Result<'static, Command<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let res = self.read_command().transpose();
+        if matches!(res, Some(Err(_))) {
+            self.failed = true;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sendstream;
+
+    #[test]
+    fn read_demo() {
+        let input = include_bytes!("../../testdata/demo.sendstream");
+        let expected: Vec<_> = Sendstream::parse_all(input)
+            .expect("failed to parse demo.sendstream")
+            .into_iter()
+            .flat_map(Sendstream::into_commands)
+            .collect();
+        let read: Vec<_> = CommandReader::new(&input[..])
+            .collect::<Result<_>>()
+            .expect("failed to read demo.sendstream");
+        assert_eq!(expected, read);
+
+        let mut truncated = CommandReader::new(&input[..input.len() - 1]);
+        assert!(truncated.any(|r| matches!(r, Err(Error::Incomplete))));
+
+        // a command that claims to be 4G long is refused before reading it
+        let start = MAGIC_HEADER.len() + 4;
+        let mut huge = input[..start].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge.extend_from_slice(&input[start + 4..start + CommandHeader::LEN]);
+        let mut reader = CommandReader::new(&huge[..]);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(Error::ParseOwned(e))) if e.code == nom::error::ErrorKind::TooLarge
+        ));
+
+        // 64K page hosts send v2 commands of up to 192K
+        assert_eq!(192 * 1024, max_command_len(2));
+
+        // malformed commands are errors, not panics
+        let command = |ty: u16, body: &[u8]| {
+            let mut stream = input[..start].to_vec();
+            stream.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            stream.extend_from_slice(&ty.to_le_bytes());
+            stream.extend_from_slice(&0u32.to_le_bytes());
+            stream.extend_from_slice(body);
+            stream
+        };
+        let mkfile = CommandType::Mkfile.as_u16();
+        for stream in [
+            command(mkfile, &[]),
+            command(999, &[]),
+            command(mkfile, &[0; 3]),
+        ] {
+            let mut reader = CommandReader::new(&stream[..]);
+            assert!(matches!(reader.next(), Some(Err(Error::ParseOwned(_)))));
+            assert!(matches!(
+                Sendstream::parse_all(&stream),
+                Err(Error::Parse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let input = include_bytes!("../../testdata/demo.sendstream");
+        let token = CancelToken::new();
+        let mut reader = CommandReader::new(&input[..]).cancel_on(token.clone());
+        assert!(matches!(reader.next(), Some(Ok(Command::Subvol(_)))));
+        token.cancel();
+        assert!(matches!(reader.next(), Some(Err(Error::Cancelled))));
+        assert!(reader.next().is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
@@ -113,11 +114,15 @@ macro_rules! tlv_impl {
     };
 }
 
+fn parse_path(data: &[u8]) -> Cow<'_, Path> {
+    Cow::Borrowed(Path::new(OsStr::from_bytes(data)))
+}
+
 tlv_impl!(
     'i,
-    &'i Path,
+    Cow<'i, Path>,
     Path,
-    |data: &'i [u8]| -> &'i Path { Path::new(OsStr::from_bytes(data)) },
+    parse_path,
     PathTo,
     ClonePath
 );
@@ -126,7 +131,7 @@ tlv_impl!(
     'i,
     crate::TemporaryPath<'i>,
     Path,
-    |data: &'i [u8]| -> crate::TemporaryPath<'i> { crate::TemporaryPath(Path::new(OsStr::from_bytes(data))) }
+    |data: &'i [u8]| -> crate::TemporaryPath<'i> { crate::TemporaryPath(parse_path(data)) }
 );
 
 tlv_impl!(
@@ -166,7 +171,7 @@ tlv_impl!(
     crate::XattrName<'i>,
     XattrName,
     |data: &'i [u8]| -> crate::XattrName<'i> {
-        crate::XattrName(data)
+        crate::XattrName(Cow::Borrowed(data))
     }
 );
 
@@ -175,7 +180,7 @@ tlv_impl!(
     crate::XattrData<'i>,
     XattrData,
     |data: &'i [u8]| -> crate::XattrData<'i> {
-        crate::XattrData(data)
+        crate::XattrData(Cow::Borrowed(data))
     }
 );
 
@@ -192,7 +197,7 @@ tlv_impl!(
     crate::Data<'i>,
     Data,
     |data: &'i [u8]| -> crate::Data<'i> {
-        crate::Data(data)
+        crate::Data(Cow::Borrowed(data))
     }
 );
 
@@ -201,7 +206,7 @@ tlv_impl!(
     crate::LinkTarget<'i>,
     Link,
     |data: &'i [u8]| -> crate::LinkTarget<'i> {
-        crate::LinkTarget(Path::new(OsStr::from_bytes(data)))
+        crate::LinkTarget(parse_path(data))
     }
 );
 
@@ -216,10 +221,52 @@ tlv_impl!(
     |data: [u8; 8]| -> crate::CloneLen { crate::CloneLen(u64::from_le_bytes(data)) }
 );
 
-tlv_impl!(u64, 8, Size, |data: [u8; 8]| -> u64 {
-    u64::from_le_bytes(data)
+tlv_impl!(
+    u64,
+    8,
+    Size,
+    |data: [u8; 8]| -> u64 { u64::from_le_bytes(data) },
+    Fileattr,
+    UnencodedFileLen,
+    UnencodedLen,
+    UnencodedOffset
+);
+
+tlv_impl!(u32, 4, Encryption, |data: [u8; 4]| -> u32 {
+    u32::from_le_bytes(data)
 });
 
+tlv_impl!(
+    crate::Compression,
+    4,
+    Compression,
+    |data: [u8; 4]| -> crate::Compression {
+        crate::Compression::from_u32(u32::from_le_bytes(data))
+    }
+);
+
+tlv_impl!(
+    crate::FallocateMode,
+    4,
+    FallocateMode,
+    |data: [u8; 4]| -> crate::FallocateMode { crate::FallocateMode(u32::from_le_bytes(data)) }
+);
+
+/// Parse the [Attr::Data] of a v2 [crate::Write] or [crate::EncodedWrite],
+/// which has no length and instead takes up the rest of the command, so that
+/// it can be larger than a TLV allows.
+pub(crate) fn parse_data_v2(input: &[u8]) -> IResult<&[u8], crate::Data<'_>> {
+    let (input, _) = nom::bytes::complete::tag(Attr::Data.tag())(input)?;
+    let (input, data) = nom::combinator::rest(input)?;
+    Ok((input, crate::Data(Cow::Borrowed(data))))
+}
+
+/// Encode data the way that [parse_data_v2] expects
+pub(crate) fn encode_data_v2(data: &crate::Data, out: &mut Vec<u8>) {
+    out.extend_from_slice(&Attr::Data.tag());
+    out.extend_from_slice(data);
+}
+
 fn parse_time(data: [u8; 12]) -> SystemTime {
     #[allow(clippy::expect_used)]
     let secs = u64::from_le_bytes(data[..8].try_into().expect("right size"));
@@ -240,6 +287,84 @@ time_tlv!(Atime);
 time_tlv!(Mtime);
 time_tlv!(Ctime);
 
+/// Encode a TLV with the value type's primary attribute tag
+pub(crate) fn encode_tlv<'i, T, const L: usize>(t: &T, out: &mut Vec<u8>) -> std::io::Result<()>
+where
+    T: Tlv<'i, L> + EncodeTlv,
+{
+    encode_tlv_with_attr::<T, T::Attr>(t, out)
+}
+
+/// Encode a TLV with an explicit attribute tag, the inverse of
+/// [parse_tlv_with_attr].
+pub(crate) fn encode_tlv_with_attr<T, Attr>(t: &T, out: &mut Vec<u8>) -> std::io::Result<()>
+where
+    T: EncodeTlv + ParsesFromAttr<Attr>,
+    Attr: AttrTypeParam,
+{
+    out.extend_from_slice(&Attr::attr().tag());
+    let len_pos = out.len();
+    out.extend_from_slice(&[0, 0]);
+    t.encode_data(out);
+    let len = out.len() - len_pos - 2;
+    let len: u16 = len.try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} is too large for a TLV ({len} bytes)", Attr::attr()),
+        )
+    })?;
+    out[len_pos..len_pos + 2].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+/// Serialize the data portion of a TLV, the inverse of [Tlv::parse] or
+/// [Tlv::parse_exact].
+pub(crate) trait EncodeTlv {
+    fn encode_data(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! encode_impl {
+    ($ty:ty, |$v:ident| $e:expr) => {
+        impl<'i> EncodeTlv for $ty {
+            fn encode_data(&self, out: &mut Vec<u8>) {
+                let $v = self;
+                out.extend_from_slice($e);
+            }
+        }
+    };
+}
+
+encode_impl!(Cow<'i, Path>, |p| p.as_os_str().as_bytes());
+encode_impl!(crate::TemporaryPath<'i>, |p| p.0.as_os_str().as_bytes());
+encode_impl!(crate::LinkTarget<'i>, |p| p.0.as_os_str().as_bytes());
+encode_impl!(Uuid, |u| &u.to_u128_le().to_le_bytes());
+encode_impl!(crate::Ctransid, |c| &c.0.to_le_bytes());
+encode_impl!(Uid, |u| &u64::from(u.as_raw()).to_le_bytes());
+encode_impl!(Gid, |g| &u64::from(g.as_raw()).to_le_bytes());
+encode_impl!(crate::Mode, |m| &u64::from(m.0).to_le_bytes());
+encode_impl!(crate::Ino, |i| &i.0.to_le_bytes());
+encode_impl!(crate::XattrName<'i>, |x| &x.0);
+encode_impl!(crate::XattrData<'i>, |x| &x.0);
+encode_impl!(crate::FileOffset, |o| &o.0.to_le_bytes());
+encode_impl!(crate::Data<'i>, |d| &d.0);
+encode_impl!(crate::Rdev, |r| &r.0.to_le_bytes());
+encode_impl!(crate::CloneLen, |l| &l.0.to_le_bytes());
+encode_impl!(u64, |u| &u.to_le_bytes());
+encode_impl!(u32, |u| &u.to_le_bytes());
+encode_impl!(crate::Compression, |c| &c.as_u32().to_le_bytes());
+encode_impl!(crate::FallocateMode, |m| &m.0.to_le_bytes());
+encode_impl!(crate::Atime, |t| &encode_time(t.0));
+encode_impl!(crate::Mtime, |t| &encode_time(t.0));
+encode_impl!(crate::Ctime, |t| &encode_time(t.0));
+
+fn encode_time(t: SystemTime) -> [u8; 12] {
+    let since_epoch = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let mut data = [0; 12];
+    data[..8].copy_from_slice(&since_epoch.as_secs().to_le_bytes());
+    data[8..].copy_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+    data
+}
+
 pub(crate) trait AttrTypeParam {
     fn attr() -> Attr;
 }
@@ -255,6 +380,9 @@ macro_rules! gen_attrs_code {
             PartialOrd,
             Ord,
         )]
+        // not every attribute is used by the commands that are currently
+        // supported
+        #[allow(dead_code)]
         pub(crate) enum $enm {
             $($v,)+
         }
@@ -270,6 +398,7 @@ macro_rules! gen_attrs_code {
         pub(crate) mod attr_types {
             /// Empty type used as type parameter for parse_tlv
             $(
+                #[allow(dead_code)]
                 pub(crate) struct $v();
                 impl super::AttrTypeParam for $v {
                     fn attr() -> super::Attr {
@@ -308,7 +437,15 @@ gen_attrs_code!(
     CloneCtransid,
     ClonePath,
     CloneOffset,
-    CloneLen
+    CloneLen,
+    // v2
+    FallocateMode,
+    Fileattr,
+    UnencodedFileLen,
+    UnencodedLen,
+    UnencodedOffset,
+    Compression,
+    Encryption
 );
 
 impl Attr {
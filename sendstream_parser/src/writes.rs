@@ -0,0 +1,202 @@
+//! Transforms that change how file data is split up between [Write]
+//! commands, without changing the resulting file contents.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::Command;
+use crate::Compression;
+use crate::Data;
+use crate::EncodedWrite;
+use crate::FileOffset;
+use crate::Sendstream;
+use crate::Write;
+
+/// `range` of `data`, still borrowing from the same buffer if it was
+fn slice<'a>(data: &Data<'a>, range: Range<usize>) -> Data<'a> {
+    Data(match &data.0 {
+        Cow::Borrowed(d) => Cow::Borrowed(&d[range]),
+        Cow::Owned(d) => Cow::Owned(d[range].to_vec()),
+    })
+}
+
+/// Ranges of at most `max_len` bytes that `len` bytes are split into
+fn pieces(len: usize, max_len: usize) -> impl Iterator<Item = Range<usize>> {
+    (0..len)
+        .step_by(max_len)
+        .map(move |start| start..(start + max_len).min(len))
+}
+
+impl<'a> Sendstream<'a> {
+    /// Merge runs of [Write]s that write contiguous ranges of the same file
+    /// into larger writes of at most `max_len` bytes each.
+    ///
+    /// Note that the v1 wire format can only encode up to [u16::MAX] bytes of
+    /// data in a single command, so choosing a larger `max_len` is only useful
+    /// for in-memory processing.
+    pub fn coalesce_writes(&self, max_len: usize) -> Sendstream<'a> {
+        let mut commands = Vec::with_capacity(self.commands.len());
+        let mut pending: Option<Write<'a>> = None;
+        for cmd in &self.commands {
+            if let Command::Write(w) = cmd {
+                match &mut pending {
+                    Some(p)
+                        if p.path == w.path
+                            && p.offset.0 + p.data.len() as u64 == w.offset.0
+                            && p.data.len() + w.data.len() <= max_len =>
+                    {
+                        p.data.0.to_mut().extend_from_slice(&w.data);
+                    }
+                    _ => {
+                        if let Some(p) = pending.replace(w.clone()) {
+                            commands.push(p.into());
+                        }
+                    }
+                }
+                continue;
+            }
+            if let Some(p) = pending.take() {
+                commands.push(p.into());
+            }
+            commands.push(cmd.clone());
+        }
+        if let Some(p) = pending {
+            commands.push(p.into());
+        }
+        Sendstream { commands }
+    }
+
+    /// Split any [Write] carrying more than `max_len` bytes into multiple
+    /// consecutive writes, for receivers that cannot handle large commands.
+    ///
+    /// Uncompressed [EncodedWrite]s are split the same way (into smaller
+    /// uncompressed [EncodedWrite]s), but compressed or encrypted data can
+    /// not be split up without re-encoding it, so those are left alone.
+    pub fn split_writes(&self, max_len: usize) -> Sendstream<'a> {
+        let max_len = max_len.max(1);
+        let mut commands = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            match cmd {
+                Command::Write(w) if w.data.len() > max_len => {
+                    commands.extend(pieces(w.data.len(), max_len).map(|r| {
+                        Write {
+                            path: w.path.clone(),
+                            offset: FileOffset(w.offset.0 + r.start as u64),
+                            data: slice(&w.data, r),
+                        }
+                        .into()
+                    }));
+                }
+                Command::EncodedWrite(w) if w.data.len() > max_len && w.decoded().is_some() => {
+                    let len = w.unencoded_file_len as usize;
+                    let skip = w.unencoded_offset as usize;
+                    commands.extend(pieces(len, max_len).map(|r| {
+                        EncodedWrite {
+                            path: w.path.clone(),
+                            offset: FileOffset(w.offset.0 + r.start as u64),
+                            unencoded_file_len: r.len() as u64,
+                            unencoded_len: r.len() as u64,
+                            unencoded_offset: 0,
+                            compression: Compression::None,
+                            encryption: 0,
+                            data: slice(&w.data, skip + r.start..skip + r.end),
+                        }
+                        .into()
+                    }));
+                }
+                _ => commands.push(cmd.clone()),
+            }
+        }
+        Sendstream { commands }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::fs::Filesystem;
+
+    fn writes_to<'a>(s: &'a Sendstream, path: &'a str) -> impl Iterator<Item = &'a Write<'a>> {
+        s.commands().iter().filter_map(move |c| match c {
+            Command::Write(w) if w.path() == Path::new(path) => Some(w),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn coalesce_writes() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let original = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        assert!(writes_to(&sendstreams[0], "hello/lorem").count() > 1);
+
+        let coalesced = sendstreams[0].coalesce_writes(1 << 20);
+        assert_eq!(1, writes_to(&coalesced, "hello/lorem").count());
+        let fs = Filesystem::from_sendstream(&coalesced).expect("failed to replay");
+        let lorem = Path::new("hello/lorem");
+        assert_eq!(
+            original.get(lorem).and_then(|i| i.contents()),
+            fs.get(lorem).and_then(|i| i.contents()),
+        );
+
+        // nothing in the demo can be merged within the limits of the v1 format
+        let bounded = sendstreams[0].coalesce_writes(u16::MAX.into());
+        assert_eq!(sendstreams[0], bounded);
+    }
+
+    #[test]
+    fn split_writes() {
+        let sendstreams = Sendstream::parse_all(include_bytes!("../testdata/demo.sendstream"))
+            .expect("failed to parse demo.sendstream");
+        let split = sendstreams[0].split_writes(4096);
+        assert!(writes_to(&split, "hello/lorem").all(|w| w.data().len() <= 4096));
+        assert!(
+            writes_to(&split, "hello/lorem").count()
+                > writes_to(&sendstreams[0], "hello/lorem").count()
+        );
+        let original = Filesystem::from_sendstream(&sendstreams[0]).expect("failed to replay");
+        let fs = Filesystem::from_sendstream(&split).expect("failed to replay");
+        for path in ["hello/lorem", "hello/lorem-reflinked"] {
+            assert_eq!(
+                original.get(Path::new(path)).and_then(|i| i.contents()),
+                fs.get(Path::new(path)).and_then(|i| i.contents()),
+            );
+        }
+
+        // uncompressed encoded writes are split too, compressed ones can not be
+        let encoded = |compression| EncodedWrite {
+            path: Cow::Borrowed(Path::new("hello/lorem")),
+            offset: FileOffset(0),
+            unencoded_file_len: 9000,
+            unencoded_len: 10000,
+            unencoded_offset: 1000,
+            compression,
+            encryption: 0,
+            data: Data(Cow::Owned((0..10000).map(|i| i as u8).collect())),
+        };
+        let stream = Sendstream {
+            commands: vec![
+                encoded(Compression::None).into(),
+                encoded(Compression::Zstd).into(),
+            ],
+        };
+        let split = stream.split_writes(4096);
+        assert_eq!(4, split.commands().len());
+        let mut joined = Vec::new();
+        for (cmd, len) in split.commands()[..3].iter().zip([4096, 4096, 808]) {
+            let Command::EncodedWrite(w) = cmd else {
+                panic!("{cmd:?} is not an encoded write");
+            };
+            assert_eq!(FileOffset(joined.len() as u64), w.offset());
+            assert_eq!(Some(len), w.decoded().map(<[u8]>::len));
+            joined.extend_from_slice(w.data());
+        }
+        assert_eq!(
+            encoded(Compression::None).decoded(),
+            Some(joined.as_slice())
+        );
+        assert_eq!(stream.commands()[1], split.commands()[3]);
+    }
+}
@@ -0,0 +1,572 @@
+//! Classification of xattr names, and typed decoders for the values of
+//! well-known xattrs.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::SetXattr;
+use crate::XattrName;
+
+/// Namespace of an xattr, which decides who can read and write it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum XattrNamespace {
+    User,
+    Trusted,
+    Security,
+    System,
+    /// Properties of btrfs itself, like `btrfs.compression`
+    Btrfs,
+}
+
+impl XattrNamespace {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Self::User => "user.",
+            Self::Trusted => "trusted.",
+            Self::Security => "security.",
+            Self::System => "system.",
+            Self::Btrfs => "btrfs.",
+        }
+    }
+}
+
+impl<'a> XattrName<'a> {
+    /// Namespace that this xattr is in, or `None` if it does not start with
+    /// one that Linux knows about
+    pub fn namespace(&self) -> Option<XattrNamespace> {
+        [
+            XattrNamespace::User,
+            XattrNamespace::Trusted,
+            XattrNamespace::Security,
+            XattrNamespace::System,
+            XattrNamespace::Btrfs,
+        ]
+        .into_iter()
+        .find(|ns| self.starts_with(ns.prefix().as_bytes()))
+    }
+
+    /// The rest of the name after the namespace prefix, like `selinux` for
+    /// `security.selinux`
+    pub fn sub_name(&self) -> Option<&[u8]> {
+        self.namespace()
+            .and_then(|ns| self.strip_prefix(ns.prefix().as_bytes()))
+    }
+
+    pub fn is_selinux(&self) -> bool {
+        self.as_slice() == b"security.selinux"
+    }
+
+    pub fn is_capability(&self) -> bool {
+        self.as_slice() == b"security.capability"
+    }
+
+    /// Whether this is either the access or default POSIX ACL
+    pub fn is_posix_acl(&self) -> bool {
+        matches!(
+            self.as_slice(),
+            b"system.posix_acl_access" | b"system.posix_acl_default"
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("xattr {actual:?} is not {expected}")]
+    WrongName {
+        expected: &'static str,
+        actual: String,
+    },
+    #[error("malformed {name}: {reason}")]
+    Malformed {
+        name: &'static str,
+        reason: &'static str,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, DecodeError>;
+
+fn wrong_name(x: &SetXattr, expected: &'static str) -> DecodeError {
+    DecodeError::WrongName {
+        expected,
+        actual: String::from_utf8_lossy(&x.name).into_owned(),
+    }
+}
+
+/// Fail with [DecodeError::WrongName] unless `x` sets the `expected` xattr
+fn expect_name(x: &SetXattr, expected: &'static str) -> Result<()> {
+    if x.name.as_slice() == expected.as_bytes() {
+        Ok(())
+    } else {
+        Err(wrong_name(x, expected))
+    }
+}
+
+/// SELinux security context, as stored in `security.selinux`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SelinuxLabel<'x> {
+    pub user: &'x str,
+    pub role: &'x str,
+    pub r#type: &'x str,
+    /// MLS/MCS sensitivity range (like `s0` or `s0-s0:c0.c1023`), which is
+    /// absent when the policy does not use MLS
+    pub level: Option<&'x str>,
+}
+
+impl<'x> SelinuxLabel<'x> {
+    /// Parse a context string, with or without the trailing NUL that the
+    /// kernel stores it with
+    pub fn parse(data: &'x [u8]) -> Result<Self> {
+        let malformed = |reason| DecodeError::Malformed {
+            name: "security.selinux",
+            reason,
+        };
+        let data = data.strip_suffix(b"\0").unwrap_or(data);
+        let label = std::str::from_utf8(data).map_err(|_| malformed("not utf-8"))?;
+        // the level contains colons of its own, so it is everything after the
+        // type
+        let mut parts = label.splitn(4, ':');
+        let mut next = || {
+            parts
+                .next()
+                .filter(|p| !p.is_empty())
+                .ok_or_else(|| malformed("expected user:role:type[:level]"))
+        };
+        Ok(Self {
+            user: next()?,
+            role: next()?,
+            r#type: next()?,
+            level: next().ok(),
+        })
+    }
+}
+
+impl Display for SelinuxLabel<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.user, self.role, self.r#type)?;
+        if let Some(level) = self.level {
+            write!(f, ":{level}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Names of the Linux capabilities, indexed by capability number
+static CAPABILITY_NAMES: &[&str] = &[
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// Names (in `getcap` style, like `cap_net_raw`) of every capability in
+/// `set`. Capabilities newer than this crate are named by number.
+pub fn capability_names(set: u64) -> Vec<Cow<'static, str>> {
+    (0..64)
+        .filter(|bit| set & (1 << bit) != 0)
+        .map(|bit| match CAPABILITY_NAMES.get(bit) {
+            Some(name) => Cow::Borrowed(*name),
+            None => Cow::Owned(format!("cap_{bit}")),
+        })
+        .collect()
+}
+
+/// File capabilities, as stored in `security.capability` (the kernel's
+/// `vfs_cap_data`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileCapabilities {
+    /// Revision of the on-disk format, 1 through 3. Revision 1 only has
+    /// room for the first 32 capabilities.
+    pub revision: u8,
+    pub permitted: u64,
+    pub inheritable: u64,
+    /// Whether the permitted capabilities are also made effective when the
+    /// file is executed
+    pub effective: bool,
+    /// Owner of the user namespace that the capabilities apply in (revision
+    /// 3 only)
+    pub rootid: Option<u32>,
+}
+
+impl FileCapabilities {
+    /// Parse a little-endian `vfs_cap_data`
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let malformed = |reason| DecodeError::Malformed {
+            name: "security.capability",
+            reason,
+        };
+        let words: Vec<u32> = data
+            .chunks(4)
+            .map(|w| w.try_into().map(u32::from_le_bytes))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| malformed("length is not a multiple of 4"))?;
+        let Some((magic, rest)) = words.split_first() else {
+            return Err(malformed("empty"));
+        };
+        let revision = (magic >> 24) as u8;
+        let (sets, rootid) = match (revision, rest.len()) {
+            (1, 2) | (2, 4) => (rest, None),
+            (3, 5) => (&rest[..4], Some(rest[4])),
+            (1..=3, _) => return Err(malformed("wrong length for its revision")),
+            _ => return Err(malformed("unknown revision")),
+        };
+        let (mut permitted, mut inheritable) = (0, 0);
+        // each pair of words holds 32 capabilities of the permitted set, then the
+        // same 32 of the inheritable set
+        for (word, pair) in sets.chunks(2).enumerate() {
+            permitted |= u64::from(pair[0]) << (32 * word);
+            inheritable |= u64::from(pair[1]) << (32 * word);
+        }
+        Ok(Self {
+            revision,
+            permitted,
+            inheritable,
+            effective: magic & 1 != 0,
+            rootid,
+        })
+    }
+
+    /// Capabilities that the process has in its effective set right after
+    /// executing the file, which are either all or none of `permitted`
+    pub fn effective_set(&self) -> u64 {
+        if self.effective {
+            self.permitted
+        } else {
+            0
+        }
+    }
+}
+
+/// Qualifier of an [AclEntry], saying who it applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AclTag {
+    /// The owner of the file
+    UserObj,
+    /// The user with this uid
+    User(u32),
+    /// The group of the file
+    GroupObj,
+    /// The group with this gid
+    Group(u32),
+    /// Upper bound on what any entry other than [AclTag::UserObj] and
+    /// [AclTag::Other] grants
+    Mask,
+    Other,
+}
+
+/// One entry of a [PosixAcl], granting `perms` (the `rwx` bits, 4, 2 and 1)
+/// to whoever `tag` matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perms: u8,
+}
+
+impl Display for AclEntry {
+    /// Format in the style of `getfacl`, like `user:1000:rw-`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.tag {
+            AclTag::UserObj => write!(f, "user::")?,
+            AclTag::User(uid) => write!(f, "user:{uid}:")?,
+            AclTag::GroupObj => write!(f, "group::")?,
+            AclTag::Group(gid) => write!(f, "group:{gid}:")?,
+            AclTag::Mask => write!(f, "mask::")?,
+            AclTag::Other => write!(f, "other::")?,
+        }
+        for (bit, c) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            write!(f, "{}", if self.perms & bit != 0 { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// POSIX ACL, as stored in `system.posix_acl_access` (the ACL of the file
+/// itself) or `system.posix_acl_default` (the ACL that new files in a
+/// directory inherit)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PosixAcl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl PosixAcl {
+    /// Parse the kernel's little-endian xattr format: a version number
+    /// followed by an 8-byte tag, perms and id for each entry
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let malformed = |reason| DecodeError::Malformed {
+            name: "POSIX ACL",
+            reason,
+        };
+        let (version, entries) = data
+            .split_first_chunk::<4>()
+            .ok_or_else(|| malformed("empty"))?;
+        if u32::from_le_bytes(*version) != 2 {
+            return Err(malformed("unknown version"));
+        }
+        if !entries.len().is_multiple_of(8) {
+            return Err(malformed("truncated entry"));
+        }
+        let entries = entries
+            .chunks(8)
+            .map(|e| {
+                let id = u32::from_le_bytes([e[4], e[5], e[6], e[7]]);
+                let tag = match u16::from_le_bytes([e[0], e[1]]) {
+                    0x01 => AclTag::UserObj,
+                    0x02 => AclTag::User(id),
+                    0x04 => AclTag::GroupObj,
+                    0x08 => AclTag::Group(id),
+                    0x10 => AclTag::Mask,
+                    0x20 => AclTag::Other,
+                    _ => return Err(malformed("unknown tag")),
+                };
+                let perms = u16::from_le_bytes([e[2], e[3]]);
+                if perms > 0o7 {
+                    return Err(malformed("unknown permission bits"));
+                }
+                Ok(AclEntry {
+                    tag,
+                    perms: perms as u8,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+impl Display for PosixAcl {
+    /// Format in the style of `getfacl`, one entry per line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for e in &self.entries {
+            writeln!(f, "{e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> SetXattr<'a> {
+    /// Decode the value of a `security.selinux` xattr
+    pub fn as_selinux_label(&self) -> Result<SelinuxLabel<'_>> {
+        expect_name(self, "security.selinux")?;
+        SelinuxLabel::parse(&self.data)
+    }
+
+    /// Decode the value of a `security.capability` xattr
+    pub fn as_capabilities(&self) -> Result<FileCapabilities> {
+        expect_name(self, "security.capability")?;
+        FileCapabilities::parse(&self.data)
+    }
+
+    /// Decode the value of a `system.posix_acl_access` or
+    /// `system.posix_acl_default` xattr
+    pub fn as_posix_acl(&self) -> Result<PosixAcl> {
+        match self.name.as_slice() {
+            b"system.posix_acl_access" | b"system.posix_acl_default" => PosixAcl::parse(&self.data),
+            _ => Err(wrong_name(
+                self,
+                "system.posix_acl_access or system.posix_acl_default",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::XattrData;
+
+    fn set_xattr(name: &'static [u8], data: &'static [u8]) -> SetXattr<'static> {
+        SetXattr {
+            path: Cow::Borrowed(Path::new("f")),
+            name: XattrName(Cow::Borrowed(name)),
+            data: XattrData(Cow::Borrowed(data)),
+        }
+    }
+
+    #[test]
+    fn selinux_label() {
+        let x = set_xattr(
+            b"security.selinux",
+            b"system_u:object_r:bin_t:s0-s0:c0.c1023\0",
+        );
+        let label = x.as_selinux_label().expect("valid label");
+        assert_eq!(
+            SelinuxLabel {
+                user: "system_u",
+                role: "object_r",
+                r#type: "bin_t",
+                level: Some("s0-s0:c0.c1023"),
+            },
+            label
+        );
+        assert_eq!("system_u:object_r:bin_t:s0-s0:c0.c1023", label.to_string());
+        assert_eq!(Ok(None), SelinuxLabel::parse(b"u:r:t").map(|l| l.level));
+        assert!(matches!(
+            SelinuxLabel::parse(b"u:r"),
+            Err(DecodeError::Malformed { .. })
+        ));
+        assert!(matches!(
+            set_xattr(b"user.foo", b"u:r:t").as_selinux_label(),
+            Err(DecodeError::WrongName { .. })
+        ));
+    }
+
+    #[test]
+    fn capabilities() {
+        // cap_net_bind_service,cap_net_raw=ep
+        let x = set_xattr(
+            b"security.capability",
+            b"\x01\x00\x00\x02\x00\x24\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        );
+        let caps = x.as_capabilities().expect("valid capabilities");
+        assert_eq!(
+            FileCapabilities {
+                revision: 2,
+                permitted: 1 << 10 | 1 << 13,
+                inheritable: 0,
+                effective: true,
+                rootid: None,
+            },
+            caps
+        );
+        assert_eq!(caps.permitted, caps.effective_set());
+        assert_eq!(
+            vec!["cap_net_bind_service", "cap_net_raw"],
+            capability_names(caps.permitted)
+        );
+        assert_eq!(
+            vec!["cap_bpf", "cap_63"],
+            capability_names(1 << 39 | 1 << 63)
+        );
+
+        // cap_sys_admin+i in a user namespace owned by uid 100000
+        let mut v3 = b"\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\x20\x00".to_vec();
+        v3.extend([0; 8]);
+        v3.extend(100000u32.to_le_bytes());
+        let caps = FileCapabilities::parse(&v3).expect("valid capabilities");
+        assert_eq!(1 << 21, caps.inheritable);
+        assert_eq!(0, caps.effective_set());
+        assert_eq!(Some(100000), caps.rootid);
+
+        assert!(matches!(
+            FileCapabilities::parse(&v3[..20]),
+            Err(DecodeError::Malformed { .. })
+        ));
+        assert!(matches!(
+            FileCapabilities::parse(b"\x00\x00\x00\x09"),
+            Err(DecodeError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn posix_acl() {
+        let mut data = 2u32.to_le_bytes().to_vec();
+        for (tag, perms, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 5, 1000),
+            (0x04, 4, u32::MAX),
+            (0x10, 7, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            data.extend(tag.to_le_bytes());
+            data.extend(perms.to_le_bytes());
+            data.extend(id.to_le_bytes());
+        }
+        let x = SetXattr {
+            path: Cow::Borrowed(Path::new("f")),
+            name: XattrName(Cow::Borrowed(b"system.posix_acl_access")),
+            data: XattrData(Cow::Owned(data.clone())),
+        };
+        let acl = x.as_posix_acl().expect("valid acl");
+        assert_eq!(
+            AclEntry {
+                tag: AclTag::User(1000),
+                perms: 5,
+            },
+            acl.entries[1]
+        );
+        assert_eq!(
+            "user::rw-\nuser:1000:r-x\ngroup::r--\nmask::rwx\nother::---\n",
+            acl.to_string()
+        );
+        assert!(matches!(
+            PosixAcl::parse(&data[..data.len() - 1]),
+            Err(DecodeError::Malformed { .. })
+        ));
+        assert!(matches!(
+            set_xattr(b"system.posix_acl_foo", b"").as_posix_acl(),
+            Err(DecodeError::WrongName { .. })
+        ));
+    }
+
+    #[test]
+    fn namespace() {
+        let name = |n: &'static [u8]| XattrName(Cow::Borrowed(n));
+        assert_eq!(
+            Some(XattrNamespace::Security),
+            name(b"security.selinux").namespace()
+        );
+        assert_eq!(
+            Some(b"antlir.demo".as_slice()),
+            name(b"user.antlir.demo").sub_name()
+        );
+        assert_eq!(
+            Some(XattrNamespace::Btrfs),
+            name(b"btrfs.compression").namespace()
+        );
+        assert_eq!(None, name(b"users.oops").namespace());
+        assert_eq!(None, name(b"users.oops").sub_name());
+        assert!(name(b"security.selinux").is_selinux());
+        assert!(!name(b"security.selinux").is_capability());
+        assert!(name(b"security.capability").is_capability());
+        assert!(name(b"system.posix_acl_default").is_posix_acl());
+        assert!(!name(b"user.posix_acl_access").is_posix_acl());
+    }
+}